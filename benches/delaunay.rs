@@ -0,0 +1,53 @@
+//! Compares the adjacency based Delaunay tetrahedralization with the Voronoi diagram based one it replaced.
+//! Run with `cargo bench --features bench`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use tri_mesh::prelude::*;
+use voxelization::tetrahedralize::DelaunayTetrahedralization;
+use voxelization::voronoi_tetrahedralization::VoronoiTetrahedralization;
+
+/// Points scattered through a unit cube, in the same order every run
+fn scattered_points(count: usize) -> Vec<Vec3> {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut next = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state >> 11) as f64 / (1u64 << 53) as f64
+    };
+    (0..count).map(|_| vec3(next(), next(), next())).collect()
+}
+
+/// Corners of a grid, like the vertices of a voxelized part. Very degenerate.
+fn grid_points(side: usize) -> Vec<Vec3> {
+    (0..side)
+        .flat_map(|z| (0..side).flat_map(move |y| (0..side).map(move |x| vec3(x as f64, y as f64, z as f64))))
+        .map(|p| p * 0.1)
+        .collect()
+}
+
+fn bench_tetrahedralize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tetrahedralize");
+    group.sample_size(10);
+
+    let inputs = vec![
+        ("scattered", 100, scattered_points(100)),
+        ("scattered", 1000, scattered_points(1000)),
+        ("grid", 125, grid_points(5)),
+        ("grid", 1000, grid_points(10)),
+    ];
+
+    for (name, count, points) in inputs {
+        group.bench_with_input(BenchmarkId::new(format!("adjacency_{}", name), count), &points, |b, points| {
+            b.iter(|| DelaunayTetrahedralization::new(points.clone()))
+        });
+        group.bench_with_input(BenchmarkId::new(format!("voronoi_{}", name), count), &points, |b, points| {
+            b.iter(|| VoronoiTetrahedralization::new(points.clone()))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_tetrahedralize);
+criterion_main!(benches);
@@ -0,0 +1,462 @@
+//! Constrained Delaunay triangulation of points in the plane,
+//! where some edges (constraints) have to be in the triangulation.
+//! Every other edge is locally Delaunay, which maximizes the smallest angle
+//! among triangulations with those edges.
+
+use float_ord::FloatOrd;
+use fnv::{FnvHashMap, FnvHashSet};
+use stable_vec::StableVec;
+use std::collections::VecDeque;
+use tri_mesh::prelude::*;
+
+use crate::predicates;
+use crate::triangulate::TriangulateError;
+use crate::util::{ArrayEx, Vec2};
+
+/// Whether 2 numbers are nonzero with opposite signs
+pub(crate) fn opposite_signs(a: f64, b: f64) -> bool {
+    (a > 0.0 && b < 0.0) || (a < 0.0 && b > 0.0)
+}
+
+#[derive(Clone, Debug)]
+pub struct ConstrainedDelaunay {
+    /// The last 3 points are the corners of a big triangle around the rest
+    points: Vec<Vec2>,
+    /// Counterclockwise
+    triangles: StableVec<[usize; 3]>,
+    /// Maps each directed edge to the triangle that has it going counterclockwise
+    edges: FnvHashMap<[usize; 2], usize>,
+    /// Sorted
+    constraints: FnvHashSet<[usize; 2]>,
+    last_triangle: usize,
+}
+
+impl ConstrainedDelaunay {
+    /// Delaunay triangulates points. The points must be distinct.
+    /// Fails if a point isn't finite.
+    pub fn new(points: Vec<Vec2>) -> Result<Self, TriangulateError> {
+        if points.iter().any(|p| !p.x.is_finite() || !p.y.is_finite()) {
+            return Err(TriangulateError::NonFiniteVertex);
+        }
+        let num_points = points.len();
+        let (min, max) = match points.first() {
+            Some(first) => points.iter().fold((*first, *first), |(min, max), p| {
+                (vec2(min.x.min(p.x), min.y.min(p.y)), vec2(max.x.max(p.x), max.y.max(p.y)))
+            }),
+            None => (vec2(0.0, 0.0), vec2(0.0, 0.0)),
+        };
+        let center = (min + max) / 2.0;
+        let size = (max - min).x.max((max - min).y);
+        let size = if size > 0.0 { size } else { 1.0 };
+
+        let mut points = points;
+        points.push(center + vec2(-20.0, -10.0) * size);
+        points.push(center + vec2(20.0, -10.0) * size);
+        points.push(center + vec2(0.0, 20.0) * size);
+
+        let mut cdt = Self {
+            points,
+            triangles: StableVec::new(),
+            edges: FnvHashMap::default(),
+            constraints: FnvHashSet::default(),
+            last_triangle: 0,
+        };
+        cdt.last_triangle = cdt.add_triangle([num_points, num_points + 1, num_points + 2]);
+
+        for i in 0..num_points {
+            cdt.insert_point(i)?;
+        }
+        Ok(cdt)
+    }
+
+    /// Number of points, not counting the corners of the big triangle
+    pub fn num_points(&self) -> usize {
+        self.points.len() - 3
+    }
+
+    pub fn points(&self) -> &[Vec2] {
+        &self.points[..self.num_points()]
+    }
+
+    /// Triangles that don't touch the big triangle, counterclockwise.
+    /// These cover the convex hull of the points, unless a constraint is missing from the hull.
+    pub fn triangles<'a>(&'a self) -> impl Iterator<Item = [usize; 3]> + 'a {
+        let n = self.num_points();
+        self.triangles.values().copied().filter(move |tri| tri.iter().all(|v| *v < n))
+    }
+
+    fn positions(&self, tri: [usize; 3]) -> [Vec2; 3] {
+        [self.points[tri[0]], self.points[tri[1]], self.points[tri[2]]]
+    }
+
+    fn add_triangle(&mut self, tri: [usize; 3]) -> usize {
+        let index = self.triangles.push(tri);
+        for i in 0..3 {
+            self.edges.insert([tri[i], tri[(i + 1) % 3]], index);
+        }
+        index
+    }
+
+    fn remove_triangle(&mut self, index: usize) {
+        let tri = self.triangles.remove(index).unwrap();
+        for i in 0..3 {
+            self.edges.remove(&[tri[i], tri[(i + 1) % 3]]);
+        }
+    }
+
+    /// Triangle with a directed edge, and the vertex of it opposite the edge
+    fn opposite_vertex(&self, edge: [usize; 2]) -> Option<(usize, usize)> {
+        let index = *self.edges.get(&edge)?;
+        let tri = self.triangles[index];
+        let i = (0..3).find(|i| tri[*i] == edge[0]).unwrap();
+        Some((index, tri[(i + 2) % 3]))
+    }
+
+    /// Finds a triangle containing a point by walking toward it.
+    /// Fails if no triangle contains it, which only happens for points outside the big triangle.
+    fn locate(&self, point: Vec2) -> Result<usize, TriangulateError> {
+        let mut current = if self.triangles.get(self.last_triangle).is_some() {
+            self.last_triangle
+        } else {
+            self.triangles.indices().next().unwrap()
+        };
+
+        for _ in 0..self.triangles.num_elements() {
+            let tri = self.triangles[current];
+            let next = (0..3).find_map(|i| {
+                let [a, b] = [tri[i], tri[(i + 1) % 3]];
+                if predicates::orient2d(self.points[a], self.points[b], point) < 0.0 {
+                    self.edges.get(&[b, a]).copied()
+                } else {
+                    None
+                }
+            });
+
+            match next {
+                Some(next) => current = next,
+                None => return Ok(current),
+            }
+        }
+
+        // The walk went in circles, so check every triangle
+        self.triangles
+            .iter()
+            .find(|(_, tri)| {
+                (0..3).all(|i| {
+                    predicates::orient2d(self.points[tri[i]], self.points[tri[(i + 1) % 3]], point) >= 0.0
+                })
+            })
+            .map(|(i, _)| i)
+            .ok_or(TriangulateError::PointNotLocated)
+    }
+
+    /// Inserts a point with Bowyer-Watson. There are no constraints yet.
+    fn insert_point(&mut self, index: usize) -> Result<(), TriangulateError> {
+        let point = self.points[index];
+        let start = self.locate(point)?;
+
+        // Triangles whose circumcircles contain the point
+        let mut cavity = FnvHashSet::default();
+        cavity.insert(start);
+        let mut to_search = vec![start];
+        let mut boundary = vec![];
+
+        while let Some(t) = to_search.pop() {
+            let tri = self.triangles[t];
+            for i in 0..3 {
+                let edge = [tri[i], tri[(i + 1) % 3]];
+                match self.edges.get(&[edge[1], edge[0]]).copied() {
+                    Some(n) if cavity.contains(&n) => {}
+                    Some(n) if {
+                        let [a, b, c] = self.positions(self.triangles[n]);
+                        predicates::incircle(a, b, c, point) > 0.0
+                    } => {
+                        cavity.insert(n);
+                        to_search.push(n);
+                    }
+                    _ => boundary.push(edge),
+                }
+            }
+        }
+
+        for t in cavity {
+            self.remove_triangle(t);
+        }
+        for [a, b] in boundary {
+            self.last_triangle = self.add_triangle([a, b, index]);
+        }
+        Ok(())
+    }
+
+    /// Flips an edge between 2 triangles that form a strictly convex quadrilateral.
+    /// Returns the new edge, or None if the flip can't happen.
+    /// Constraints never get flipped.
+    fn flip(&mut self, [a, b]: [usize; 2]) -> Option<[usize; 2]> {
+        if self.constraints.contains(&[a, b].sorted()) {
+            return None;
+        }
+        let (t1, c) = self.opposite_vertex([a, b])?;
+        let (t2, d) = self.opposite_vertex([b, a])?;
+
+        let [pa, pb, pc] = self.positions([a, b, c]);
+        let pd = self.points[d];
+        if predicates::orient2d(pa, pd, pc) <= 0.0 || predicates::orient2d(pd, pb, pc) <= 0.0 {
+            return None;
+        }
+
+        self.remove_triangle(t1);
+        self.remove_triangle(t2);
+        self.add_triangle([a, d, c]);
+        self.last_triangle = self.add_triangle([d, b, c]);
+        Some([c, d].sorted())
+    }
+
+    /// Makes an edge between 2 points part of the triangulation.
+    /// If the edge goes through other points, it gets split at them.
+    /// Fails if the edge crosses a constraint that's already there.
+    /// Returns the pieces the edge got split into, in order from `edge[0]` to `edge[1]`.
+    pub fn insert_constraint(&mut self, edge: [usize; 2]) -> Result<Vec<[usize; 2]>, TriangulateError> {
+        let [u, v] = edge;
+        let (pu, pv) = (self.points[u], self.points[v]);
+
+        let mut on_edge = (0..self.num_points())
+            .filter(|w| *w != u && *w != v)
+            .filter(|w| {
+                let pw = self.points[*w];
+                predicates::orient2d(pu, pv, pw) == 0.0 && (pw - pu).dot(pv - pu) > 0.0 && (pw - pv).dot(pu - pv) > 0.0
+            })
+            .collect::<Vec<_>>();
+        on_edge.sort_by_key(|w| FloatOrd((self.points[*w] - pu).magnitude2()));
+
+        let stops = std::iter::once(u).chain(on_edge).chain(std::iter::once(v)).collect::<Vec<_>>();
+        let pieces = stops.windows(2).map(|w| [w[0], w[1]]).collect::<Vec<_>>();
+        for piece in &pieces {
+            self.insert_segment(*piece)?;
+        }
+        Ok(pieces)
+    }
+
+    /// Inserts a constraint that doesn't go through any points, by flipping away edges that cross it (Sloan's method).
+    fn insert_segment(&mut self, [u, v]: [usize; 2]) -> Result<(), TriangulateError> {
+        if u == v {
+            return Ok(());
+        }
+        if self.edges.contains_key(&[u, v]) || self.edges.contains_key(&[v, u]) {
+            self.constraints.insert([u, v].sorted());
+            return Ok(());
+        }
+
+        let (pu, pv) = (self.points[u], self.points[v]);
+        let crosses = |points: &[Vec2], [a, b]: [usize; 2]| {
+            let (pa, pb) = (points[a], points[b]);
+            opposite_signs(predicates::orient2d(pu, pv, pa), predicates::orient2d(pu, pv, pb))
+                && opposite_signs(predicates::orient2d(pa, pb, pu), predicates::orient2d(pa, pb, pv))
+        };
+
+        let mut crossing = self
+            .edges
+            .keys()
+            .filter(|[a, b]| a < b && crosses(&self.points, [*a, *b]))
+            .copied()
+            .collect::<VecDeque<_>>();
+        // Edges of triangles made by flips, which might not be locally Delaunay
+        let mut to_check = vec![];
+
+        // Edges that can't be flipped yet go to the back of the line.
+        // Going through the whole line without a flip means a crossing edge is a constraint.
+        let mut failed_flips = 0;
+        while let Some(edge) = crossing.pop_front() {
+            match self.flip(edge) {
+                Some(new_edge) => {
+                    failed_flips = 0;
+                    let [a, b] = edge;
+                    let [c, d] = new_edge;
+                    to_check.extend(vec![[a, c], [a, d], [b, c], [b, d]]);
+
+                    if crosses(&self.points, new_edge) {
+                        crossing.push_back(new_edge);
+                    } else {
+                        to_check.push(new_edge);
+                    }
+                }
+                None => {
+                    crossing.push_back(edge);
+                    failed_flips += 1;
+                    if failed_flips >= crossing.len() {
+                        return Err(TriangulateError::CrossingConstraints);
+                    }
+                }
+            }
+        }
+
+        self.constraints.insert([u, v].sorted());
+        self.make_delaunay(to_check);
+        Ok(())
+    }
+
+    /// Flips edges that aren't locally Delaunay, starting with some edges
+    /// and continuing with the edges around flipped ones
+    fn make_delaunay(&mut self, edges: Vec<[usize; 2]>) {
+        let mut to_check = edges;
+
+        while let Some([a, b]) = to_check.pop() {
+            if self.constraints.contains(&[a, b].sorted()) {
+                continue;
+            }
+            let (c, d) = match (self.opposite_vertex([a, b]), self.opposite_vertex([b, a])) {
+                (Some((_, c)), Some((_, d))) => (c, d),
+                _ => continue,
+            };
+
+            let [pa, pb, pc] = self.positions([a, b, c]);
+            if predicates::incircle(pa, pb, pc, self.points[d]) > 0.0 && self.flip([a, b]).is_some() {
+                to_check.extend(vec![[a, d], [d, b], [b, c], [c, a]]);
+            }
+        }
+    }
+
+    /// Triangles enclosed by directed boundary edges that have the inside on their left,
+    /// such as the pieces returned by `insert_constraint`.
+    /// Triangles are counterclockwise and sorted.
+    pub fn enclosed_triangles(&self, boundary: &[[usize; 2]]) -> Vec<[usize; 3]> {
+        let mut inside = FnvHashSet::default();
+        let mut to_search = boundary.iter().filter_map(|edge| self.edges.get(edge).copied()).collect::<Vec<_>>();
+
+        // Spread to neighbors, but not across constraints
+        while let Some(t) = to_search.pop() {
+            if !inside.insert(t) {
+                continue;
+            }
+            let tri = self.triangles[t];
+            for i in 0..3 {
+                let [a, b] = [tri[i], tri[(i + 1) % 3]];
+                if !self.constraints.contains(&[a, b].sorted()) {
+                    to_search.extend(self.edges.get(&[b, a]).copied());
+                }
+            }
+        }
+
+        let n = self.num_points();
+        let mut triangles = inside
+            .into_iter()
+            .map(|t| self.triangles[t])
+            .filter(|tri| tri.iter().all(|v| *v < n))
+            .collect::<Vec<_>>();
+        triangles.sort();
+        triangles
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Checks that triangles are counterclockwise and that edges that aren't constraints are locally Delaunay
+    fn assert_constrained_delaunay(cdt: &ConstrainedDelaunay) {
+        for (_, tri) in cdt.triangles.iter() {
+            let [a, b, c] = cdt.positions(*tri);
+            assert!(predicates::orient2d(a, b, c) > 0.0);
+        }
+
+        for [a, b] in cdt.edges.keys() {
+            if cdt.constraints.contains(&[*a, *b].sorted()) {
+                continue;
+            }
+            if let (Some((_, c)), Some((_, d))) = (cdt.opposite_vertex([*a, *b]), cdt.opposite_vertex([*b, *a])) {
+                let [pa, pb, pc] = cdt.positions([*a, *b, c]);
+                assert!(predicates::incircle(pa, pb, pc, cdt.points[d]) <= 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_delaunay_square() {
+        let cdt = ConstrainedDelaunay::new(vec![
+            vec2(0.0, 0.0),
+            vec2(1.0, 0.0),
+            vec2(1.0, 1.0),
+            vec2(0.0, 1.0),
+            vec2(0.5, 0.5),
+        ])
+        .unwrap();
+
+        assert_constrained_delaunay(&cdt);
+        let mut triangles = cdt.triangles().map(|tri| tri.sorted()).collect::<Vec<_>>();
+        triangles.sort();
+        assert_eq!(triangles, vec![[0, 1, 4], [0, 3, 4], [1, 2, 4], [2, 3, 4]]);
+    }
+
+    #[test]
+    fn test_constraint() {
+        // The Delaunay triangulation of a flat diamond uses the short diagonal
+        let mut cdt = ConstrainedDelaunay::new(vec![
+            vec2(0.0, 0.0),
+            vec2(4.0, 0.0),
+            vec2(2.0, 1.0),
+            vec2(2.0, -1.0),
+            vec2(1.0, 3.0),
+        ])
+        .unwrap();
+        assert!(cdt.edges.contains_key(&[2, 3]) || cdt.edges.contains_key(&[3, 2]));
+
+        assert_eq!(cdt.insert_constraint([0, 1]), Ok(vec![[0, 1]]));
+        assert!(cdt.edges.contains_key(&[0, 1]) && cdt.edges.contains_key(&[1, 0]));
+        assert_constrained_delaunay(&cdt);
+
+        let boundary = vec![[0, 3], [3, 1], [1, 4], [4, 0]];
+        for edge in &boundary[1..] {
+            cdt.insert_constraint(*edge).unwrap();
+        }
+        cdt.insert_constraint(boundary[0]).unwrap();
+        let area = cdt
+            .enclosed_triangles(&boundary)
+            .into_iter()
+            .map(|tri| {
+                let [a, b, c] = cdt.positions(tri);
+                (b - a).perp_dot(c - a) / 2.0
+            })
+            .sum::<f64>();
+        assert!((area - 8.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_constraint_through_point() {
+        let mut cdt = ConstrainedDelaunay::new(vec![
+            vec2(0.0, 0.0),
+            vec2(2.0, 0.0),
+            vec2(4.0, 0.0),
+            vec2(2.0, 1.0),
+            vec2(2.0, -1.0),
+        ])
+        .unwrap();
+
+        assert_eq!(cdt.insert_constraint([0, 2]), Ok(vec![[0, 1], [1, 2]]));
+        assert_constrained_delaunay(&cdt);
+    }
+
+    #[test]
+    fn test_non_finite_point() {
+        assert_eq!(
+            ConstrainedDelaunay::new(vec![vec2(0.0, 0.0), vec2(1.0, 0.0), vec2(f64::INFINITY, 1.0)]).unwrap_err(),
+            TriangulateError::NonFiniteVertex
+        );
+    }
+
+    #[test]
+    fn test_crossing_constraints() {
+        // The Delaunay triangulation of a flat diamond uses the short diagonal
+        let mut cdt = ConstrainedDelaunay::new(vec![
+            vec2(0.0, 0.0),
+            vec2(4.0, 0.0),
+            vec2(2.0, 1.0),
+            vec2(2.0, -1.0),
+        ])
+        .unwrap();
+        assert_eq!(cdt.insert_constraint([2, 3]), Ok(vec![[2, 3]]));
+
+        // The long diagonal crosses it
+        assert_eq!(cdt.insert_constraint([0, 1]), Err(TriangulateError::CrossingConstraints));
+        assert!(cdt.edges.contains_key(&[2, 3]) || cdt.edges.contains_key(&[3, 2]));
+        assert_constrained_delaunay(&cdt);
+    }
+}
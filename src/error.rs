@@ -0,0 +1,95 @@
+//! Errors that can happen while voxelizing.
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use tri_mesh::mesh_builder;
+
+use crate::tetrahedralize::TetError;
+use crate::triangulate::TriangulateError;
+use crate::validation::MeshValidation;
+use crate::voxels::Vec3i;
+
+#[derive(Debug)]
+pub enum VoxelizationError {
+    /// A mesh could not be built, usually because it isn't manifold
+    Mesh(mesh_builder::Error),
+    /// Tetrahedralizing the inside of a voxel failed
+    Tet(TetError),
+    /// Triangulating a face of a voxel failed
+    Triangulate(TriangulateError),
+    /// The mesh's connectivity broke while slicing it, usually because it isn't closed
+    Topology(&'static str),
+    /// Material IDs start at 1
+    ZeroMaterialID,
+    /// Reading an input file failed
+    Io(io::Error),
+    /// An input file is malformed
+    Parse(String),
+    /// The input mesh failed validation
+    InvalidMesh(MeshValidation),
+    /// Something went wrong while processing the voxel at this position in grid coordinates
+    AtVoxel(Vec3i, Box<VoxelizationError>),
+    /// A voxelization option is out of range
+    InvalidOptions(String),
+}
+
+impl VoxelizationError {
+    /// Attaches the position of the voxel being processed
+    pub fn at_voxel(self, pos: Vec3i) -> Self {
+        VoxelizationError::AtVoxel(pos, Box::new(self))
+    }
+}
+
+impl Display for VoxelizationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            VoxelizationError::Mesh(error) => write!(f, "invalid mesh: {:?}", error),
+            VoxelizationError::Tet(error) => write!(f, "tetrahedralization failed: {:?}", error),
+            VoxelizationError::Triangulate(error) => write!(f, "triangulation failed: {:?}", error),
+            VoxelizationError::Topology(message) => write!(f, "invalid mesh topology: {}", message),
+            VoxelizationError::ZeroMaterialID => write!(f, "material ID can't be 0"),
+            VoxelizationError::Io(error) => write!(f, "{}", error),
+            VoxelizationError::Parse(message) => write!(f, "{}", message),
+            VoxelizationError::InvalidMesh(validation) => write!(f, "invalid input mesh: {}", validation),
+            VoxelizationError::AtVoxel(pos, error) => {
+                write!(f, "at voxel ({}, {}, {}): {}", pos.x, pos.y, pos.z, error)
+            }
+            VoxelizationError::InvalidOptions(message) => write!(f, "invalid options: {}", message),
+        }
+    }
+}
+
+impl Error for VoxelizationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            VoxelizationError::AtVoxel(_, error) => Some(error.as_ref()),
+            VoxelizationError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<mesh_builder::Error> for VoxelizationError {
+    fn from(error: mesh_builder::Error) -> Self {
+        VoxelizationError::Mesh(error)
+    }
+}
+
+impl From<io::Error> for VoxelizationError {
+    fn from(error: io::Error) -> Self {
+        VoxelizationError::Io(error)
+    }
+}
+
+impl From<TetError> for VoxelizationError {
+    fn from(error: TetError) -> Self {
+        VoxelizationError::Tet(error)
+    }
+}
+
+impl From<TriangulateError> for VoxelizationError {
+    fn from(error: TriangulateError) -> Self {
+        VoxelizationError::Triangulate(error)
+    }
+}
@@ -7,9 +7,27 @@ extern crate tri_mesh;
 extern crate combination;
 extern crate bvh;
 
+pub mod cdt;
+pub mod error;
 pub mod material_mesh;
+pub mod material_table;
 pub mod plc;
+pub mod predicates;
+pub mod spatial_sort;
+pub mod statistics;
+pub mod stl;
+pub mod surface;
+pub mod tet_export;
+pub mod tet_mesh;
+pub mod tet_quality;
 pub mod tetrahedralize;
+pub mod three_mf;
 pub mod triangulate;
 pub mod util;
+pub mod validation;
+pub mod vox;
+/// The Voronoi diagram based Delaunay tetrahedralization that `tetrahedralize` replaced,
+/// kept only as a baseline for `benches/delaunay.rs`
+#[cfg(feature = "bench")]
+pub mod voronoi_tetrahedralization;
 pub mod voxels;
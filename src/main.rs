@@ -1,11 +1,302 @@
+use std::env;
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use voxelization::error::VoxelizationError;
 use voxelization::material_mesh::MaterialMesh;
-use voxelization::voxels::Voxels;
+use voxelization::validation::RepairOptions;
+use voxelization::vox::ComplexVoxelPolicy;
+use voxelization::voxels::{VoxelizationOptions, Voxels};
+
+const USAGE: &str = "\
+Usage: voxelization --input <file.obj> --output <file> [options]
+
+Options:
+    -i, --input <path>        OBJ file to voxelize
+    -o, --output <path>       Where to write the result
+    -s, --voxel-size <size>   Edge length of a voxel in mesh units (default 1)
+    -m, --materials <mode>    How to read materials from the OBJ: single or multi (default multi)
+    -f, --format <format>     Output format: vxlz, vox, surface-obj, or debug-obj
+                              (default: chosen from the output extension)
+    -j, --threads <count>     Number of threads to use (default: one per core)
+        --fail-on-fallback    Fail if a complex voxel can't be tetrahedralized
+                              instead of approximating it with a convex hull
+        --validate            Fail if the mesh has holes, flipped faces, or other problems
+        --repair              Weld vertices, fill small holes, and fix orientation first
+    -h, --help                Print this message";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum MaterialMode {
+    /// Every face gets material 1
+    Single,
+    /// Materials come from `usemtl` statements
+    Multi,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Format {
+    /// Binary voxel format, see `Voxels::save`
+    Vxlz,
+    /// MagicaVoxel
+    Vox,
+    /// Welded surface around each material
+    SurfaceObj,
+    /// Cubes and complex voxel boundaries for debugging
+    DebugObj,
+}
+
+impl Format {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "vxlz" => Ok(Format::Vxlz),
+            "vox" => Ok(Format::Vox),
+            "surface-obj" => Ok(Format::SurfaceObj),
+            "debug-obj" => Ok(Format::DebugObj),
+            _ => Err(format!("Unknown format '{}'", name)),
+        }
+    }
+
+    fn from_extension(path: &Path) -> Result<Self, String> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("vxlz") => Ok(Format::Vxlz),
+            Some("vox") => Ok(Format::Vox),
+            Some("obj") => Ok(Format::SurfaceObj),
+            _ => Err(format!(
+                "Cannot tell the output format from '{}'; pass --format",
+                path.display()
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Args {
+    input: PathBuf,
+    output: PathBuf,
+    voxel_size: f64,
+    materials: MaterialMode,
+    format: Format,
+    threads: Option<usize>,
+    fail_on_fallback: bool,
+    validate: bool,
+    repair: bool,
+}
+
+impl Args {
+    /// Parses arguments, not including the program name.
+    /// Returns Ok(None) if help was requested.
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Option<Self>, String> {
+        let mut input = None;
+        let mut output = None;
+        let mut voxel_size = 1.0;
+        let mut materials = MaterialMode::Multi;
+        let mut format = None;
+        let mut threads = None;
+        let mut fail_on_fallback = false;
+        let mut validate = false;
+        let mut repair = false;
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("Missing value for {}", arg));
+
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "-i" | "--input" => input = Some(PathBuf::from(value()?)),
+                "-o" | "--output" => output = Some(PathBuf::from(value()?)),
+                "-s" | "--voxel-size" => {
+                    let value = value()?;
+                    voxel_size = value
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|size| *size > 0.0 && size.is_finite())
+                        .ok_or_else(|| format!("Invalid voxel size '{}'", value))?;
+                }
+                "-m" | "--materials" => {
+                    materials = match value()?.as_str() {
+                        "single" => MaterialMode::Single,
+                        "multi" => MaterialMode::Multi,
+                        mode => return Err(format!("Unknown material mode '{}'", mode)),
+                    }
+                }
+                "-f" | "--format" => format = Some(Format::parse(&value()?)?),
+                "-j" | "--threads" => {
+                    let value = value()?;
+                    threads = Some(
+                        value
+                            .parse::<usize>()
+                            .ok()
+                            .filter(|threads| *threads > 0)
+                            .ok_or_else(|| format!("Invalid thread count '{}'", value))?,
+                    );
+                }
+                "--fail-on-fallback" => fail_on_fallback = true,
+                "--validate" => validate = true,
+                "--repair" => repair = true,
+                _ => return Err(format!("Unknown argument '{}'", arg)),
+            }
+        }
+
+        let input = input.ok_or("Missing --input")?;
+        let output = output.ok_or("Missing --output")?;
+        let format = match format {
+            Some(format) => format,
+            None => Format::from_extension(&output)?,
+        };
+
+        Ok(Some(Self {
+            input,
+            output,
+            voxel_size,
+            materials,
+            format,
+            threads,
+            fail_on_fallback,
+            validate,
+            repair,
+        }))
+    }
+}
+
+fn run(args: Args) -> Result<(), String> {
+    if let Some(threads) = args.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .map_err(|e| format!("Could not set up threads: {}", e))?;
+    }
+
+    let source = fs::read_to_string(&args.input)
+        .map_err(|e| format!("Could not read '{}': {}", args.input.display(), e))?;
+    let mesh = match args.materials {
+        MaterialMode::Single => MaterialMesh::from_obj_1_material(source).map_err(VoxelizationError::from),
+        MaterialMode::Multi => MaterialMesh::from_obj_multi_material(source),
+    }
+    .map_err(|e| format!("Invalid mesh in '{}': {}", args.input.display(), e))?;
+
+    let mesh = if args.repair {
+        mesh.repair(&RepairOptions::new())
+            .map_err(|e| format!("Could not repair '{}': {}", args.input.display(), e))?
+    } else {
+        mesh
+    };
+
+    let options = VoxelizationOptions::new()
+        .with_voxel_size(args.voxel_size)
+        .with_fail_on_fallback(args.fail_on_fallback)
+        .with_validate_input(args.validate);
+    let (voxels, report) = Voxels::with_options_and_report(mesh, &options)
+        .map_err(|e| format!("Voxelization failed: {}", e))?;
+
+    for fallback in &report.fallbacks {
+        eprintln!(
+            "warning: fell back to a convex hull at voxel ({}, {}, {}) because {:?}, volume error {}",
+            fallback.pos.x, fallback.pos.y, fallback.pos.z, fallback.error, fallback.volume_error
+        );
+    }
+
+    let output = &args.output;
+    let write_error = |e: std::io::Error| format!("Could not write '{}': {}", output.display(), e);
+    match args.format {
+        Format::Vxlz => voxels.save(output).map_err(write_error),
+        Format::Vox => voxels.export_vox(output, ComplexVoxelPolicy::Majority).map_err(write_error),
+        Format::SurfaceObj => {
+            let surface = voxels
+                .surface()
+                .map_err(|e| format!("Surface extraction failed: {}", e))?
+                .ok_or("The voxelization is empty")?;
+            surface.export_obj(output).map_err(write_error)
+        }
+        Format::DebugObj => voxels.export_obj(output).map_err(write_error),
+    }
+}
 
 fn main() {
-    let source = fs::read_to_string("assets/test/test_multi_sphere.obj").expect("File not read");
-    let mesh = MaterialMesh::from_obj_multi_material(source).expect("Invalid mesh");
+    let args = match Args::parse(env::args().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(message) => {
+            eprintln!("error: {}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(message) = run(args) {
+        eprintln!("error: {}", message);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<Args>, String> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse_defaults() {
+        let args = parse(&["-i", "in.obj", "-o", "out.vox"]).unwrap().unwrap();
+        assert_eq!(args.input, PathBuf::from("in.obj"));
+        assert_eq!(args.output, PathBuf::from("out.vox"));
+        assert_eq!(args.voxel_size, 1.0);
+        assert_eq!(args.materials, MaterialMode::Multi);
+        assert_eq!(args.format, Format::Vox);
+        assert_eq!(args.threads, None);
+        assert!(!args.fail_on_fallback && !args.validate && !args.repair);
+    }
+
+    #[test]
+    fn test_parse_all_options() {
+        let args = parse(&[
+            "--input",
+            "in.obj",
+            "--output",
+            "out.bin",
+            "--voxel-size",
+            "0.25",
+            "--materials",
+            "single",
+            "--format",
+            "vxlz",
+            "--threads",
+            "3",
+            "--fail-on-fallback",
+            "--validate",
+            "--repair",
+        ])
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(args.voxel_size, 0.25);
+        assert_eq!(args.materials, MaterialMode::Single);
+        // The explicit format wins over the extension
+        assert_eq!(args.format, Format::Vxlz);
+        assert_eq!(args.threads, Some(3));
+        assert!(args.fail_on_fallback && args.validate && args.repair);
+    }
+
+    #[test]
+    fn test_parse_help() {
+        assert!(parse(&["-i", "in.obj", "--help"]).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |args: &[&str]| parse(args).unwrap_err();
 
-    let voxels = Voxels::from(mesh);
-    voxels.export_debug_obj("assets/debug/voxels.obj");
+        assert_eq!(error(&["-i", "in.obj", "-o", "out.vox", "--bogus"]), "Unknown argument '--bogus'");
+        assert_eq!(error(&["-o", "out.vox"]), "Missing --input");
+        assert_eq!(error(&["-i", "in.obj"]), "Missing --output");
+        assert_eq!(error(&["-i", "in.obj", "-o"]), "Missing value for -o");
+        assert_eq!(error(&["-i", "in.obj", "-o", "out.vox", "-s", "-1"]), "Invalid voxel size '-1'");
+        assert_eq!(error(&["-i", "in.obj", "-o", "out.vox", "-s", "NaN"]), "Invalid voxel size 'NaN'");
+        assert_eq!(error(&["-i", "in.obj", "-o", "out.vox", "-j", "0"]), "Invalid thread count '0'");
+        assert_eq!(error(&["-i", "in.obj", "-o", "out.vox", "-m", "some"]), "Unknown material mode 'some'");
+        assert_eq!(error(&["-i", "in.obj", "-o", "out.vox", "-f", "stl"]), "Unknown format 'stl'");
+        assert!(error(&["-i", "in.obj", "-o", "out.txt"]).contains("pass --format"));
+    }
 }
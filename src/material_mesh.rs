@@ -3,17 +3,23 @@ use fnv::{FnvHashMap, FnvHashSet};
 use petgraph::graph::Edges;
 use petgraph::prelude::*;
 use petgraph::unionfind::UnionFind;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::f64::consts::PI;
 use std::fs;
+use std::io;
 use std::num::NonZeroU32;
 use std::path::Path;
 use tri_mesh::mesh_builder;
 use tri_mesh::prelude::*;
-use bvh::bvh::BVH;
+use bvh::bvh::{BVH, BVHNode};
 use bvh::nalgebra::Point3 as NPoint3;
 use bvh::aabb::{AABB, Bounded};
 use bvh::bounding_hierarchy::{BoundingHierarchy, BHShape};
 
-use crate::triangulate::Polygon;
+use crate::error::VoxelizationError;
+use crate::material_table::{MaterialInfo, MaterialTable};
+use crate::triangulate::{Polygon, TriangulateError, TriangulationMethod};
 use crate::util::{GraphEx, HashVec2, HashVec3, Vec2};
 
 /// The ID type for a material
@@ -28,15 +34,22 @@ impl Default for MaterialID {
 }
 
 impl MaterialID {
+    /// Panics if the ID is 0. See `try_new` for a fallible version.
     pub fn new(id: u32) -> Self {
         Self(NonZeroU32::new(id).expect("Material ID can't be 0"))
     }
+
+    /// Returns an error if the ID is 0
+    pub fn try_new(id: u32) -> Result<Self, VoxelizationError> {
+        NonZeroU32::new(id).map(Self).ok_or(VoxelizationError::ZeroMaterialID)
+    }
 }
 
 /// A triangle mesh with material
 #[derive(Debug)]
 pub struct MaterialMesh {
     mesh: Mesh<MaterialID>,
+    materials: MaterialTable,
 }
 #[derive(Clone, Debug, Default)]
 struct Intermediate {
@@ -59,13 +72,39 @@ struct FaceRange {
     min: f64,
     max: f64,
     face_proj_area: f64,
+    material: MaterialID,
+}
+
+/// The materials on either side of a face, either of which can be empty space.
+/// The face's normal points from the back to the front.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct FaceSides {
+    pub back: Option<MaterialID>,
+    pub front: Option<MaterialID>,
 }
 
 impl MaterialMesh {
     const EPSILON: f64 = 1e-5;
 
     pub fn new(mesh: Mesh<MaterialID>) -> Self {
-        Self { mesh }
+        Self {
+            mesh,
+            materials: MaterialTable::default(),
+        }
+    }
+
+    /// Sets the names and properties of the materials
+    pub fn with_materials(mut self, materials: MaterialTable) -> Self {
+        self.materials = materials;
+        self
+    }
+
+    pub fn materials(&self) -> &MaterialTable {
+        &self.materials
+    }
+
+    pub fn materials_mut(&mut self) -> &mut MaterialTable {
+        &mut self.materials
     }
 
     pub fn mesh(&self) -> &Mesh<MaterialID> {
@@ -76,11 +115,49 @@ impl MaterialMesh {
         &mut self.mesh
     }
 
+    /// Gets a copy of this mesh with a transformation applied
+    pub fn transformed(&self, transform: Mat4) -> Self {
+        Self::new(self.mesh.transformed(transform)).with_materials(self.materials.clone())
+    }
+
+    /// Exports the mesh as an OBJ file, along with an MTL file with the material names and colors.
+    /// Panics if the files can't be written; see `export_obj`.
     pub fn export_debug_obj<P: AsRef<Path> + Clone>(&self, path: P) {
-        let (obj, mtl) = self.mesh.parse_as_obj_multimaterial(|mat| mat.0.get() as usize - 1);
-        fs::write(path.clone(), obj).expect("Could not debug obj");
+        self.export_obj(path).expect("Could not debug obj");
+    }
+
+    /// Exports the mesh as an OBJ file, along with an MTL file with the material names and colors
+    pub fn export_obj<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
         let path = path.as_ref();
-        fs::write(path.with_extension("mtl"), mtl).expect("Could not debug mtl");
+        let mut obj = String::from("o object\n");
+        if let Some(name) = path.with_extension("mtl").file_name() {
+            obj += &format!("mtllib {}\n", name.to_string_lossy());
+        }
+
+        let mut indexes = FnvHashMap::default();
+        for (i, v) in self.mesh.vertex_iter().enumerate() {
+            let pos = self.mesh.vertex_position(v);
+            obj += &format!("v {} {} {}\n", pos.x, pos.y, pos.z);
+            indexes.insert(v, i + 1);
+        }
+
+        let mut faces = self.mesh.face_iter().collect::<Vec<_>>();
+        faces.sort_by_key(|f| self.mesh.face_tag(*f));
+
+        let mut materials = vec![];
+        for f in faces {
+            let material = self.mesh.face_tag(f);
+            if materials.last() != Some(&material) {
+                obj += &format!("usemtl {}\n", self.materials.obj_name(material));
+                materials.push(material);
+            }
+
+            let (v0, v1, v2) = self.mesh.face_vertices(f);
+            obj += &format!("f {} {} {}\n", indexes[&v0], indexes[&v1], indexes[&v2]);
+        }
+
+        fs::write(path, obj)?;
+        fs::write(path.with_extension("mtl"), self.materials.to_mtl(materials))
     }
 
     pub fn debug_vertices_faces(&self) {
@@ -100,15 +177,82 @@ impl MaterialMesh {
             .with_default_tag(MaterialID::new(1))
             .build()?;
 
-        Ok(Self { mesh })
+        Ok(Self::new(mesh))
     }
 
-    pub fn from_obj_multi_material(source: String) -> Result<Self, mesh_builder::Error> {
+    /// Constructs a material mesh from an OBJ string, with a material per `usemtl` name.
+    /// Materials are numbered in order of first use, and their names go in the material table.
+    /// Faces before the first `usemtl` get a material named "default".
+    /// Polygons are triangulated as fans, and texture coordinates and normals are ignored.
+    pub fn from_obj_multi_material(source: String) -> Result<Self, VoxelizationError> {
+        let mut materials = MaterialTable::new();
+        let mut ids = FnvHashMap::<String, MaterialID>::default();
+        let mut material_id = |name: String| -> Result<MaterialID, VoxelizationError> {
+            if let Some(id) = ids.get(&name) {
+                return Ok(*id);
+            }
+            let id = MaterialID::try_new(ids.len() as u32 + 1)?;
+            materials.insert(id, MaterialInfo::new(name.clone()));
+            ids.insert(name, id);
+            Ok(id)
+        };
+
+        let mut positions = vec![];
+        let mut indexes = vec![];
+        let mut tags = vec![];
+        let mut material = None;
+
+        for (i, line) in source.lines().enumerate() {
+            let parse_error = |message: &str| VoxelizationError::Parse(format!("OBJ line {}: {}", i + 1, message));
+            let mut tokens = line.split_whitespace();
+
+            match tokens.next() {
+                Some("v") => {
+                    for _ in 0..3 {
+                        let coord = tokens.next().and_then(|token| token.parse::<f64>().ok());
+                        positions.push(coord.ok_or_else(|| parse_error("bad vertex"))?);
+                    }
+                }
+                Some("usemtl") => material = Some(material_id(tokens.collect::<Vec<_>>().join(" "))?),
+                Some("f") => {
+                    let num_vertices = positions.len() / 3;
+                    let vertices = tokens
+                        .map(|token| {
+                            // Indexes start at 1, and negative ones count back from the latest vertex
+                            let index = token.split('/').next().and_then(|index| index.parse::<i64>().ok());
+                            match index {
+                                Some(index) if index > 0 && index as usize <= num_vertices => Ok(index as u32 - 1),
+                                Some(index) if index < 0 && (-index) as usize <= num_vertices => {
+                                    Ok((num_vertices as i64 + index) as u32)
+                                }
+                                _ => Err(parse_error("bad face vertex")),
+                            }
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    if vertices.len() < 3 {
+                        return Err(parse_error("face has less than 3 vertices"));
+                    }
+
+                    let material = match material {
+                        Some(material) => material,
+                        None => material_id(String::from("default"))?,
+                    };
+                    for j in 1..vertices.len() - 1 {
+                        indexes.extend_from_slice(&[vertices[0], vertices[j], vertices[j + 1]]);
+                        tags.push(material);
+                    }
+                }
+                _ => {}
+            }
+        }
+
         let mesh = MeshBuilder::new()
-            .with_obj_multimaterial(source, |i| MaterialID::new(i as u32 + 1))
+            .with_positions(positions)
+            .with_indices(indexes)
+            .with_tags(tags)
             .build()?;
 
-        Ok(Self { mesh })
+        Ok(Self::new(mesh).with_materials(materials))
     }
 
     /// Dissolve an unnecessary boundary vertex
@@ -360,7 +504,14 @@ impl MaterialMesh {
 
     /// Draws contours on the mesh along evenly spaced axis-aligned planes.
     /// One of the planes crosses the origin.
-    pub fn axis_contour(&mut self, axis: Axis, spacing: f64, min_slice: f64, max_slice: f64) {
+    /// Fails if the mesh isn't closed, or if its connectivity can't be kept valid while splitting edges.
+    pub fn axis_contour(
+        &mut self,
+        axis: Axis,
+        spacing: f64,
+        min_slice: f64,
+        max_slice: f64,
+    ) -> Result<(), VoxelizationError> {
         let axis_id = axis as usize;
         let min = min_slice;
         let max = max_slice;
@@ -369,7 +520,7 @@ impl MaterialMesh {
         let mut ranges = self
             .mesh
             .edge_iter()
-            .map(|mut halfedge_id| {
+            .map(|mut halfedge_id| -> Result<_, VoxelizationError> {
                 let mut pos = self.mesh.edge_positions(halfedge_id);
 
                 // We want the edge to point in the +axis direction
@@ -380,17 +531,17 @@ impl MaterialMesh {
                         .walker_from_halfedge(halfedge_id)
                         .as_twin()
                         .halfedge_id()
-                        .expect("Half-edge doesn't have a twin");
+                        .ok_or(VoxelizationError::Topology("Half-edge doesn't have a twin"))?;
                 }
 
-                EdgeRange {
+                Ok(EdgeRange {
                     min: pos.0[axis_id],
                     max: pos.1[axis_id],
                     halfedge_id,
                     triangulation: false,
-                }
+                })
             })
-            .collect::<Vec<_>>();
+            .collect::<Result<Vec<_>, _>>()?;
 
         ranges.sort_by_key(|range| FloatOrd(range.min));
         ranges.reverse();
@@ -442,11 +593,13 @@ impl MaterialMesh {
             for range in tri_edges.drain(..) {
                 self.mesh
                     .flip_edge(range.halfedge_id)
-                    .expect("Could not flip triangulation edge");
+                    .map_err(|_| VoxelizationError::Topology("Could not flip triangulation edge"))?;
             }
 
             slice_coord += spacing;
         }
+
+        Ok(())
     }
 
     /// Slices the mesh into regions based on the contours.
@@ -457,7 +610,7 @@ impl MaterialMesh {
         spacing: f64,
         min_slice: f64,
         max_slice: f64,
-    ) -> Vec<(f64, MaterialMesh)> {
+    ) -> Result<Vec<(f64, MaterialMesh)>, VoxelizationError> {
         let axis_id = axis as usize;
 
         let min = min_slice;
@@ -489,7 +642,7 @@ impl MaterialMesh {
         imms.into_iter()
             .enumerate()
             .filter(|(i, imm)| imm.vertex_ids.len() > 0)
-            .map(|(i, imm)| {
+            .map(|(i, imm)| -> Result<_, VoxelizationError> {
                 let mut positions = vec![0.0; imm.vertex_ids.len() * 3];
                 for (vertex, index) in imm.vertex_ids {
                     let position = self.mesh.vertex_position(vertex);
@@ -498,17 +651,16 @@ impl MaterialMesh {
                     positions[index * 3 + 2] = position[2];
                 }
 
-                (
+                Ok((
                     min + i as f64 * spacing,
                     MaterialMesh::new(
                         MeshBuilder::new()
                             .with_positions(positions)
                             .with_indices(imm.indexes)
                             .with_tags(imm.tags)
-                            .build()
-                            .expect("Invalid mesh"),
+                            .build()?,
                     ),
-                )
+                ))
             })
             .collect()
     }
@@ -519,22 +671,27 @@ impl MaterialMesh {
     /// Does not use the mesh's split_at_intersection() method because
     /// the runtime can be faster in this case.
     ///
-    /// Also returns the lesser slice coordinate for each slice
-    pub fn axis_slice(mut self, axis: Axis, spacing: f64) -> Vec<(f64, MaterialMesh)> {
+    /// Also returns the lesser slice coordinate for each slice.
+    /// Fails if the mesh isn't closed, since then the contours can't be followed.
+    pub fn axis_slice(
+        mut self,
+        axis: Axis,
+        spacing: f64,
+    ) -> Result<Vec<(f64, MaterialMesh)>, VoxelizationError> {
         let extreme = self.mesh.extreme_coordinates();
         let min = (extreme.0[axis as usize] / spacing - Self::EPSILON).floor() * spacing;
         let max = (extreme.1[axis as usize] / spacing + Self::EPSILON).ceil() * spacing;
 
-        self.axis_contour(axis, spacing, min, max);
-        let mut slices = self.contour_slice(axis, spacing, min, max);
+        self.axis_contour(axis, spacing, min, max)?;
+        let mut slices = self.contour_slice(axis, spacing, min, max)?;
         for (_, slice) in slices.iter_mut() {
             slice.decimate();
         }
-        slices
+        Ok(slices)
     }
 
     /// Calculates the ranges that the faces take up along some axes
-    /// and calculates the in-out gradient of each range.
+    /// and calculates the in-out gradient of each range, summed over all materials.
     /// The in-out gradient is
     /// * -1 if travelling along the +axis direction goes from outside to inside
     /// * 0 if there is no change
@@ -544,6 +701,23 @@ impl MaterialMesh {
         axis: Axis,
         cross_section_area: f64,
     ) -> Vec<(f64, f64, i32)> {
+        self.axis_ranges_and_material_gradients(axis, cross_section_area)
+            .into_iter()
+            .map(|(min, max, gradients)| (min, max, gradients.iter().map(|(_, grad)| grad).sum()))
+            .collect()
+    }
+
+    /// Calculates the ranges that the faces take up along some axis
+    /// and the in-out gradient of each material in each range, as in `axis_ranges_and_in_out_gradients`.
+    /// Materials with a gradient of 0 are left out, and the rest are sorted by material.
+    ///
+    /// Where two materials share a wall, crossing it exits one material and enters the other,
+    /// so tracking each material separately tells which material is on each side of the wall.
+    pub fn axis_ranges_and_material_gradients(
+        &self,
+        axis: Axis,
+        cross_section_area: f64,
+    ) -> Vec<(f64, f64, Vec<(MaterialID, i32)>)> {
         let axis_id = axis as usize;
         let axis_vec = axis.unit_dir();
 
@@ -557,6 +731,7 @@ impl MaterialMesh {
                     min: pos.0[axis_id].min(pos.1[axis_id]).min(pos.2[axis_id]),
                     max: pos.0[axis_id].max(pos.1[axis_id]).max(pos.2[axis_id]),
                     face_proj_area: (pos.1 - pos.0).cross(pos.2 - pos.0).dot(axis_vec) / 2.0,
+                    material: self.mesh.face_tag(f),
                 }
             })
             .collect::<Vec<_>>();
@@ -572,24 +747,27 @@ impl MaterialMesh {
                 ranges.push(Interval {
                     min: face_range.min,
                     max: face_range.max,
-                    proj_area: face_range.face_proj_area,
+                    proj_areas: FnvHashMap::default(),
                 });
-            } else {
-                // Expand old interval
-                let range = ranges.last_mut().unwrap();
-                range.max = range.max.max(face_range.max);
-                range.proj_area += face_range.face_proj_area;
             }
+
+            let range = ranges.last_mut().unwrap();
+            range.max = range.max.max(face_range.max);
+            *range.proj_areas.entry(face_range.material).or_insert(0.0) += face_range.face_proj_area;
         }
 
         ranges
             .into_iter()
             .map(|range| {
-                (
-                    range.min,
-                    range.max,
-                    (range.proj_area / cross_section_area).round() as i32,
-                )
+                let mut gradients = range
+                    .proj_areas
+                    .into_iter()
+                    .map(|(material, area)| (material, (area / cross_section_area).round() as i32))
+                    .filter(|(_, grad)| *grad != 0)
+                    .collect::<Vec<_>>();
+                gradients.sort();
+
+                (range.min, range.max, gradients)
             })
             .collect()
     }
@@ -603,8 +781,7 @@ impl MaterialMesh {
             let mut pos = self.mesh.vertex_position(vertex_id);
             let slice_plane = (pos[axis as usize] / spacing).round() * spacing;
 
-            // Bigger epsilon here to mask tet face recovery bug
-            if (slice_plane - pos[axis as usize]).abs() < 0.001 {
+            if (slice_plane - pos[axis as usize]).abs() < Self::EPSILON {
                 pos[axis as usize] = slice_plane;
                 self.mesh.move_vertex_to(vertex_id, pos);
             }
@@ -656,6 +833,53 @@ impl MaterialMesh {
         graph
     }
 
+    /// Gets the boundary edges of the mesh along with the material of the face each one borders.
+    fn boundary_edge_materials(&self) -> Vec<([Vec3; 2], MaterialID)> {
+        self.mesh
+            .halfedge_iter()
+            .filter_map(|halfedge| {
+                let face = self.mesh.walker_from_halfedge(halfedge).face_id()?;
+                if self.mesh.is_edge_on_boundary(halfedge) {
+                    let (v0, v1) = self.mesh.edge_vertices(halfedge);
+                    Some((
+                        [self.mesh.vertex_position(v0), self.mesh.vertex_position(v1)],
+                        self.mesh.face_tag(face),
+                    ))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Gets the material with the largest total face area.
+    /// Returns the default material if the mesh has no faces.
+    pub fn dominant_material(&self) -> MaterialID {
+        let mut areas = FnvHashMap::default();
+        for f in self.mesh.face_iter() {
+            let pos = self.mesh.face_positions(f);
+            *areas.entry(self.mesh.face_tag(f)).or_insert(0.0) +=
+                (pos.1 - pos.0).cross(pos.2 - pos.0).magnitude();
+        }
+
+        areas
+            .into_iter()
+            .max_by_key(|(material, area)| (FloatOrd(*area), *material))
+            .map(|(material, _)| material)
+            .unwrap_or_default()
+    }
+
+    /// Gets the signed volume enclosed by the faces of each material.
+    /// Assumes each material is bounded by a closed, outward-facing shell of faces with its tag.
+    pub fn material_volumes(&self) -> FnvHashMap<MaterialID, f64> {
+        let mut volumes = FnvHashMap::default();
+        for f in self.mesh.face_iter() {
+            let pos = self.mesh.face_positions(f);
+            *volumes.entry(self.mesh.face_tag(f)).or_insert(0.0) += pos.0.dot(pos.1.cross(pos.2)) / 6.0;
+        }
+        volumes
+    }
+
     /// Combine vertices with equal positions on the boundary graph.
     /// Also removes resulting self-loops.
     fn combine_equal_vertices(boundary: &Graph<Vec2, ()>) -> Graph<Vec2, ()> {
@@ -880,13 +1104,14 @@ impl MaterialMesh {
             // TODO: Handle holes with slits
 
             // Obtain bottommost vertex. In case of tie, get rightmost vertex
-            let node = boundary
-                .node_indices()
-                .min_by_key(|n| {
-                    let pos = boundary[*n];
-                    (FloatOrd(pos.y), FloatOrd(-pos.x))
-                })
-                .expect("There should be a node here");
+            let node = match boundary.node_indices().min_by_key(|n| {
+                let pos = boundary[*n];
+                (FloatOrd(pos.y), FloatOrd(-pos.x))
+            }) {
+                Some(node) => node,
+                // Edges always have nodes
+                None => return false,
+            };
 
             // The vertex is guaranteed to be convex and at an outer hole.
             // Determine which way it goes.
@@ -949,16 +1174,15 @@ impl MaterialMesh {
     fn intersect_center_unit_square(
         mesh_fn: impl FnOnce() -> Self,
         mut boundary: Graph<Vec2, ()>,
-    ) -> Vec<[Vec2; 3]> {
+        method: TriangulationMethod,
+    ) -> Result<Vec<[Vec2; 3]>, TriangulateError> {
         if !Self::intersect_center_unit_square_on_graph(&mut boundary) {
             mesh_fn().intersect_center_unit_square_with_context(&mut boundary);
         }
 
         boundary.reverse();
 
-        Polygon::from_boundary(boundary)
-            .expect("Bad complex voxel boundary")
-            .triangulate()
+        Polygon::from_boundary(boundary)?.triangulate_with(method)
     }
 
     /// Gets the intersection of a unit cube
@@ -966,17 +1190,32 @@ impl MaterialMesh {
     /// It is assumed that the mesh's boundary is entirely
     /// on the surface of the cube and that no triangles
     /// are coplanar with a cube face.
-    pub fn intersect_unit_cube(mut self, cube_min: Vec3) -> Self {
+    ///
+    /// Faces keep their materials. Faces filled in on the cube's surface
+    /// take the material of the closest boundary edge on the same cube face.
+    pub fn intersect_unit_cube(self, cube_min: Vec3) -> Result<Self, VoxelizationError> {
+        self.intersect_unit_cube_with(cube_min, TriangulationMethod::default())
+    }
+
+    /// Like `intersect_unit_cube`, but with a choice of how to triangulate the cube's faces.
+    /// Constrained Delaunay triangulation makes better shaped triangles, which are easier to tetrahedralize.
+    pub fn intersect_unit_cube_with(
+        mut self,
+        cube_min: Vec3,
+        method: TriangulationMethod,
+    ) -> Result<Self, VoxelizationError> {
         self.mesh.translate(-cube_min - vec3(0.5, 0.5, 0.5));
         // Cube center is at origin
         let boundary = self.boundary_graph();
+        let boundary_materials = self.boundary_edge_materials();
+        let dominant_material = self.dominant_material();
 
         let mut triangles = self
             .mesh
             .face_iter()
             .map(|f| {
                 let pos = self.mesh.face_positions(f);
-                [pos.0, pos.1, pos.2]
+                ([pos.0, pos.1, pos.2], self.mesh.face_tag(f))
             })
             .collect::<Vec<_>>();
 
@@ -1019,11 +1258,21 @@ impl MaterialMesh {
                 |_, e| Some(*e),
             );
 
+            // Prefer boundary edges on this face when picking materials
+            let face_materials = boundary_materials
+                .iter()
+                .filter(|([p0, p1], _)| {
+                    (p0.dot(normal) - 0.5).abs() < Self::EPSILON && (p1.dot(normal) - 0.5).abs() < Self::EPSILON
+                })
+                .copied()
+                .collect::<Vec<_>>();
+
             triangles.extend(
                 Self::intersect_center_unit_square(
                     || MaterialMesh::new(self.mesh.transformed(inv_square_transform)),
                     sub,
-                )
+                    method,
+                )?
                 .into_iter()
                 .map(|tri_2d| {
                     let mut tri_3d = [Vec3::zero(); 3];
@@ -1034,23 +1283,51 @@ impl MaterialMesh {
                             ))
                             .to_vec();
                     }
-                    tri_3d
+
+                    let center = (tri_3d[0] + tri_3d[1] + tri_3d[2]) / 3.0;
+                    let material = Self::closest_edge_material(&face_materials, center)
+                        .or_else(|| Self::closest_edge_material(&boundary_materials, center))
+                        .unwrap_or(dominant_material);
+                    (tri_3d, material)
                 }),
             );
         }
 
-        let mesh = Self::manifold_from_triangle_soup(triangles);
-        MaterialMesh::new(mesh.mesh.translated(cube_min + vec3(0.5, 0.5, 0.5)))
+        let mesh = Self::manifold_from_material_triangle_soup(triangles)?;
+        Ok(MaterialMesh::new(mesh.mesh.translated(cube_min + vec3(0.5, 0.5, 0.5))))
+    }
+
+    /// Gets the material of the edge closest to some point, if there are any edges.
+    fn closest_edge_material(edges: &[([Vec3; 2], MaterialID)], point: Vec3) -> Option<MaterialID> {
+        edges
+            .iter()
+            .min_by_key(|([p0, p1], _)| {
+                let dir = *p1 - *p0;
+                let t = ((point - *p0).dot(dir) / dir.magnitude2()).max(0.0).min(1.0);
+                FloatOrd((*p0 + dir * t - point).magnitude2())
+            })
+            .map(|(_, material)| *material)
     }
 
     /// Constructs a manifold mesh, possibly with boundary,
     /// from a triangle soup by combining overlapping edges.
-    fn manifold_from_triangle_soup(triangles: Vec<[Vec3; 3]>) -> Self {
+    #[cfg(test)]
+    fn manifold_from_triangle_soup(triangles: Vec<[Vec3; 3]>) -> Result<Self, mesh_builder::Error> {
+        Self::manifold_from_material_triangle_soup(
+            triangles.into_iter().map(|tri| (tri, MaterialID::default())).collect(),
+        )
+    }
+
+    /// Constructs a manifold mesh, possibly with boundary,
+    /// from a triangle soup with materials by combining overlapping edges.
+    pub(crate) fn manifold_from_material_triangle_soup(
+        triangles: Vec<([Vec3; 3], MaterialID)>,
+    ) -> Result<Self, mesh_builder::Error> {
+        let tags = triangles.iter().map(|(_, material)| *material).collect::<Vec<_>>();
         let positions = triangles
             .into_iter()
-            .flat_map(|[a, b, c]| vec![a, b, c].into_iter())
+            .flat_map(|([a, b, c], _)| vec![a, b, c].into_iter())
             .collect::<Vec<_>>();
-
         let mut index_sets = UnionFind::new(positions.len());
 
         let mut edge_face_map = FnvHashMap::default();
@@ -1132,26 +1409,77 @@ impl MaterialMesh {
             points[3 * index + 2] = pos.z;
         }
 
-        MaterialMesh::new(
+        Ok(MaterialMesh::new(
             MeshBuilder::new()
                 .with_positions(points)
                 .with_indices(indexes)
-                .with_default_tag(MaterialID::new(1))
-                .build()
-                .expect("Invalid mesh"),
+                .with_tags(tags)
+                .build()?,
+        ))
+    }
+
+    /// Constructs a mesh from triangles with a material on each side,
+    /// so that a wall between two materials only has to be given once.
+    /// Each side with a material becomes a face of that material's shell, facing away from it.
+    /// Triangles with the same material on both sides are inside that material and are dropped.
+    pub fn from_two_sided_triangles(
+        triangles: Vec<([Vec3; 3], FaceSides)>,
+    ) -> Result<Self, mesh_builder::Error> {
+        Self::manifold_from_material_triangle_soup(
+            triangles
+                .into_iter()
+                .filter(|(_, sides)| sides.back != sides.front)
+                .flat_map(|([a, b, c], sides)| {
+                    sides
+                        .back
+                        .map(|material| ([a, b, c], material))
+                        .into_iter()
+                        .chain(sides.front.map(|material| ([a, c, b], material)))
+                })
+                .collect(),
         )
     }
 
+    /// Gets the materials on both sides of each face.
+    /// The back is the face's own material, and the front is the material of a face
+    /// with the same vertex positions in the opposite order, if there is one.
+    pub fn face_sides(&self) -> FnvHashMap<FaceID, FaceSides> {
+        // Positions rotated so that the smallest comes first, to compare faces regardless of start vertex
+        let key = |p: [Vec3; 3]| {
+            let p = p
+                .iter()
+                .map(|p| (FloatOrd(p.x), FloatOrd(p.y), FloatOrd(p.z)))
+                .collect::<Vec<_>>();
+            let min = (0..3).min_by_key(|i| p[*i]).unwrap();
+            [p[min], p[(min + 1) % 3], p[(min + 2) % 3]]
+        };
+
+        let mut faces_by_key = FnvHashMap::default();
+        for f in self.mesh.face_iter() {
+            let (a, b, c) = self.mesh.face_positions(f);
+            faces_by_key.entry(key([a, b, c])).or_insert_with(Vec::new).push(f);
+        }
+
+        self.mesh
+            .face_iter()
+            .map(|f| {
+                let material = self.mesh.face_tag(f);
+                let (a, b, c) = self.mesh.face_positions(f);
+                let front = faces_by_key
+                    .get(&key([a, c, b]))
+                    .and_then(|faces| faces.iter().map(|f| self.mesh.face_tag(*f)).find(|m| *m != material));
+
+                (f, FaceSides { back: Some(material), front })
+            })
+            .collect()
+    }
+
     /// Builds a BVH from the triangles.
     pub fn bvh(&self) -> (BVH, Vec<BvhTriangle>) {
         let mut triangles = self.mesh.face_iter().map(|f| {
             let (p0, p1, p2) = self.mesh.face_positions(f);
 
-            BvhTriangle {
-                points: [p0, p1, p2],
-                material: self.mesh.face_tag(f),
-                node_index: 0
-            }
+            BvhTriangle::new([p0, p1, p2], self.mesh.face_tag(f))
         }).collect::<Vec<_>>();
 
         let bvh = BVH::build(&mut triangles);
@@ -1162,7 +1490,8 @@ impl MaterialMesh {
 struct Interval {
     min: f64,
     max: f64,
-    proj_area: f64,
+    /// Projected area of the faces of each material
+    proj_areas: FnvHashMap<MaterialID, f64>,
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -1189,6 +1518,11 @@ pub struct BvhTriangle {
 }
 
 impl BvhTriangle {
+    /// A triangle that isn't in a BVH yet
+    pub fn new(points: [Vec3; 3], material: MaterialID) -> Self {
+        Self { points, material, node_index: 0 }
+    }
+
     pub fn material(&self) -> MaterialID {
         self.material
     }
@@ -1223,6 +1557,266 @@ impl BvhTriangle {
             None
         }
     }
+
+    pub fn points(&self) -> [Vec3; 3] {
+        self.points
+    }
+
+    /// The normal of the triangle, scaled by twice its area.
+    pub fn area_normal(&self) -> Vec3 {
+        (self.points[1] - self.points[0]).cross(self.points[2] - self.points[0])
+    }
+
+    /// Finds the point on this triangle closest to some point,
+    /// along with the barycentric coordinates of that closest point.
+    fn closest_point_barycentric(&self, point: Vec3) -> (Vec3, [f64; 3]) {
+        let [a, b, c] = self.points;
+        let ab = b - a;
+        let ac = c - a;
+
+        // Vertex region of a
+        let ap = point - a;
+        let d1 = ab.dot(ap);
+        let d2 = ac.dot(ap);
+        if d1 <= 0.0 && d2 <= 0.0 {
+            return (a, [1.0, 0.0, 0.0]);
+        }
+
+        // Vertex region of b
+        let bp = point - b;
+        let d3 = ab.dot(bp);
+        let d4 = ac.dot(bp);
+        if d3 >= 0.0 && d4 <= d3 {
+            return (b, [0.0, 1.0, 0.0]);
+        }
+
+        // Edge region of ab
+        let vc = d1 * d4 - d3 * d2;
+        if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+            let v = d1 / (d1 - d3);
+            return (a + ab * v, [1.0 - v, v, 0.0]);
+        }
+
+        // Vertex region of c
+        let cp = point - c;
+        let d5 = ab.dot(cp);
+        let d6 = ac.dot(cp);
+        if d6 >= 0.0 && d5 <= d6 {
+            return (c, [0.0, 0.0, 1.0]);
+        }
+
+        // Edge region of ac
+        let vb = d5 * d2 - d1 * d6;
+        if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+            let w = d2 / (d2 - d6);
+            return (a + ac * w, [1.0 - w, 0.0, w]);
+        }
+
+        // Edge region of bc
+        let va = d3 * d6 - d5 * d4;
+        if va <= 0.0 && d4 - d3 >= 0.0 && d5 - d6 >= 0.0 {
+            let w = (d4 - d3) / ((d4 - d3) + (d5 - d6));
+            return (b + (c - b) * w, [0.0, 1.0 - w, w]);
+        }
+
+        // Face region
+        let sum = va + vb + vc;
+        if sum <= 0.0 {
+            // Degenerate triangle. Settle for the closest vertex.
+            return [(a, [1.0, 0.0, 0.0]), (b, [0.0, 1.0, 0.0]), (c, [0.0, 0.0, 1.0])]
+                .iter()
+                .copied()
+                .min_by_key(|(vertex, _)| FloatOrd((*vertex - point).magnitude2()))
+                .unwrap();
+        }
+        let v = vb / sum;
+        let w = vc / sum;
+        (a + ab * v + ac * w, [1.0 - v - w, v, w])
+    }
+
+    /// Finds the point on this triangle closest to some point.
+    pub fn closest_point(&self, point: Vec3) -> Vec3 {
+        self.closest_point_barycentric(point).0
+    }
+
+    /// Weight of this triangle's normal in the normal at a point on its boundary
+    /// with some barycentric coordinates: the triangle's angle at a vertex, or pi on an edge.
+    fn boundary_angle(&self, barycentric: [f64; 3]) -> f64 {
+        match barycentric.iter().position(|b| *b > 1.0 - MaterialMesh::EPSILON) {
+            Some(i) => {
+                let ab = self.points[(i + 1) % 3] - self.points[i];
+                let ac = self.points[(i + 2) % 3] - self.points[i];
+                (ab.dot(ac) / (ab.magnitude() * ac.magnitude())).max(-1.0).min(1.0).acos()
+            }
+            None => PI,
+        }
+    }
+
+    /// The signed solid angle this triangle subtends as seen from some point.
+    /// Positive if the point is behind the triangle.
+    pub fn solid_angle(&self, point: Vec3) -> f64 {
+        let a = self.points[0] - point;
+        let b = self.points[1] - point;
+        let c = self.points[2] - point;
+        let (la, lb, lc) = (a.magnitude(), b.magnitude(), c.magnitude());
+
+        let numer = a.dot(b.cross(c));
+        let denom = la * lb * lc + a.dot(b) * lc + a.dot(c) * lb + b.dot(c) * la;
+        2.0 * numer.atan2(denom)
+    }
+}
+
+/// Lower bound on the distance squared between a point and an AABB.
+/// The AABB is padded a bit because its bounds were rounded to f32.
+pub(crate) fn aabb_distance2(aabb: &AABB, point: Vec3) -> f64 {
+    let bounds = [
+        (point.x, aabb.min.x, aabb.max.x),
+        (point.y, aabb.min.y, aabb.max.y),
+        (point.z, aabb.min.z, aabb.max.z),
+    ];
+
+    bounds
+        .iter()
+        .map(|(p, min, max)| {
+            let pad = MaterialMesh::EPSILON * (1.0 + p.abs());
+            let d = (*min as f64 - pad - p).max(p - *max as f64 - pad).max(0.0);
+            d * d
+        })
+        .sum()
+}
+
+/// Finds the triangles within some distance squared of a point,
+/// skipping BVH nodes that are too far.
+fn triangles_within<'a>(bvh: &BVH, triangles: &'a [BvhTriangle], point: Vec3, max_dist2: f64) -> Vec<&'a BvhTriangle> {
+    let mut found = vec![];
+    if triangles.is_empty() {
+        return found;
+    }
+
+    let mut stack = vec![0];
+    while let Some(index) = stack.pop() {
+        match bvh.nodes[index] {
+            BVHNode::Leaf { shape_index, .. } => {
+                let triangle = &triangles[shape_index];
+                if (triangle.closest_point(point) - point).magnitude2() <= max_dist2 {
+                    found.push(triangle);
+                }
+            }
+
+            BVHNode::Node { child_l_index, ref child_l_aabb, child_r_index, ref child_r_aabb, .. } => {
+                if aabb_distance2(child_l_aabb, point) <= max_dist2 {
+                    stack.push(child_l_index);
+                }
+                if aabb_distance2(child_r_aabb, point) <= max_dist2 {
+                    stack.push(child_r_index);
+                }
+            }
+        }
+    }
+
+    found
+}
+
+/// Finds the triangle closest to a point, and the closest point on it,
+/// by traversing the BVH closest node first and pruning nodes that are too far.
+/// Ties, such as between the two copies of a wall between materials, go to the triangle
+/// the point is behind, and then to the lowest material, so the result doesn't depend on the BVH.
+/// Returns None if there are no triangles.
+pub fn closest_triangle<'a>(
+    bvh: &BVH,
+    triangles: &'a [BvhTriangle],
+    point: Vec3,
+) -> Option<(&'a BvhTriangle, Vec3)> {
+    // Coincident triangles can be a rounding error apart
+    const TIE_DISTANCE: f64 = 1e-10;
+
+    if triangles.is_empty() {
+        return None;
+    }
+
+    let tie_key = |triangle: &BvhTriangle, closest: Vec3| {
+        ((point - closest).dot(triangle.area_normal()) >= 0.0, triangle.material())
+    };
+
+    let mut best: Option<(&BvhTriangle, Vec3, f64)> = None;
+    let mut heap = BinaryHeap::new();
+    heap.push(Reverse((FloatOrd(0.0), 0)));
+
+    while let Some(Reverse((FloatOrd(dist2), index))) = heap.pop() {
+        if best.map(|(_, _, best_dist)| dist2.sqrt() > best_dist + TIE_DISTANCE).unwrap_or(false) {
+            break;
+        }
+
+        match bvh.nodes[index] {
+            BVHNode::Leaf { shape_index, .. } => {
+                let triangle = &triangles[shape_index];
+                let closest = triangle.closest_point(point);
+                let dist = (closest - point).magnitude();
+                let better = match best {
+                    None => true,
+                    Some((best_triangle, best_closest, best_dist)) => {
+                        if (dist - best_dist).abs() <= TIE_DISTANCE {
+                            tie_key(triangle, closest) < tie_key(best_triangle, best_closest)
+                        } else {
+                            dist < best_dist
+                        }
+                    }
+                };
+                if better {
+                    best = Some((triangle, closest, dist));
+                }
+            }
+
+            BVHNode::Node { child_l_index, ref child_l_aabb, child_r_index, ref child_r_aabb, .. } => {
+                heap.push(Reverse((FloatOrd(aabb_distance2(child_l_aabb, point)), child_l_index)));
+                heap.push(Reverse((FloatOrd(aabb_distance2(child_r_aabb, point)), child_r_index)));
+            }
+        }
+    }
+
+    best.map(|(triangle, closest, _)| (triangle, closest))
+}
+
+/// Finds the material of the volume enclosing a point.
+///
+/// If the closest point on the surface is inside a triangle, the side of that triangle
+/// the point is on decides. Otherwise the closest point is on an edge or vertex,
+/// so the side is decided for each material by the angle-weighted normal there,
+/// using only the nearby triangles that touch that point.
+/// If the point is inside more than one material, the one it's deepest in is chosen.
+/// Returns None if the point isn't inside any material.
+pub fn enclosing_material(bvh: &BVH, triangles: &[BvhTriangle], point: Vec3) -> Option<MaterialID> {
+    let (triangle, _) = closest_triangle(bvh, triangles, point)?;
+    let (closest, barycentric) = triangle.closest_point_barycentric(point);
+
+    if barycentric.iter().all(|b| *b > MaterialMesh::EPSILON) {
+        return if (point - closest).dot(triangle.area_normal()) < 0.0 {
+            Some(triangle.material())
+        } else {
+            None
+        };
+    }
+
+    let radius = (closest - point).magnitude() + MaterialMesh::EPSILON;
+    let mut normals = FnvHashMap::default();
+    for other in triangles_within(bvh, triangles, point, radius * radius) {
+        let (other_closest, barycentric) = other.closest_point_barycentric(point);
+        let area_normal = other.area_normal();
+        if (other_closest - closest).magnitude() > MaterialMesh::EPSILON || area_normal.magnitude2() == 0.0 {
+            continue;
+        }
+
+        *normals.entry(other.material()).or_insert_with(Vec3::zero) +=
+            area_normal.normalize() * other.boundary_angle(barycentric);
+    }
+
+    normals
+        .into_iter()
+        .filter(|(_, normal)| normal.magnitude2() > 0.0)
+        .map(|(material, normal)| (material, (point - closest).dot(normal.normalize())))
+        .filter(|(_, side)| *side < 0.0)
+        .min_by_key(|(material, side)| (FloatOrd(*side), *material))
+        .map(|(material, _)| material)
 }
 
 impl Bounded for BvhTriangle {
@@ -1252,6 +1846,32 @@ impl BHShape for BvhTriangle {
     }
 }
 
+/// Corners of a box, and the triangles of its faces with the front side facing outward.
+/// Bits 0, 1, and 2 of a corner's index choose its max x, y, and z.
+#[cfg(test)]
+pub(crate) fn box_corners_and_triangles(min: Vec3, max: Vec3) -> (Vec<Vec3>, Vec<[usize; 3]>) {
+    let corners = (0..8)
+        .map(|i| {
+            vec3(
+                if i & 1 == 0 { min.x } else { max.x },
+                if i & 2 == 0 { min.y } else { max.y },
+                if i & 4 == 0 { min.z } else { max.z },
+            )
+        })
+        .collect();
+    let quads = vec![
+        [0, 2, 3, 1], // -z
+        [4, 5, 7, 6], // +z
+        [0, 1, 5, 4], // -y
+        [2, 6, 7, 3], // +y
+        [0, 4, 6, 2], // -x
+        [1, 3, 7, 5], // +x
+    ];
+
+    let triangles = quads.into_iter().flat_map(|[a, b, c, d]| vec![[a, b, c], [a, c, d]]).collect();
+    (corners, triangles)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -1294,6 +1914,7 @@ mod test {
 
     fn create_mesh(positions: Vec<f64>, indexes: Vec<u32>) -> MaterialMesh {
         MaterialMesh {
+            materials: MaterialTable::default(),
             mesh: MeshBuilder::<MaterialID>::new()
                 .with_positions(positions)
                 .with_indices(indexes)
@@ -1302,6 +1923,98 @@ mod test {
         }
     }
 
+    /// Square at some x with normal +x, as 2 triangles
+    fn x_square(x: f64) -> Vec<[Vec3; 3]> {
+        vec![
+            [vec3(x, 0.0, 0.0), vec3(x, 1.0, 0.0), vec3(x, 1.0, 1.0)],
+            [vec3(x, 0.0, 0.0), vec3(x, 1.0, 1.0), vec3(x, 0.0, 1.0)],
+        ]
+    }
+
+    fn two_sided_walls() -> MaterialMesh {
+        let m1 = Some(MaterialID::new(1));
+        let m2 = Some(MaterialID::new(2));
+        let walls = vec![(0.0, None, m1), (1.0, m1, m2), (2.0, m2, None)];
+
+        MaterialMesh::from_two_sided_triangles(
+            walls
+                .into_iter()
+                .flat_map(|(x, back, front)| x_square(x).into_iter().map(move |tri| (tri, FaceSides { back, front })))
+                .collect(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_two_sided_face_sides() {
+        let mesh = two_sided_walls();
+        assert_eq!(mesh.mesh.num_faces(), 8);
+
+        for (f, sides) in mesh.face_sides() {
+            let x = mesh.mesh.face_positions(f).0.x;
+            let expected_front = if x == 1.0 && sides.back == Some(MaterialID::new(1)) {
+                Some(MaterialID::new(2))
+            } else if x == 1.0 {
+                Some(MaterialID::new(1))
+            } else {
+                None
+            };
+            assert_eq!(sides.front, expected_front);
+        }
+    }
+
+    #[test]
+    fn test_two_sided_material_gradients() {
+        let ranges = two_sided_walls().axis_ranges_and_material_gradients(Axis::X, 1.0);
+        let (m1, m2) = (MaterialID::new(1), MaterialID::new(2));
+
+        assert_eq!(
+            ranges,
+            vec![
+                (0.0, 0.0, vec![(m1, -1)]),
+                (1.0, 1.0, vec![(m1, 1), (m2, -1)]),
+                (2.0, 2.0, vec![(m2, 1)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_obj_multi_material_by_name() {
+        // "zinc" is used first but sorts after "copper", and "copper" is used again after "zinc"
+        let obj = "\
+mtllib metals.mtl
+v 0 0 0
+v 1 0 0
+v 0 1 0
+v 0 0 1
+usemtl zinc
+f 1 3 2
+usemtl copper
+f 1 2 4
+f 1/1/1 4/2/1 3/3/1
+usemtl zinc
+f -3 -2 -1
+";
+        let mesh = MaterialMesh::from_obj_multi_material(obj.to_string()).unwrap();
+        let (zinc, copper) = (MaterialID::new(1), MaterialID::new(2));
+        assert_eq!(mesh.materials().name(zinc), "zinc");
+        assert_eq!(mesh.materials().name(copper), "copper");
+
+        assert_eq!(mesh.mesh.num_faces(), 4);
+        for f in mesh.mesh.face_iter() {
+            let (p0, p1, p2) = mesh.mesh.face_positions(f);
+            let on_plane = |axis: usize| p0[axis] == 0.0 && p1[axis] == 0.0 && p2[axis] == 0.0;
+            // The faces on the x = 0 and y = 0 planes are copper
+            let expected = if on_plane(0) || on_plane(1) { copper } else { zinc };
+            assert_eq!(mesh.mesh.face_tag(f), expected);
+        }
+
+        assert!(matches!(
+            MaterialMesh::from_obj_multi_material("v 0 0 0\nf 1 2 3\n".to_string()),
+            Err(VoxelizationError::Parse(_))
+        ));
+    }
+
     #[test]
     fn test_dissolve_boundary_vertex_simple() {
         let mut mesh = create_mesh(
@@ -1902,7 +2615,7 @@ mod test {
             vec3(1.0, 0.0, 0.0),
             vec3(0.0, 1.0, 0.0),
         ]];
-        let mesh = MaterialMesh::manifold_from_triangle_soup(triangles);
+        let mesh = MaterialMesh::manifold_from_triangle_soup(triangles).unwrap();
 
         assert_eq!(mesh.mesh.num_vertices(), 3);
         assert_eq!(mesh.mesh.num_edges(), 3);
@@ -1923,7 +2636,7 @@ mod test {
                 vec3(1.0, 0.0, 0.0),
             ],
         ];
-        let mesh = MaterialMesh::manifold_from_triangle_soup(triangles);
+        let mesh = MaterialMesh::manifold_from_triangle_soup(triangles).unwrap();
 
         assert_eq!(mesh.mesh.num_vertices(), 4);
         assert_eq!(mesh.mesh.num_edges(), 5);
@@ -1954,7 +2667,7 @@ mod test {
                 vec3(0.0, 0.0, 0.0),
             ],
         ];
-        let mesh = MaterialMesh::manifold_from_triangle_soup(triangles);
+        let mesh = MaterialMesh::manifold_from_triangle_soup(triangles).unwrap();
 
         assert_eq!(mesh.mesh.num_vertices(), 8);
         assert_eq!(mesh.mesh.num_edges(), 10);
@@ -1985,7 +2698,7 @@ mod test {
                 vec3(0.0, 1.0, 1.0),
             ],
         ];
-        let mesh = MaterialMesh::manifold_from_triangle_soup(triangles);
+        let mesh = MaterialMesh::manifold_from_triangle_soup(triangles).unwrap();
 
         assert_eq!(mesh.mesh.num_vertices(), 4);
         assert_eq!(mesh.mesh.num_edges(), 6);
@@ -2010,7 +2723,7 @@ mod test {
         .map(|(x, y, z)| HashVec3(vec3(x, y, z)))
         .collect::<FnvHashSet<_>>();
 
-        let mesh = mesh.intersect_unit_cube(Vec3::zero());
+        let mesh = mesh.intersect_unit_cube(Vec3::zero()).unwrap();
         let vertices = mesh
             .mesh
             .vertex_iter()
@@ -2022,4 +2735,137 @@ mod test {
         assert_eq!(mesh.mesh.num_edges(), 12);
         assert_eq!(mesh.mesh.num_faces(), 8);
     }
+
+    #[test]
+    fn test_intersect_unit_cube_diagonal_plane_constrained_delaunay() {
+        let mesh = create_mesh(
+            vec![0.0, 1.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 1.0, 0.0, 1.0, 1.0],
+            vec![0, 1, 2, 2, 3, 0],
+        );
+
+        let mesh = mesh
+            .intersect_unit_cube_with(Vec3::zero(), TriangulationMethod::ConstrainedDelaunay)
+            .unwrap();
+
+        // Right triangular prism expected
+        assert_eq!(mesh.mesh.num_vertices(), 6);
+        assert_eq!(mesh.mesh.num_edges(), 12);
+        assert_eq!(mesh.mesh.num_faces(), 8);
+        assert!((mesh.material_volumes()[&MaterialID::default()] - 0.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_bvh_triangle_closest_point() {
+        let triangle = BvhTriangle {
+            points: [vec3(0.0, 0.0, 0.0), vec3(2.0, 0.0, 0.0), vec3(0.0, 2.0, 0.0)],
+            material: MaterialID::default(),
+            node_index: 0,
+        };
+
+        // Face, edge, and vertex regions
+        assert_eq!(triangle.closest_point(vec3(0.5, 0.5, 3.0)), vec3(0.5, 0.5, 0.0));
+        assert_eq!(triangle.closest_point(vec3(1.0, -1.0, 1.0)), vec3(1.0, 0.0, 0.0));
+        assert_eq!(triangle.closest_point(vec3(2.0, 2.0, 0.0)), vec3(1.0, 1.0, 0.0));
+        assert_eq!(triangle.closest_point(vec3(-1.0, -1.0, -1.0)), vec3(0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn test_enclosing_material_tetrahedron() {
+        let mesh = create_mesh(
+            vec![0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0],
+            vec![1, 0, 2, 2, 0, 3, 3, 0, 1, 1, 2, 3],
+        );
+        let (bvh, triangles) = mesh.bvh();
+
+        // Winding number is 1 inside and 0 outside
+        let inside = vec3(0.1, 0.1, 0.1);
+        let angle = triangles.iter().map(|tri| tri.solid_angle(inside)).sum::<f64>();
+        assert!((angle - 4.0 * PI).abs() < 1e-10);
+        let angle = triangles.iter().map(|tri| tri.solid_angle(vec3(1.0, 1.0, 1.0))).sum::<f64>();
+        assert!(angle.abs() < 1e-10);
+
+        assert_eq!(enclosing_material(&bvh, &triangles, inside), Some(MaterialID::default()));
+        assert_eq!(enclosing_material(&bvh, &triangles, vec3(1.0, 1.0, 1.0)), None);
+        // Closest to a vertex
+        assert_eq!(enclosing_material(&bvh, &triangles, vec3(-0.1, -0.1, -0.1)), None);
+        // Closest to an edge
+        assert_eq!(enclosing_material(&bvh, &triangles, vec3(0.5, -0.1, -0.1)), None);
+    }
+
+    #[test]
+    fn test_enclosing_material_reflex_edge() {
+        // L-shaped prism, which is concave along the edge x = y = 1
+        let mut triangles = vec![];
+        let outline = [
+            vec2(0.0, 0.0),
+            vec2(2.0, 0.0),
+            vec2(2.0, 1.0),
+            vec2(1.0, 1.0),
+            vec2(1.0, 2.0),
+            vec2(0.0, 2.0),
+        ];
+        for i in 0..outline.len() {
+            let (a, b) = (outline[i], outline[(i + 1) % outline.len()]);
+            triangles.push([a.extend(0.0), b.extend(0.0), b.extend(1.0)]);
+            triangles.push([a.extend(0.0), b.extend(1.0), a.extend(1.0)]);
+        }
+        for [a, b, c] in [[0, 1, 2], [0, 2, 3], [0, 3, 4], [0, 4, 5]].iter() {
+            triangles.push([outline[*a].extend(0.0), outline[*c].extend(0.0), outline[*b].extend(0.0)]);
+            triangles.push([outline[*a].extend(1.0), outline[*b].extend(1.0), outline[*c].extend(1.0)]);
+        }
+        let mesh = MaterialMesh::manifold_from_triangle_soup(triangles).unwrap();
+        let (bvh, triangles) = mesh.bvh();
+
+        // Closest to the reflex edge, and in the notch outside it
+        assert_eq!(enclosing_material(&bvh, &triangles, vec3(0.9, 0.9, 0.5)), Some(MaterialID::default()));
+        assert_eq!(enclosing_material(&bvh, &triangles, vec3(1.1, 1.1, 0.5)), None);
+    }
+
+    #[test]
+    fn test_enclosing_material_wall_meets_shell() {
+        // Two unit cubes side by side, with a wall at x = 1 between material 1 and material 2
+        let (m1, m2) = (Some(MaterialID::new(1)), Some(MaterialID::new(2)));
+        let square = |o: Vec3, u: Vec3, v: Vec3| vec![[o, o + u, o + u + v], [o, o + u + v, o + v]];
+        let (x, y, z) = (vec3(1.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0), vec3(0.0, 0.0, 1.0));
+        let o = Vec3::zero();
+
+        let mut triangles = vec![];
+        for (min, material) in vec![(o, m1), (x, m2)] {
+            let faces = vec![
+                square(min, z, y),
+                square(min + x, y, z),
+                square(min, x, z),
+                square(min + y, z, x),
+                square(min, y, x),
+                square(min + z, x, y),
+            ];
+            let sides = FaceSides { back: material, front: None };
+            triangles.extend(faces.into_iter().flatten().map(|t| (t, sides)));
+        }
+        // Replace the cubes' faces at x = 1 with the wall
+        triangles.retain(|(t, _)| t.iter().any(|p| p.x != 1.0));
+        triangles.extend(square(x, y, z).into_iter().map(|t| (t, FaceSides { back: m1, front: m2 })));
+
+        let mesh = MaterialMesh::from_two_sided_triangles(triangles).unwrap();
+        let (bvh, triangles) = mesh.bvh();
+
+        for (point, expected) in vec![
+            (vec3(0.5, 0.5, 0.5), m1),
+            (vec3(1.5, 0.5, 0.5), m2),
+            // Closest to the inside of the wall, which has a face for each material
+            (vec3(0.9, 0.5, 0.4), m1),
+            (vec3(1.1, 0.5, 0.4), m2),
+            // Closest to where the wall meets the outer shell
+            (vec3(1.0, 0.5, -0.1), None),
+            (vec3(1.0, -0.1, -0.1), None),
+        ] {
+            assert_eq!(enclosing_material(&bvh, &triangles, point), expected, "at {:?}", point);
+        }
+
+        // The copy of the wall that the point is behind wins the tie
+        let (triangle, _) = closest_triangle(&bvh, &triangles, vec3(1.1, 0.5, 0.4)).unwrap();
+        assert_eq!(Some(triangle.material()), m2);
+        let (triangle, _) = closest_triangle(&bvh, &triangles, vec3(0.9, 0.5, 0.4)).unwrap();
+        assert_eq!(Some(triangle.material()), m1);
+    }
 }
@@ -0,0 +1,153 @@
+//! Names and printing properties of materials, keyed by material ID.
+
+use std::collections::BTreeMap;
+use tri_mesh::prelude::*;
+
+use crate::material_mesh::MaterialID;
+use crate::util::hue_color;
+
+/// What's known about a material
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaterialInfo {
+    pub name: String,
+    /// sRGB color with alpha, used for display
+    pub color: Option<[u8; 4]>,
+    /// Density in mass per unit volume, for estimating weight
+    pub density: Option<f64>,
+    /// Which extruder of the printer prints this material
+    pub extruder: Option<u32>,
+}
+
+impl MaterialInfo {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            color: None,
+            density: None,
+            extruder: None,
+        }
+    }
+
+    pub fn with_color(mut self, color: [u8; 4]) -> Self {
+        self.color = Some(color);
+        self
+    }
+
+    pub fn with_density(mut self, density: f64) -> Self {
+        self.density = Some(density);
+        self
+    }
+
+    pub fn with_extruder(mut self, extruder: u32) -> Self {
+        self.extruder = Some(extruder);
+        self
+    }
+}
+
+/// Table of material info. Materials don't have to be in the table;
+/// missing names and colors are made up from the ID.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MaterialTable {
+    materials: BTreeMap<MaterialID, MaterialInfo>,
+}
+
+impl MaterialTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the info of a material, returning the old info if there was any
+    pub fn insert(&mut self, id: MaterialID, info: MaterialInfo) -> Option<MaterialInfo> {
+        self.materials.insert(id, info)
+    }
+
+    pub fn get(&self, id: MaterialID) -> Option<&MaterialInfo> {
+        self.materials.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: MaterialID) -> Option<&mut MaterialInfo> {
+        self.materials.get_mut(&id)
+    }
+
+    /// Iterates over materials in the table in order of ID
+    pub fn iter(&self) -> impl Iterator<Item = (MaterialID, &MaterialInfo)> {
+        self.materials.iter().map(|(id, info)| (*id, info))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.materials.is_empty()
+    }
+
+    /// Adds materials from another table. Materials already in this table are kept.
+    pub fn merge(&mut self, other: &MaterialTable) {
+        for (id, info) in other.iter() {
+            self.materials.entry(id).or_insert_with(|| info.clone());
+        }
+    }
+
+    /// Name of a material, or `mat<ID - 1>` if it's not in the table
+    pub fn name(&self, id: MaterialID) -> String {
+        self.get(id)
+            .map(|info| info.name.clone())
+            .unwrap_or_else(|| format!("mat{}", id.0.get() - 1))
+    }
+
+    /// Display color of a material. Materials without a color get a hue based on their ID,
+    /// spaced by the golden ratio so that nearby IDs look different.
+    pub fn color(&self, id: MaterialID) -> [u8; 4] {
+        self.get(id).and_then(|info| info.color).unwrap_or_else(|| {
+            let hue = ((id.0.get() - 1) as f64 * 0.618_033_988_749_895).fract();
+            let color = hue_color(hue);
+            [
+                (color.x * 255.0).round() as u8,
+                (color.y * 255.0).round() as u8,
+                (color.z * 255.0).round() as u8,
+                255,
+            ]
+        })
+    }
+
+    /// Display color of a material as RGB in [0, 1]
+    pub fn color_rgb(&self, id: MaterialID) -> Vec3 {
+        let [r, g, b, _] = self.color(id);
+        vec3(r as f64, g as f64, b as f64) / 255.0
+    }
+
+    /// Name of a material usable in OBJ and MTL files, which split names at whitespace
+    pub fn obj_name(&self, id: MaterialID) -> String {
+        self.name(id).split_whitespace().collect::<Vec<_>>().join("_")
+    }
+
+    /// Writes an MTL file with the name and color of each material
+    pub fn to_mtl<I: IntoIterator<Item = MaterialID>>(&self, ids: I) -> String {
+        let mut mtl = String::new();
+
+        for id in ids {
+            let color = self.color_rgb(id);
+            mtl += &format!("newmtl {}\n", self.obj_name(id));
+            mtl += &format!("Kd {} {} {}\n", color.x, color.y, color.z);
+            let alpha = self.color(id)[3];
+            if alpha != 255 {
+                mtl += &format!("d {}\n", alpha as f64 / 255.0);
+            }
+        }
+
+        mtl
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_material_table_fallbacks() {
+        let mut table = MaterialTable::new();
+        table.insert(MaterialID::new(2), MaterialInfo::new("PLA").with_color([1, 2, 3, 4]));
+
+        assert_eq!(table.name(MaterialID::new(2)), "PLA");
+        assert_eq!(table.color(MaterialID::new(2)), [1, 2, 3, 4]);
+        assert_eq!(table.name(MaterialID::new(3)), "mat2");
+        assert_ne!(table.color(MaterialID::new(3)), table.color(MaterialID::new(4)));
+    }
+}
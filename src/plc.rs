@@ -19,6 +19,8 @@ pub struct PiecewiseLinearComplex {
 
 impl PiecewiseLinearComplex {
     const EPSILON: f64 = 1e-5;
+    /// Tets with a smaller dihedral angle, in radians, get flipped away if possible (about 5 degrees)
+    const SLIVER_ANGLE: f64 = 0.087;
 
     /// Constructs a new piecewise linear complex
     /// from a hopefully manifold tri-mesh.
@@ -26,28 +28,37 @@ impl PiecewiseLinearComplex {
         let mesh = mesh.mesh();
         let id_map = mesh.vertex_iter().enumerate().map(|(i, v)| (v, i))
             .collect::<FnvHashMap<_, _>>();
-        let vertices = mesh.vertex_iter().map(|v| mesh.vertex_position(v));
+        let positions = mesh.vertex_iter().map(|v| mesh.vertex_position(v)).collect();
 
-        let faces = mesh
+        let triangles = mesh
             .face_iter()
-            .map(|f| (mesh.face_vertices(f), mesh.face_normal(f), mesh.face_tag(f)));
+            .map(|f| {
+                let (a, b, c) = mesh.face_vertices(f);
+                ([id_map[&a], id_map[&b], id_map[&c]], mesh.face_tag(f))
+            })
+            .collect::<Vec<_>>();
+
+        Self::from_triangles(positions, &triangles)
+    }
 
+    /// Constructs a new piecewise linear complex from triangles that index into positions.
+    /// Each triangle becomes a face, with the same conventions as `MaterialMesh`:
+    /// each material is enclosed by outward-facing triangles of that material.
+    pub fn from_triangles(positions: Vec<Vec3>, triangles: &[([usize; 3], MaterialID)]) -> Self {
         let mut graph = Graph::new();
         let mut normals = vec![];
         let mut materials = vec![];
-        for pos in vertices {
+        for pos in positions {
             graph.add_node(pos);
         }
-        for (i, ((a, b, c), normal, mat)) in faces.enumerate() {
-            let a = NodeIndex::new(id_map[&a] as usize);
-            let b = NodeIndex::new(id_map[&b] as usize);
-            let c = NodeIndex::new(id_map[&c] as usize);
+        for (i, ([a, b, c], mat)) in triangles.iter().enumerate() {
+            let (a, b, c) = (NodeIndex::new(*a), NodeIndex::new(*b), NodeIndex::new(*c));
             graph.add_edge(a, b, i);
             graph.add_edge(b, c, i);
             graph.add_edge(c, a, i);
 
-            normals.push(normal);
-            materials.push(mat);
+            normals.push((graph[b] - graph[a]).cross(graph[c] - graph[a]).normalize());
+            materials.push(*mat);
         }
 
         Self {
@@ -57,6 +68,30 @@ impl PiecewiseLinearComplex {
         }
     }
 
+    /// Position of a vertex. Vertices are numbered the same as in the tetrahedralizations.
+    pub fn position(&self, vertex: usize) -> Vec3 {
+        self.mesh[NodeIndex::new(vertex)]
+    }
+
+    /// Whether two vertices are connected by an edge of some face
+    pub fn has_edge(&self, [s, t]: [usize; 2]) -> bool {
+        let (ns, nt) = (NodeIndex::new(s), NodeIndex::new(t));
+        self.mesh.find_edge(ns, nt).is_some() || self.mesh.find_edge(nt, ns).is_some()
+    }
+
+    /// The longest edge of each face, by face ID
+    pub fn longest_face_edges(&self) -> FnvHashMap<usize, [usize; 2]> {
+        let mut longest = FnvHashMap::<usize, ([usize; 2], f64)>::default();
+        for edge in self.mesh.edge_references() {
+            let length2 = (self.mesh[edge.target()] - self.mesh[edge.source()]).magnitude2();
+            let entry = longest.entry(*edge.weight()).or_insert(([0, 0], -1.0));
+            if length2 > entry.1 {
+                *entry = ([edge.source().index(), edge.target().index()], length2);
+            }
+        }
+        longest.into_iter().map(|(face, (edge, _))| (face, edge)).collect()
+    }
+
     /// Gets rid of triangulation edges,
     /// i.e. edges that were only there to triangulate the mesh
     pub fn dissolve(&mut self) {
@@ -103,9 +138,8 @@ impl PiecewiseLinearComplex {
     }
 
     fn recover_edges(&mut self, dt: &mut DelaunayTetrahedralization) -> Result<(), TetError> {
-        // Just subdivide each missing edge until it exists in the DT.
         // Edges here are undirected and thus their vertices are sorted by index.
-        let mut edges = self
+        let edges = self
             .mesh
             .edge_references()
             .map(|e| {
@@ -118,54 +152,84 @@ impl PiecewiseLinearComplex {
             })
             .collect::<FnvHashSet<_>>();
 
-        let mut missing = edges
+        let missing = edges
             .difference(&dt.tetrahedron_edges().collect())
             .copied()
             .collect::<FnvHashSet<_>>();
 
+        self.recover_missing_edges(dt, missing)
+    }
+
+    /// Just subdivide each missing edge until it exists in the DT.
+    /// Only the edges that are missing get looked at, so recovery stays local.
+    fn recover_missing_edges(
+        &mut self,
+        dt: &mut DelaunayTetrahedralization,
+        mut missing: FnvHashSet<(usize, usize)>,
+    ) -> Result<(), TetError> {
         while let Some((s, t)) = missing.iter().next().copied() {
             missing.remove(&(s, t));
-            let ns = NodeIndex::new(s);
-            let nt = NodeIndex::new(t);
-
-            // Subdivide
-            let pos = (self.mesh[ns] + self.mesh[nt]) / 2.0;
-            let v_new = self.mesh.add_node(pos);
-
-            // At least one of these edges exists
-            for (ns, nt) in vec![(ns, nt), (nt, ns)] {
-                if let Some(edge) = self.mesh.find_edge(ns, nt) {
-                    let face = self.mesh.remove_edge(edge).unwrap();
-                    self.mesh.add_edge(ns, v_new, face);
-                    self.mesh.add_edge(v_new, nt, face);
-                }
-            }
+            self.subdivide_edge(dt, (s, t), &mut missing)?;
+        }
 
-            edges.remove(&(s, t));
-            edges.insert((s, v_new.index()));
-            edges.insert((t, v_new.index()));
-            missing.insert((s, v_new.index()));
-            missing.insert((t, v_new.index()));
+        Ok(())
+    }
 
-            let (removed, added) = dt.add_point(pos)?;
-            for edge in removed {
-                if edges.contains(&edge) {
-                    missing.insert(edge);
-                }
-            }
-            for edge in added {
-                if edges.contains(&edge) {
-                    missing.remove(&edge);
-                }
+    /// Splits an edge at its midpoint in every face that has it, and adds the midpoint to the DT.
+    /// Edges that the new point removes from the DT, and the 2 halves if they aren't in the DT,
+    /// get added to `missing`. Returns the midpoint.
+    fn subdivide_edge(
+        &mut self,
+        dt: &mut DelaunayTetrahedralization,
+        (s, t): (usize, usize),
+        missing: &mut FnvHashSet<(usize, usize)>,
+    ) -> Result<usize, TetError> {
+        let ns = NodeIndex::new(s);
+        let nt = NodeIndex::new(t);
+
+        // Subdivide
+        let pos = (self.mesh[ns] + self.mesh[nt]) / 2.0;
+        let (removed, added) = dt.add_point(pos)?;
+        let v_new = self.mesh.add_node(pos);
+
+        // At least one of these edges exists
+        for (ns, nt) in vec![(ns, nt), (nt, ns)] {
+            // Edges between materials are in more than one face
+            while let Some(edge) = self.mesh.find_edge(ns, nt) {
+                let face = self.mesh.remove_edge(edge).unwrap();
+                self.mesh.add_edge(ns, v_new, face);
+                self.mesh.add_edge(v_new, nt, face);
             }
         }
 
-        Ok(())
+        missing.insert((s, v_new.index()));
+        missing.insert((t, v_new.index()));
+        self.update_missing(missing, removed, added);
+        Ok(v_new.index())
     }
 
-    /// Tetrahedralizes the mesh and returns the
-    /// vertex positions and tetrahedrons.
-    pub fn tetrahedralize(mut self) -> Result<Tetrahedralization, TetError> {
+    /// Updates which edges are missing from the DT after adding a point to it
+    fn update_missing(
+        &self,
+        missing: &mut FnvHashSet<(usize, usize)>,
+        removed: Vec<(usize, usize)>,
+        added: Vec<(usize, usize)>,
+    ) {
+        for (s, t) in removed {
+            if self.has_edge([s, t]) {
+                missing.insert((s, t));
+            }
+        }
+        for edge in added {
+            missing.remove(&edge);
+        }
+    }
+
+    /// Tetrahedralizes the vertices and recovers the edges,
+    /// splitting them where needed. This is the first half of `tetrahedralize`.
+    /// The result can be refined with `insert_steiner_point` and `split_edge`
+    /// before the faces get recovered with `recover_faces`.
+    pub fn delaunay(&mut self) -> Result<DelaunayTetrahedralization, TetError> {
         let mut dt = self.tetrahedralize_vertices()?;
         //dt.export_debug_obj("assets/debug/test_-1_03_57_vertices.obj");
         //dt.export_voronoi_debug_obj("assets/debug/test_-1_03_57_voronoi_vertices.obj");
@@ -174,6 +238,58 @@ impl PiecewiseLinearComplex {
         //dt.export_debug_obj("assets/debug/test_-17_001_040_edges.obj");
         //dt.export_voronoi_debug_obj("assets/debug/test_-17_001_040_voronoi_edges.obj");
 
+        Ok(dt)
+    }
+
+    /// Adds a point that isn't on any face to the complex and to a DT from `delaunay`.
+    /// Only the edges that the point's insertion removed from the DT get recovered again.
+    pub fn insert_steiner_point(
+        &mut self,
+        dt: &mut DelaunayTetrahedralization,
+        pos: Vec3,
+    ) -> Result<(), TetError> {
+        let (removed, added) = dt.add_point(pos)?;
+        self.mesh.add_node(pos);
+
+        let mut missing = FnvHashSet::default();
+        self.update_missing(&mut missing, removed, added);
+        self.recover_missing_edges(dt, missing)
+    }
+
+    /// Splits an edge of the complex at its midpoint, in every face that has it
+    /// and in a DT from `delaunay`.
+    /// Only the edges that the midpoint's insertion removed from the DT get recovered again.
+    /// Returns the midpoint, or fails if the edge isn't in the complex, such as an edge split earlier.
+    pub fn split_edge(
+        &mut self,
+        dt: &mut DelaunayTetrahedralization,
+        [s, t]: [usize; 2],
+    ) -> Result<usize, TetError> {
+        if !self.has_edge([s, t]) {
+            return Err(TetError::EdgeNotInComplex);
+        }
+        let mut missing = FnvHashSet::default();
+        let mid = self.subdivide_edge(dt, (s.min(t), s.max(t)), &mut missing)?;
+        self.recover_missing_edges(dt, missing)?;
+        Ok(mid)
+    }
+
+    /// Tetrahedralizes the mesh and returns the
+    /// vertex positions and tetrahedrons.
+    /// Each tet gets the material of the faces enclosing it.
+    /// Slivers are flipped away where possible, and `Tetrahedralization::flat_tets` lists the flat ones left.
+    pub fn tetrahedralize(mut self) -> Result<Tetrahedralization, TetError> {
+        let dt = self.delaunay()?;
+        self.recover_faces(&dt).map(|(tets, _)| tets)
+    }
+
+    /// Recovers every face from scratch in a copy of a DT from `delaunay`, and removes the tets outside them.
+    /// This is the second half of `tetrahedralize`.
+    /// Also returns the triangles that make up each face, along with the face ID.
+    pub fn recover_faces(
+        &self,
+        dt: &DelaunayTetrahedralization,
+    ) -> Result<(Tetrahedralization, Vec<([usize; 3], usize)>), TetError> {
         // Recover faces
         let (vertices, tets) = dt.tetrahedrons()?;
         let mut tets = Tetrahedralization::new(vertices, tets);
@@ -193,17 +309,28 @@ impl PiecewiseLinearComplex {
         }
         //tets.export_debug_obj("assets/debug/test_-17_001_040_after_flips.obj");
 
-        // Remove outside tets
-        let mut boundary = vec![];
-        for (face_id, face) in &faces {
-            boundary.extend(tets.plc_face_triangles(
-                &face.iter().map(|[a, b]| [a.index(), b.index()]).collect::<Vec<_>>(),
-                self.normals[*face_id]
-            )?);
-        }
-        tets.remove_tets_outside_boundary(&boundary);
+        let triangulate_faces = |tets: &Tetrahedralization| -> Result<Vec<([usize; 3], usize)>, TetError> {
+            let mut triangles = vec![];
+            for (face_id, face) in &faces {
+                triangles.extend(tets.plc_face_triangles(
+                    &face.iter().map(|[a, b]| [a.index(), b.index()]).collect::<Vec<_>>(),
+                    self.normals[*face_id]
+                )?.into_iter().map(|triangle| (triangle, *face_id)));
+            }
+            Ok(triangles)
+        };
 
-        Ok(tets)
+        // Remove outside tets, keeping track of which material each boundary triangle encloses
+        let boundary = triangulate_faces(&tets)?
+            .into_iter()
+            .map(|(triangle, face_id)| (triangle, self.materials[face_id]))
+            .collect::<Vec<_>>();
+        tets.remove_tets_outside_boundary(&boundary)?;
+        tets.remove_slivers(Self::SLIVER_ANGLE);
+
+        // Flips can change how a face is split into triangles
+        let face_triangles = triangulate_faces(&tets)?;
+        Ok((tets, face_triangles))
     }
 
     pub fn export_debug_obj<P: AsRef<Path>>(&self, path: P) {
@@ -226,6 +353,8 @@ impl PiecewiseLinearComplex {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::material_mesh::box_corners_and_triangles;
+    use crate::tet_quality;
     use petgraph::algo;
     use petgraph::data::{Element, FromElements};
 
@@ -297,6 +426,44 @@ mod test {
         assert_eq!(plc.materials, vec![MaterialID::default(); 4]);
     }
 
+    #[test]
+    fn test_refine_incrementally() {
+        let (corners, triangles) = box_corners_and_triangles(vec3(0.0, 0.0, 0.0), vec3(1.0, 1.0, 1.0));
+        let positions = corners.into_iter().flat_map(|p| vec![p.x, p.y, p.z]).collect();
+        let indexes = triangles.into_iter().flat_map(|[a, b, c]| vec![a as u32, b as u32, c as u32]).collect();
+        let mut plc = PiecewiseLinearComplex::new(create_mesh(positions, indexes));
+        let mut dt = plc.delaunay().unwrap();
+
+        let edge = *plc.longest_face_edges().values().next().unwrap();
+        let mid = plc.split_edge(&mut dt, edge).unwrap();
+        assert_eq!(plc.position(mid), (plc.position(edge[0]) + plc.position(edge[1])) / 2.0);
+        assert!(!plc.has_edge(edge));
+        assert!(plc.has_edge([edge[0], mid]) && plc.has_edge([mid, edge[1]]));
+        assert_eq!(plc.split_edge(&mut dt, edge), Err(TetError::EdgeNotInComplex));
+        plc.insert_steiner_point(&mut dt, vec3(0.5, 0.5, 0.5)).unwrap();
+
+        let (tets, triangles) = plc.recover_faces(&dt).unwrap();
+        let (positions, tets) = tets.tets_with_materials();
+        assert!(positions.contains(&vec3(0.5, 0.5, 0.5)));
+        assert!(positions.contains(&plc.position(mid)));
+
+        let volume = tets
+            .iter()
+            .map(|([a, b, c, d], _)| tet_quality::signed_volume([positions[*a], positions[*b], positions[*c], positions[*d]]))
+            .sum::<f64>();
+        assert!((volume - 1.0).abs() < 1e-9);
+
+        // The face triangles cover the cube's surface
+        let area = triangles
+            .iter()
+            .map(|([a, b, c], _)| {
+                let (a, b, c) = (plc.position(*a), plc.position(*b), plc.position(*c));
+                (b - a).cross(c - a).magnitude() / 2.0
+            })
+            .sum::<f64>();
+        assert!((area - 6.0).abs() < 1e-9);
+    }
+
     #[test]
     fn test_dissolve() {
         let mesh = create_mesh(
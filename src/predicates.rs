@@ -0,0 +1,410 @@
+//! Exact geometric predicates.
+//!
+//! Each predicate evaluates its determinant in floating point first and checks it against
+//! an error bound, as in Shewchuk's "Adaptive Precision Floating-Point Arithmetic and
+//! Fast Robust Geometric Predicates". Only when the sign is in doubt is the determinant
+//! evaluated again exactly, with expansions: sums of nonoverlapping doubles.
+//! The sign of the result is always right; the magnitude is only an approximation.
+
+use tri_mesh::prelude::*;
+
+use crate::util::Vec2;
+
+/// Relative error of a rounded operation, half an ulp of 1
+const EPSILON: f64 = f64::EPSILON / 2.0;
+
+const ORIENT2D_BOUND: f64 = (3.0 + 16.0 * EPSILON) * EPSILON;
+const ORIENT3D_BOUND: f64 = (7.0 + 56.0 * EPSILON) * EPSILON;
+const INCIRCLE_BOUND: f64 = (10.0 + 96.0 * EPSILON) * EPSILON;
+const INSPHERE_BOUND: f64 = (16.0 + 224.0 * EPSILON) * EPSILON;
+const PLANE_SIDE_BOUND: f64 = (5.0 + 64.0 * EPSILON) * EPSILON;
+
+/// Positive if a, b, c wind counterclockwise, negative if clockwise, and 0 if they're collinear.
+/// Same sign as `(b - a).perp_dot(c - a)`.
+pub fn orient2d(a: Vec2, b: Vec2, c: Vec2) -> f64 {
+    let det_left = (a.x - c.x) * (b.y - c.y);
+    let det_right = (a.y - c.y) * (b.x - c.x);
+    let det = det_left - det_right;
+    if det.abs() > ORIENT2D_BOUND * (det_left.abs() + det_right.abs()) {
+        return det;
+    }
+
+    let acx = Expansion::diff(a.x, c.x);
+    let acy = Expansion::diff(a.y, c.y);
+    let bcx = Expansion::diff(b.x, c.x);
+    let bcy = Expansion::diff(b.y, c.y);
+    acx.mul(&bcy).sub(&acy.mul(&bcx)).estimate()
+}
+
+/// Positive if d is on the side of the plane through a, b, c that the plane's normal points to,
+/// when a, b, c wind counterclockwise around the normal. Negative if it's on the other side,
+/// and 0 if the points are coplanar. Same sign as `(b - a).cross(c - a).dot(d - a)`.
+pub fn orient3d(a: Vec3, b: Vec3, c: Vec3, d: Vec3) -> f64 {
+    let (adx, ady, adz) = (a.x - d.x, a.y - d.y, a.z - d.z);
+    let (bdx, bdy, bdz) = (b.x - d.x, b.y - d.y, b.z - d.z);
+    let (cdx, cdy, cdz) = (c.x - d.x, c.y - d.y, c.z - d.z);
+
+    let (bdxcdy, cdxbdy) = (bdx * cdy, cdx * bdy);
+    let (cdxady, adxcdy) = (cdx * ady, adx * cdy);
+    let (adxbdy, bdxady) = (adx * bdy, bdx * ady);
+
+    let det = adz * (bdxcdy - cdxbdy) + bdz * (cdxady - adxcdy) + cdz * (adxbdy - bdxady);
+    let permanent = (bdxcdy.abs() + cdxbdy.abs()) * adz.abs()
+        + (cdxady.abs() + adxcdy.abs()) * bdz.abs()
+        + (adxbdy.abs() + bdxady.abs()) * cdz.abs();
+    // Shewchuk's determinant has the opposite sign
+    if det.abs() > ORIENT3D_BOUND * permanent {
+        return -det;
+    }
+
+    let [adx, ady, adz] = exact_diff(a, d);
+    let [bdx, bdy, bdz] = exact_diff(b, d);
+    let [cdx, cdy, cdz] = exact_diff(c, d);
+    let det = adz
+        .mul(&bdx.mul(&cdy).sub(&cdx.mul(&bdy)))
+        .add(&bdz.mul(&cdx.mul(&ady).sub(&adx.mul(&cdy))))
+        .add(&cdz.mul(&adx.mul(&bdy).sub(&bdx.mul(&ady))));
+    -det.estimate()
+}
+
+/// Positive if d is inside the circle through a, b, c, when they wind counterclockwise.
+/// Negative if it's outside, and 0 if the points are cocircular.
+/// The sign flips when a, b, c wind clockwise.
+pub fn incircle(a: Vec2, b: Vec2, c: Vec2, d: Vec2) -> f64 {
+    let (adx, ady) = (a.x - d.x, a.y - d.y);
+    let (bdx, bdy) = (b.x - d.x, b.y - d.y);
+    let (cdx, cdy) = (c.x - d.x, c.y - d.y);
+
+    let (bdxcdy, cdxbdy) = (bdx * cdy, cdx * bdy);
+    let (cdxady, adxcdy) = (cdx * ady, adx * cdy);
+    let (adxbdy, bdxady) = (adx * bdy, bdx * ady);
+    let a_lift = adx * adx + ady * ady;
+    let b_lift = bdx * bdx + bdy * bdy;
+    let c_lift = cdx * cdx + cdy * cdy;
+
+    let det = a_lift * (bdxcdy - cdxbdy) + b_lift * (cdxady - adxcdy) + c_lift * (adxbdy - bdxady);
+    let permanent = (bdxcdy.abs() + cdxbdy.abs()) * a_lift
+        + (cdxady.abs() + adxcdy.abs()) * b_lift
+        + (adxbdy.abs() + bdxady.abs()) * c_lift;
+    if det.abs() > INCIRCLE_BOUND * permanent {
+        return det;
+    }
+
+    let (adx, ady) = (Expansion::diff(a.x, d.x), Expansion::diff(a.y, d.y));
+    let (bdx, bdy) = (Expansion::diff(b.x, d.x), Expansion::diff(b.y, d.y));
+    let (cdx, cdy) = (Expansion::diff(c.x, d.x), Expansion::diff(c.y, d.y));
+    let a_lift = adx.mul(&adx).add(&ady.mul(&ady));
+    let b_lift = bdx.mul(&bdx).add(&bdy.mul(&bdy));
+    let c_lift = cdx.mul(&cdx).add(&cdy.mul(&cdy));
+
+    a_lift
+        .mul(&bdx.mul(&cdy).sub(&cdx.mul(&bdy)))
+        .add(&b_lift.mul(&cdx.mul(&ady).sub(&adx.mul(&cdy))))
+        .add(&c_lift.mul(&adx.mul(&bdy).sub(&bdx.mul(&ady))))
+        .estimate()
+}
+
+/// Positive if e is inside the sphere through a, b, c, d, when `orient3d(a, b, c, d)` is positive.
+/// Negative if it's outside, and 0 if the points are cospherical.
+/// The sign flips when `orient3d(a, b, c, d)` is negative.
+pub fn insphere(a: Vec3, b: Vec3, c: Vec3, d: Vec3, e: Vec3) -> f64 {
+    let (aex, aey, aez) = (a.x - e.x, a.y - e.y, a.z - e.z);
+    let (bex, bey, bez) = (b.x - e.x, b.y - e.y, b.z - e.z);
+    let (cex, cey, cez) = (c.x - e.x, c.y - e.y, c.z - e.z);
+    let (dex, dey, dez) = (d.x - e.x, d.y - e.y, d.z - e.z);
+
+    let (aexbey, bexaey) = (aex * bey, bex * aey);
+    let (bexcey, cexbey) = (bex * cey, cex * bey);
+    let (cexdey, dexcey) = (cex * dey, dex * cey);
+    let (dexaey, aexdey) = (dex * aey, aex * dey);
+    let (aexcey, cexaey) = (aex * cey, cex * aey);
+    let (bexdey, dexbey) = (bex * dey, dex * bey);
+    let ab = aexbey - bexaey;
+    let bc = bexcey - cexbey;
+    let cd = cexdey - dexcey;
+    let da = dexaey - aexdey;
+    let ac = aexcey - cexaey;
+    let bd = bexdey - dexbey;
+
+    let abc = aez * bc - bez * ac + cez * ab;
+    let bcd = bez * cd - cez * bd + dez * bc;
+    let cda = cez * da + dez * ac + aez * cd;
+    let dab = dez * ab + aez * bd + bez * da;
+
+    let a_lift = aex * aex + aey * aey + aez * aez;
+    let b_lift = bex * bex + bey * bey + bez * bez;
+    let c_lift = cex * cex + cey * cey + cez * cez;
+    let d_lift = dex * dex + dey * dey + dez * dez;
+
+    let det = (d_lift * abc - c_lift * dab) + (b_lift * cda - a_lift * bcd);
+
+    let ab_plus = aexbey.abs() + bexaey.abs();
+    let bc_plus = bexcey.abs() + cexbey.abs();
+    let cd_plus = cexdey.abs() + dexcey.abs();
+    let da_plus = dexaey.abs() + aexdey.abs();
+    let ac_plus = aexcey.abs() + cexaey.abs();
+    let bd_plus = bexdey.abs() + dexbey.abs();
+    let permanent = (cd_plus * bez.abs() + bd_plus * cez.abs() + bc_plus * dez.abs()) * a_lift
+        + (da_plus * cez.abs() + ac_plus * dez.abs() + cd_plus * aez.abs()) * b_lift
+        + (ab_plus * dez.abs() + bd_plus * aez.abs() + da_plus * bez.abs()) * c_lift
+        + (bc_plus * aez.abs() + ac_plus * bez.abs() + ab_plus * cez.abs()) * d_lift;
+    // Shewchuk's determinant has the opposite sign, because his orient3d does
+    if det.abs() > INSPHERE_BOUND * permanent {
+        return -det;
+    }
+
+    let [aex, aey, aez] = exact_diff(a, e);
+    let [bex, bey, bez] = exact_diff(b, e);
+    let [cex, cey, cez] = exact_diff(c, e);
+    let [dex, dey, dez] = exact_diff(d, e);
+
+    let ab = aex.mul(&bey).sub(&bex.mul(&aey));
+    let bc = bex.mul(&cey).sub(&cex.mul(&bey));
+    let cd = cex.mul(&dey).sub(&dex.mul(&cey));
+    let da = dex.mul(&aey).sub(&aex.mul(&dey));
+    let ac = aex.mul(&cey).sub(&cex.mul(&aey));
+    let bd = bex.mul(&dey).sub(&dex.mul(&bey));
+
+    let abc = aez.mul(&bc).sub(&bez.mul(&ac)).add(&cez.mul(&ab));
+    let bcd = bez.mul(&cd).sub(&cez.mul(&bd)).add(&dez.mul(&bc));
+    let cda = cez.mul(&da).add(&dez.mul(&ac)).add(&aez.mul(&cd));
+    let dab = dez.mul(&ab).add(&aez.mul(&bd)).add(&bez.mul(&da));
+
+    let lift = |x: &Expansion, y: &Expansion, z: &Expansion| x.mul(x).add(&y.mul(y)).add(&z.mul(z));
+    let det = lift(&dex, &dey, &dez)
+        .mul(&abc)
+        .sub(&lift(&cex, &cey, &cez).mul(&dab))
+        .add(&lift(&bex, &bey, &bez).mul(&cda))
+        .sub(&lift(&aex, &aey, &aez).mul(&bcd));
+    -det.estimate()
+}
+
+/// Positive if p is on the side of the plane through some point that the normal points to,
+/// negative if it's on the other side, and 0 if it's on the plane.
+/// Same sign as `normal.dot(p - point)`.
+pub fn plane_side(point: Vec3, normal: Vec3, p: Vec3) -> f64 {
+    let products = [
+        normal.x * (p.x - point.x),
+        normal.y * (p.y - point.y),
+        normal.z * (p.z - point.z),
+    ];
+    let det = products[0] + products[1] + products[2];
+    let permanent = products.iter().map(|p| p.abs()).sum::<f64>();
+    if det.abs() > PLANE_SIDE_BOUND * permanent {
+        return det;
+    }
+
+    let [dx, dy, dz] = exact_diff(p, point);
+    dx.scale(normal.x).add(&dy.scale(normal.y)).add(&dz.scale(normal.z)).estimate()
+}
+
+/// Positive if the triangle a, b, c winds counterclockwise around a normal,
+/// negative if it winds clockwise, and 0 if the normal is parallel to the triangle
+/// or the triangle is degenerate. Same sign as `(b - a).cross(c - a).dot(normal)`.
+pub fn orient_around(a: Vec3, b: Vec3, c: Vec3, normal: Vec3) -> f64 {
+    let (bax, bay, baz) = (b.x - a.x, b.y - a.y, b.z - a.z);
+    let (cax, cay, caz) = (c.x - a.x, c.y - a.y, c.z - a.z);
+
+    let (baycaz, bazcay) = (bay * caz, baz * cay);
+    let (bazcax, baxcaz) = (baz * cax, bax * caz);
+    let (baxcay, baycax) = (bax * cay, bay * cax);
+
+    let det = normal.x * (baycaz - bazcay) + normal.y * (bazcax - baxcaz) + normal.z * (baxcay - baycax);
+    let permanent = (baycaz.abs() + bazcay.abs()) * normal.x.abs()
+        + (bazcax.abs() + baxcaz.abs()) * normal.y.abs()
+        + (baxcay.abs() + baycax.abs()) * normal.z.abs();
+    if det.abs() > ORIENT3D_BOUND * permanent {
+        return det;
+    }
+
+    let [bax, bay, baz] = exact_diff(b, a);
+    let [cax, cay, caz] = exact_diff(c, a);
+    bay.mul(&caz)
+        .sub(&baz.mul(&cay))
+        .scale(normal.x)
+        .add(&baz.mul(&cax).sub(&bax.mul(&caz)).scale(normal.y))
+        .add(&bax.mul(&cay).sub(&bay.mul(&cax)).scale(normal.z))
+        .estimate()
+}
+
+/// Exact differences of the coordinates of 2 points
+fn exact_diff(a: Vec3, b: Vec3) -> [Expansion; 3] {
+    [
+        Expansion::diff(a.x, b.x),
+        Expansion::diff(a.y, b.y),
+        Expansion::diff(a.z, b.z),
+    ]
+}
+
+/// Computes a + b, along with the rounding error so that the sum of the two is exact
+fn two_sum(a: f64, b: f64) -> (f64, f64) {
+    let x = a + b;
+    let b_virtual = x - a;
+    let a_virtual = x - b_virtual;
+    (x, (a - a_virtual) + (b - b_virtual))
+}
+
+/// Computes a * b, along with the rounding error so that the sum of the two is exact
+fn two_product(a: f64, b: f64) -> (f64, f64) {
+    let x = a * b;
+    (x, a.mul_add(b, -x))
+}
+
+/// A number represented exactly as a sum of nonoverlapping doubles,
+/// in order of increasing magnitude and without zeros
+#[derive(Clone, Debug, PartialEq)]
+struct Expansion(Vec<f64>);
+
+impl Expansion {
+    /// Exactly a - b
+    fn diff(a: f64, b: f64) -> Self {
+        let (x, error) = two_sum(a, -b);
+        Self(vec![error, x].into_iter().filter(|c| *c != 0.0).collect())
+    }
+
+    /// Adds a double. Shewchuk's GROW-EXPANSION with zeros removed.
+    fn grow(&self, b: f64) -> Self {
+        let mut q = b;
+        let mut components = Vec::with_capacity(self.0.len() + 1);
+        for e in &self.0 {
+            let (sum, error) = two_sum(q, *e);
+            if error != 0.0 {
+                components.push(error);
+            }
+            q = sum;
+        }
+        if q != 0.0 {
+            components.push(q);
+        }
+        Self(components)
+    }
+
+    fn add(&self, other: &Self) -> Self {
+        other.0.iter().fold(self.clone(), |sum, b| sum.grow(*b))
+    }
+
+    fn sub(&self, other: &Self) -> Self {
+        other.0.iter().fold(self.clone(), |sum, b| sum.grow(-*b))
+    }
+
+    /// Multiplies by a double. Shewchuk's SCALE-EXPANSION with zeros removed.
+    fn scale(&self, b: f64) -> Self {
+        let mut components = Vec::with_capacity(self.0.len() * 2);
+        let mut iter = self.0.iter();
+
+        let mut q = match iter.next() {
+            Some(e) => {
+                let (product, error) = two_product(*e, b);
+                components.push(error);
+                product
+            }
+            None => return Self(vec![]),
+        };
+
+        for e in iter {
+            let (product, product_error) = two_product(*e, b);
+            let (sum, error) = two_sum(q, product_error);
+            components.push(error);
+            let (sum, error) = two_sum(product, sum);
+            components.push(error);
+            q = sum;
+        }
+        components.push(q);
+
+        Self(components.into_iter().filter(|c| *c != 0.0).collect())
+    }
+
+    fn mul(&self, other: &Self) -> Self {
+        other.0.iter().fold(Self(vec![]), |sum, b| sum.add(&self.scale(*b)))
+    }
+
+    /// The biggest component, which has the sign of the whole expansion and approximates it
+    fn estimate(&self) -> f64 {
+        self.0.last().copied().unwrap_or(0.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_expansion_exact() {
+        // 1 + 2^-60 isn't representable, but its expansion is
+        let tiny = 2f64.powi(-60);
+        let sum = Expansion::diff(1.0, -tiny);
+        assert_eq!(sum.0, vec![tiny, 1.0]);
+        assert_eq!(sum.sub(&Expansion::diff(1.0, 0.0)).estimate(), tiny);
+        assert_eq!(sum.mul(&sum).sub(&sum.scale(2.0)).grow(1.0).estimate(), tiny * tiny);
+    }
+
+    #[test]
+    fn test_orient2d() {
+        assert!(orient2d(vec2(0.0, 0.0), vec2(1.0, 0.0), vec2(0.0, 1.0)) > 0.0);
+        assert!(orient2d(vec2(0.0, 0.0), vec2(0.0, 1.0), vec2(1.0, 0.0)) < 0.0);
+
+        // Nearly collinear points where the floating point determinant is wrong
+        let a = vec2(0.5, 0.5);
+        let b = vec2(12.0, 12.0);
+        let c = vec2(24.0, 24.0);
+        assert_eq!(orient2d(a, b, c), 0.0);
+        for i in 1..64 {
+            let p = vec2(a.x + i as f64 * f64::EPSILON, a.y);
+            assert!(orient2d(p, b, c) < 0.0);
+        }
+    }
+
+    #[test]
+    fn test_orient3d() {
+        let a = vec3(0.0, 0.0, 0.0);
+        let b = vec3(1.0, 0.0, 0.0);
+        let c = vec3(0.0, 1.0, 0.0);
+        assert!(orient3d(a, b, c, vec3(0.0, 0.0, 1.0)) > 0.0);
+        assert!(orient3d(a, b, c, vec3(0.0, 0.0, -1.0)) < 0.0);
+        assert_eq!(orient3d(a, b, c, vec3(0.3, 0.7, 0.0)), 0.0);
+
+        // Coplanar points on a tilted plane far from the origin
+        let offset = 1.0e8;
+        let a = vec3(offset, offset, offset);
+        let b = vec3(offset + 0.125, offset, offset + 0.125);
+        let c = vec3(offset, offset + 0.25, offset + 0.25);
+        let d = vec3(offset + 0.375, offset + 0.5, offset + 0.875);
+        assert_eq!(orient3d(a, b, c, d), 0.0);
+        assert!(orient3d(a, b, c, d + vec3(0.0, 0.0, 1e-7)) > 0.0);
+        assert!(orient3d(a, b, c, d - vec3(0.0, 0.0, 1e-7)) < 0.0);
+        assert_eq!(orient3d(b, b, c, a), 0.0);
+    }
+
+    #[test]
+    fn test_incircle_and_insphere() {
+        let a = vec2(0.0, 0.0);
+        let b = vec2(1.0, 0.0);
+        let c = vec2(0.0, 1.0);
+        assert!(incircle(a, b, c, vec2(0.5, 0.5)) > 0.0);
+        assert!(incircle(a, b, c, vec2(2.0, 2.0)) < 0.0);
+        assert_eq!(incircle(a, b, c, vec2(1.0, 1.0)), 0.0);
+
+        let a = vec3(0.0, 0.0, 0.0);
+        let b = vec3(1.0, 0.0, 0.0);
+        let c = vec3(0.0, 1.0, 0.0);
+        let d = vec3(0.0, 0.0, 1.0);
+        assert!(insphere(a, b, c, d, vec3(0.25, 0.25, 0.25)) > 0.0);
+        assert!(insphere(a, b, c, d, vec3(3.0, 3.0, 3.0)) < 0.0);
+        assert_eq!(insphere(a, b, c, d, vec3(1.0, 1.0, 1.0)), 0.0);
+        assert!(insphere(b, a, c, d, vec3(0.25, 0.25, 0.25)) < 0.0);
+    }
+
+    #[test]
+    fn test_plane_side_and_orient_around() {
+        let normal = vec3(0.0, 0.0, 1.0);
+        assert!(plane_side(vec3(0.0, 0.0, 1.0), normal, vec3(5.0, 5.0, 1.0 + f64::EPSILON)) > 0.0);
+        assert_eq!(plane_side(vec3(0.0, 0.0, 1.0), normal, vec3(5.0, 5.0, 1.0)), 0.0);
+
+        let a = vec3(0.0, 0.0, 0.0);
+        let b = vec3(1.0, 0.0, 0.0);
+        let c = vec3(0.0, 1.0, 0.0);
+        assert!(orient_around(a, b, c, normal) > 0.0);
+        assert!(orient_around(a, c, b, normal) < 0.0);
+        assert_eq!(orient_around(a, b, c, vec3(1.0, 0.0, 0.0)), 0.0);
+    }
+}
@@ -0,0 +1,128 @@
+//! Orders points so that consecutive points tend to be close together,
+//! which keeps point location walks short when building a Delaunay tetrahedralization.
+
+use tri_mesh::prelude::*;
+
+/// Number of bits per coordinate of the grid the Hilbert curve runs through
+const HILBERT_BITS: u32 = 10;
+
+/// Orders points with a biased randomized insertion order (BRIO).
+/// Each point is put in the last round with probability 1/2, the round before with probability 1/4,
+/// and so on, so each round is about as big as all the rounds before it together.
+/// Points within a round follow a Hilbert curve.
+/// The "random" choices hash the point index, so the order is the same every time.
+/// Returns the indexes of the points in order.
+pub fn brio_order(points: &[Vec3]) -> Vec<usize> {
+    if points.is_empty() {
+        return vec![];
+    }
+
+    let min = points.iter().fold(vec3(f64::MAX, f64::MAX, f64::MAX), |min, p| {
+        vec3(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z))
+    });
+    let max = points.iter().fold(vec3(f64::MIN, f64::MIN, f64::MIN), |max, p| {
+        vec3(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z))
+    });
+    let size = (max - min).x.max((max - min).y).max((max - min).z);
+    let cells = (1u32 << HILBERT_BITS) - 1;
+    let scale = if size > 0.0 { cells as f64 / size } else { 0.0 };
+
+    let last_round = (points.len() as f64).log2().ceil() as u32;
+    let mut keys = points
+        .iter()
+        .enumerate()
+        .map(|(i, p)| {
+            let round = last_round - splitmix64(i as u64).trailing_zeros().min(last_round);
+            let cell = (*p - min) * scale;
+            let cell = [cell.x as u32, cell.y as u32, cell.z as u32];
+            ((round, hilbert_index(cell, HILBERT_BITS)), i)
+        })
+        .collect::<Vec<_>>();
+
+    keys.sort();
+    keys.into_iter().map(|(_, i)| i).collect()
+}
+
+/// Position along a Hilbert curve through a grid with 2^bits cells on each side.
+/// Uses Skilling's transform from axes to the transposed Hilbert index.
+fn hilbert_index(mut x: [u32; 3], bits: u32) -> u64 {
+    let m = 1 << (bits - 1);
+
+    // Undo excess work
+    let mut q = m;
+    while q > 1 {
+        let p = q - 1;
+        for i in 0..3 {
+            if x[i] & q != 0 {
+                x[0] ^= p;
+            } else {
+                let t = (x[0] ^ x[i]) & p;
+                x[0] ^= t;
+                x[i] ^= t;
+            }
+        }
+        q >>= 1;
+    }
+
+    // Gray encode
+    for i in 1..3 {
+        x[i] ^= x[i - 1];
+    }
+    let mut t = 0;
+    let mut q = m;
+    while q > 1 {
+        if x[2] & q != 0 {
+            t ^= q - 1;
+        }
+        q >>= 1;
+    }
+    for coord in &mut x {
+        *coord ^= t;
+    }
+
+    // Interleave the transposed index
+    let mut index = 0;
+    for b in (0..bits).rev() {
+        for coord in &x {
+            index = (index << 1) | ((coord >> b) & 1) as u64;
+        }
+    }
+    index
+}
+
+/// Scrambles a number
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hilbert_index_adjacent() {
+        // Consecutive cells along the curve share a face
+        let mut cells = (0..64u32).map(|i| [i & 3, (i >> 2) & 3, i >> 4]).collect::<Vec<_>>();
+        cells.sort_by_key(|cell| hilbert_index(*cell, 2));
+
+        for (a, b) in cells.iter().zip(cells.iter().skip(1)) {
+            let dist = (0..3).map(|i| (a[i] as i32 - b[i] as i32).abs()).sum::<i32>();
+            assert_eq!(dist, 1);
+        }
+    }
+
+    #[test]
+    fn test_brio_order_permutation() {
+        let points = (0..1000)
+            .map(|i| vec3((i % 10) as f64, ((i / 10) % 10) as f64, (i / 100) as f64))
+            .collect::<Vec<_>>();
+
+        let mut order = brio_order(&points);
+        assert_ne!(order, (0..1000).collect::<Vec<_>>());
+        order.sort();
+        assert_eq!(order, (0..1000).collect::<Vec<_>>());
+    }
+}
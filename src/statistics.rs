@@ -0,0 +1,201 @@
+//! Material usage statistics for voxelizations, for quoting and sanity checks.
+
+use fnv::FnvHashMap;
+use tri_mesh::prelude::*;
+
+use crate::material_mesh::{MaterialID, MaterialMesh};
+use crate::surface::{area_vector, intersection};
+use crate::voxels::{chunk_offsets, Chunk, GridTransform, VoxelRef, Voxels};
+
+/// Statistics about a voxelization. Volumes and areas are in world units.
+#[derive(Clone, Debug, Default)]
+pub struct VoxelStatistics {
+    /// Total volume of each material, including partial volumes in complex voxels
+    pub volumes: FnvHashMap<MaterialID, f64>,
+    /// Area of the interface between each pair of materials, keyed with the smaller material first
+    pub interface_areas: FnvHashMap<(MaterialID, MaterialID), f64>,
+    /// Area of each material that borders empty space
+    pub exposed_areas: FnvHashMap<MaterialID, f64>,
+    pub uniform_chunks: usize,
+    pub complex_chunks: usize,
+    pub pure_voxels: usize,
+    pub complex_voxels: usize,
+}
+
+/// Volume of a material in a voxelization and in the mesh it came from
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct VolumeComparison {
+    pub voxels: f64,
+    pub mesh: f64,
+}
+
+impl VolumeComparison {
+    /// Difference relative to the mesh's volume.
+    /// Infinite if the mesh has none of the material but the voxelization does.
+    pub fn relative_error(&self) -> f64 {
+        if self.mesh == 0.0 && self.voxels == 0.0 {
+            0.0
+        } else {
+            (self.voxels - self.mesh).abs() / self.mesh.abs()
+        }
+    }
+}
+
+impl VoxelStatistics {
+    /// Compares material volumes against those of the mesh, for each material in either.
+    pub fn compare_volumes(&self, mesh: &MaterialMesh) -> FnvHashMap<MaterialID, VolumeComparison> {
+        let mesh_volumes = mesh.material_volumes();
+
+        self.volumes
+            .keys()
+            .chain(mesh_volumes.keys())
+            .map(|material| {
+                (*material, VolumeComparison {
+                    voxels: self.volumes.get(material).copied().unwrap_or(0.0),
+                    mesh: mesh_volumes.get(material).copied().unwrap_or(0.0),
+                })
+            })
+            .collect()
+    }
+}
+
+impl Voxels {
+    /// Computes material volumes, interface areas, and chunk and voxel counts.
+    pub fn statistics(&self) -> VoxelStatistics {
+        let transform = self.transform();
+        let size = transform.voxel_size();
+        let voxel_volume = size.x * size.y * size.z;
+
+        let mut stats = VoxelStatistics::default();
+        let (uniform_chunks, complex_chunks) = self.chunk_counts();
+        stats.uniform_chunks = uniform_chunks;
+        stats.complex_chunks = complex_chunks;
+
+        for (_, chunk) in self.chunks() {
+            let chunk = match chunk {
+                Chunk::Uniform(material) => {
+                    // No need to look at each voxel
+                    let count = self.chunk_size().pow(3);
+                    stats.pure_voxels += count;
+                    *stats.volumes.entry(*material).or_insert(0.0) += count as f64 * voxel_volume;
+                    continue;
+                }
+                Chunk::Complex(chunk) => chunk,
+            };
+
+            for offset in chunk_offsets(chunk.size()) {
+                match chunk.voxel_ref(offset) {
+                    VoxelRef::Empty => {}
+
+                    VoxelRef::Pure(material) => {
+                        stats.pure_voxels += 1;
+                        *stats.volumes.entry(material).or_insert(0.0) += voxel_volume;
+                    }
+
+                    VoxelRef::Complex(complex) => {
+                        stats.complex_voxels += 1;
+                        for (material, volume) in complex.material_volumes() {
+                            *stats.volumes.entry(material).or_insert(0.0) += volume * voxel_volume;
+                        }
+                    }
+                }
+            }
+        }
+
+        let boundaries = self.material_boundaries();
+        let mut total_areas = FnvHashMap::<MaterialID, f64>::default();
+        let mut planes = FnvHashMap::default();
+
+        for (cell, faces) in &boundaries {
+            *total_areas.entry(cell.material).or_insert(0.0) += faces
+                .aligned
+                .iter()
+                .chain(&faces.opposed)
+                .map(|polygon| world_area(&transform, polygon))
+                .sum::<f64>();
+
+            planes
+                .entry(cell.with_material(MaterialID::default()))
+                .or_insert_with(Vec::new)
+                .push((cell.material, faces));
+        }
+
+        // Faces between two materials appear in both of their boundaries, facing opposite ways
+        for materials in planes.values() {
+            for (i, (m0, f0)) in materials.iter().enumerate() {
+                for (m1, f1) in &materials[i + 1..] {
+                    let area = overlap_area(&transform, &f0.aligned, &f1.opposed, -f1.normal)
+                        + overlap_area(&transform, &f0.opposed, &f1.aligned, f1.normal);
+
+                    if area > 0.0 {
+                        let key = if m0 < m1 { (*m0, *m1) } else { (*m1, *m0) };
+                        *stats.interface_areas.entry(key).or_insert(0.0) += area;
+                    }
+                }
+            }
+        }
+
+        stats.exposed_areas = total_areas;
+        for ((m0, m1), area) in &stats.interface_areas {
+            for material in &[*m0, *m1] {
+                if let Some(exposed) = stats.exposed_areas.get_mut(material) {
+                    *exposed = (*exposed - area).max(0.0);
+                }
+            }
+        }
+
+        stats
+    }
+}
+
+/// Area of a polygon in grid coordinates, measured in world units
+fn world_area(transform: &GridTransform, polygon: &[Vec3]) -> f64 {
+    let world = polygon.iter().map(|p| transform.grid_to_world(*p)).collect::<Vec<_>>();
+    area_vector(&world).magnitude()
+}
+
+/// Total area of the overlaps between polygons in `ps` and polygons in `qs`,
+/// where `qs` wind counterclockwise around `q_normal`.
+fn overlap_area(transform: &GridTransform, ps: &[Vec<Vec3>], qs: &[Vec<Vec3>], q_normal: Vec3) -> f64 {
+    ps.iter()
+        .flat_map(|p| qs.iter().filter_map(move |q| intersection(p, q, q_normal)))
+        .map(|polygon| world_area(transform, &polygon))
+        .sum()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_statistics_pure_voxels() {
+        let mut voxels = Voxels::default();
+        voxels.fill_box(vec3(10, 10, 10), vec3(12, 11, 11), Some(MaterialID::new(1)));
+        voxels.set(vec3(12, 10, 10), Some(MaterialID::new(2)));
+
+        let stats = voxels.statistics();
+        assert_eq!(stats.pure_voxels, 3);
+        assert_eq!(stats.complex_voxels, 0);
+
+        let volume = |id| stats.volumes.get(&MaterialID::new(id)).copied().unwrap_or(0.0);
+        assert!((volume(1) - 2.0).abs() < 1e-9, "{:?}", stats);
+        assert!((volume(2) - 1.0).abs() < 1e-9, "{:?}", stats);
+
+        let interface = stats.interface_areas[&(MaterialID::new(1), MaterialID::new(2))];
+        assert!((interface - 1.0).abs() < 1e-9, "{:?}", stats);
+        assert!((stats.exposed_areas[&MaterialID::new(1)] - 9.0).abs() < 1e-9, "{:?}", stats);
+        assert!((stats.exposed_areas[&MaterialID::new(2)] - 5.0).abs() < 1e-9, "{:?}", stats);
+    }
+
+    #[test]
+    fn test_statistics_uniform_chunk() {
+        let mut voxels = Voxels::default();
+        voxels.fill_box(vec3(0, 0, 0), vec3(16, 16, 16), Some(MaterialID::new(1)));
+        voxels.set(vec3(16, 0, 0), Some(MaterialID::new(1)));
+
+        let stats = voxels.statistics();
+        assert_eq!((stats.uniform_chunks, stats.complex_chunks), (1, 1));
+        assert_eq!(stats.pure_voxels, 16 * 16 * 16 + 1);
+        assert!((stats.volumes[&MaterialID::new(1)] - 4097.0).abs() < 1e-9, "{:?}", stats);
+    }
+}
@@ -0,0 +1,254 @@
+//! Import of STL files, both ASCII and binary.
+//!
+//! STL stores every triangle with its own copy of each vertex,
+//! so vertices with equal positions are welded to get a half-edge mesh.
+
+use fnv::FnvHashMap;
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+use tri_mesh::prelude::*;
+
+use crate::error::VoxelizationError;
+use crate::material_mesh::{MaterialID, MaterialMesh};
+use crate::material_table::{MaterialInfo, MaterialTable};
+use crate::util::HashVec3;
+
+/// Size of the header of a binary STL file
+const HEADER_SIZE: usize = 80;
+
+/// Size of a triangle in a binary STL file: normal, 3 vertices, and attribute byte count
+const TRIANGLE_SIZE: usize = 50;
+
+impl MaterialMesh {
+    /// Constructs a material mesh from the contents of an STL file, ASCII or binary,
+    /// where every face gets the same material.
+    pub fn from_stl(source: &[u8], material: MaterialID) -> Result<Self, VoxelizationError> {
+        Self::from_stls(&[(source, material)])
+    }
+
+    /// Constructs a material mesh from the contents of several STL files, each with its own material.
+    /// Vertices are only welded within the same file.
+    pub fn from_stls(sources: &[(&[u8], MaterialID)]) -> Result<Self, VoxelizationError> {
+        let mut positions = vec![];
+        let mut indexes = vec![];
+        let mut tags = vec![];
+
+        for (source, material) in sources {
+            let triangles = parse_stl(source)?;
+            let mut index_map = FnvHashMap::default();
+
+            for triangle in triangles {
+                let face = triangle
+                    .iter()
+                    .map(|pos| {
+                        *index_map.entry(HashVec3(*pos)).or_insert_with(|| {
+                            positions.extend_from_slice(&[pos.x, pos.y, pos.z]);
+                            (positions.len() / 3 - 1) as u32
+                        })
+                    })
+                    .collect::<Vec<_>>();
+
+                // Skip triangles that collapsed to a line or point
+                if face[0] != face[1] && face[1] != face[2] && face[2] != face[0] {
+                    indexes.extend(face);
+                    tags.push(*material);
+                }
+            }
+        }
+
+        let mesh = MeshBuilder::new()
+            .with_positions(positions)
+            .with_indices(indexes)
+            .with_tags(tags)
+            .build()?;
+
+        Ok(Self::new(mesh))
+    }
+
+    /// Reads several STL files into one material mesh.
+    /// The file at index i gets material i + 1, named after the file.
+    pub fn from_stl_files<P: AsRef<Path>>(paths: &[P]) -> Result<Self, VoxelizationError> {
+        let sources = paths.iter().map(fs::read).collect::<Result<Vec<_>, _>>()?;
+        let sources = sources
+            .iter()
+            .enumerate()
+            .map(|(i, source)| Ok((source.as_slice(), MaterialID::try_new(i as u32 + 1)?)))
+            .collect::<Result<Vec<_>, VoxelizationError>>()?;
+
+        let mut materials = MaterialTable::new();
+        for (path, (_, material)) in paths.iter().zip(&sources) {
+            if let Some(stem) = path.as_ref().file_stem() {
+                materials.insert(*material, MaterialInfo::new(stem.to_string_lossy()));
+            }
+        }
+
+        Ok(Self::from_stls(&sources)?.with_materials(materials))
+    }
+}
+
+/// Parses the triangles of an STL file.
+/// Binary files can also start with "solid", so the size is checked too.
+fn parse_stl(source: &[u8]) -> Result<Vec<[Vec3; 3]>, VoxelizationError> {
+    let is_binary_size = source.len() >= HEADER_SIZE + 4 && {
+        let count = u32::from_le_bytes(source[HEADER_SIZE..HEADER_SIZE + 4].try_into().unwrap()) as usize;
+        source.len() == HEADER_SIZE + 4 + count * TRIANGLE_SIZE
+    };
+
+    if source.starts_with(b"solid") && !is_binary_size {
+        parse_ascii_stl(source)
+    } else {
+        parse_binary_stl(source)
+    }
+}
+
+fn parse_binary_stl(source: &[u8]) -> Result<Vec<[Vec3; 3]>, VoxelizationError> {
+    if source.len() < HEADER_SIZE + 4 {
+        return Err(VoxelizationError::Parse(String::from("STL file is too short")));
+    }
+
+    let count = u32::from_le_bytes(source[HEADER_SIZE..HEADER_SIZE + 4].try_into().unwrap()) as usize;
+    let data = &source[HEADER_SIZE + 4..];
+    if data.len() < count * TRIANGLE_SIZE {
+        return Err(VoxelizationError::Parse(format!(
+            "STL file says it has {} triangles, but only has room for {}",
+            count,
+            data.len() / TRIANGLE_SIZE
+        )));
+    }
+
+    let read_f32 = |offset: usize| f32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()) as f64;
+
+    Ok((0..count)
+        .map(|i| {
+            // Skip the normal; it's recomputed from the winding anyway
+            let start = i * TRIANGLE_SIZE + 12;
+            let mut triangle = [Vec3::zero(); 3];
+            for (j, vertex) in triangle.iter_mut().enumerate() {
+                let offset = start + j * 12;
+                *vertex = vec3(read_f32(offset), read_f32(offset + 4), read_f32(offset + 8));
+            }
+            triangle
+        })
+        .collect())
+}
+
+fn parse_ascii_stl(source: &[u8]) -> Result<Vec<[Vec3; 3]>, VoxelizationError> {
+    let source = std::str::from_utf8(source)
+        .map_err(|_| VoxelizationError::Parse(String::from("ASCII STL file is not valid UTF-8")))?;
+
+    let mut tokens = source.split_whitespace();
+    let mut vertices = vec![];
+
+    while let Some(token) = tokens.next() {
+        if token == "vertex" {
+            let mut coord = || {
+                tokens
+                    .next()
+                    .and_then(|token| token.parse::<f64>().ok())
+                    .ok_or_else(|| VoxelizationError::Parse(format!("Bad vertex #{} in STL file", vertices.len())))
+            };
+            let pos = vec3(coord()?, coord()?, coord()?);
+            vertices.push(pos);
+        }
+    }
+
+    if vertices.len() % 3 != 0 {
+        return Err(VoxelizationError::Parse(String::from(
+            "STL file has a facet without exactly 3 vertices",
+        )));
+    }
+
+    Ok(vertices.chunks_exact(3).map(|v| [v[0], v[1], v[2]]).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tetrahedron() -> Vec<[Vec3; 3]> {
+        let p = [
+            vec3(0.0, 0.0, 0.0),
+            vec3(1.0, 0.0, 0.0),
+            vec3(0.0, 1.0, 0.0),
+            vec3(0.0, 0.0, 1.0),
+        ];
+        vec![[p[0], p[2], p[1]], [p[0], p[1], p[3]], [p[0], p[3], p[2]], [p[1], p[2], p[3]]]
+    }
+
+    fn binary_stl(triangles: &[[Vec3; 3]]) -> Vec<u8> {
+        let mut bytes = vec![0; HEADER_SIZE];
+        bytes.extend_from_slice(&(triangles.len() as u32).to_le_bytes());
+        for triangle in triangles {
+            bytes.extend_from_slice(&[0; 12]);
+            for pos in triangle {
+                for i in 0..3 {
+                    bytes.extend_from_slice(&(pos[i] as f32).to_le_bytes());
+                }
+            }
+            bytes.extend_from_slice(&[0; 2]);
+        }
+        bytes
+    }
+
+    fn ascii_stl(triangles: &[[Vec3; 3]]) -> String {
+        let mut output = String::from("solid test\n");
+        for triangle in triangles {
+            output += "facet normal 0 0 0\nouter loop\n";
+            for pos in triangle {
+                output += &format!("vertex {} {} {}\n", pos.x, pos.y, pos.z);
+            }
+            output += "endloop\nendfacet\n";
+        }
+        output += "endsolid test\n";
+        output
+    }
+
+    #[test]
+    fn test_from_stl_binary() {
+        let mesh = MaterialMesh::from_stl(&binary_stl(&tetrahedron()), MaterialID::new(2)).unwrap();
+
+        assert_eq!(mesh.mesh().num_vertices(), 4);
+        assert_eq!(mesh.mesh().num_faces(), 4);
+        assert!(mesh.mesh().face_iter().all(|f| mesh.mesh().face_tag(f) == MaterialID::new(2)));
+    }
+
+    #[test]
+    fn test_from_stl_ascii() {
+        let mesh = MaterialMesh::from_stl(ascii_stl(&tetrahedron()).as_bytes(), MaterialID::new(1)).unwrap();
+
+        assert_eq!(mesh.mesh().num_vertices(), 4);
+        assert_eq!(mesh.mesh().num_faces(), 4);
+    }
+
+    #[test]
+    fn test_from_stls_materials() {
+        let shifted = tetrahedron()
+            .into_iter()
+            .map(|tri| [tri[0] + vec3(2.0, 0.0, 0.0), tri[1] + vec3(2.0, 0.0, 0.0), tri[2] + vec3(2.0, 0.0, 0.0)])
+            .collect::<Vec<_>>();
+        let a = binary_stl(&tetrahedron());
+        let b = ascii_stl(&shifted);
+
+        let mesh = MaterialMesh::from_stls(&[
+            (a.as_slice(), MaterialID::new(1)),
+            (b.as_bytes(), MaterialID::new(2)),
+        ])
+        .unwrap();
+
+        assert_eq!(mesh.mesh().num_vertices(), 8);
+        let mut counts = FnvHashMap::default();
+        for f in mesh.mesh().face_iter() {
+            *counts.entry(mesh.mesh().face_tag(f)).or_insert(0) += 1;
+        }
+        assert_eq!(counts[&MaterialID::new(1)], 4);
+        assert_eq!(counts[&MaterialID::new(2)], 4);
+    }
+
+    #[test]
+    fn test_from_stl_truncated() {
+        let mut bytes = binary_stl(&tetrahedron());
+        bytes.truncate(bytes.len() - 10);
+        assert!(MaterialMesh::from_stl(&bytes, MaterialID::new(1)).is_err());
+    }
+}
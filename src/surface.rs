@@ -0,0 +1,432 @@
+//! Extraction of a watertight surface from a voxelization.
+//!
+//! Every pure voxel contributes the faces of its cube and every complex voxel contributes the
+//! faces of its hulls. Faces of the same material that lie in the same plane and face each other
+//! are interior to that material, so their overlap is subtracted away. What remains is a closed
+//! shell around each material, and faces between two materials appear once in each shell.
+
+use fnv::FnvHashMap;
+use tri_mesh::prelude::*;
+
+use crate::error::VoxelizationError;
+use crate::material_mesh::{MaterialID, MaterialMesh};
+use crate::voxels::{Vec3i, VoxelRef, Voxels};
+
+/// Distance under which points are considered to be on a line, in grid units
+const EPSILON: f64 = 1e-7;
+
+/// Positions are welded together if they round to the same multiple of this, in grid units
+const WELD_PRECISION: f64 = 1e-6;
+
+/// Identifies a region of a plane that faces can share.
+/// Faces inside a voxel can only overlap faces of the same voxel,
+/// and faces on a cube face can only overlap faces on the same cube face.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct PlaneCell {
+    /// Unit normal of the plane, with the sign chosen to make the first nonzero component positive
+    normal: [i64; 3],
+    distance: i64,
+    cell: Vec3i,
+    pub material: MaterialID,
+}
+
+impl PlaneCell {
+    /// The same plane cell, but for another material
+    pub fn with_material(self, material: MaterialID) -> Self {
+        Self { material, ..self }
+    }
+}
+
+/// Faces in a plane cell, split by whether they face along the canonical normal or against it
+#[derive(Debug)]
+pub(crate) struct Faces {
+    pub normal: Vec3,
+    pub aligned: Vec<Vec<Vec3>>,
+    pub opposed: Vec<Vec<Vec3>>,
+}
+
+impl Voxels {
+    /// Faces on the boundary of each material in grid coordinates, grouped by plane cell.
+    /// Faces where a material meets itself have been subtracted away.
+    pub(crate) fn material_boundaries(&self) -> FnvHashMap<PlaneCell, Faces> {
+        let mut cells = FnvHashMap::<PlaneCell, Faces>::default();
+
+        for (pos, voxel) in self.iter() {
+            let min = pos.cast::<f64>().unwrap();
+
+            match voxel {
+                VoxelRef::Empty => {}
+
+                VoxelRef::Pure(material) => {
+                    for axis in 0..3 {
+                        for sign in [-1, 1].iter().copied() {
+                            let mut dir = vec3(0, 0, 0);
+                            dir[axis] = sign;
+
+                            // Cheap check for the most common case, which doesn't need subtraction
+                            if let VoxelRef::Pure(neighbor) = self.get(pos + dir) {
+                                if neighbor == material {
+                                    continue;
+                                }
+                            }
+
+                            add_face(&mut cells, cube_face(min, axis, sign > 0), material);
+                        }
+                    }
+                }
+
+                VoxelRef::Complex(complex) => {
+                    for ([p0, p1, p2], material) in complex.hull_triangles() {
+                        add_face(&mut cells, vec![p0 + min, p1 + min, p2 + min], material);
+                    }
+                }
+            }
+        }
+
+        for faces in cells.values_mut() {
+            let aligned = std::mem::replace(&mut faces.aligned, vec![]);
+            let opposed = std::mem::replace(&mut faces.opposed, vec![]);
+            faces.aligned = subtract_all(aligned.clone(), &opposed, -faces.normal);
+            faces.opposed = subtract_all(opposed, &aligned, faces.normal);
+        }
+
+        cells.retain(|_, faces| !faces.aligned.is_empty() || !faces.opposed.is_empty());
+        cells
+    }
+
+    /// Extracts a closed surface around each material, in world coordinates.
+    /// Only faces between differing materials or empty space are kept, and shared vertices are welded.
+    /// Returns None if there are no non-empty voxels.
+    pub fn surface(&self) -> Result<Option<MaterialMesh>, VoxelizationError> {
+        let polygons = self
+            .material_boundaries()
+            .into_iter()
+            .flat_map(|(cell, faces)| {
+                faces
+                    .aligned
+                    .into_iter()
+                    .chain(faces.opposed)
+                    .map(move |polygon| (polygon, cell.material))
+            })
+            .collect::<Vec<_>>();
+
+        let polygons = weld(polygons);
+        let polygons = split_t_junctions(polygons);
+
+        let transform = self.transform();
+        let triangles = polygons
+            .into_iter()
+            .flat_map(|(polygon, material)| triangulate(polygon).into_iter().map(move |tri| (tri, material)))
+            .map(|([p0, p1, p2], material)| {
+                let tri = [
+                    transform.grid_to_world(p0),
+                    transform.grid_to_world(p1),
+                    transform.grid_to_world(p2),
+                ];
+                (tri, material)
+            })
+            .collect::<Vec<_>>();
+
+        if triangles.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(
+                MaterialMesh::manifold_from_material_triangle_soup(triangles)?.with_materials(self.materials().clone()),
+            ))
+        }
+    }
+}
+
+/// The face of the unit cube with some min corner that is perpendicular to an axis,
+/// on the max side if `max` is true. Winds counterclockwise when viewed from outside the cube.
+fn cube_face(min: Vec3, axis: usize, max: bool) -> Vec<Vec3> {
+    let mut base = min;
+    if max {
+        base[axis] += 1.0;
+    }
+    let mut u = vec3(0.0, 0.0, 0.0);
+    u[(axis + 1) % 3] = 1.0;
+    let mut v = vec3(0.0, 0.0, 0.0);
+    v[(axis + 2) % 3] = 1.0;
+
+    let mut face = vec![base, base + u, base + u + v, base + v];
+    if !max {
+        face.reverse();
+    }
+    face
+}
+
+/// Area vector of a planar polygon, which points along its normal by the right-hand rule
+pub(crate) fn area_vector(polygon: &[Vec3]) -> Vec3 {
+    let p0 = polygon[0];
+    (1..polygon.len().saturating_sub(1))
+        .map(|i| (polygon[i] - p0).cross(polygon[i + 1] - p0))
+        .fold(vec3(0.0, 0.0, 0.0), |sum, v| sum + v)
+        / 2.0
+}
+
+fn quantize(x: f64) -> i64 {
+    (x / WELD_PRECISION).round() as i64
+}
+
+fn add_face(cells: &mut FnvHashMap<PlaneCell, Faces>, polygon: Vec<Vec3>, material: MaterialID) {
+    let area = area_vector(&polygon);
+    if area.magnitude() < EPSILON {
+        return;
+    }
+    let normal = area.normalize();
+
+    let sign = (0..3)
+        .map(|i| normal[i])
+        .find(|x| x.abs() > WELD_PRECISION)
+        .map_or(1.0, |x| x.signum());
+    let canonical = normal * sign;
+
+    let centroid = polygon.iter().fold(vec3(0.0, 0.0, 0.0), |sum, p| sum + *p) / polygon.len() as f64;
+    let mut cell = vec3(0, 0, 0);
+    for i in 0..3 {
+        // Faces on a cube face get the same cell on both sides of it
+        cell[i] = if (canonical[i] - 1.0).abs() < WELD_PRECISION {
+            centroid[i].round() as i32
+        } else {
+            centroid[i].floor() as i32
+        };
+    }
+
+    let key = PlaneCell {
+        normal: [quantize(canonical.x), quantize(canonical.y), quantize(canonical.z)],
+        distance: quantize(canonical.dot(polygon[0])),
+        cell,
+        material,
+    };
+
+    let faces = cells.entry(key).or_insert_with(|| Faces {
+        normal: canonical,
+        aligned: vec![],
+        opposed: vec![],
+    });
+    if sign > 0.0 {
+        faces.aligned.push(polygon);
+    } else {
+        faces.opposed.push(polygon);
+    }
+}
+
+/// Intersects two convex polygons in the same plane, where `q` winds counterclockwise around `q_normal`.
+/// Returns None if they don't overlap.
+pub(crate) fn intersection(p: &[Vec3], q: &[Vec3], q_normal: Vec3) -> Option<Vec<Vec3>> {
+    let mut rest = p.to_vec();
+
+    for i in 0..q.len() {
+        let a = q[i];
+        let inward = q_normal.cross(q[(i + 1) % q.len()] - a).normalize();
+        rest = clip(&rest, |pos| inward.dot(pos - a));
+        if rest.len() < 3 {
+            return None;
+        }
+    }
+
+    if area_vector(&rest).magnitude() > EPSILON {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+/// Keeps the part of a convex polygon where `dist` is nonnegative
+fn clip<F: Fn(Vec3) -> f64>(polygon: &[Vec3], dist: F) -> Vec<Vec3> {
+    let mut result = vec![];
+
+    for i in 0..polygon.len() {
+        let p0 = polygon[i];
+        let p1 = polygon[(i + 1) % polygon.len()];
+        let (d0, d1) = (dist(p0), dist(p1));
+
+        if d0 >= -EPSILON {
+            result.push(p0);
+        }
+        if (d0 < -EPSILON && d1 > EPSILON) || (d0 > EPSILON && d1 < -EPSILON) {
+            result.push(p0 + (p1 - p0) * (d0 / (d0 - d1)));
+        }
+    }
+
+    result
+}
+
+/// Subtracts convex polygon `q` from convex polygon `p`, where both lie in the same plane
+/// and `q` winds counterclockwise around `q_normal`. Returns convex pieces.
+fn difference(p: &[Vec3], q: &[Vec3], q_normal: Vec3) -> Vec<Vec<Vec3>> {
+    let inwards = (0..q.len())
+        .map(|i| {
+            let a = q[i];
+            let b = q[(i + 1) % q.len()];
+            (a, q_normal.cross(b - a).normalize())
+        })
+        .collect::<Vec<_>>();
+
+    // Skip splitting up polygons that don't overlap
+    if inwards.iter().any(|(a, inward)| p.iter().all(|pos| inward.dot(*pos - *a) <= EPSILON)) {
+        return vec![p.to_vec()];
+    }
+
+    let mut rest = p.to_vec();
+    let mut pieces = vec![];
+
+    for (a, inward) in inwards {
+        let outside = clip(&rest, |pos| -inward.dot(pos - a));
+        if outside.len() >= 3 && area_vector(&outside).magnitude() > EPSILON {
+            pieces.push(outside);
+        }
+
+        rest = clip(&rest, |pos| inward.dot(pos - a));
+        if rest.len() < 3 {
+            break;
+        }
+    }
+
+    pieces
+}
+
+/// Subtracts every cutter from every face
+fn subtract_all(faces: Vec<Vec<Vec3>>, cutters: &[Vec<Vec3>], cutter_normal: Vec3) -> Vec<Vec<Vec3>> {
+    cutters.iter().fold(faces, |faces, cutter| {
+        faces
+            .iter()
+            .flat_map(|face| difference(face, cutter, cutter_normal))
+            .collect()
+    })
+}
+
+/// Snaps positions that are close together to the same position,
+/// and removes vertices that become duplicates.
+fn weld(polygons: Vec<(Vec<Vec3>, MaterialID)>) -> Vec<(Vec<Vec3>, MaterialID)> {
+    let mut reps = FnvHashMap::default();
+
+    polygons
+        .into_iter()
+        .filter_map(|(polygon, material)| {
+            let mut welded = polygon
+                .into_iter()
+                .map(|p| *reps.entry([quantize(p.x), quantize(p.y), quantize(p.z)]).or_insert(p))
+                .collect::<Vec<_>>();
+            welded.dedup();
+            while welded.len() > 1 && welded.first() == welded.last() {
+                welded.pop();
+            }
+
+            if welded.len() >= 3 {
+                Some((welded, material))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Inserts vertices that lie on the edges of other polygons into those edges,
+/// so that neighboring polygons share edges exactly.
+fn split_t_junctions(polygons: Vec<(Vec<Vec3>, MaterialID)>) -> Vec<(Vec<Vec3>, MaterialID)> {
+    let mut grid = FnvHashMap::<Vec3i, Vec<Vec3>>::default();
+    for (polygon, _) in &polygons {
+        for p in polygon {
+            let cell = vec3(p.x.floor() as i32, p.y.floor() as i32, p.z.floor() as i32);
+            let points = grid.entry(cell).or_insert_with(Vec::new);
+            if !points.contains(p) {
+                points.push(*p);
+            }
+        }
+    }
+
+    polygons
+        .into_iter()
+        .map(|(polygon, material)| {
+            let mut split = vec![];
+
+            for i in 0..polygon.len() {
+                let a = polygon[i];
+                let b = polygon[(i + 1) % polygon.len()];
+                let dir = b - a;
+                let length2 = dir.magnitude2();
+                split.push(a);
+
+                let min = vec3(a.x.min(b.x), a.y.min(b.y), a.z.min(b.z));
+                let max = vec3(a.x.max(b.x), a.y.max(b.y), a.z.max(b.z));
+                let mut on_edge = vec![];
+                for z in (min.z - EPSILON).floor() as i32..=(max.z + EPSILON).floor() as i32 {
+                    for y in (min.y - EPSILON).floor() as i32..=(max.y + EPSILON).floor() as i32 {
+                        for x in (min.x - EPSILON).floor() as i32..=(max.x + EPSILON).floor() as i32 {
+                            for p in grid.get(&vec3(x, y, z)).into_iter().flatten() {
+                                let t = (*p - a).dot(dir) / length2;
+                                let dist2 = (a + dir * t - *p).magnitude2();
+                                if t * t * length2 > WELD_PRECISION * WELD_PRECISION
+                                    && (1.0 - t) * (1.0 - t) * length2 > WELD_PRECISION * WELD_PRECISION
+                                    && t > 0.0
+                                    && t < 1.0
+                                    && dist2 < WELD_PRECISION * WELD_PRECISION
+                                {
+                                    on_edge.push((t, *p));
+                                }
+                            }
+                        }
+                    }
+                }
+
+                on_edge.sort_by(|(t0, _), (t1, _)| t0.partial_cmp(t1).unwrap());
+                split.extend(on_edge.into_iter().map(|(_, p)| p));
+            }
+
+            (split, material)
+        })
+        .collect()
+}
+
+/// Triangulates a convex polygon.
+/// Polygons with vertices in the middle of edges are fanned from their centroid
+/// to avoid degenerate triangles.
+fn triangulate(polygon: Vec<Vec3>) -> Vec<[Vec3; 3]> {
+    let n = polygon.len();
+    let has_straight_vertex = (0..n).any(|i| {
+        let prev = polygon[(i + n - 1) % n];
+        let next = polygon[(i + 1) % n];
+        (polygon[i] - prev).cross(next - polygon[i]).magnitude() < EPSILON
+    });
+
+    if has_straight_vertex {
+        let centroid = polygon.iter().fold(vec3(0.0, 0.0, 0.0), |sum, p| sum + *p) / n as f64;
+        (0..n).map(|i| [centroid, polygon[i], polygon[(i + 1) % n]]).collect()
+    } else {
+        (1..n - 1).map(|i| [polygon[0], polygon[i], polygon[i + 1]]).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_surface_box() {
+        let mut voxels = Voxels::default();
+        voxels.fill_box(vec3(0, 0, 0), vec3(2, 2, 2), Some(MaterialID::new(1)));
+
+        let surface = voxels.surface().unwrap().expect("Surface should exist");
+        // 24 welded quads
+        assert_eq!(surface.mesh().num_faces(), 48);
+        assert_eq!(surface.mesh().num_vertices(), 26);
+    }
+
+    #[test]
+    fn test_surface_two_materials() {
+        let mut voxels = Voxels::default();
+        voxels.set(vec3(0, 0, 0), Some(MaterialID::new(1)));
+        voxels.set(vec3(1, 0, 0), Some(MaterialID::new(2)));
+
+        let surface = voxels.surface().unwrap().expect("Surface should exist");
+        // Each cube is its own closed shell, including the face between them
+        assert_eq!(surface.mesh().num_faces(), 24);
+    }
+
+    #[test]
+    fn test_surface_empty() {
+        assert!(Voxels::default().surface().unwrap().is_none());
+    }
+}
@@ -0,0 +1,496 @@
+//! Export of tetrahedral meshes to Gmsh's .msh format (version 4.1)
+//! and VTK's XML (.vtu) and legacy (.vtk) unstructured grid formats, for finite element solvers and ParaView.
+//!
+//! All formats get the material of each tet and some quality measures of each tet.
+//! In .msh files, each material is a volume entity and a physical group with the same tag as the material ID.
+//! Each node goes in the volume of the lowest material ID whose tets use it.
+//! A `Tetrahedralization` can still have flat tets, listed by `Tetrahedralization::flat_tets`,
+//! which get written like any other tet. A `TetMesh` never has any.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use tri_mesh::prelude::*;
+
+use crate::material_mesh::MaterialID;
+use crate::material_table::MaterialTable;
+use crate::tet_mesh::TetMesh;
+use crate::tet_quality;
+use crate::tetrahedralize::Tetrahedralization;
+
+/// Gmsh element type of 4-node tets
+const MSH_TET: u32 = 4;
+
+/// VTK cell type of 4-node tets
+const VTK_TETRA: u8 = 10;
+
+/// Tets to export, with positively oriented tets that index into the positions
+struct TetExport<'a> {
+    positions: &'a [Vec3],
+    tets: Vec<([usize; 4], MaterialID)>,
+    materials: &'a MaterialTable,
+}
+
+impl<'a> TetExport<'a> {
+    fn tet_positions(&self, tet: [usize; 4]) -> [Vec3; 4] {
+        [self.positions[tet[0]], self.positions[tet[1]], self.positions[tet[2]], self.positions[tet[3]]]
+    }
+
+    /// Names and values of quality measures of each tet.
+    /// Angles are in degrees. Infinite values are written as the biggest float, since readers choke on "inf".
+    fn quality_fields(&self) -> Vec<(&'static str, Vec<f64>)> {
+        let finite = |x: f64| if x.is_finite() { x } else { f64::MAX };
+
+        vec![
+            (
+                "min_dihedral_angle",
+                self.tets
+                    .iter()
+                    .map(|(tet, _)| tet_quality::min_dihedral_angle(self.tet_positions(*tet)).to_degrees())
+                    .collect(),
+            ),
+            (
+                "aspect_ratio",
+                self.tets
+                    .iter()
+                    .map(|(tet, _)| finite(tet_quality::aspect_ratio(self.tet_positions(*tet))))
+                    .collect(),
+            ),
+        ]
+    }
+
+    fn sorted_materials(&self) -> Vec<MaterialID> {
+        let mut materials = self.tets.iter().map(|(_, material)| *material).collect::<Vec<_>>();
+        materials.sort();
+        materials.dedup();
+        materials
+    }
+
+    fn write_msh<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let materials = self.sorted_materials();
+
+        writeln!(writer, "$MeshFormat\n4.1 0 8\n$EndMeshFormat")?;
+
+        writeln!(writer, "$PhysicalNames\n{}", materials.len())?;
+        for material in &materials {
+            // Quotes would end the name early
+            let name = self.materials.name(*material).replace('"', "'");
+            writeln!(writer, "3 {} \"{}\"", material.0, name)?;
+        }
+        writeln!(writer, "$EndPhysicalNames")?;
+
+        writeln!(writer, "$Entities\n0 0 0 {}", materials.len())?;
+        for material in &materials {
+            let (min, max) = self
+                .tets
+                .iter()
+                .filter(|(_, m)| m == material)
+                .flat_map(|(tet, _)| tet.iter().map(|v| self.positions[*v]))
+                .fold(
+                    (vec3(f64::MAX, f64::MAX, f64::MAX), vec3(f64::MIN, f64::MIN, f64::MIN)),
+                    |(min, max), p| {
+                        (
+                            vec3(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z)),
+                            vec3(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z)),
+                        )
+                    },
+                );
+            writeln!(
+                writer,
+                "{} {} {} {} {} {} {} 1 {} 0",
+                material.0, min.x, min.y, min.z, max.x, max.y, max.z, material.0
+            )?;
+        }
+        writeln!(writer, "$EndEntities")?;
+
+        // Nodes are shared between volumes, so each goes in the first volume that uses it.
+        // Nodes that no tet uses go in the first volume too.
+        let num_nodes = self.positions.len();
+        let mut node_materials = vec![None; num_nodes];
+        for (tet, material) in &self.tets {
+            for v in tet {
+                let node_material = node_materials[*v].get_or_insert(*material);
+                *node_material = (*node_material).min(*material);
+            }
+        }
+        let first_material = materials.first().copied().unwrap_or_default();
+        let blocks = materials
+            .iter()
+            .map(|material| {
+                let nodes = (0..num_nodes)
+                    .filter(|v| node_materials[*v].unwrap_or(first_material) == *material)
+                    .collect::<Vec<_>>();
+                (*material, nodes)
+            })
+            .filter(|(_, nodes)| !nodes.is_empty())
+            .collect::<Vec<_>>();
+
+        writeln!(writer, "$Nodes\n{} {} 1 {}", blocks.len(), num_nodes, num_nodes)?;
+        for (material, nodes) in &blocks {
+            writeln!(writer, "3 {} 0 {}", material.0, nodes.len())?;
+            for v in nodes {
+                writeln!(writer, "{}", v + 1)?;
+            }
+            for v in nodes {
+                let pos = self.positions[*v];
+                writeln!(writer, "{} {} {}", pos.x, pos.y, pos.z)?;
+            }
+        }
+        writeln!(writer, "$EndNodes")?;
+
+        // Element tags are 1 + the index of the tet
+        let num_tets = self.tets.len();
+        writeln!(writer, "$Elements\n{} {} 1 {}", materials.len(), num_tets, num_tets)?;
+        for material in &materials {
+            let block = self
+                .tets
+                .iter()
+                .enumerate()
+                .filter(|(_, (_, m))| m == material)
+                .collect::<Vec<_>>();
+            writeln!(writer, "3 {} {} {}", material.0, MSH_TET, block.len())?;
+            for (i, (tet, _)) in block {
+                writeln!(writer, "{} {} {} {} {}", i + 1, tet[0] + 1, tet[1] + 1, tet[2] + 1, tet[3] + 1)?;
+            }
+        }
+        writeln!(writer, "$EndElements")?;
+
+        for (name, values) in self.quality_fields() {
+            // 1 string tag (name), 1 real tag (time), 3 integer tags (time step, components, count)
+            writeln!(writer, "$ElementData\n1\n\"{}\"\n1\n0\n3\n0\n1\n{}", name, num_tets)?;
+            for (i, value) in values.iter().enumerate() {
+                writeln!(writer, "{} {}", i + 1, value)?;
+            }
+            writeln!(writer, "$EndElementData")?;
+        }
+
+        Ok(())
+    }
+
+    fn write_vtu<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writeln!(writer, "<?xml version=\"1.0\"?>")?;
+        writeln!(
+            writer,
+            "<VTKFile type=\"UnstructuredGrid\" version=\"0.1\" byte_order=\"LittleEndian\" header_type=\"UInt64\">"
+        )?;
+        writeln!(writer, "<UnstructuredGrid>")?;
+        writeln!(
+            writer,
+            "<Piece NumberOfPoints=\"{}\" NumberOfCells=\"{}\">",
+            self.positions.len(),
+            self.tets.len()
+        )?;
+
+        writeln!(writer, "<Points>")?;
+        writeln!(writer, "<DataArray type=\"Float64\" NumberOfComponents=\"3\" format=\"ascii\">")?;
+        for pos in self.positions {
+            writeln!(writer, "{} {} {}", pos.x, pos.y, pos.z)?;
+        }
+        writeln!(writer, "</DataArray>\n</Points>")?;
+
+        writeln!(writer, "<Cells>")?;
+        writeln!(writer, "<DataArray type=\"Int64\" Name=\"connectivity\" format=\"ascii\">")?;
+        for (tet, _) in &self.tets {
+            writeln!(writer, "{} {} {} {}", tet[0], tet[1], tet[2], tet[3])?;
+        }
+        writeln!(writer, "</DataArray>")?;
+        writeln!(writer, "<DataArray type=\"Int64\" Name=\"offsets\" format=\"ascii\">")?;
+        for i in 0..self.tets.len() {
+            writeln!(writer, "{}", 4 * (i + 1))?;
+        }
+        writeln!(writer, "</DataArray>")?;
+        writeln!(writer, "<DataArray type=\"UInt8\" Name=\"types\" format=\"ascii\">")?;
+        for _ in 0..self.tets.len() {
+            writeln!(writer, "{}", VTK_TETRA)?;
+        }
+        writeln!(writer, "</DataArray>\n</Cells>")?;
+
+        writeln!(writer, "<CellData Scalars=\"material\">")?;
+        writeln!(writer, "<DataArray type=\"UInt32\" Name=\"material\" format=\"ascii\">")?;
+        for (_, material) in &self.tets {
+            writeln!(writer, "{}", material.0)?;
+        }
+        writeln!(writer, "</DataArray>")?;
+        for (name, values) in self.quality_fields() {
+            writeln!(writer, "<DataArray type=\"Float64\" Name=\"{}\" format=\"ascii\">", name)?;
+            for value in values {
+                writeln!(writer, "{}", value)?;
+            }
+            writeln!(writer, "</DataArray>")?;
+        }
+        writeln!(writer, "</CellData>")?;
+
+        writeln!(writer, "</Piece>\n</UnstructuredGrid>\n</VTKFile>")
+    }
+
+    fn write_vtk<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writeln!(writer, "# vtk DataFile Version 3.0\nTetrahedral mesh\nASCII\nDATASET UNSTRUCTURED_GRID")?;
+
+        writeln!(writer, "POINTS {} double", self.positions.len())?;
+        for pos in self.positions {
+            writeln!(writer, "{} {} {}", pos.x, pos.y, pos.z)?;
+        }
+
+        // Each cell is its number of points followed by the points
+        writeln!(writer, "CELLS {} {}", self.tets.len(), 5 * self.tets.len())?;
+        for (tet, _) in &self.tets {
+            writeln!(writer, "4 {} {} {} {}", tet[0], tet[1], tet[2], tet[3])?;
+        }
+        writeln!(writer, "CELL_TYPES {}", self.tets.len())?;
+        for _ in 0..self.tets.len() {
+            writeln!(writer, "{}", VTK_TETRA)?;
+        }
+
+        writeln!(writer, "CELL_DATA {}", self.tets.len())?;
+        writeln!(writer, "SCALARS material unsigned_int 1\nLOOKUP_TABLE default")?;
+        for (_, material) in &self.tets {
+            writeln!(writer, "{}", material.0)?;
+        }
+        for (name, values) in self.quality_fields() {
+            writeln!(writer, "SCALARS {} double 1\nLOOKUP_TABLE default", name)?;
+            for value in values {
+                writeln!(writer, "{}", value)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn export_with<P: AsRef<Path>>(path: P, write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write(&mut writer)?;
+    writer.flush()
+}
+
+impl TetMesh {
+    fn export(&self) -> TetExport<'_> {
+        TetExport {
+            positions: self.positions(),
+            tets: self.tets().iter().copied().zip(self.tet_materials().iter().copied()).collect(),
+            materials: self.materials(),
+        }
+    }
+
+    /// Exports the tets to a Gmsh .msh file, version 4.1.
+    /// Each material becomes a physical volume named after the material,
+    /// and the minimum dihedral angle in degrees and aspect ratio of each tet become element data.
+    pub fn export_msh<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        export_with(path, |writer| self.write_msh(writer))
+    }
+
+    /// Writes the tets in .msh format. See `export_msh`.
+    pub fn write_msh<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.export().write_msh(writer)
+    }
+
+    /// Exports the tets to a VTK .vtu file, with the material ID,
+    /// minimum dihedral angle in degrees and aspect ratio of each tet as cell data.
+    pub fn export_vtu<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        export_with(path, |writer| self.write_vtu(writer))
+    }
+
+    /// Writes the tets in .vtu format. See `export_vtu`.
+    pub fn write_vtu<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.export().write_vtu(writer)
+    }
+
+    /// Exports the tets to a legacy VTK .vtk file, for readers that don't support .vtu.
+    /// Has the same cell data as `export_vtu`.
+    pub fn export_vtk<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        export_with(path, |writer| self.write_vtk(writer))
+    }
+
+    /// Writes the tets in legacy .vtk format. See `export_vtk`.
+    pub fn write_vtk<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.export().write_vtk(writer)
+    }
+}
+
+impl Tetrahedralization {
+    /// Exports the tets to a Gmsh .msh file, version 4.1. See `TetMesh::export_msh`.
+    /// There's no material table, so materials get names made up from their IDs.
+    pub fn export_msh<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        export_with(path, |writer| self.write_msh(writer))
+    }
+
+    /// Writes the tets in .msh format. See `export_msh`.
+    pub fn write_msh<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let (positions, tets) = self.tets_with_materials();
+        let materials = MaterialTable::new();
+        TetExport { positions: &positions, tets, materials: &materials }.write_msh(writer)
+    }
+
+    /// Exports the tets to a VTK .vtu file. See `TetMesh::export_vtu`.
+    pub fn export_vtu<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        export_with(path, |writer| self.write_vtu(writer))
+    }
+
+    /// Writes the tets in .vtu format. See `export_vtu`.
+    pub fn write_vtu<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let (positions, tets) = self.tets_with_materials();
+        let materials = MaterialTable::new();
+        TetExport { positions: &positions, tets, materials: &materials }.write_vtu(writer)
+    }
+
+    /// Exports the tets to a legacy VTK .vtk file. See `TetMesh::export_vtk`.
+    pub fn export_vtk<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        export_with(path, |writer| self.write_vtk(writer))
+    }
+
+    /// Writes the tets in legacy .vtk format. See `export_vtk`.
+    pub fn write_vtk<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let (positions, tets) = self.tets_with_materials();
+        let materials = MaterialTable::new();
+        TetExport { positions: &positions, tets, materials: &materials }.write_vtk(writer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::material_table::MaterialInfo;
+
+    /// Two tets of different materials sharing a face
+    fn two_tets() -> (Vec<Vec3>, Vec<([usize; 4], MaterialID)>) {
+        let positions = vec![
+            vec3(0.0, 0.0, 0.0),
+            vec3(1.0, 0.0, 0.0),
+            vec3(0.0, 1.0, 0.0),
+            vec3(0.0, 0.0, 1.0),
+            vec3(0.0, 0.0, -1.0),
+        ];
+        let tets = vec![([0, 1, 2, 3], MaterialID::new(2)), ([0, 2, 1, 4], MaterialID::new(5))];
+        (positions, tets)
+    }
+
+    #[test]
+    fn test_write_msh() {
+        let (positions, tets) = two_tets();
+        let mut materials = MaterialTable::new();
+        materials.insert(MaterialID::new(2), MaterialInfo::new("steel"));
+        let export = TetExport { positions: &positions, tets, materials: &materials };
+
+        let mut output = vec![];
+        export.write_msh(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines = output.lines().collect::<Vec<_>>();
+
+        assert_eq!(&lines[..3], &["$MeshFormat", "4.1 0 8", "$EndMeshFormat"]);
+        assert!(lines.contains(&"3 2 \"steel\""));
+        assert!(lines.contains(&"3 5 \"mat4\""));
+        assert!(lines.contains(&"2 5 1 5"));
+        assert!(lines.contains(&"2 2 1 2"));
+        assert!(lines.contains(&"3 2 4 1"));
+        assert!(lines.contains(&"1 1 2 3 4"));
+        assert!(lines.contains(&"2 1 3 2 5"));
+        assert!(lines.contains(&"\"min_dihedral_angle\""));
+        assert!(lines.contains(&"\"aspect_ratio\""));
+        assert_eq!(lines.iter().filter(|line| **line == "$EndElementData").count(), 2);
+    }
+
+    /// Reads the node blocks of a .msh file as (entity, node tags),
+    /// and the element blocks as (entity, element tags and node tags)
+    fn read_msh(output: &str) -> (Vec<(u32, Vec<usize>)>, Vec<(u32, Vec<Vec<usize>>)>) {
+        fn numbers(lines: &mut std::str::Lines, count: usize) -> Vec<usize> {
+            let numbers = lines
+                .next()
+                .unwrap()
+                .split_whitespace()
+                .map(|n| n.parse().unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(numbers.len(), count);
+            numbers
+        }
+
+        let mut lines = output.lines();
+
+        let mut node_blocks = vec![];
+        let mut element_blocks = vec![];
+        while let Some(line) = lines.next() {
+            match line {
+                "$Nodes" => {
+                    let header = numbers(&mut lines, 4);
+                    for _ in 0..header[0] {
+                        let block = numbers(&mut lines, 4);
+                        let tags = (0..block[3]).map(|_| numbers(&mut lines, 1)[0]).collect::<Vec<_>>();
+                        // Skip the coordinates
+                        for _ in 0..block[3] {
+                            lines.next();
+                        }
+                        node_blocks.push((block[1] as u32, tags));
+                    }
+                    assert_eq!(node_blocks.iter().map(|(_, tags)| tags.len()).sum::<usize>(), header[1]);
+                    assert_eq!(lines.next(), Some("$EndNodes"));
+                }
+                "$Elements" => {
+                    let header = numbers(&mut lines, 4);
+                    for _ in 0..header[0] {
+                        let block = numbers(&mut lines, 4);
+                        assert_eq!(block[2], MSH_TET as usize);
+                        let elements = (0..block[3]).map(|_| numbers(&mut lines, 5)).collect::<Vec<_>>();
+                        element_blocks.push((block[1] as u32, elements));
+                    }
+                    assert_eq!(element_blocks.iter().map(|(_, elements)| elements.len()).sum::<usize>(), header[1]);
+                    assert_eq!(lines.next(), Some("$EndElements"));
+                }
+                _ => {}
+            }
+        }
+
+        (node_blocks, element_blocks)
+    }
+
+    #[test]
+    fn test_read_msh_back() {
+        let (positions, tets) = two_tets();
+        let materials = MaterialTable::new();
+        let export = TetExport { positions: &positions, tets, materials: &materials };
+
+        let mut output = vec![];
+        export.write_msh(&mut output).unwrap();
+        let (node_blocks, element_blocks) = read_msh(&String::from_utf8(output).unwrap());
+
+        // The shared face goes with material 2, the apex of the other tet with material 5
+        assert_eq!(node_blocks, vec![(2, vec![1, 2, 3, 4]), (5, vec![5])]);
+        assert_eq!(element_blocks, vec![(2, vec![vec![1, 1, 2, 3, 4]]), (5, vec![vec![2, 1, 3, 2, 5]])]);
+    }
+
+    #[test]
+    fn test_write_vtk() {
+        let (positions, tets) = two_tets();
+        let materials = MaterialTable::new();
+        let export = TetExport { positions: &positions, tets, materials: &materials };
+
+        let mut output = vec![];
+        export.write_vtk(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert!(output.starts_with("# vtk DataFile Version 3.0\n"));
+        assert!(output.contains("DATASET UNSTRUCTURED_GRID\nPOINTS 5 double\n"));
+        assert!(output.contains("CELLS 2 10\n4 0 1 2 3\n4 0 2 1 4\nCELL_TYPES 2\n10\n10\n"));
+        assert!(output.contains("CELL_DATA 2\nSCALARS material unsigned_int 1\nLOOKUP_TABLE default\n2\n5\n"));
+        assert!(output.contains("SCALARS min_dihedral_angle double 1\n"));
+        assert!(output.contains("SCALARS aspect_ratio double 1\n"));
+    }
+
+    #[test]
+    fn test_write_vtu() {
+        let (positions, tets) = two_tets();
+        let materials = MaterialTable::new();
+        let export = TetExport { positions: &positions, tets, materials: &materials };
+
+        let mut output = vec![];
+        export.write_vtu(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert!(output.contains("<Piece NumberOfPoints=\"5\" NumberOfCells=\"2\">"));
+        assert!(output.contains("Name=\"connectivity\" format=\"ascii\">\n0 1 2 3\n0 2 1 4\n</DataArray>"));
+        assert!(output.contains("Name=\"offsets\" format=\"ascii\">\n4\n8\n</DataArray>"));
+        assert!(output.contains("Name=\"types\" format=\"ascii\">\n10\n10\n</DataArray>"));
+        assert!(output.contains("Name=\"material\" format=\"ascii\">\n2\n5\n</DataArray>"));
+        assert!(output.contains("Name=\"min_dihedral_angle\""));
+        assert!(output.contains("Name=\"aspect_ratio\""));
+        assert!(output.trim_end().ends_with("</VTKFile>"));
+    }
+}
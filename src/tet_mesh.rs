@@ -0,0 +1,463 @@
+//! Quality tetrahedral meshes of whole parts, for finite element simulation.
+
+use bvh::aabb::{Bounded, AABB};
+use bvh::bounding_hierarchy::{BHShape, BoundingHierarchy};
+use bvh::bvh::{BVHNode, BVH};
+use bvh::nalgebra::Point3 as NPoint3;
+use fnv::{FnvHashMap, FnvHashSet};
+use float_ord::FloatOrd;
+use tri_mesh::prelude::*;
+
+use crate::error::VoxelizationError;
+use crate::material_mesh::{aabb_distance2, enclosing_material, MaterialID, MaterialMesh};
+use crate::material_table::MaterialTable;
+use crate::plc::PiecewiseLinearComplex;
+use crate::predicates;
+use crate::tet_quality::{self, Histogram, TetQuality};
+use crate::tetrahedralize::{DelaunayTetrahedralization, TetError};
+use crate::util::ArrayEx;
+
+/// Options for `MaterialMesh::tetrahedralize`
+#[derive(Clone, Debug)]
+pub struct TetMeshOptions {
+    /// Tets with a bigger ratio of circumradius to shortest edge get refined
+    max_radius_edge_ratio: f64,
+    /// Tets with a bigger volume get refined
+    max_volume: Option<f64>,
+    /// Refinement stops after adding this many points, even if some tets are still bad
+    max_steiner_points: usize,
+}
+
+impl Default for TetMeshOptions {
+    fn default() -> Self {
+        Self {
+            max_radius_edge_ratio: 2.0,
+            max_volume: None,
+            max_steiner_points: 100_000,
+        }
+    }
+}
+
+impl TetMeshOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the biggest allowed ratio of a tet's circumradius to its shortest edge.
+    /// Refinement is only guaranteed to finish for ratios of 2 or more.
+    pub fn with_max_radius_edge_ratio(mut self, ratio: f64) -> Self {
+        assert!(ratio > 0.0, "Radius-edge ratio must be positive");
+        self.max_radius_edge_ratio = ratio;
+        self
+    }
+
+    pub fn max_radius_edge_ratio(&self) -> f64 {
+        self.max_radius_edge_ratio
+    }
+
+    /// Sets the biggest allowed tet volume
+    pub fn with_max_volume(mut self, volume: f64) -> Self {
+        assert!(volume > 0.0, "Max volume must be positive");
+        self.max_volume = Some(volume);
+        self
+    }
+
+    pub fn max_volume(&self) -> Option<f64> {
+        self.max_volume
+    }
+
+    /// Sets how many points refinement may add before it gives up
+    pub fn with_max_steiner_points(mut self, count: usize) -> Self {
+        self.max_steiner_points = count;
+        self
+    }
+
+    pub fn max_steiner_points(&self) -> usize {
+        self.max_steiner_points
+    }
+}
+
+/// A tetrahedral mesh where each tet has a material
+#[derive(Clone, Debug)]
+pub struct TetMesh {
+    positions: Vec<Vec3>,
+    /// Positively oriented, so `orient3d` of each tet is positive
+    tets: Vec<[usize; 4]>,
+    tet_materials: Vec<MaterialID>,
+    materials: MaterialTable,
+    /// Tets that are still too big or badly shaped
+    num_bad_tets: usize,
+}
+
+impl TetMesh {
+    pub fn positions(&self) -> &[Vec3] {
+        &self.positions
+    }
+
+    /// Tets as indexes into the positions, positively oriented
+    pub fn tets(&self) -> &[[usize; 4]] {
+        &self.tets
+    }
+
+    /// Material of each tet
+    pub fn tet_materials(&self) -> &[MaterialID] {
+        &self.tet_materials
+    }
+
+    /// Names and properties of the materials
+    pub fn materials(&self) -> &MaterialTable {
+        &self.materials
+    }
+
+    pub fn num_tets(&self) -> usize {
+        self.tets.len()
+    }
+
+    /// Number of tets that don't meet the bounds in the `TetMeshOptions`,
+    /// because refinement ran out of passes or Steiner points, or couldn't fix them.
+    /// 0 if every tet meets the bounds.
+    pub fn num_bad_tets(&self) -> usize {
+        self.num_bad_tets
+    }
+
+    pub fn tet_positions(&self, index: usize) -> [Vec3; 4] {
+        let [a, b, c, d] = self.tets[index];
+        [self.positions[a], self.positions[b], self.positions[c], self.positions[d]]
+    }
+
+    pub fn volume(&self, index: usize) -> f64 {
+        tet_quality::signed_volume(self.tet_positions(index))
+    }
+
+    /// Ratio of a tet's circumradius to its shortest edge. Infinite for flat tets.
+    pub fn radius_edge_ratio(&self, index: usize) -> f64 {
+        tet_quality::radius_edge_ratio(self.tet_positions(index))
+    }
+
+    pub fn quality(&self, index: usize) -> TetQuality {
+        TetQuality::new(self.tet_positions(index))
+    }
+
+    /// Histogram of a quality measure over all tets, such as
+    /// `mesh.quality_histogram(|q| q.min_dihedral_angle.to_degrees(), 0.0, 90.0, 18)`.
+    /// None if there are no bins or the range isn't finite and nonempty, like `Histogram::new`.
+    pub fn quality_histogram(
+        &self,
+        measure: impl Fn(&TetQuality) -> f64,
+        min: f64,
+        max: f64,
+        bins: usize,
+    ) -> Option<Histogram> {
+        let mut histogram = Histogram::new(min, max, bins)?;
+        histogram.extend((0..self.num_tets()).map(|i| measure(&self.quality(i))));
+        Some(histogram)
+    }
+
+    /// Total volume of each material
+    pub fn material_volumes(&self) -> FnvHashMap<MaterialID, f64> {
+        let mut volumes = FnvHashMap::default();
+        for (i, material) in self.tet_materials.iter().enumerate() {
+            *volumes.entry(*material).or_insert(0.0) += self.volume(i);
+        }
+        volumes
+    }
+
+    /// Fails if some tet has no volume, since solvers can't use those
+    fn reject_flat_tets(self) -> Result<Self, VoxelizationError> {
+        let flat = (0..self.num_tets()).any(|i| {
+            let [p0, p1, p2, p3] = self.tet_positions(i);
+            predicates::orient3d(p0, p1, p2, p3) == 0.0
+        });
+        if flat {
+            Err(TetError::FlatTets.into())
+        } else {
+            Ok(self)
+        }
+    }
+}
+
+/// Center and radius of the smallest sphere through the corners of a triangle
+fn diametral_sphere([a, b, c]: [Vec3; 3]) -> (Vec3, f64) {
+    let ab = b - a;
+    let ac = c - a;
+    let normal = ab.cross(ac);
+    let offset = (normal.cross(ab) * ac.magnitude2() + ac.cross(normal) * ab.magnitude2()) / (2.0 * normal.magnitude2());
+    (a + offset, offset.magnitude())
+}
+
+/// The diametral sphere of a boundary triangle, which refinement shouldn't put points in
+#[derive(Clone, Debug)]
+struct DiametralSphere {
+    center: Vec3,
+    radius: f64,
+    /// The PLC face the triangle is part of
+    face: usize,
+    node_index: usize,
+}
+
+impl Bounded for DiametralSphere {
+    fn aabb(&self) -> AABB {
+        let min = self.center - vec3(self.radius, self.radius, self.radius);
+        let max = self.center + vec3(self.radius, self.radius, self.radius);
+        AABB::with_bounds(
+            NPoint3::new(min.x as f32, min.y as f32, min.z as f32),
+            NPoint3::new(max.x as f32, max.y as f32, max.z as f32),
+        )
+    }
+}
+
+impl BHShape for DiametralSphere {
+    fn set_bh_node_index(&mut self, index: usize) {
+        self.node_index = index;
+    }
+
+    fn bh_node_index(&self) -> usize {
+        self.node_index
+    }
+}
+
+/// Finds a diametral sphere that a point is strictly inside, skipping BVH nodes that don't contain the point
+fn encroached_sphere<'a>(bvh: &BVH, spheres: &'a [DiametralSphere], point: Vec3) -> Option<&'a DiametralSphere> {
+    if spheres.is_empty() {
+        return None;
+    }
+
+    let mut stack = vec![0];
+    while let Some(index) = stack.pop() {
+        match bvh.nodes[index] {
+            BVHNode::Leaf { shape_index, .. } => {
+                let sphere = &spheres[shape_index];
+                if (point - sphere.center).magnitude() < sphere.radius {
+                    return Some(sphere);
+                }
+            }
+
+            BVHNode::Node { child_l_index, ref child_l_aabb, child_r_index, ref child_r_aabb, .. } => {
+                if aabb_distance2(child_l_aabb, point) == 0.0 {
+                    stack.push(child_l_index);
+                }
+                if aabb_distance2(child_r_aabb, point) == 0.0 {
+                    stack.push(child_r_index);
+                }
+            }
+        }
+    }
+
+    None
+}
+
+impl MaterialMesh {
+    /// Fills the inside of the mesh with tets for finite element simulation.
+    /// Each tet gets the material of the shell it's in.
+    /// Bad tets are refined by adding their circumcenters,
+    /// unless a circumcenter encroaches on the boundary, in which case the boundary gets split instead.
+    /// Points are added to one Delaunay tetrahedralization as refinement goes,
+    /// and only the boundary edges they break get recovered again.
+    /// Faces aren't recovered incrementally: each pass recovers all of them
+    /// and removes slivers in a fresh copy of the tetrahedralization,
+    /// so a pass costs time proportional to the size of the whole mesh.
+    /// If refinement stops before every tet meets the bounds, `TetMesh::num_bad_tets` says how many don't.
+    /// Fails if flat tets are left after removing slivers.
+    /// The mesh should be valid, as checked by `validate`.
+    pub fn tetrahedralize(&self, options: &TetMeshOptions) -> Result<TetMesh, VoxelizationError> {
+        /// Refinement gives up after this many passes even if it's still adding points
+        const MAX_PASSES: usize = 64;
+
+        let (bvh, bvh_triangles) = self.bvh();
+        let mesh = self.mesh();
+        let id_map = mesh.vertex_iter().enumerate().map(|(i, v)| (v, i)).collect::<FnvHashMap<_, _>>();
+        let triangles = mesh
+            .face_iter()
+            .map(|f| {
+                let (a, b, c) = mesh.face_vertices(f);
+                ([id_map[&a], id_map[&b], id_map[&c]], mesh.face_tag(f))
+            })
+            .collect::<Vec<_>>();
+        let mut plc = PiecewiseLinearComplex::from_triangles(
+            mesh.vertex_iter().map(|v| mesh.vertex_position(v)).collect(),
+            &triangles,
+        );
+        let mut dt = plc.delaunay()?;
+        let mut steiner_points = 0;
+
+        let mut pass = 0;
+        loop {
+            let (tets, face_triangles) = plc.recover_faces(&dt)?;
+            let (positions, tets) = tets.tets_with_materials();
+
+            let mut mesh = TetMesh {
+                positions,
+                tets: tets.iter().map(|(tet, _)| *tet).collect(),
+                tet_materials: tets.iter().map(|(_, material)| *material).collect(),
+                materials: self.materials().clone(),
+                num_bad_tets: 0,
+            };
+
+            // Worst tets first
+            let mut bad = (0..mesh.num_tets())
+                .map(|i| (i, mesh.radius_edge_ratio(i)))
+                .filter(|(i, ratio)| {
+                    *ratio > options.max_radius_edge_ratio
+                        || options.max_volume.map_or(false, |max| mesh.volume(*i) > max)
+                })
+                .collect::<Vec<_>>();
+            bad.sort_by_key(|(_, ratio)| FloatOrd(-*ratio));
+            mesh.num_bad_tets = bad.len();
+
+            pass += 1;
+            let budget = options.max_steiner_points.saturating_sub(steiner_points);
+            if bad.is_empty() || pass > MAX_PASSES || budget == 0 {
+                return mesh.reject_flat_tets();
+            }
+
+            let mut spheres = face_triangles
+                .iter()
+                .map(|([a, b, c], face)| {
+                    let (center, radius) = diametral_sphere([plc.position(*a), plc.position(*b), plc.position(*c)]);
+                    DiametralSphere { center, radius, face: *face, node_index: 0 }
+                })
+                .collect::<Vec<_>>();
+            let sphere_bvh = BVH::build(&mut spheres);
+            let longest_edges = plc.longest_face_edges();
+
+            let mut added = vec![];
+            let mut edges_to_split = FnvHashSet::default();
+            for (i, _) in bad {
+                if added.len() + edges_to_split.len() >= budget {
+                    break;
+                }
+
+                let pos = mesh.tet_positions(i);
+                let center = match DelaunayTetrahedralization::circumcenter(pos) {
+                    Some(center) => center,
+                    None => continue,
+                };
+                let radius = (center - pos[0]).magnitude();
+
+                // Split the longest edge of an encroached boundary face instead
+                if let Some(sphere) = encroached_sphere(&sphere_bvh, &spheres, center) {
+                    edges_to_split.insert(longest_edges[&sphere.face].sorted());
+                    continue;
+                }
+
+                // Circumcenters that end up outside without encroaching anything are of tets on the boundary
+                if enclosing_material(&bvh, &bvh_triangles, center).is_none() {
+                    continue;
+                }
+
+                // Points added in the same pass shouldn't crowd each other
+                if added.iter().any(|(other, other_radius): &(Vec3, f64)| {
+                    (center - *other).magnitude() < radius.min(*other_radius)
+                }) {
+                    continue;
+                }
+
+                added.push((center, radius));
+            }
+
+            if added.is_empty() && edges_to_split.is_empty() {
+                return mesh.reject_flat_tets();
+            }
+
+            steiner_points += added.len() + edges_to_split.len();
+            for edge in edges_to_split {
+                match plc.split_edge(&mut dt, edge) {
+                    // Recovering an edge split earlier in the pass can already have split this one
+                    Err(TetError::EdgeNotInComplex) => {}
+                    result => {
+                        result?;
+                    }
+                }
+            }
+            for (center, _) in added {
+                match plc.insert_steiner_point(&mut dt, center) {
+                    // A boundary split can put a point there first
+                    Err(TetError::DuplicatePoint) => {}
+                    result => result?,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::material_mesh::{box_corners_and_triangles, FaceSides};
+
+    /// Triangles of the faces of a box, with the front side facing outward
+    fn box_triangles(min: Vec3, max: Vec3) -> Vec<[Vec3; 3]> {
+        let (corners, triangles) = box_corners_and_triangles(min, max);
+        triangles.into_iter().map(|[a, b, c]| [corners[a], corners[b], corners[c]]).collect()
+    }
+
+    fn two_boxes() -> MaterialMesh {
+        let m1 = MaterialID::new(1);
+        let m2 = MaterialID::new(2);
+        let mut triangles = vec![];
+
+        for (min_x, material) in vec![(0.0, m1), (1.0, m2)] {
+            for triangle in box_triangles(vec3(min_x, 0.0, 0.0), vec3(min_x + 1.0, 1.0, 1.0)) {
+                // The wall between the boxes is added by both boxes, with opposite windings
+                if triangle.iter().all(|p| p.x == 1.0) && material == m2 {
+                    continue;
+                }
+                let sides = if triangle.iter().all(|p| p.x == 1.0) {
+                    FaceSides { back: Some(m1), front: Some(m2) }
+                } else {
+                    FaceSides { back: Some(material), front: None }
+                };
+                triangles.push((triangle, sides));
+            }
+        }
+
+        MaterialMesh::from_two_sided_triangles(triangles).unwrap()
+    }
+
+    #[test]
+    fn test_diametral_sphere() {
+        let (center, radius) = diametral_sphere([vec3(0.0, 0.0, 1.0), vec3(2.0, 0.0, 1.0), vec3(0.0, 2.0, 1.0)]);
+        assert!((center - vec3(1.0, 1.0, 1.0)).magnitude() < 1e-12);
+        assert!((radius - 2f64.sqrt()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_tetrahedralize_two_materials() {
+        let mesh = two_boxes();
+        let options = TetMeshOptions::new().with_max_volume(0.05);
+        let tets = mesh.tetrahedralize(&options).unwrap();
+
+        let volumes = tets.material_volumes();
+        assert_eq!(volumes.len(), 2);
+        assert!((volumes[&MaterialID::new(1)] - 1.0).abs() < 1e-9);
+        assert!((volumes[&MaterialID::new(2)] - 1.0).abs() < 1e-9);
+
+        assert_eq!(tets.num_bad_tets(), 0);
+        for i in 0..tets.num_tets() {
+            assert!(tets.volume(i) > 0.0);
+            assert!(tets.volume(i) <= 0.05);
+            let center = tets.tet_positions(i).iter().sum::<Vec3>() / 4.0;
+            let expected = if center.x < 1.0 { MaterialID::new(1) } else { MaterialID::new(2) };
+            assert_eq!(tets.tet_materials()[i], expected);
+        }
+    }
+
+    #[test]
+    fn test_tetrahedralize_out_of_steiner_points() {
+        let mesh = two_boxes();
+        let options = TetMeshOptions::new().with_max_volume(0.001).with_max_steiner_points(4);
+        let tets = mesh.tetrahedralize(&options).unwrap();
+
+        // Meeting the bounds takes at least 2000 tets
+        let bad = (0..tets.num_tets())
+            .filter(|i| tets.radius_edge_ratio(*i) > options.max_radius_edge_ratio() || tets.volume(*i) > 0.001)
+            .count();
+        assert!(bad > 0);
+        assert_eq!(tets.num_bad_tets(), bad);
+
+        // The tets are still a valid mesh, just a coarse one
+        let volumes = tets.material_volumes();
+        assert!((volumes[&MaterialID::new(1)] - 1.0).abs() < 1e-9);
+        assert!((volumes[&MaterialID::new(2)] - 1.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,242 @@
+//! Measures of how well shaped tetrahedrons are.
+//! Flat or needle-like tets make finite element simulations inaccurate.
+
+use std::f64::consts::PI;
+use tri_mesh::prelude::*;
+
+use crate::tetrahedralize::DelaunayTetrahedralization;
+
+/// Edges of a tet, in the order `dihedral_angles` returns angles in
+pub const TET_EDGES: [[usize; 2]; 6] = [[0, 1], [0, 2], [0, 3], [1, 2], [1, 3], [2, 3]];
+
+/// Normal of the face opposite a vertex, pointing away from that vertex
+fn outward_normal(pos: [Vec3; 4], opposite: usize) -> Vec3 {
+    let [a, b, c] = match opposite {
+        0 => [pos[1], pos[2], pos[3]],
+        1 => [pos[0], pos[2], pos[3]],
+        2 => [pos[0], pos[1], pos[3]],
+        _ => [pos[0], pos[1], pos[2]],
+    };
+    let normal = (b - a).cross(c - a);
+    if normal.dot(pos[opposite] - a) > 0.0 {
+        -normal
+    } else {
+        normal
+    }
+}
+
+/// Interior angles between the two faces at each edge, in radians, in the order of `TET_EDGES`.
+/// Angles of flat tets are 0 or pi.
+pub fn dihedral_angles(pos: [Vec3; 4]) -> [f64; 6] {
+    let mut angles = [0.0; 6];
+    for (i, [a, b]) in TET_EDGES.iter().enumerate() {
+        // The faces at an edge are the ones opposite the other two vertices
+        let others = (0..4).filter(|v| v != a && v != b).collect::<Vec<_>>();
+        let n0 = outward_normal(pos, others[0]);
+        let n1 = outward_normal(pos, others[1]);
+        let cos = n0.dot(n1) / (n0.magnitude() * n1.magnitude());
+        angles[i] = PI - cos.max(-1.0).min(1.0).acos();
+    }
+    angles
+}
+
+pub fn min_dihedral_angle(pos: [Vec3; 4]) -> f64 {
+    dihedral_angles(pos).iter().copied().fold(f64::INFINITY, f64::min)
+}
+
+pub fn max_dihedral_angle(pos: [Vec3; 4]) -> f64 {
+    dihedral_angles(pos).iter().copied().fold(0.0, f64::max)
+}
+
+/// Volume, which is negative if the tet is negatively oriented
+pub fn signed_volume(pos: [Vec3; 4]) -> f64 {
+    (pos[1] - pos[0]).cross(pos[2] - pos[0]).dot(pos[3] - pos[0]) / 6.0
+}
+
+/// Ratio of the circumradius to the shortest edge. Infinite for flat tets.
+/// This is about 0.61 for a regular tet, but slivers can have a small ratio too,
+/// so check dihedral angles to find those.
+pub fn radius_edge_ratio(pos: [Vec3; 4]) -> f64 {
+    let shortest = TET_EDGES
+        .iter()
+        .map(|[a, b]| (pos[*a] - pos[*b]).magnitude())
+        .fold(f64::INFINITY, f64::min);
+
+    match DelaunayTetrahedralization::circumcenter(pos) {
+        Some(center) => (center - pos[0]).magnitude() / shortest,
+        None => f64::INFINITY,
+    }
+}
+
+/// Circumradius divided by 3 times the inradius.
+/// This is 1 for a regular tet, bigger for worse tets, and infinite for flat tets.
+pub fn aspect_ratio(pos: [Vec3; 4]) -> f64 {
+    let volume = signed_volume(pos).abs();
+    let area = (0..4).map(|i| outward_normal(pos, i).magnitude() / 2.0).sum::<f64>();
+    if volume == 0.0 {
+        return f64::INFINITY;
+    }
+    let inradius = 3.0 * volume / area;
+
+    match DelaunayTetrahedralization::circumcenter(pos) {
+        Some(center) => (center - pos[0]).magnitude() / (3.0 * inradius),
+        None => f64::INFINITY,
+    }
+}
+
+/// Quality measures of a tet. Angles are in radians.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TetQuality {
+    pub min_dihedral_angle: f64,
+    pub max_dihedral_angle: f64,
+    pub radius_edge_ratio: f64,
+    pub aspect_ratio: f64,
+    /// Always positive or 0
+    pub volume: f64,
+}
+
+impl TetQuality {
+    pub fn new(pos: [Vec3; 4]) -> Self {
+        let angles = dihedral_angles(pos);
+
+        Self {
+            min_dihedral_angle: angles.iter().copied().fold(f64::INFINITY, f64::min),
+            max_dihedral_angle: angles.iter().copied().fold(0.0, f64::max),
+            radius_edge_ratio: radius_edge_ratio(pos),
+            aspect_ratio: aspect_ratio(pos),
+            volume: signed_volume(pos).abs(),
+        }
+    }
+}
+
+/// Counts of values in equal-width bins over a range.
+/// Values outside the range are counted in the first or last bin.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Histogram {
+    min: f64,
+    max: f64,
+    counts: Vec<usize>,
+}
+
+impl Histogram {
+    /// An empty histogram, or None if there are no bins or the range isn't finite and nonempty
+    pub fn new(min: f64, max: f64, bins: usize) -> Option<Self> {
+        if bins == 0 || !min.is_finite() || !max.is_finite() || max <= min {
+            return None;
+        }
+
+        Some(Self {
+            min,
+            max,
+            counts: vec![0; bins],
+        })
+    }
+
+    /// Counts a value. NaNs aren't counted.
+    pub fn add(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        let bins = self.counts.len();
+        let bin = ((value - self.min) / (self.max - self.min) * bins as f64).floor();
+        self.counts[bin.max(0.0).min((bins - 1) as f64) as usize] += 1;
+    }
+
+    pub fn counts(&self) -> &[usize] {
+        &self.counts
+    }
+
+    /// Min and max of the values a bin counts, not including values outside the range
+    pub fn bin_range(&self, bin: usize) -> (f64, f64) {
+        let width = (self.max - self.min) / self.counts.len() as f64;
+        (self.min + width * bin as f64, self.min + width * (bin + 1) as f64)
+    }
+
+    /// Number of values counted
+    pub fn total(&self) -> usize {
+        self.counts.iter().sum()
+    }
+}
+
+impl Extend<f64> for Histogram {
+    fn extend<I: IntoIterator<Item = f64>>(&mut self, values: I) {
+        for value in values {
+            self.add(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn regular() -> [Vec3; 4] {
+        [
+            vec3(1.0, 1.0, 1.0),
+            vec3(1.0, -1.0, -1.0),
+            vec3(-1.0, 1.0, -1.0),
+            vec3(-1.0, -1.0, 1.0),
+        ]
+    }
+
+    #[test]
+    fn test_regular() {
+        let expected = (1.0f64 / 3.0).acos();
+        for angle in dihedral_angles(regular()).iter() {
+            assert!((angle - expected).abs() < 1e-12);
+        }
+        assert!((aspect_ratio(regular()) - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_corner() {
+        let pos = [
+            vec3(0.0, 0.0, 0.0),
+            vec3(1.0, 0.0, 0.0),
+            vec3(0.0, 1.0, 0.0),
+            vec3(0.0, 0.0, 1.0),
+        ];
+        let angles = dihedral_angles(pos);
+
+        // Right angles at the edges from the corner
+        for i in 0..3 {
+            assert!((angles[i] - PI / 2.0).abs() < 1e-12);
+        }
+        for i in 3..6 {
+            assert!((angles[i] - (1.0f64 / 3.0).sqrt().acos()).abs() < 1e-12);
+        }
+        assert!(aspect_ratio(pos) > 1.0);
+    }
+
+    #[test]
+    fn test_flat() {
+        let pos = [
+            vec3(0.0, 0.0, 0.0),
+            vec3(1.0, 0.0, 0.0),
+            vec3(0.0, 1.0, 0.0),
+            vec3(1.0, 1.0, 0.0),
+        ];
+        assert_eq!(aspect_ratio(pos), f64::INFINITY);
+        assert_eq!(radius_edge_ratio(pos), f64::INFINITY);
+
+        let quality = TetQuality::new(pos);
+        assert_eq!(quality.volume, 0.0);
+        assert_eq!(quality.min_dihedral_angle, 0.0);
+        assert!((quality.max_dihedral_angle - PI).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::new(0.0, 180.0, 6).unwrap();
+        histogram.extend(vec![-5.0, 0.0, 29.9, 30.0, 100.0, 180.0, 400.0, f64::NAN]);
+
+        assert_eq!(histogram.counts(), &[3, 1, 0, 1, 0, 2]);
+        assert_eq!(histogram.total(), 7);
+        assert_eq!(histogram.bin_range(1), (30.0, 60.0));
+
+        assert_eq!(Histogram::new(0.0, 1.0, 0), None);
+        assert_eq!(Histogram::new(1.0, 1.0, 4), None);
+        assert_eq!(Histogram::new(0.0, f64::INFINITY, 4), None);
+        assert_eq!(Histogram::new(f64::NAN, 1.0, 4), None);
+    }
+}
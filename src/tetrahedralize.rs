@@ -1,11 +1,15 @@
 use float_ord::FloatOrd;
 use fnv::{FnvHashMap, FnvHashSet};
-use petgraph::{unionfind::UnionFind, prelude::*};
+use petgraph::unionfind::UnionFind;
 use stable_vec::StableVec;
 use std::path::Path;
 use tri_mesh::prelude::*;
 use std::collections::BinaryHeap;
 
+use crate::material_mesh::MaterialID;
+use crate::predicates;
+use crate::spatial_sort;
+use crate::tet_quality::{Histogram, TetQuality};
 use crate::util::ArrayEx;
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -23,94 +27,81 @@ pub enum TetError {
     NoTetOnFaceEdge,
     /// The flip algorithm secretly failed and some face was not actually recovered.
     FaceNotRecovered,
+    /// There were no boundary faces to tell the inside from the outside
+    EmptyBoundary,
+    /// A boundary face is not a face of any tet
+    BoundaryFaceNotInTets,
+    /// A point was added twice to a Delaunay tetrahedralization
+    DuplicatePoint,
+    /// A point added to a Delaunay tetrahedralization is outside its enclosing tetrahedron
+    PointOutsideBounds,
+    /// The tets around a face to flip don't form any known flip configuration
+    UnexpectedFlipConfiguration,
+    /// Some tets have no volume and couldn't be flipped away
+    FlatTets,
+    /// An edge to split isn't an edge of any face of the complex
+    EdgeNotInComplex,
 }
 
-/// A tetrahedralization of a bunch of points.
-/// Uses Bowyer's algorithm
+/// Marks a missing neighbor, across a face of the enclosing tetrahedron
+const NO_TET: usize = usize::MAX;
+
+/// A tet of a `DelaunayTetrahedralization`.
+/// The vertices are positively oriented, so `orient3d` of them is positive,
+/// and neighbor i is the tet across the face opposite vertex i.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct DelaunayTet {
+    vertices: [usize; 4],
+    neighbors: [usize; 4],
+}
+
+/// A Delaunay tetrahedralization of a bunch of points.
+/// Uses the Bowyer-Watson algorithm on an array of tets that know their neighbors,
+/// finding the tet that contains each new point by walking towards it.
 #[derive(Clone, Debug)]
 pub struct DelaunayTetrahedralization {
-    /// Graph of voronoi diagram, where vertices are
-    /// meeting points of voronoi regions, and edges are
-    /// connections between meeting points, with the input point
-    /// on the opposite side of the edge as the weight.
-    /// Each vertex corresponds to a tetrahedron formed by
-    /// the 4 forming input points of the vertex.
-    /// Vertex 0 is reserved for the vertex at infinity.
-    voronoi: StableGraph<(), NodeIndex>,
-    /// Graph of edges in the tetrahedralization
-    tet_edges: StableUnGraph<Vec3, ()>,
-    /// Which vertices each point is adjacent to.
-    /// Does not include the vertex at infinity.
-    point_vertices: Vec<FnvHashSet<NodeIndex>>,
-    points_to_add: Vec<Vec3>,
+    /// Point positions. The first 4 are the corners of a big tetrahedron that contains all the others.
+    points: Vec<Vec3>,
+    tets: StableVec<DelaunayTet>,
+    /// Number of tets each edge is in. Edges are sorted.
+    edges: FnvHashMap<[usize; 2], u32>,
+    /// Tet to start the next point location walk from
+    last_tet: usize,
 }
 
 impl DelaunayTetrahedralization {
-    const EPSILON: f64 = Tetrahedralization::EPSILON;
-
-    fn init(mut points: Vec<Vec3>) -> Self {
+    fn init(points: &[Vec3]) -> Self {
         // Initialize big tetrahedron that contains all points inside it
         // Make the tetrahedron REALLY big to hopefully avoid concave tetrahedralization
         // after removing the tetrahedron
         let min = points.iter().fold(
             Vec3::new(f64::MAX, f64::MAX, f64::MAX),
-            |Vec3 {
-                 x: x1,
-                 y: y1,
-                 z: z1,
-             },
-             Vec3 {
-                 x: x2,
-                 y: y2,
-                 z: z2,
-             }| vec3(x1.min(*x2), y1.min(*y2), z1.min(*z2)),
+            |min, p| vec3(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z)),
         ) + vec3(-1.0, -1.0, -1.0) * 1000.0;
         let max = points.iter().fold(
             Vec3::new(f64::MIN, f64::MIN, f64::MIN),
-            |Vec3 {
-                 x: x1,
-                 y: y1,
-                 z: z1,
-             },
-             Vec3 {
-                 x: x2,
-                 y: y2,
-                 z: z2,
-             }| vec3(x1.max(*x2), y1.max(*y2), z1.max(*z2)),
+            |max, p| vec3(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z)),
         ) + vec3(1.0, 1.0, 1.0) * 1000.0;
 
-        let mut tet_edges = StableUnGraph::default();
-        tet_edges.add_node(min);
-        tet_edges.add_node(min + Vec3::unit_x() * 3.0 * (max.x - min.x));
-        tet_edges.add_node(min + Vec3::unit_y() * 3.0 * (max.y - min.y));
-        tet_edges.add_node(min + Vec3::unit_z() * 3.0 * (max.z - min.z));
-        for (i, j) in vec![(0, 1), (0, 2), (0, 3), (1, 2), (1, 3), (2, 3)].into_iter() {
-            tet_edges.add_edge(NodeIndex::new(i), NodeIndex::new(j), ());
-        }
-
-        let point_vertices = vec![NodeIndex::new(1); 4]
-            .into_iter()
-            .map(|n| std::iter::once(n).collect())
-            .collect();
-
-        let mut voronoi = StableGraph::new();
-        let inf_vertex = voronoi.add_node(()); // vertex at infinity
-        let tet_vertex = voronoi.add_node(()); // outer tetrahedron voronoi vertex
-        for i in (0..4).map(NodeIndex::new) {
-            voronoi.add_edge(tet_vertex, inf_vertex, i);
-        }
+        let corners = vec![
+            min,
+            min + Vec3::unit_x() * 3.0 * (max.x - min.x),
+            min + Vec3::unit_y() * 3.0 * (max.y - min.y),
+            min + Vec3::unit_z() * 3.0 * (max.z - min.z),
+        ];
 
-        points.reverse();
-        Self {
-            voronoi,
-            tet_edges,
-            point_vertices,
-            points_to_add: points,
-        }
+        let mut tet = Self {
+            points: corners,
+            tets: StableVec::new(),
+            edges: FnvHashMap::default(),
+            last_tet: 0,
+        };
+        tet.last_tet = tet.push_tet([0, 1, 2, 3], [NO_TET; 4]);
+        tet
     }
 
     /// Returns None if the points are coplanar
-    fn circumcenter(points: [Vec3; 4]) -> Option<Vec3> {
+    pub(crate) fn circumcenter(points: [Vec3; 4]) -> Option<Vec3> {
         let [p0, p1, p2, p3] = points;
 
         Transform::<Point3<f64>>::inverse_transform(
@@ -127,301 +118,219 @@ impl DelaunayTetrahedralization {
         })
     }
 
-    fn line_endpoints(&self, vertex: NodeIndex, visited: &mut FnvHashSet<NodeIndex>) -> Result<Vec<Vec3>, TetError> {
-        visited.insert(vertex);
-
-        let mut endpoints = vec![];
-
-        // Tree search for vertices that have a position
-        for vertex in self.voronoi.neighbors(vertex) {
-            if let Some(pos) = self.vertex_position_helper(vertex)? {
-                endpoints.push(pos);
-            } else if !visited.contains(&vertex) {
-                endpoints.extend(self.line_endpoints(vertex, visited)?);
-            }
-        }
-
-        Ok(endpoints)
+    fn positions(&self, vertices: [usize; 4]) -> [Vec3; 4] {
+        [
+            self.points[vertices[0]],
+            self.points[vertices[1]],
+            self.points[vertices[2]],
+            self.points[vertices[3]],
+        ]
     }
 
-    /// Finds the position of a voronoi vertex,
-    /// which is the circumcenter of its 4 forming points,
-    /// if it can find the position in the first place.
-    fn vertex_position_helper(&self, vertex: NodeIndex) -> Result<Option<Vec3>, TetError> {
-        let mut points = self.voronoi.edges(vertex).map(|e| e.weight());
-        let p0 = self.tet_edges[*points.next().ok_or(TetError::VoronoiNot4Regular)?];
-        let p1 = self.tet_edges[*points.next().ok_or(TetError::VoronoiNot4Regular)?];
-        let p2 = self.tet_edges[*points.next().ok_or(TetError::VoronoiNot4Regular)?];
-        let p3 = self.tet_edges[*points.next().ok_or(TetError::VoronoiNot4Regular)?];
-
-        Ok(Self::circumcenter([p0, p1, p2, p3]))
+    /// `orient3d` of a tet with vertex i replaced by a point.
+    /// Positive if the point is on the same side of face i as vertex i.
+    fn orient_with_point(&self, vertices: [usize; 4], i: usize, point: Vec3) -> f64 {
+        let mut pos = self.positions(vertices);
+        pos[i] = point;
+        predicates::orient3d(pos[0], pos[1], pos[2], pos[3])
     }
 
-    /// Finds the position of a voronoi vertex,
-    /// which is the circumcenter of its 4 forming points.
-    fn vertex_position(&self, vertex: NodeIndex) -> Result<Vec3, TetError> {
-        if let Some(pos) = self.vertex_position_helper(vertex)? {
-            Ok(pos)
-        } else {
-            let points = self.line_endpoints(vertex, &mut FnvHashSet::default())?;
-            let len = points.len();
-            Ok(points.into_iter().sum::<Vec3>() / len as f64)
-        }
+    fn tet_edges(vertices: [usize; 4]) -> impl Iterator<Item = [usize; 2]> {
+        (0..4).flat_map(move |i| ((i + 1)..4).map(move |j| [vertices[i], vertices[j]].sorted()))
     }
 
-    /// Finds a voronoi vertex to delete when point is added.
-    /// The point is closer to this vertex than any of its forming points.
-    fn find_vertex_to_delete(&self, point: Vec3) -> Result<NodeIndex, TetError> {
-        // Find the nearest point first
-        let mut nearest = NodeIndex::new(self.tet_edges.node_count() - 1);
-        while {
-            let maybe_closer = self
-                .tet_edges
-                .edges_directed(nearest, Outgoing)
-                .map(|e| e.target())
-                .min_by_key(|p| FloatOrd((point - self.tet_edges[*p]).magnitude2()))
-                .unwrap();
-
-            if (self.tet_edges[maybe_closer] - point).magnitude2()
-                < (self.tet_edges[nearest] - point).magnitude2()
-            {
-                nearest = maybe_closer;
-                true
-            } else {
-                false
-            }
-        } {}
-
-        let mut error = false;
-        // Find vertex that's closer to the new point than a forming point.
-        // Such a vertex is guaranteed to exist
-        let res = self.point_vertices[nearest.index()]
-            .iter()
-            .find(|v| {
-                if let Ok(pos) = self.vertex_position(**v) {
-                    (point - pos).magnitude() <= (self.tet_edges[nearest] - pos).magnitude() + Self::EPSILON
-                } else {
-                    error = true;
-                    false
-                }
-            })
-            .copied();
+    fn push_tet(&mut self, mut vertices: [usize; 4], mut neighbors: [usize; 4]) -> usize {
+        let [p0, p1, p2, p3] = self.positions(vertices);
+        if predicates::orient3d(p0, p1, p2, p3) < 0.0 {
+            vertices.swap(2, 3);
+            neighbors.swap(2, 3);
+        }
 
-        if error {
-            Err(TetError::VoronoiNot4Regular)?;
+        for edge in Self::tet_edges(vertices) {
+            *self.edges.entry(edge).or_insert(0) += 1;
         }
-        res.ok_or(TetError::NoCloserVoronoiVertex)
+        self.tets.push(DelaunayTet { vertices, neighbors })
     }
 
-    fn find_vertices_to_delete_helper(
-        &self,
-        point: Vec3,
-        vertex: NodeIndex,
-        visited: &mut FnvHashSet<NodeIndex>,
-    ) -> Result<Vec<NodeIndex>, TetError> {
-        let mut vertices = vec![];
-
-        for v in self.voronoi.edges(vertex).map(|e| e.target()) {
-            if v.index() != 0 && visited.insert(v) {
-                let pos = self.vertex_position(v)?;
-                let form_point = self.voronoi.edges(v).next().unwrap().weight();
-                if (point - pos).magnitude() <= (self.tet_edges[*form_point] - pos).magnitude() + Self::EPSILON {
-                    vertices.push(v);
-                    vertices.extend(self.find_vertices_to_delete_helper(point, v, visited)?);
-                }
+    /// Finds a tet that contains the point, possibly on its boundary.
+    /// Walks from the last tet added towards the point,
+    /// crossing a face that the point is on the other side of each step.
+    fn locate(&self, point: Vec3) -> Result<usize, TetError> {
+        let mut index = self.last_tet;
+
+        // A walk can't revisit tets with exact predicates, so it takes at most this many steps
+        for step in 0..=self.tets.num_elements() {
+            let tet = self.tets[index];
+            // Rotate the first face checked so degenerate cases can't cycle
+            let next = (0..4)
+                .map(|i| (i + step) % 4)
+                .find(|i| self.orient_with_point(tet.vertices, *i, point) < 0.0);
+
+            match next {
+                Some(i) if tet.neighbors[i] == NO_TET => Err(TetError::PointOutsideBounds)?,
+                Some(i) => index = tet.neighbors[i],
+                None => return Ok(index),
             }
         }
 
-        Ok(vertices)
+        // Fall back to checking every tet
+        self.tets
+            .iter()
+            .find(|(_, tet)| (0..4).all(|i| self.orient_with_point(tet.vertices, i, point) >= 0.0))
+            .map(|(index, _)| index)
+            .ok_or(TetError::PointOutsideBounds)
     }
 
-    fn find_vertices_to_delete(&self, point: Vec3) -> Result<Vec<NodeIndex>, TetError> {
-        let vertex = self.find_vertex_to_delete(point)?;
+    /// Finds the tets whose circumspheres contain the point.
+    /// The cavity left by removing them is star-shaped from the point,
+    /// so it gets filled by connecting the point to its boundary.
+    fn cavity(&self, point: Vec3, start: usize) -> FnvHashSet<usize> {
+        let mut cavity = std::iter::once(start).collect::<FnvHashSet<_>>();
+        let mut to_search = vec![start];
 
-        let mut to_delete = vec![vertex];
-        to_delete.extend(
-            self.find_vertices_to_delete_helper(
-                point,
-                vertex,
-                &mut std::iter::once(vertex).collect(),
-            )?
-            .into_iter(),
-        );
-        Ok(to_delete)
-    }
+        while let Some(index) = to_search.pop() {
+            let tet = self.tets[index];
 
-    /// Get the points that form some Voronoi edge s→t
-    /// This is all the points opposite some edge from s that isn't s→t.
-    fn points_forming_vertex<'a>(
-        &'a self,
-        vertex: NodeIndex,
-    ) -> impl Iterator<Item = NodeIndex> + 'a {
-        self.voronoi.edges(vertex).map(|e| *e.weight())
-    }
+            for (i, neighbor) in tet.neighbors.iter().enumerate() {
+                if *neighbor == NO_TET || cavity.contains(neighbor) {
+                    continue;
+                }
 
-    /// Get the points that form some Voronoi edge s→t
-    /// This is all the points opposite some edge from s that isn't s→t.
-    fn points_forming_edge<'a>(&'a self, edge: EdgeIndex) -> impl Iterator<Item = NodeIndex> + 'a {
-        let (s, t) = self.voronoi.edge_endpoints(edge).unwrap();
-        let mut iter = self.voronoi.neighbors(s).detach();
-        std::iter::from_fn(move || iter.next_edge(&self.voronoi))
-            .filter(move |e| *e != edge)
-            .map(move |e| self.voronoi[e])
-    }
+                let [p0, p1, p2, p3] = self.positions(self.tets[*neighbor].vertices);
+                // Also take the neighbor if the point is on the plane of the shared face,
+                // or the new tet on that face would be flat
+                if predicates::insphere(p0, p1, p2, p3, point) > 0.0
+                    || self.orient_with_point(tet.vertices, i, point) <= 0.0
+                {
+                    cavity.insert(*neighbor);
+                    to_search.push(*neighbor);
+                }
+            }
+        }
 
-    /// Adds the next point to the tetrahedralization
-    /// Returns (tet edges removed, tet edges added)
-    pub fn add_point(&mut self, point: Vec3) -> Result<(Vec<(usize, usize)>, Vec<(usize, usize)>), TetError> {
-        // Find all vertices to delete
-        //for v in self.voronoi.node_indices().skip(1) {
-        //    println!("Vertex {}: {:?}", v.index(), self.voronoi.edges(v)
-        //        .map(|e| (e.source().index(), e.target().index(), e.weight()))
-        //        .collect::<Vec<_>>());
-        //}
-        //println!();
-        let v_delete = self.find_vertices_to_delete(point)?;
+        cavity
+    }
 
-        // Points forming deleted vertices
-        let near_points = v_delete
-            .iter()
-            .flat_map(|v| self.voronoi.edges(*v).map(|e| *e.weight()))
-            .collect::<FnvHashSet<_>>();
+    /// Inserts a point that's already in the point list.
+    /// Returns (tet edges removed, tet edges added), with sorted edges.
+    fn insert(&mut self, p_index: usize) -> Result<(Vec<[usize; 2]>, Vec<[usize; 2]>), TetError> {
+        let point = self.points[p_index];
+        let start = self.locate(point)?;
+        if self.tets[start].vertices.iter().any(|v| self.points[*v] == point) {
+            Err(TetError::DuplicatePoint)?;
+        }
+        let cavity = self.cavity(point, start);
 
-        // edges from vertex-to-delete to vertex-to-not-delete
-        let edges = v_delete
+        // Faces on the boundary of the cavity, as the cavity tet and the index of the vertex opposite the face
+        let boundary = cavity
             .iter()
-            .flat_map(|v| {
-                let mut iter = self.voronoi.neighbors(*v).detach();
-                let voronoi = &self.voronoi;
-                let v_delete = &v_delete;
-                std::iter::from_fn(move || iter.next_edge(voronoi))
-                    .filter(move |e| !v_delete.contains(&voronoi.edge_endpoints(*e).unwrap().1))
+            .flat_map(|index| {
+                let tet = self.tets[*index];
+                let cavity = &cavity;
+                (0..4)
+                    .filter(move |i| !cavity.contains(&tet.neighbors[*i]))
+                    .map(move |i| (*index, tet, i))
             })
             .collect::<Vec<_>>();
 
-        let p_index = self.tet_edges.add_node(point);
-
-        // Add new vertices to voronoi diagram. Keep the edges they were added on top of.
-        let v_new = edges
-            .iter()
-            .map(|e| {
-                let (s, t) = self.voronoi.edge_endpoints(*e).unwrap();
-                let vertex = self.voronoi.add_node(());
-                self.voronoi.add_edge(vertex, t, p_index);
-                if t.index() != 0 {
-                    self.voronoi.add_edge(
-                        t,
-                        vertex,
-                        self.voronoi[self.voronoi.find_edge(t, s).unwrap()],
-                    );
+        // Edges on the boundary of the cavity come back when it's filled,
+        // so only the edges still unused afterwards are removed
+        let mut maybe_removed = vec![];
+        for index in &cavity {
+            let tet = self.tets.remove(*index).unwrap();
+            for edge in Self::tet_edges(tet.vertices) {
+                let count = self.edges.get_mut(&edge).unwrap();
+                *count -= 1;
+                if *count == 0 {
+                    maybe_removed.push(edge);
                 }
+            }
+        }
 
-                (vertex, *e)
-            })
-            .collect::<FnvHashSet<_>>();
+        // Fill the cavity. Faces with the new point are matched up by their other 2 vertices.
+        let mut edges_added = FnvHashSet::default();
+        let mut open_faces = FnvHashMap::<[usize; 2], (usize, usize)>::default();
+        for (old_index, tet, i) in boundary {
+            let mut vertices = tet.vertices;
+            vertices[i] = p_index;
+            let mut neighbors = [NO_TET; 4];
+            neighbors[i] = tet.neighbors[i];
+
+            let new_index = self.push_tet(vertices, neighbors);
+            let new_tet = self.tets[new_index];
+
+            let outside = tet.neighbors[i];
+            if outside != NO_TET {
+                let outside_tet = &mut self.tets[outside];
+                let j = (0..4).find(|j| outside_tet.neighbors[*j] == old_index).unwrap();
+                outside_tet.neighbors[j] = new_index;
+            }
 
-        // Add edges connecting new vertices in voronoi diagram
-        for ((v1, e1), (v2, e2)) in v_new
-            .iter()
-            .flat_map(|v1| v_new.iter().map(move |v2| (v1, v2)))
-        {
-            if v1 < v2 {
-                let (s1, t1) = self.voronoi.edge_endpoints(*e1).unwrap();
-                let (s2, t2) = self.voronoi.edge_endpoints(*e2).unwrap();
-
-                let p1 = self.points_forming_edge(*e1).collect::<FnvHashSet<_>>();
-                let p2 = self.points_forming_edge(*e2).collect::<FnvHashSet<_>>();
-
-                if p1.intersection(&p2).count() == 2 {
-                    self.voronoi
-                        .add_edge(*v1, *v2, *p1.difference(&p2).next().ok_or(TetError::VoronoiEdgeFormedByLessThan3Points)?);
-                    self.voronoi
-                        .add_edge(*v2, *v1, *p2.difference(&p1).next().ok_or(TetError::VoronoiEdgeFormedByLessThan3Points)?);
+            for j in 0..4 {
+                if new_tet.vertices[j] == p_index {
+                    continue;
                 }
-            }
-        }
+                edges_added.insert([new_tet.vertices[j], p_index].sorted());
 
-        // Add new point adjacencies
-        let mut edges_added = vec![];
-        for point in &near_points {
-            self.tet_edges.add_edge(*point, p_index, ());
+                // The face opposite vertex j has the new point and 2 other vertices
+                let others = (0..4)
+                    .filter(|k| *k != j && new_tet.vertices[*k] != p_index)
+                    .map(|k| new_tet.vertices[k])
+                    .collect::<Vec<_>>();
+                let key = [others[0], others[1]].sorted();
 
-            if point.index() >= 4 && p_index.index() >= 4 {
-                edges_added.push((
-                    if *point < p_index {
-                        point.index()
-                    } else {
-                        p_index.index()
-                    } - 4,
-                    if *point < p_index {
-                        p_index.index()
-                    } else {
-                        point.index()
-                    } - 4,
-                ));
+                if let Some((other_index, other_j)) = open_faces.remove(&key) {
+                    self.tets[new_index].neighbors[j] = other_index;
+                    self.tets[other_index].neighbors[other_j] = new_index;
+                } else {
+                    open_faces.insert(key, (new_index, j));
+                }
             }
-        }
 
-        // Add new adjacenices from points to vertices
-        self.point_vertices.resize(
-            self.point_vertices.len().max(p_index.index() + 1),
-            FnvHashSet::default(),
-        );
-        for (vertex, _) in v_new {
-            for edge in self.voronoi.edges(vertex) {
-                self.point_vertices[edge.weight().index()].insert(vertex);
-            }
+            self.last_tet = new_index;
         }
 
-        // Delete old vertices from voronoi diagram
-        for vertex in &v_delete {
-            self.voronoi.remove_node(*vertex);
-        }
-
-        // Delete invalid point-vertex adjacencies
-        for point in &near_points {
-            self.point_vertices[point.index()].retain(|v| !v_delete.contains(v));
+        let edges_removed = maybe_removed
+            .into_iter()
+            .filter(|edge| self.edges[edge] == 0)
+            .collect::<Vec<_>>();
+        for edge in &edges_removed {
+            self.edges.remove(edge);
         }
 
-        // Delete invalid adjacencies in points
-        let edges = near_points
-            .iter()
-            .flat_map(|p| {
-                let mut iter = self.tet_edges.neighbors(*p).detach();
-                let near_points = &near_points;
-                let tet_edges = &self.tet_edges;
-                std::iter::from_fn(move || iter.next_edge(&tet_edges))
-                    .filter(move |e| near_points.contains(&tet_edges.edge_endpoints(*e).unwrap().1))
-            })
-            .collect::<FnvHashSet<_>>();
-
-        let mut edges_removed = vec![];
-        for edge in edges {
-            let (s, t) = self.tet_edges.edge_endpoints(edge).unwrap();
-            let vs = &self.point_vertices[s.index()];
-            let vt = &self.point_vertices[t.index()];
-
-            if vs.is_disjoint(&vt) {
-                self.tet_edges.remove_edge(edge);
-
-                if s.index() >= 4 && t.index() >= 4 {
-                    edges_removed.push((
-                        if s < t { s.index() } else { t.index() } - 4,
-                        if s < t { t.index() } else { s.index() } - 4,
-                    ))
-                }
-            }
-        }
+        Ok((edges_removed, edges_added.into_iter().collect()))
+    }
 
-        Ok((edges_removed, edges_added))
+    /// Adds the next point to the tetrahedralization
+    /// Returns (tet edges removed, tet edges added)
+    pub fn add_point(&mut self, point: Vec3) -> Result<(Vec<(usize, usize)>, Vec<(usize, usize)>), TetError> {
+        self.points.push(point);
+        let (removed, added) = self.insert(self.points.len() - 1).map_err(|error| {
+            self.points.pop();
+            error
+        })?;
+
+        // Ignore edges of the enclosing tetrahedron
+        let to_output = |edges: Vec<[usize; 2]>| {
+            edges
+                .into_iter()
+                .filter(|[s, _]| *s >= 4)
+                .map(|[s, t]| (s - 4, t - 4))
+                .collect::<Vec<_>>()
+        };
+        Ok((to_output(removed), to_output(added)))
     }
 
     /// Constructs a tetrahedralization of the input points.
+    /// The points are inserted in a biased randomized order that follows a Hilbert curve
+    /// within each round, so each walk starts near the point it's looking for.
     pub fn new(points: Vec<Vec3>) -> Result<Self, TetError> {
-        let mut tet = Self::init(points);
+        let mut tet = Self::init(&points);
+        let order = spatial_sort::brio_order(&points);
+        tet.points.extend(points);
 
-        while let Some(point) = tet.points_to_add.pop() {
-            tet.add_point(point)?;
+        for index in order {
+            tet.insert(index + 4)?;
         }
 
         Ok(tet)
@@ -429,86 +338,64 @@ impl DelaunayTetrahedralization {
 
     /// Obtain the tetrahedrons of the tetrahedralization
     pub fn tetrahedrons(&self) -> Result<(Vec<Vec3>, Vec<[usize; 4]>), TetError> {
-        let positions = self
-            .tet_edges
-            .node_indices()
-            .skip(4)
-            .map(|p| self.tet_edges[p])
-            .collect();
+        let positions = self.points[4..].to_vec();
 
-        let mut error = false;
         let tets = self
-            .voronoi
-            .node_indices()
-            .filter(move |v| v.index() != 0)
-            .map(move |v| {
-                let mut iter = self.points_forming_vertex(v);
-                let p0 = iter.next()?;
-                let p1 = iter.next()?;
-                let p2 = iter.next()?;
-                let p3 = iter.next()?;
-                Some([p0.index(), p1.index(), p2.index(), p3.index()])
-            })
-            .inspect(|opt| if opt.is_none() { error = true; })
-            .flatten()
-            .filter(|tet| tet.iter().all(|i| *i >= 4))
+            .tets
+            .values()
+            .filter(|tet| tet.vertices.iter().all(|v| *v >= 4))
             // Subtract 4 to ignore outer tetrahedron points
-            .map(|[p0, p1, p2, p3]| [p0 - 4, p1 - 4, p2 - 4, p3 - 4])
+            .map(|tet| {
+                let [p0, p1, p2, p3] = tet.vertices;
+                [p0 - 4, p1 - 4, p2 - 4, p3 - 4]
+            })
             .collect();
 
-        if error {
-            Err(TetError::VoronoiNot4Regular)?;
-        }
-
         Ok((positions, tets))
     }
 
     /// Obtain the edges of the tetrahedrons
     pub fn tetrahedron_edges<'a>(&'a self) -> impl Iterator<Item = (usize, usize)> + 'a {
-        self.tet_edges
-            .edge_indices()
-            .map(move |e| {
-                let (s, t) = self.tet_edges.edge_endpoints(e).unwrap();
-                (s.index(), t.index())
-            })
-            .filter(|(s, t)| *s >= 4 && *t >= 4)
-            .map(|(s, t)| (s - 4, t - 4))
+        self.edges
+            .keys()
+            .filter(|[s, _]| *s >= 4)
+            .map(|[s, t]| (s - 4, t - 4))
     }
 
     pub fn export_debug_obj<P: AsRef<Path>>(&self, path: P) {
         let mut output = String::from("o object\n");
 
-        for i in self.tet_edges.node_indices() {
-            let pos = self.tet_edges[i];
+        for pos in &self.points {
             output += &format!("v {} {} {}\n", pos.x, pos.y, pos.z);
         }
 
-        for e in self.tet_edges.edge_indices() {
-            let (s, t) = self.tet_edges.edge_endpoints(e).unwrap();
-            output += &format!("l {} {}\n", s.index() + 1, t.index() + 1);
+        for [s, t] in self.edges.keys() {
+            output += &format!("l {} {}\n", s + 1, t + 1);
         }
 
         std::fs::write(path, output).expect("Could not debug obj");
     }
 
+    /// Exports the Voronoi diagram, whose vertices are the circumcenters of the tets
+    /// and whose edges connect the circumcenters of adjacent tets.
     pub fn export_voronoi_debug_obj<P: AsRef<Path>>(&self, path: P) {
         let mut output = String::from("o object\n");
 
-        let graph: Graph<_, _> = self.voronoi.clone().into();
-
-        for i in self.voronoi.node_indices().skip(1) {
-            let pos = self.vertex_position(i).unwrap();
-            output += &format!("v {} {} {}\n", pos.x, pos.y, pos.z);
-        }
-        for i in self.tet_edges.node_indices() {
-            let pos = self.tet_edges[i];
-            output += &format!("v {} {} {}\n", pos.x, pos.y, pos.z);
+        let mut obj_indexes = FnvHashMap::default();
+        for (index, tet) in self.tets.iter() {
+            if let Some(pos) = Self::circumcenter(self.positions(tet.vertices)) {
+                obj_indexes.insert(index, obj_indexes.len() + 1);
+                output += &format!("v {} {} {}\n", pos.x, pos.y, pos.z);
+            }
         }
 
-        for e in graph.edge_indices() {
-            let (s, t) = graph.edge_endpoints(e).unwrap();
-            if t.index() != 0 {
-                output += &format!("l {} {}\n", s.index(), t.index());
+        for (index, tet) in self.tets.iter() {
+            for neighbor in &tet.neighbors {
+                if index < *neighbor {
+                    if let (Some(s), Some(t)) = (obj_indexes.get(&index), obj_indexes.get(neighbor)) {
+                        output += &format!("l {} {}\n", s, t);
+                    }
+                }
             }
         }
 
@@ -524,6 +411,8 @@ pub struct Tetrahedralization {
     /// Faces and adjacent tetrahedrons, for convenience. Face and tet vertices are sorted.
     faces: FnvHashMap<[usize; 3], FnvHashSet<usize>>,
     tets: StableVec<[usize; 4]>,
+    /// Materials of tets, assigned once the tets outside the boundary are removed.
+    materials: FnvHashMap<usize, MaterialID>,
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -533,9 +422,16 @@ enum EdgeFlex {
     Concave,
 }
 
-impl Tetrahedralization {
-    const EPSILON: f64 = 1e-5;
+/// Tets a flip removes, by index, and the sorted tets it adds instead
+#[derive(Clone, Debug)]
+struct Flip {
+    remove: Vec<usize>,
+    add: Vec<[usize; 4]>,
+}
 
+impl Tetrahedralization {
+    /// Constructs a tetrahedralization from tets that index into the positions.
+    /// Flat tets are kept so that there are no holes; `remove_slivers` can flip them away.
     pub fn new(positions: Vec<Vec3>, tetrahedrons: Vec<[usize; 4]>) -> Self {
         let mut vertices = positions
             .into_iter()
@@ -547,12 +443,6 @@ impl Tetrahedralization {
         let mut tets = StableVec::new();
 
         for mut tet in tetrahedrons.into_iter() {
-            // No sliver tets (hack)
-            let pos = tet.iter().map(|v| vertices[*v].0).collect::<Vec<_>>();
-            if (pos[1] - pos[0]).cross(pos[2] - pos[0]).dot(pos[3] - pos[0]).abs() < Self::EPSILON {
-                continue;
-            }
-
             tet.sort();
             let i = tets.push(tet);
 
@@ -575,9 +465,159 @@ impl Tetrahedralization {
             vertices,
             faces,
             tets,
+            materials: FnvHashMap::default(),
         }
     }
 
+    /// Material of a tet by index.
+    /// Tets that haven't been assigned a material get the default one.
+    pub fn material(&self, index: usize) -> MaterialID {
+        self.materials.get(&index).copied().unwrap_or_default()
+    }
+
+    /// The positions of vertices that are in some tet, and the tets with their materials.
+    /// Tets index into the positions and are positively oriented, so `orient3d` of them is positive,
+    /// except for the tets in `flat_tets`, for which it's 0.
+    pub fn tets_with_materials(&self) -> (Vec<Vec3>, Vec<([usize; 4], MaterialID)>) {
+        let mut index_map = FnvHashMap::default();
+        let mut positions = vec![];
+
+        let mut tets = vec![];
+        for (index, tet) in self.tets.iter() {
+            let mut new_tet = [0; 4];
+            for (i, v) in tet.iter().enumerate() {
+                new_tet[i] = *index_map.entry(*v).or_insert_with(|| {
+                    positions.push(self.vertices[*v].0);
+                    positions.len() - 1
+                });
+            }
+
+            let [p0, p1, p2, p3] = [
+                positions[new_tet[0]],
+                positions[new_tet[1]],
+                positions[new_tet[2]],
+                positions[new_tet[3]],
+            ];
+            if predicates::orient3d(p0, p1, p2, p3) < 0.0 {
+                new_tet.swap(2, 3);
+            }
+            tets.push((new_tet, self.material(index)));
+        }
+
+        (positions, tets)
+    }
+
+    fn tet_positions(&self, tet: [usize; 4]) -> [Vec3; 4] {
+        [
+            self.vertices[tet[0]].0,
+            self.vertices[tet[1]].0,
+            self.vertices[tet[2]].0,
+            self.vertices[tet[3]].0,
+        ]
+    }
+
+    /// Quality measures of a tet by index
+    pub fn tet_quality(&self, index: usize) -> TetQuality {
+        TetQuality::new(self.tet_positions(self.tets[index]))
+    }
+
+    /// Indexes of tets with no volume, which `remove_slivers` couldn't flip away
+    pub fn flat_tets(&self) -> Vec<usize> {
+        self.tets
+            .iter()
+            .filter(|(_, tet)| {
+                let [p0, p1, p2, p3] = self.tet_positions(**tet);
+                predicates::orient3d(p0, p1, p2, p3) == 0.0
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Quality measures of all tets, with their indexes
+    pub fn qualities<'a>(&'a self) -> impl Iterator<Item = (usize, TetQuality)> + 'a {
+        self.tets.indices().map(move |i| (i, self.tet_quality(i)))
+    }
+
+    /// Histogram of a quality measure over all tets, such as
+    /// `tets.quality_histogram(|q| q.min_dihedral_angle.to_degrees(), 0.0, 90.0, 18)`.
+    /// None if there are no bins or the range isn't finite and nonempty, like `Histogram::new`.
+    pub fn quality_histogram(
+        &self,
+        measure: impl Fn(&TetQuality) -> f64,
+        min: f64,
+        max: f64,
+        bins: usize,
+    ) -> Option<Histogram> {
+        let mut histogram = Histogram::new(min, max, bins)?;
+        histogram.extend(self.qualities().map(|(_, quality)| measure(&quality)));
+        Some(histogram)
+    }
+
+    /// Flips away slivers, which are tets with a dihedral angle smaller than `min_dihedral_angle` (in radians),
+    /// including flat tets. A face of a sliver is flipped (2-3, 3-2, or 4-4)
+    /// if that makes the smallest dihedral angle of the tets involved bigger.
+    /// Only tets of the same material are flipped together, so faces between materials stay.
+    /// Some slivers can't be flipped away and are left alone.
+    /// Returns the number of flips done.
+    pub fn remove_slivers(&mut self, min_dihedral_angle: f64) -> usize {
+        let mut to_search = self
+            .qualities()
+            .filter(|(_, quality)| quality.min_dihedral_angle < min_dihedral_angle)
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        let mut flips = 0;
+
+        while let Some(index) = to_search.pop() {
+            if self.tets.get(index).is_none() || self.tet_quality(index).min_dihedral_angle >= min_dihedral_angle {
+                continue;
+            }
+            let material = self.materials.get(&index).copied();
+
+            for face in Self::tet_faces(self.tets[index]) {
+                // Flips involve tets that share an edge of the face
+                let region = face
+                    .iter()
+                    .flat_map(|v| self.vertices[*v].1.iter().copied())
+                    .filter(|i| self.materials.get(i).copied() == material)
+                    .collect::<FnvHashSet<_>>();
+
+                let flip = match self.plan_flip(face, &region) {
+                    Ok(Some(flip)) => flip,
+                    _ => continue,
+                };
+
+                let old = flip.remove.iter().map(|i| self.tet_quality(*i)).collect::<Vec<_>>();
+                let new = flip.add.iter().map(|tet| TetQuality::new(self.tet_positions(*tet))).collect::<Vec<_>>();
+                let old_min = old.iter().map(|q| q.min_dihedral_angle).fold(f64::INFINITY, f64::min);
+                let new_min = new.iter().map(|q| q.min_dihedral_angle).fold(f64::INFINITY, f64::min);
+
+                // The new tets would overlap if the volume changes
+                let old_volume = old.iter().map(|q| q.volume).sum::<f64>();
+                let new_volume = new.iter().map(|q| q.volume).sum::<f64>();
+                if new_min <= old_min || (new_volume - old_volume).abs() > old_volume * 1e-9 {
+                    continue;
+                }
+
+                for i in flip.remove {
+                    self.remove_tet(i);
+                }
+                for tet in flip.add {
+                    let i = self.add_tet(tet);
+                    if let Some(material) = material {
+                        self.materials.insert(i, material);
+                    }
+                    if self.tet_quality(i).min_dihedral_angle < min_dihedral_angle {
+                        to_search.push(i);
+                    }
+                }
+                flips += 1;
+                break;
+            }
+        }
+
+        flips
+    }
+
     /// Assumes tet is sorted, faces returned are sorted
     fn tet_faces_and_opposite(tet: [usize; 4]) -> impl Iterator<Item = ([usize; 3], usize)> {
         (0..4).map(move |i| {
@@ -746,6 +786,7 @@ impl Tetrahedralization {
     /// Removes a tet by index, assuming it exists.
     fn remove_tet(&mut self, index: usize) {
         let tet = self.tets.remove(index).unwrap();
+        self.materials.remove(&index);
 
         for v in tet.iter() {
             self.vertices[*v].1.remove(&index);
@@ -764,28 +805,24 @@ impl Tetrahedralization {
     /// Assumes edge is sorted
     fn edge_flex(&self, edge: [usize; 2], v_e: usize, v_f1: usize, v_f2: usize) -> EdgeFlex {
         let pos_e1 = self.vertices[edge[0]].0;
-        let pos_e = self.vertices[edge[1]].0 - pos_e1;
-        let pos_v = self.vertices[v_e].0 - pos_e1;
-        let pos_f1 = self.vertices[v_f1].0 - pos_e1;
-        let pos_f2 = self.vertices[v_f2].0 - pos_e1;
-
-        // Obtain normals of faces adjacent to edge
-        let mut normal1 = pos_e.cross(pos_f1).normalize();
-        let mut normal2 = pos_f2.cross(pos_e).normalize();
-
-        // Point them in the right direction, away from v_e
-        if normal1.dot(pos_v) > 0.0 {
-            normal1 *= -1.0;
-            normal2 *= -1.0;
+        let pos_e2 = self.vertices[edge[1]].0;
+        let pos_v = self.vertices[v_e].0;
+        let mut pos_f1 = self.vertices[v_f1].0;
+        let mut pos_f2 = self.vertices[v_f2].0;
+
+        // The edge is convex if v_f2 is on the same side of the plane [e1, e2, v_f1] as v_e.
+        // If v_f1 is in the plane of the face, as in a flat tet, that plane is the face's,
+        // so use the plane through v_f2 instead.
+        let mut side_v = predicates::orient3d(pos_e1, pos_e2, pos_f1, pos_v);
+        if side_v == 0.0 {
+            std::mem::swap(&mut pos_f1, &mut pos_f2);
+            side_v = predicates::orient3d(pos_e1, pos_e2, pos_f1, pos_v);
         }
+        let side_f2 = predicates::orient3d(pos_e1, pos_e2, pos_f1, pos_f2);
 
-        // Normalize normal in case exterior dihedral angle is close to 0° or 360°
-        let normal = (normal1 + normal2).normalize();
-        let dot = normal.dot(pos_f1);
-
-        if dot.abs() < Self::EPSILON {
+        if side_f2 == 0.0 {
             EdgeFlex::Flat
-        } else if dot < 0.0 {
+        } else if side_f2.signum() == side_v.signum() {
             EdgeFlex::Convex
         } else {
             EdgeFlex::Concave
@@ -815,12 +852,11 @@ impl Tetrahedralization {
         let mut boundary = FnvHashSet::default();
         for index in tet_indexes {
             for (mut face, vertex) in Self::tet_faces_and_opposite(self.tets[*index]) {
-                let base = self.vertices[face[0]].0;
-                let pos1 = self.vertices[face[1]].0 - base;
-                let pos2 = self.vertices[face[2]].0 - base;
-                let pos3 = self.vertices[vertex].0 - base;
+                let pos0 = self.vertices[face[0]].0;
+                let pos1 = self.vertices[face[1]].0;
+                let pos2 = self.vertices[face[2]].0;
 
-                if pos1.cross(pos2).dot(pos3) > 0.0 {
+                if predicates::orient3d(pos0, pos1, pos2, self.vertices[vertex].0) > 0.0 {
                     let tmp = face[1];
                     face[1] = face[2];
                     face[2] = tmp;
@@ -836,7 +872,7 @@ impl Tetrahedralization {
     }
 
 
-    pub fn flip(&mut self, mut face: [usize; 3]) -> Vec<usize> {
+    pub fn flip(&mut self, face: [usize; 3]) -> Result<Vec<usize>, TetError> {
         let mut region = self.tets.indices().collect();
         self.flip_in_region(face, &mut region)
     }
@@ -845,15 +881,43 @@ impl Tetrahedralization {
     /// for inserting constraining faces into a tetrahedralization
     /// via flips.
     /// Returns the tet indexes that got added, or an empty vec if the flip didn't happen
-    pub fn flip_in_region(&mut self, mut face: [usize; 3], region: &mut FnvHashSet<usize>) -> Vec<usize> {
+    pub fn flip_in_region(
+        &mut self,
+        mut face: [usize; 3],
+        region: &mut FnvHashSet<usize>,
+    ) -> Result<Vec<usize>, TetError> {
         face.sort();
 
+        let flip = match self.plan_flip(face, region)? {
+            Some(flip) => flip,
+            None => return Ok(vec![]),
+        };
+
+        for i in flip.remove {
+            self.remove_tet(i);
+            region.remove(&i);
+        }
+
+        let mut new_tets = vec![];
+        for tet in flip.add {
+            new_tets.push(self.add_tet(tet));
+            region.insert(*new_tets.last().unwrap());
+        }
+        Ok(new_tets)
+    }
+
+    /// Figures out which tets flipping away a face would remove and add, without flipping.
+    /// Returns None if the flip can't happen, either because the tets around the face aren't convex
+    /// or because a tet that would have to be removed isn't in the region.
+    /// Fails if the tets around the face aren't in any configuration a flip handles.
+    /// Assumes face is sorted.
+    fn plan_flip(&self, face: [usize; 3], region: &FnvHashSet<usize>) -> Result<Option<Flip>, TetError> {
         let mut iter = self.face_tet_indexes_and_tets(face);
         let s = iter.next();
         // Obtain adjacent tetrahedrons
         let ((s, s_tet), (t, t_tet)) = match (s, iter.next()) {
             (Some((s, s_tet)), Some((t, t_tet))) => ((s, s_tet), (t, t_tet)),
-            _ => return vec![],
+            _ => return Ok(None),
         };
         std::mem::drop(iter);
 
@@ -873,8 +937,6 @@ impl Tetrahedralization {
             }
         }
 
-        let mut new_tets = vec![];
-
         let v_change = v_remove
             .iter()
             .chain(v_add.iter())
@@ -908,6 +970,11 @@ impl Tetrahedralization {
         match v_change.len() {
             5 => {
                 // Tetrahedron replacement (general)
+                if x_add.len() == 1 {
+                    // 4-1 flip, which only comes up around flat tets and would lose a vertex
+                    return Ok(None);
+                }
+
                 let x_remove = x_remove
                     .into_iter()
                     .map(|tet| self.tet_index([tet[0], tet[1], tet[2], tet[3]]))
@@ -915,16 +982,13 @@ impl Tetrahedralization {
 
                 if x_remove.iter().any(|i| i.map(|i| !region.contains(&i)).unwrap_or(true)) {
                     // Concave angle. Do not flip.
-                    return vec![];
-                }
-                for i in x_remove.into_iter().flatten() {
-                    self.remove_tet(i);
-                    region.remove(&i);
-                }
-                for tet in x_add {
-                    new_tets.push(self.add_tet([tet[0], tet[1], tet[2], tet[3]]));
-                    region.insert(*new_tets.last().unwrap());
+                    return Ok(None);
                 }
+
+                Ok(Some(Flip {
+                    remove: x_remove.into_iter().flatten().collect(),
+                    add: x_add.into_iter().map(|tet| [tet[0], tet[1], tet[2], tet[3]]).collect(),
+                }))
             }
 
             4 => {
@@ -948,12 +1012,7 @@ impl Tetrahedralization {
 
                 if x_remove.iter().any(|i| i.map(|i| !region.contains(&i)).unwrap_or(true)) {
                     // Concave angle or 4th-party tet. Do not flip.
-                    return vec![];
-                }
-
-                for i in x_remove.into_iter().flatten() {
-                    self.remove_tet(i);
-                    region.remove(&i);
+                    return Ok(None);
                 }
 
                 let x_add = x_add.into_iter().flat_map(|face| {
@@ -964,10 +1023,10 @@ impl Tetrahedralization {
                     })
                 });
 
-                for tet in x_add {
-                    new_tets.push(self.add_tet(tet));
-                    region.insert(*new_tets.last().unwrap());
-                }
+                Ok(Some(Flip {
+                    remove: x_remove.into_iter().flatten().collect(),
+                    add: x_add.collect(),
+                }))
             }
 
             3 => {
@@ -991,12 +1050,7 @@ impl Tetrahedralization {
 
                 if x_remove.iter().any(|i| i.map(|i| !region.contains(&i)).unwrap_or(true)) {
                     // 3rd-party tet. Do not flip.
-                    return vec![];
-                }
-
-                for i in x_remove.into_iter().flatten() {
-                    self.remove_tet(i);
-                    region.remove(&i);
+                    return Ok(None);
                 }
 
                 let x_add = x_add.into_iter().flat_map(|edge| {
@@ -1007,16 +1061,14 @@ impl Tetrahedralization {
                     })
                 });
 
-                for tet in x_add {
-                    new_tets.push(self.add_tet(tet));
-                    region.insert(*new_tets.last().unwrap());
-                }
+                Ok(Some(Flip {
+                    remove: x_remove.into_iter().flatten().collect(),
+                    add: x_add.collect(),
+                }))
             }
 
-            _ => panic!("Unexpected number of vertices involved: {}", v_change.len()),
+            _ => Err(TetError::UnexpectedFlipConfiguration),
         }
-
-        new_tets
     }
 
     fn find_first_intersecting_edge(
@@ -1072,7 +1124,8 @@ impl Tetrahedralization {
                     }
 
                     // Check if tetrahedron overlaps face
-                    if (normal.dot(o0_pos) >= 0.0) != (normal.dot(o1_pos) >= 0.0) {
+                    let side = |v: usize| predicates::plane_side(point, normal, self.vertices[v].0) >= 0.0;
+                    if side(oppose[0]) != side(oppose[1]) {
                         edges_to_search.push([oppose[0], oppose[1]].sorted());
                         inner_tets.insert(index);
                         inner_faces.insert([oppose[0], oppose[1], edge[0]].sorted());
@@ -1097,6 +1150,8 @@ impl Tetrahedralization {
         face_edges: &mut FnvHashSet<[usize; 2]>,
         vertex_set: &FnvHashSet<usize>,
     ) {
+        let side = |v: usize| predicates::plane_side(point, normal, self.vertices[v].0) >= 0.0;
+
         // Find the rest of the inner tets.
         // This edge is sorted.
         while let Some(edge) = edges_to_search.pop() {
@@ -1135,18 +1190,15 @@ impl Tetrahedralization {
                     } else {
                         0
                     };
-                    let other = if (normal.dot(if i == 0 { o0_pos } else { o1_pos }) >= 0.0)
-                        == (normal.dot(e0_pos) >= 0.0)
-                    {
+                    let other = if side(oppose[i]) == side(edge[0]) {
                         edge[1]
                     } else {
                         edge[0]
                     };
                     edges_to_search.push([oppose[i], other].sorted());
-                } else if (normal.dot(o0_pos) >= 0.0) != (normal.dot(o1_pos) >= 0.0) {
+                } else if side(oppose[0]) != side(oppose[1]) {
                     // Opposite edge intersects face
-                    let other_index = if (normal.dot(o0_pos) >= 0.0) == (normal.dot(e0_pos) >= 0.0)
-                    {
+                    let other_index = if side(oppose[0]) == side(edge[0]) {
                         1
                     } else {
                         0
@@ -1156,7 +1208,7 @@ impl Tetrahedralization {
                     edges_to_search.push([oppose[1], edge[1 - other_index]].sorted());
                 } else {
                     // Opposite edge is on one side of face
-                    let other = if (normal.dot(o0_pos) >= 0.0) == (normal.dot(e0_pos) >= 0.0) {
+                    let other = if side(oppose[0]) == side(edge[0]) {
                         edge[1]
                     } else {
                         edge[0]
@@ -1291,7 +1343,7 @@ impl Tetrahedralization {
                 //    println!("opps: {:?}, pos: {:?}", opposite, [self.vertices[opposite[0]].0, self.vertices[opposite[1]].0]);
                 //    self.export_debug_obj("assets/debug/tet_test_before_flip_248_260_634.obj");
                 //}
-                let added_tets = self.flip_in_region(face, &mut inner_tets);
+                let added_tets = self.flip_in_region(face, &mut inner_tets)?;
 
                 for face in self.boundary(&added_tets) {
                     //if iteration == 2 {
@@ -1303,7 +1355,7 @@ impl Tetrahedralization {
                     // Check if boundary face intersects plc face
                     let dots = face.iter()
                         .filter(|v| !vertex_set.contains(*v)) // these don't count
-                        .map(|v| normal.dot(self.vertices[*v].0 - point));
+                        .map(|v| predicates::plane_side(point, normal, self.vertices[*v].0));
                     let dots2 = dots.clone();
 
                     if dots.zip(dots2.skip(1)).any(|(d1, d2)| (d1 >= 0.0) != (d2 >= 0.0)) {
@@ -1328,25 +1380,12 @@ impl Tetrahedralization {
     ) -> Result<(), TetError> {
         let mut edge_set = edges.iter().copied().collect::<FnvHashSet<_>>();
 
-        //if (normal - vec3(0.0, 0.0, -1.0)).magnitude() < Self::EPSILON &&
-        //    (self.vertices[edges[0][0]].0.z + 0.3).abs() < Self::EPSILON {
-        //    println!("{}", self.vertices[edges[0][0]].0.y);
-        //}
-        let mut i = 0;
-        //if (normal - vec3(0.0, 0.0, 1.0)).magnitude() < Self::EPSILON &&
-        //    self.vertices[edges[0][0]].0.y >= 0.84 && self.vertices[edges[0][0]].0.y <= 1.36 &&
-        //    (self.vertices[edges[0][0]].0.z - 0.3).abs() < Self::EPSILON {
-        //    println!("Preserving...");
-        //    self.export_debug_obj("assets/debug/tet_test_before_flip_x_0.85_0.3.obj");
-        //    i = 1;
-        //}
-
         while !edge_set.is_empty() {
             //println!("Edge set: {:?}", edge_set);
             //for edge in &edge_set {
             //    println!("{:?}: {:?}", edge, [self.vertices[edge[0]].0, self.vertices[edge[1]].0]);
             //}
-            let edges = self.recover_plc_face_piece(&edge_set, normal, i)?;
+            let edges = self.recover_plc_face_piece(&edge_set, normal, 0)?;
             if edges.is_empty() {
                 Err(TetError::NoTetOnFaceEdge)?;
             }
@@ -1356,9 +1395,6 @@ impl Tetrahedralization {
                     edge_set.insert([edge[1], edge[0]]);
                 }
             }
-            if i > 0 {
-                i += 1;
-            }
         }
 
         Ok(())
@@ -1384,12 +1420,12 @@ impl Tetrahedralization {
 
             // Get triangle inside PLC face
             let vertex = self.opposite_vertices_of_edge(edge.sorted()).find(|vertex| {
-                let base = self.vertices[edge[0]].0;
-                let p1 = self.vertices[edge[1]].0 - base;
-                let p2 = self.vertices[*vertex].0 - base;
+                let p0 = self.vertices[edge[0]].0;
+                let p1 = self.vertices[edge[1]].0;
+                let p2 = self.vertices[*vertex].0;
 
                 // Triangle must be inside, winding counterclockwise
-                vertex_set.contains(vertex) && p1.cross(p2).dot(normal) > 0.0
+                vertex_set.contains(vertex) && predicates::orient_around(p0, p1, p2, normal) > 0.0
             }).ok_or(TetError::FaceNotRecovered)?;
 
             triangles.push([edge[0], edge[1], vertex]);
@@ -1403,27 +1439,29 @@ impl Tetrahedralization {
         Ok(triangles)
     }
 
-    /// Removes tets that are outside a boundary delimited by faces.
+    /// Removes tets that are outside a boundary delimited by faces,
+    /// and assigns each remaining tet the material of the boundary faces around its region.
     /// Assumes the boundary is a manifold and covered by tets.
-    pub fn remove_tets_outside_boundary(&mut self, boundary: &[[usize; 3]]) {
+    pub fn remove_tets_outside_boundary(&mut self, boundary: &[([usize; 3], MaterialID)]) -> Result<(), TetError> {
         let mut tets_to_search = vec![];
         let mut is_inside = StableVec::with_capacity(self.tets.next_push_index());
         
         // Find assignment for a tet to start things off
-        let face = boundary[0];
-        let (index, tet) = self.face_tet_indexes_and_tets(face.sorted()).next().unwrap();
-        let vertex = Self::opposite_vertex_of_face(tet, face.sorted());
-
-        let base = self.vertices[face[0]].0;
-        let p1 = self.vertices[face[1]].0 - base;
-        let p2 = self.vertices[face[2]].0 - base;
-        let p3 = self.vertices[vertex].0 - base;
-
-        is_inside.insert(index, p1.cross(p2).dot(p3) < 0.0);
+        let (face, _) = *boundary.first().ok_or(TetError::EmptyBoundary)?;
+        let (index, tet) = self
+            .face_tet_indexes_and_tets(face.sorted())
+            .next()
+            .ok_or(TetError::BoundaryFaceNotInTets)?;
+        is_inside.insert(index, self.is_behind_face(face, tet));
         tets_to_search.push(index);
 
-        // each face is sorted
-        let c_boundary = boundary.iter().copied().map(ArrayEx::sorted).collect::<FnvHashSet<_>>();
+        // each face is sorted.
+        // A face shared by 2 materials appears twice and doesn't separate inside from outside.
+        let mut face_counts = FnvHashMap::<[usize; 3], usize>::default();
+        for (face, _) in boundary {
+            *face_counts.entry(face.sorted()).or_insert(0) += 1;
+        }
+        let c_boundary = face_counts.keys().copied().collect::<FnvHashSet<_>>();
 
         // Expand assignment to all tets
         while let Some(index) = tets_to_search.pop() {
@@ -1435,7 +1473,8 @@ impl Tetrahedralization {
                     .filter(|(_, other)| tet != *other)
                     .next()
                 {
-                    if is_inside.insert(adj_index, inside != c_boundary.contains(&face)).is_none() {
+                    let crosses = face_counts.get(&face).map(|count| count % 2 == 1).unwrap_or(false);
+                    if is_inside.insert(adj_index, inside != crosses).is_none() {
                         tets_to_search.push(adj_index);
                     }
                 }
@@ -1447,6 +1486,59 @@ impl Tetrahedralization {
                 self.remove_tet(index);
             }
         }
+
+        self.assign_materials(boundary, &c_boundary);
+        Ok(())
+    }
+
+    /// Whether a tet is on the back side of a face, i.e. the side the face's winding points away from.
+    /// The face is oriented, the tet is sorted, and the tet must contain the face.
+    fn is_behind_face(&self, face: [usize; 3], tet: [usize; 4]) -> bool {
+        let vertex = Self::opposite_vertex_of_face(tet, face.sorted());
+
+        let p0 = self.vertices[face[0]].0;
+        let p1 = self.vertices[face[1]].0;
+        let p2 = self.vertices[face[2]].0;
+        let p3 = self.vertices[vertex].0;
+
+        predicates::orient3d(p0, p1, p2, p3) < 0.0
+    }
+
+    /// Floods the material of each boundary face into the region of tets behind it.
+    /// Regions are separated by the boundary, whose faces are sorted in `c_boundary`.
+    fn assign_materials(&mut self, boundary: &[([usize; 3], MaterialID)], c_boundary: &FnvHashSet<[usize; 3]>) {
+        self.materials.clear();
+
+        for (face, material) in boundary {
+            let start = self.face_tet_indexes_and_tets(face.sorted())
+                .find(|(_, tet)| self.is_behind_face(*face, *tet))
+                .map(|(index, _)| index);
+
+            let mut tets_to_search = match start {
+                Some(index) if !self.materials.contains_key(&index) => vec![index],
+                _ => continue,
+            };
+            self.materials.insert(tets_to_search[0], *material);
+
+            while let Some(index) = tets_to_search.pop() {
+                for face in Self::tet_faces(self.tets[index]) {
+                    if c_boundary.contains(&face) {
+                        continue;
+                    }
+
+                    let adjacent = self.face_tet_indexes_and_tets(face)
+                        .map(|(adj_index, _)| adj_index)
+                        .collect::<Vec<_>>();
+
+                    for adj_index in adjacent {
+                        if !self.materials.contains_key(&adj_index) {
+                            self.materials.insert(adj_index, *material);
+                            tets_to_search.push(adj_index);
+                        }
+                    }
+                }
+            }
+        }
     }
 
     fn is_boundary_convex(&self, boundary: &[[usize; 3]]) -> bool {
@@ -1460,16 +1552,29 @@ impl Tetrahedralization {
 
         // Check bend of edge
         edges.iter().all(|(edge, opps)| {
-            let base = self.vertices[edge[0]].0;
-            let pos1 = self.vertices[edge[1]].0 - base;
-            let pos2 = self.vertices[*opps].0 - base;
-            let pos3 = self.vertices[edges[&[edge[1], edge[0]]]].0 - base;
+            let pos0 = self.vertices[edge[0]].0;
+            let pos1 = self.vertices[edge[1]].0;
+            let pos2 = self.vertices[*opps].0;
+            let pos3 = self.vertices[edges[&[edge[1], edge[0]]]].0;
 
-            pos1.cross(pos2).dot(pos3) < Self::EPSILON
+            predicates::orient3d(pos0, pos1, pos2, pos3) <= 0.0
         })
     }
 
-    pub fn convex_hulls(&self) -> Vec<Vec<Vec3>> {
+    /// Whether all the tets given have the same material.
+    fn same_material<'a>(&self, mut tets: impl Iterator<Item = &'a usize>) -> bool {
+        match tets.next() {
+            Some(first) => {
+                let material = self.material(*first);
+                tets.all(|index| self.material(*index) == material)
+            }
+            None => true,
+        }
+    }
+
+    /// Merges tets into convex hulls, never merging tets of different materials.
+    /// Returns the vertices of each hull along with its material.
+    pub fn convex_hulls(&self) -> Vec<(Vec<Vec3>, MaterialID)> {
         let mut hull_find = UnionFind::new(self.tets.next_push_index());
 
         let expand_fn = |tets: &Tetrahedralization, hull: &mut FnvHashSet<usize>, hull_find: &UnionFind<usize>| {
@@ -1497,6 +1602,7 @@ impl Tetrahedralization {
 
             // Do NOT delete boundary vertices
             if !boundary.iter().any(|face| face.contains(&vertex)) &&
+                self.same_material(tets.iter()) &&
                 self.is_boundary_convex(&boundary)
             {
                 for (t1, t2) in tets.iter().zip(tets.iter().skip(1)) {
@@ -1515,6 +1621,7 @@ impl Tetrahedralization {
 
             // To contain an edge, the boundary has to contain a face which contains the edge
             if !boundary.iter().any(|face| Self::face_edges(face.sorted()).any(|e| e == edge)) &&
+                self.same_material(tets.iter()) &&
                 self.is_boundary_convex(&boundary)
             {
                 for (t1, t2) in tets.iter().zip(tets.iter().skip(1)) {
@@ -1532,7 +1639,7 @@ impl Tetrahedralization {
                 .collect::<Vec<_>>();
 
             // No boundary faces. Each face must have 2 tets adjacent.
-            if tets.len() == 2 && self.is_boundary_convex(&boundary) {
+            if tets.len() == 2 && self.same_material(tets.iter()) && self.is_boundary_convex(&boundary) {
                 for (t1, t2) in tets.iter().zip(tets.iter().skip(1)) {
                     hull_find.union(*t1, *t2);
                 }
@@ -1547,10 +1654,11 @@ impl Tetrahedralization {
         }
 
         hulls.into_iter().map(|(_, hull)| {
-            self.boundary(&hull)
+            let vertices = self.boundary(&hull)
                 .flat_map(|face| vec![face[0], face[1], face[2]].into_iter())
                 .map(|v| self.vertices[v].0)
-                .collect()
+                .collect();
+            (vertices, self.material(hull[0]))
         }).collect()
     }
 
@@ -1671,42 +1779,10 @@ mod test {
     use super::*;
     use fnv::FnvHashSet;
     use petgraph::algo;
-    use petgraph::data::{Element, FromElements};
+    use petgraph::prelude::*;
     use std::collections::BTreeSet;
     use std::hash::Hash;
 
-    fn create_graph<N, I, E>(
-        vertices: Vec<N>,
-        edges: Vec<(usize, usize, I)>,
-        mut edge_fn: impl FnMut(I) -> E,
-    ) -> StableGraph<N, E> {
-        StableGraph::from_elements(
-            vertices
-                .into_iter()
-                .map(|vertex| Element::Node { weight: vertex })
-                .chain(edges.into_iter().map(|(s, t, e)| Element::Edge {
-                    source: s,
-                    target: t,
-                    weight: edge_fn(e),
-                })),
-        )
-    }
-
-    fn graph_edges<'a, N, E: Clone>(
-        graph: &'a StableGraph<N, E>,
-    ) -> impl Iterator<Item = (NodeIndex, NodeIndex, E)> + 'a {
-        graph.edge_indices().map(move |i| {
-            let (s, t) = graph.edge_endpoints(i).unwrap();
-            (s, t, graph[i].clone())
-        })
-    }
-
-    fn point_vertices(vec: Vec<Vec<usize>>) -> Vec<FnvHashSet<NodeIndex>> {
-        vec.into_iter()
-            .map(|v| v.into_iter().map(NodeIndex::new).collect())
-            .collect()
-    }
-
     fn is_point_in_tetrahedron(point: Vec3, tet: [Vec3; 4]) -> bool {
         let mtx = Mat4::from_cols(
             tet[0].extend(1.0),
@@ -1734,27 +1810,16 @@ mod test {
                 (0..2).flat_map(move |y| (0..2).map(move |x| vec3(x as f64, y as f64, z as f64)))
             })
             .collect::<Vec<_>>();
-        let exp_voronoi = create_graph(
-            vec![(), ()],
-            vec![(1, 0, 0), (1, 0, 1), (1, 0, 2), (1, 0, 3)],
-            NodeIndex::<u32>::new,
-        );
-        let exp_point_vertices = point_vertices(vec![vec![1], vec![1], vec![1], vec![1]]);
 
-        let tet = DelaunayTetrahedralization::init(points.clone());
-        assert_eq!(tet.voronoi.node_count(), exp_voronoi.node_count());
-        assert_eq!(
-            graph_edges(&tet.voronoi).collect::<FnvHashSet<_>>(),
-            graph_edges(&exp_voronoi).collect::<FnvHashSet<_>>()
-        );
-        assert_eq!(tet.point_vertices, exp_point_vertices);
+        let tet = DelaunayTetrahedralization::init(&points);
+        assert_eq!(tet.tets.num_elements(), 1);
+        assert_eq!(tet.edges.len(), 6);
 
-        let one_tet = (0..4)
-            .map(|i| tet.tet_edges[NodeIndex::new(i)])
-            .collect::<Vec<_>>();
-        let one_tet = [one_tet[0], one_tet[1], one_tet[2], one_tet[3]];
+        let one_tet = tet.tets[tet.last_tet];
+        assert_eq!(one_tet.neighbors, [NO_TET; 4]);
         for point in points {
-            assert!(is_point_in_tetrahedron(point, one_tet));
+            assert!(is_point_in_tetrahedron(point, tet.positions(one_tet.vertices)));
+            assert_eq!(tet.locate(point), Ok(tet.last_tet));
         }
     }
 
@@ -1820,6 +1885,55 @@ mod test {
         assert_eq!(tets, exp_tets);
     }
 
+    #[test]
+    fn test_tetrahedralize_add_point() {
+        let mut dt = DelaunayTetrahedralization::new(vec![
+            vec3(0.0, 0.0, 0.0),
+            vec3(1.0, 0.0, 0.0),
+            vec3(0.0, 1.0, 0.0),
+            vec3(0.0, 0.0, 1.0),
+        ]).unwrap();
+
+        let (removed, mut added) = dt.add_point(vec3(0.2, 0.2, 0.2)).unwrap();
+        added.sort();
+        assert_eq!(removed, vec![]);
+        assert_eq!(added, vec![(0, 4), (1, 4), (2, 4), (3, 4)]);
+        assert_eq!(dt.tetrahedron_edges().count(), 10);
+        assert_eq!(dt.tetrahedrons().unwrap().1.len(), 4);
+        assert_eq!(dt.add_point(vec3(0.2, 0.2, 0.2)), Err(TetError::DuplicatePoint));
+
+        // Neighbors point back at each other and share a face
+        for (index, tet) in dt.tets.iter() {
+            for (i, neighbor) in tet.neighbors.iter().enumerate() {
+                if *neighbor != NO_TET {
+                    let other = dt.tets[*neighbor];
+                    assert!(other.neighbors.contains(&index));
+                    let shared = tet.vertices.iter().filter(|v| other.vertices.contains(v)).count();
+                    assert_eq!(shared, 3);
+                    assert!(!other.vertices.contains(&tet.vertices[i]));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_tetrahedralize_cospherical() {
+        // Corners of a cube, which all lie on one sphere
+        let points = (0..8)
+            .map(|i| vec3((i & 1) as f64, ((i >> 1) & 1) as f64, (i >> 2) as f64) * 0.1 + vec3(-23.05, 1.35, 0.3))
+            .collect::<Vec<_>>();
+        let (positions, tets) = DelaunayTetrahedralization::new(points).unwrap().tetrahedrons().unwrap();
+
+        let volume = tets
+            .iter()
+            .map(|tet| {
+                let [p0, p1, p2, p3] = [positions[tet[0]], positions[tet[1]], positions[tet[2]], positions[tet[3]]];
+                (p1 - p0).cross(p2 - p0).dot(p3 - p0).abs() / 6.0
+            })
+            .sum::<f64>();
+        assert!((volume - 0.001).abs() < 1e-9);
+    }
+
     //#[test]
     //fn export_debug_obj() {
     //    let points = vec![
@@ -1890,6 +2004,7 @@ mod test {
             vertices,
             faces,
             tets,
+            materials: FnvHashMap::default(),
         }
     }
 
@@ -2106,7 +2221,7 @@ mod test {
         ];
         let tets = vec![[0, 1, 2, 3], [0, 1, 2, 4]];
         let mut tet = Tetrahedralization::new(positions.clone(), tets);
-        tet.flip([0, 1, 2]);
+        tet.flip([0, 1, 2]).unwrap();
         tet.canonicalize();
 
         let exp_tets = vec![[0, 1, 3, 4], [1, 2, 3, 4], [0, 2, 3, 4]];
@@ -2128,7 +2243,7 @@ mod test {
         ];
         let tets = vec![[0, 1, 3, 4], [1, 2, 3, 4], [0, 2, 3, 4]];
         let mut tet = Tetrahedralization::new(positions.clone(), tets);
-        tet.flip([0, 3, 4]);
+        tet.flip([0, 3, 4]).unwrap();
         tet.canonicalize();
 
         let exp_tets = vec![[0, 1, 2, 3], [0, 1, 2, 4]];
@@ -2151,7 +2266,7 @@ mod test {
         let tets = vec![[0, 1, 3, 4], [0, 2, 3, 4]];
         let mut tet = Tetrahedralization::new(positions.clone(), tets);
         let clone = tet.clone();
-        assert_eq!(tet.flip([0, 3, 4]), vec![]);
+        assert_eq!(tet.flip([0, 3, 4]).unwrap(), vec![]);
         assert_eq!(tet, clone);
     }
 
@@ -2167,7 +2282,7 @@ mod test {
         ];
         let tets = vec![[0, 1, 2, 4], [0, 1, 3, 4], [0, 2, 3, 4], [1, 2, 3, 4]];
         let mut tet = Tetrahedralization::new(positions.clone(), tets);
-        tet.flip([0, 1, 4]);
+        tet.flip([0, 1, 4]).unwrap();
         tet.canonicalize();
 
         let exp_tets = vec![[0, 1, 2, 3]];
@@ -2190,7 +2305,7 @@ mod test {
         let tets = vec![[0, 1, 2, 4], [0, 1, 3, 4], [1, 2, 3, 4]];
         let mut tet = Tetrahedralization::new(positions.clone(), tets);
         let clone = tet.clone();
-        assert_eq!(tet.flip([0, 1, 4]), vec![]);
+        assert_eq!(tet.flip([0, 1, 4]).unwrap(), vec![]);
         assert_eq!(tet, clone);
     }
 
@@ -2206,7 +2321,7 @@ mod test {
         ];
         let tets = vec![[0, 1, 2, 3], [0, 1, 2, 4]];
         let mut tet = Tetrahedralization::new(positions.clone(), tets);
-        tet.flip([0, 1, 2]);
+        tet.flip([0, 1, 2]).unwrap();
         tet.canonicalize();
 
         let exp_tets = vec![[0, 1, 3, 4], [0, 2, 3, 4]];
@@ -2230,7 +2345,7 @@ mod test {
         let tets = vec![[0, 1, 2, 3], [0, 1, 2, 4], [1, 2, 4, 5]];
         let mut tet = Tetrahedralization::new(positions.clone(), tets);
         let clone = tet.clone();
-        assert_eq!(tet.flip([0, 1, 2]), vec![]);
+        assert_eq!(tet.flip([0, 1, 2]).unwrap(), vec![]);
         assert_eq!(tet, clone);
     }
 
@@ -2246,7 +2361,7 @@ mod test {
         ];
         let tets = vec![[0, 1, 2, 3], [0, 1, 2, 4], [0, 2, 3, 4]];
         let mut tet = Tetrahedralization::new(positions.clone(), tets);
-        tet.flip([0, 1, 2]);
+        tet.flip([0, 1, 2]).unwrap();
         tet.canonicalize();
 
         let exp_tets = vec![[0, 1, 3, 4]];
@@ -2269,7 +2384,7 @@ mod test {
         let tets = vec![[0, 1, 2, 3], [0, 1, 2, 4]];
         let mut tet = Tetrahedralization::new(positions.clone(), tets);
         let clone = tet.clone();
-        assert_eq!(tet.flip([0, 1, 2]), vec![]);
+        assert_eq!(tet.flip([0, 1, 2]).unwrap(), vec![]);
         assert_eq!(tet, clone);
     }
 
@@ -2287,7 +2402,7 @@ mod test {
         let tets = vec![[0, 1, 2, 3], [0, 1, 2, 4], [0, 2, 3, 4], [1, 2, 4, 5]];
         let mut tet = Tetrahedralization::new(positions.clone(), tets);
         let clone = tet.clone();
-        assert_eq!(tet.flip([0, 1, 2]), vec![]);
+        assert_eq!(tet.flip([0, 1, 2]).unwrap(), vec![]);
         assert_eq!(tet, clone);
     }
 
@@ -2303,7 +2418,7 @@ mod test {
         ];
         let tets = vec![[0, 1, 2, 3], [0, 1, 2, 4]];
         let mut tet = Tetrahedralization::new(positions.clone(), tets);
-        tet.flip([0, 1, 2]);
+        tet.flip([0, 1, 2]).unwrap();
         tet.canonicalize();
 
         let exp_tets = vec![[0, 1, 3, 4]];
@@ -2327,10 +2442,70 @@ mod test {
         let tets = vec![[0, 1, 2, 3], [0, 1, 2, 4], [1, 2, 4, 5]];
         let mut tet = Tetrahedralization::new(positions.clone(), tets);
         let clone = tet.clone();
-        assert_eq!(tet.flip([0, 1, 2]), vec![]);
+        assert_eq!(tet.flip([0, 1, 2]).unwrap(), vec![]);
         assert_eq!(tet, clone);
     }
 
+    #[test]
+    fn test_tetrahedralization_remove_slivers() {
+        // 2 nearly flat tets to 3 tets around the short edge between the apexes.
+        // Those are still slivers, but less bad ones.
+        let positions = vec![
+            vec3(0.0, 1.0, 0.0),
+            vec3(-1.0, -1.0, 0.0),
+            vec3(1.0, -1.0, 0.0),
+            vec3(0.0, 0.0, -0.01),
+            vec3(0.0, 0.0, 0.01),
+        ];
+        let tets = vec![[0, 1, 2, 3], [0, 1, 2, 4]];
+        let mut tet = Tetrahedralization::new(positions.clone(), tets);
+        let min_angle = |tet: &Tetrahedralization| {
+            tet.qualities().map(|(_, q)| q.min_dihedral_angle).fold(f64::INFINITY, f64::min)
+        };
+        let before = min_angle(&tet);
+
+        assert_eq!(tet.remove_slivers(10f64.to_radians()), 1);
+        assert!(min_angle(&tet) > before);
+        assert_eq!(tet.quality_histogram(|q| q.min_dihedral_angle.to_degrees(), 0.0, 90.0, 9).unwrap().counts()[0], 3);
+        tet.canonicalize();
+
+        let exp_tets = vec![[0, 1, 3, 4], [1, 2, 3, 4], [0, 2, 3, 4]];
+        let mut exp_tet = Tetrahedralization::new(positions, exp_tets);
+        exp_tet.canonicalize();
+
+        assert_eq!(tet, exp_tet);
+    }
+
+    #[test]
+    fn test_tetrahedralization_remove_flat_tet() {
+        // Point 3 is in the middle of triangle [0, 1, 2], so tet [0, 1, 2, 3] is flat.
+        // Flipping its bottom face leaves 6 tets around point 3.
+        let positions = vec![
+            vec3(0.0, 1.0, 0.0),
+            vec3(-1.0, -1.0, 0.0),
+            vec3(1.0, -1.0, 0.0),
+            vec3(0.0, 0.0, 0.0),
+            vec3(0.0, 0.0, 1.0),
+            vec3(0.0, 0.0, -1.0),
+        ];
+        let tets = vec![[0, 1, 3, 4], [1, 2, 3, 4], [0, 2, 3, 4], [0, 1, 2, 3], [0, 1, 2, 5]];
+        let mut tet = Tetrahedralization::new(positions.clone(), tets);
+        assert_eq!(tet.flat_tets().len(), 1);
+
+        assert_eq!(tet.remove_slivers(10f64.to_radians()), 1);
+        assert!(tet.flat_tets().is_empty());
+        assert_eq!(tet.quality_histogram(|q| q.min_dihedral_angle.to_degrees(), 0.0, 90.0, 9).unwrap().counts()[0], 0);
+        let volume = tet.qualities().map(|(_, q)| q.volume).sum::<f64>();
+        assert!((volume - 4.0 / 3.0).abs() < 1e-12);
+        tet.canonicalize();
+
+        let exp_tets = vec![[0, 1, 3, 4], [1, 2, 3, 4], [0, 2, 3, 4], [0, 1, 3, 5], [1, 2, 3, 5], [0, 2, 3, 5]];
+        let mut exp_tet = Tetrahedralization::new(positions, exp_tets);
+        exp_tet.canonicalize();
+
+        assert_eq!(tet, exp_tet);
+    }
+
     #[test]
     fn test_tetrahedralization_find_first_intersecting_edge() {
         let positions = vec![
@@ -2584,4 +2759,40 @@ mod test {
             vec![[0, 1], [1, 2], [2, 3], [3, 0]].into_iter().collect()
         );
     }
+
+    #[test]
+    fn test_tetrahedralization_remove_tets_outside_boundary_materials() {
+        let positions = vec![
+            vec3(0.0, 0.0, 0.0),
+            vec3(1.0, 0.0, 0.0),
+            vec3(0.0, 1.0, 0.0),
+            vec3(0.0, 0.0, 1.0),
+            vec3(0.0, 0.0, -1.0),
+            vec3(1.0, 1.0, 1.0),
+        ];
+        let tets = vec![[0, 1, 2, 3], [0, 1, 2, 4], [1, 2, 3, 5]];
+        let mut tet = Tetrahedralization::new(positions, tets);
+
+        let a = MaterialID::new(1);
+        let b = MaterialID::new(2);
+        let boundary = vec![
+            ([0, 1, 3], a),
+            ([0, 3, 2], a),
+            ([1, 2, 3], a),
+            ([0, 2, 1], a),
+            ([0, 4, 1], b),
+            ([0, 2, 4], b),
+            ([1, 4, 2], b),
+            ([0, 1, 2], b),
+        ];
+        tet.remove_tets_outside_boundary(&boundary).unwrap();
+
+        assert_eq!(tet.tets.indices().count(), 2);
+        assert_eq!(tet.material(0), a);
+        assert_eq!(tet.material(1), b);
+
+        let mut hulls = tet.convex_hulls().into_iter().map(|(_, material)| material).collect::<Vec<_>>();
+        hulls.sort();
+        assert_eq!(hulls, vec![a, b]);
+    }
 }
@@ -0,0 +1,433 @@
+//! Import of 3MF files.
+//!
+//! A 3MF file is a zip archive containing an XML model. Triangles refer to entries of
+//! property groups (base materials or colors), and each entry used becomes its own material.
+//! Objects can be built from other objects, and build items place objects with a transform.
+
+use fnv::FnvHashMap;
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use tri_mesh::prelude::*;
+use xml::attribute::OwnedAttribute;
+use xml::reader::{EventReader, XmlEvent};
+use zip::ZipArchive;
+
+use crate::error::VoxelizationError;
+use crate::material_mesh::{MaterialID, MaterialMesh};
+use crate::material_table::{MaterialInfo, MaterialTable};
+
+/// Where the model usually is, in case the relationships don't say
+const DEFAULT_MODEL_PATH: &str = "3D/3dmodel.model";
+
+/// Relationship type of the main model
+const MODEL_RELATIONSHIP: &str = "http://schemas.microsoft.com/3dmanufacturing/2013/01/3dmodel";
+
+/// A property group entry, by group ID and index in the group
+type PropertyKey = (u32, u32);
+
+#[derive(Debug, Default)]
+struct Object {
+    vertices: Vec<Vec3>,
+    triangles: Vec<([usize; 3], Option<PropertyKey>)>,
+    /// Property of triangles that don't have one
+    property: Option<PropertyKey>,
+    /// Other objects this object is made of, with their transforms
+    components: Vec<(u32, Mat4)>,
+}
+
+/// Model as read from the XML
+#[derive(Debug, Default)]
+struct Model {
+    /// Property group entries in the order they're declared
+    properties: Vec<(PropertyKey, MaterialInfo)>,
+    objects: FnvHashMap<u32, Object>,
+    build: Vec<(u32, Mat4)>,
+}
+
+impl MaterialMesh {
+    /// Constructs a material mesh from the contents of a 3MF file.
+    /// Each base material or color used gets its own material ID, in the order they're declared,
+    /// and triangles with no property get an extra material. Names and colors go in the material table.
+    pub fn from_3mf(source: &[u8]) -> Result<Self, VoxelizationError> {
+        let mut archive = ZipArchive::new(Cursor::new(source)).map_err(zip_error)?;
+
+        let model_path = match archive.by_name("_rels/.rels") {
+            Ok(mut rels) => {
+                let mut xml = vec![];
+                rels.read_to_end(&mut xml)?;
+                model_path(&xml)?
+            }
+            Err(_) => None,
+        }
+        .unwrap_or_else(|| String::from(DEFAULT_MODEL_PATH));
+
+        let mut xml = vec![];
+        archive
+            .by_name(&model_path)
+            .map_err(zip_error)?
+            .read_to_end(&mut xml)?;
+
+        parse_model(&xml)?.into_mesh()
+    }
+
+    /// Reads a 3MF file. See `from_3mf`.
+    pub fn from_3mf_file<P: AsRef<Path>>(path: P) -> Result<Self, VoxelizationError> {
+        Self::from_3mf(&fs::read(path)?)
+    }
+}
+
+impl Model {
+    fn into_mesh(self) -> Result<MaterialMesh, VoxelizationError> {
+        let mut key_materials = FnvHashMap::default();
+        let mut materials = MaterialTable::new();
+        for (i, (key, material)) in self.properties.into_iter().enumerate() {
+            let id = MaterialID::try_new(i as u32 + 1)?;
+            key_materials.insert(key, id);
+            materials.insert(id, material);
+        }
+
+        let mut positions = vec![];
+        let mut indexes = vec![];
+        let mut tags = vec![];
+        let mut default_material = None;
+
+        for (object_id, transform) in &self.build {
+            let mut stack = vec![(*object_id, *transform, 0)];
+
+            while let Some((id, transform, depth)) = stack.pop() {
+                let object = self.objects.get(&id).ok_or_else(|| {
+                    VoxelizationError::Parse(format!("3MF file refers to missing object {}", id))
+                })?;
+                // Components can't refer to themselves, but a bad file could
+                if depth > self.objects.len() {
+                    return Err(VoxelizationError::Parse(String::from("3MF file has cyclic components")));
+                }
+
+                let offset = positions.len() as u32 / 3;
+                for vertex in &object.vertices {
+                    let pos = transform.transform_point(Point3::from_vec(*vertex));
+                    positions.extend_from_slice(&[pos.x, pos.y, pos.z]);
+                }
+
+                for (triangle, property) in &object.triangles {
+                    let material = match property.or(object.property) {
+                        Some(key) => *key_materials.get(&key).ok_or_else(|| {
+                            VoxelizationError::Parse(format!("3MF file refers to missing property {:?}", key))
+                        })?,
+                        None => *default_material.get_or_insert(MaterialID::try_new(key_materials.len() as u32 + 1)?),
+                    };
+
+                    if triangle.iter().any(|v| *v >= object.vertices.len()) {
+                        return Err(VoxelizationError::Parse(format!(
+                            "3MF object {} has a triangle with a vertex out of range",
+                            id
+                        )));
+                    }
+                    indexes.extend(triangle.iter().map(|v| offset + *v as u32));
+                    tags.push(material);
+                }
+
+                for (child, child_transform) in &object.components {
+                    stack.push((*child, transform * *child_transform, depth + 1));
+                }
+            }
+        }
+
+        if let Some(id) = default_material {
+            materials.insert(id, MaterialInfo::new("default"));
+        }
+
+        let mesh = MeshBuilder::new()
+            .with_positions(positions)
+            .with_indices(indexes)
+            .with_tags(tags)
+            .build()?;
+
+        Ok(MaterialMesh::new(mesh).with_materials(materials))
+    }
+}
+
+fn zip_error(error: zip::result::ZipError) -> VoxelizationError {
+    VoxelizationError::Parse(format!("Bad 3MF archive: {}", error))
+}
+
+fn xml_error(error: xml::reader::Error) -> VoxelizationError {
+    VoxelizationError::Parse(format!("Bad 3MF XML: {}", error))
+}
+
+fn attribute<'a>(attributes: &'a [OwnedAttribute], name: &str) -> Option<&'a str> {
+    attributes
+        .iter()
+        .find(|attr| attr.name.local_name == name)
+        .map(|attr| attr.value.as_str())
+}
+
+fn parse_attribute<T: std::str::FromStr>(attributes: &[OwnedAttribute], name: &str) -> Result<Option<T>, VoxelizationError> {
+    attribute(attributes, name)
+        .map(|value| {
+            value
+                .trim()
+                .parse::<T>()
+                .map_err(|_| VoxelizationError::Parse(format!("Bad value '{}' for 3MF attribute {}", value, name)))
+        })
+        .transpose()
+}
+
+fn required_attribute<T: std::str::FromStr>(attributes: &[OwnedAttribute], name: &str) -> Result<T, VoxelizationError> {
+    parse_attribute(attributes, name)?
+        .ok_or_else(|| VoxelizationError::Parse(format!("Missing 3MF attribute {}", name)))
+}
+
+/// Parses a color like #RRGGBB or #RRGGBBAA
+fn parse_color(color: &str) -> Option<[u8; 4]> {
+    let hex = color.trim().strip_prefix('#')?;
+    if hex.len() != 6 && hex.len() != 8 {
+        return None;
+    }
+
+    let channel = |i: usize| u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok();
+    Some([channel(0)?, channel(1)?, channel(2)?, if hex.len() == 8 { channel(3)? } else { 255 }])
+}
+
+/// Parses a 3MF transform, which is a 4x3 matrix in row-major order that multiplies row vectors
+fn parse_transform(attributes: &[OwnedAttribute]) -> Result<Mat4, VoxelizationError> {
+    let transform = match attribute(attributes, "transform") {
+        Some(transform) => transform,
+        None => return Ok(Mat4::identity()),
+    };
+
+    let m = transform
+        .split_whitespace()
+        .map(|value| value.parse::<f64>().ok())
+        .collect::<Option<Vec<_>>>()
+        .filter(|m| m.len() == 12)
+        .ok_or_else(|| VoxelizationError::Parse(format!("Bad 3MF transform '{}'", transform)))?;
+
+    Ok(Mat4::new(
+        m[0], m[1], m[2], 0.0,
+        m[3], m[4], m[5], 0.0,
+        m[6], m[7], m[8], 0.0,
+        m[9], m[10], m[11], 1.0,
+    ))
+}
+
+/// Finds the path of the main model in the relationships file
+fn model_path(xml: &[u8]) -> Result<Option<String>, VoxelizationError> {
+    for event in EventReader::new(xml) {
+        if let XmlEvent::StartElement { name, attributes, .. } = event.map_err(xml_error)? {
+            if name.local_name == "Relationship" && attribute(&attributes, "Type") == Some(MODEL_RELATIONSHIP) {
+                return Ok(attribute(&attributes, "Target").map(|target| target.trim_start_matches('/').to_owned()));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+fn parse_model(xml: &[u8]) -> Result<Model, VoxelizationError> {
+    let mut model = Model::default();
+    // Property group being read, and the number of entries read so far
+    let mut group: Option<(u32, u32)> = None;
+    let mut object: Option<(u32, Object)> = None;
+
+    for event in EventReader::new(xml) {
+        match event.map_err(xml_error)? {
+            XmlEvent::StartElement { name, attributes, .. } => match name.local_name.as_str() {
+                "basematerials" | "colorgroup" => group = Some((required_attribute(&attributes, "id")?, 0)),
+
+                "base" | "color" => {
+                    if let Some((id, index)) = group.as_mut() {
+                        let color = attribute(&attributes, if name.local_name == "base" { "displaycolor" } else { "color" })
+                            .and_then(parse_color);
+                        let name = attribute(&attributes, "name")
+                            .map(str::to_owned)
+                            .unwrap_or_else(|| format!("{} {}", id, index));
+
+                        let mut material = MaterialInfo::new(name);
+                        material.color = color;
+                        model.properties.push(((*id, *index), material));
+                        *index += 1;
+                    }
+                }
+
+                "object" => {
+                    let id = required_attribute(&attributes, "id")?;
+                    let property = match parse_attribute::<u32>(&attributes, "pid")? {
+                        Some(pid) => Some((pid, parse_attribute(&attributes, "pindex")?.unwrap_or(0))),
+                        None => None,
+                    };
+                    object = Some((id, Object {
+                        property,
+                        ..Object::default()
+                    }));
+                }
+
+                "vertex" => {
+                    if let Some((_, object)) = object.as_mut() {
+                        object.vertices.push(vec3(
+                            required_attribute(&attributes, "x")?,
+                            required_attribute(&attributes, "y")?,
+                            required_attribute(&attributes, "z")?,
+                        ));
+                    }
+                }
+
+                "triangle" => {
+                    if let Some((_, object)) = object.as_mut() {
+                        let triangle = [
+                            required_attribute(&attributes, "v1")?,
+                            required_attribute(&attributes, "v2")?,
+                            required_attribute(&attributes, "v3")?,
+                        ];
+                        // Per-vertex properties aren't supported, so the first vertex's is used
+                        let property = match parse_attribute::<u32>(&attributes, "pid")? {
+                            Some(pid) => Some((pid, parse_attribute(&attributes, "p1")?.unwrap_or(0))),
+                            None => match (object.property, parse_attribute::<u32>(&attributes, "p1")?) {
+                                (Some((pid, _)), Some(index)) => Some((pid, index)),
+                                _ => None,
+                            },
+                        };
+                        object.triangles.push((triangle, property));
+                    }
+                }
+
+                "component" => {
+                    if let Some((_, object)) = object.as_mut() {
+                        object
+                            .components
+                            .push((required_attribute(&attributes, "objectid")?, parse_transform(&attributes)?));
+                    }
+                }
+
+                "item" => model
+                    .build
+                    .push((required_attribute(&attributes, "objectid")?, parse_transform(&attributes)?)),
+
+                _ => {}
+            },
+
+            XmlEvent::EndElement { name } => match name.local_name.as_str() {
+                "basematerials" | "colorgroup" => group = None,
+                "object" => {
+                    if let Some((id, object)) = object.take() {
+                        model.objects.insert(id, object);
+                    }
+                }
+                _ => {}
+            },
+
+            _ => {}
+        }
+    }
+
+    Ok(model)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+    use zip::write::{FileOptions, ZipWriter};
+
+    const TETRAHEDRON: &str = r#"
+        <mesh>
+            <vertices>
+                <vertex x="0" y="0" z="0"/>
+                <vertex x="1" y="0" z="0"/>
+                <vertex x="0" y="1" z="0"/>
+                <vertex x="0" y="0" z="1"/>
+            </vertices>
+            <triangles>
+                <triangle v1="0" v2="2" v3="1"/>
+                <triangle v1="0" v2="1" v3="3"/>
+                <triangle v1="0" v2="3" v3="2" pid="1" p1="1"/>
+                <triangle v1="1" v2="2" v3="3"/>
+            </triangles>
+        </mesh>"#;
+
+    fn archive(model: &str) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(vec![]));
+        let options = FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+
+        writer.start_file("_rels/.rels", options).unwrap();
+        write!(
+            writer,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">
+                <Relationship Target="/3D/model.model" Id="rel0" Type="{}"/>
+            </Relationships>"#,
+            MODEL_RELATIONSHIP
+        )
+        .unwrap();
+
+        writer.start_file("3D/model.model", options).unwrap();
+        writer.write_all(model.as_bytes()).unwrap();
+
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_from_3mf_materials_and_transforms() {
+        let model = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <model unit="millimeter" xmlns="http://schemas.microsoft.com/3dmanufacturing/core/2015/02">
+                <resources>
+                    <basematerials id="1">
+                        <base name="Red PLA" displaycolor="#FF0000"/>
+                        <base name="Support" displaycolor="#FFFFFF80"/>
+                    </basematerials>
+                    <object id="2" type="model" pid="1" pindex="0">{}</object>
+                    <object id="3" type="model">
+                        <components>
+                            <component objectid="2" transform="1 0 0 0 1 0 0 0 1 5 0 0"/>
+                        </components>
+                    </object>
+                </resources>
+                <build>
+                    <item objectid="2"/>
+                    <item objectid="3" transform="1 0 0 0 1 0 0 0 1 0 0 10"/>
+                </build>
+            </model>"#,
+            TETRAHEDRON
+        );
+
+        let mesh = MaterialMesh::from_3mf(&archive(&model)).unwrap();
+        let materials = mesh.materials();
+        assert_eq!(mesh.mesh().num_vertices(), 8);
+        assert_eq!(mesh.mesh().num_faces(), 8);
+
+        assert_eq!(materials.name(MaterialID::new(1)), "Red PLA");
+        assert_eq!(materials.color(MaterialID::new(1)), [255, 0, 0, 255]);
+        assert_eq!(materials.color(MaterialID::new(2)), [255, 255, 255, 128]);
+
+        let mut counts = FnvHashMap::default();
+        for f in mesh.mesh().face_iter() {
+            *counts.entry(mesh.mesh().face_tag(f)).or_insert(0) += 1;
+        }
+        assert_eq!(counts[&MaterialID::new(1)], 6);
+        assert_eq!(counts[&MaterialID::new(2)], 2);
+
+        // The component's transform is applied before the item's
+        let max = mesh
+            .mesh()
+            .vertex_iter()
+            .map(|v| mesh.mesh().vertex_position(v))
+            .fold(vec3(f64::MIN, f64::MIN, f64::MIN), |max, p| vec3(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z)));
+        assert_eq!(max, vec3(6.0, 1.0, 11.0));
+    }
+
+    #[test]
+    fn test_from_3mf_missing_object() {
+        let model = r#"<model><resources/><build><item objectid="7"/></build></model>"#;
+        assert!(MaterialMesh::from_3mf(&archive(model)).is_err());
+    }
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!(parse_color("#12AbEf"), Some([0x12, 0xab, 0xef, 255]));
+        assert_eq!(parse_color("#12AbEf00"), Some([0x12, 0xab, 0xef, 0]));
+        assert_eq!(parse_color("12AbEf"), None);
+        assert_eq!(parse_color("#12AbE"), None);
+    }
+}
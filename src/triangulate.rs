@@ -1,6 +1,7 @@
 //! Implementation of polygon triangulation
 //! that uses monotone decomposition and beautification
-//! based on area/perimeter ratios
+//! based on area/perimeter ratios,
+//! or constrained Delaunay triangulation for better shaped triangles
 
 use float_ord::FloatOrd;
 use fnv::{FnvHashMap, FnvHashSet};
@@ -8,6 +9,8 @@ use petgraph::prelude::*;
 use std::collections::VecDeque;
 use tri_mesh::prelude::*;
 
+use crate::cdt::{opposite_signs, ConstrainedDelaunay};
+use crate::predicates;
 use crate::util::{GraphEx, HashVec2, Vec2};
 
 /// A polygon structure to help with triangulation.
@@ -17,9 +20,41 @@ pub struct Polygon {
     boundary: Graph<Vec2, ()>,
 }
 
+/// Errors from building or triangulating a polygon.
+/// Rings are numbered in the order they're given, with each piece's holes right after its outer ring.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum TriangulateError {
+    /// A vertex of the boundary graph has a different number of edges going in and out
     UnbalancedDegreeVertex,
+    /// A ring has fewer than 3 distinct vertices or they're all on a line
+    DegenerateRing { ring: usize },
+    /// A ring crosses or touches itself
+    SelfIntersectingRing { ring: usize },
+    /// Two rings cross or touch, or one is inside the other when it shouldn't be
+    OverlappingRings { rings: [usize; 2] },
+    /// A hole isn't inside its piece's outer ring
+    HoleOutsideOuterRing { ring: usize },
+    /// An edge of a constrained Delaunay triangulation crosses a constraint edge
+    CrossingConstraints,
+    /// A vertex has a NaN or infinite coordinate
+    NonFiniteVertex,
+    /// No triangle of a constrained Delaunay triangulation contains a point, which shouldn't happen for finite points
+    PointNotLocated,
+}
+
+/// How to triangulate a polygon
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TriangulationMethod {
+    /// Monotone decomposition. Fast, but triangles can be skinny.
+    Monotone,
+    /// Constrained Delaunay triangulation, which makes the smallest angle as big as possible
+    ConstrainedDelaunay,
+}
+
+impl Default for TriangulationMethod {
+    fn default() -> Self {
+        TriangulationMethod::Monotone
+    }
 }
 
 /// Used for the plane sweep.
@@ -175,7 +210,171 @@ impl Polygon {
         Ok(())
     }
 
+    /// Constructs a polygon from an outer ring and hole rings.
+    /// See `from_pieces`.
+    pub fn from_rings(outer: &[Vec2], holes: &[Vec<Vec2>]) -> Result<Self, TriangulateError> {
+        Self::from_pieces(&[(outer.to_vec(), holes.to_vec())])
+    }
+
+    /// Constructs a polygon from pieces, each of which is an outer ring and hole rings.
+    /// Rings are lists of vertices in either winding order, and the last vertex shouldn't repeat the first.
+    /// Outer rings get wound counterclockwise and holes clockwise.
+    /// Rings can't cross or touch each other or themselves,
+    /// and a piece can be inside a hole of another piece but can't overlap another piece otherwise.
+    pub fn from_pieces(pieces: &[(Vec<Vec2>, Vec<Vec<Vec2>>)]) -> Result<Self, TriangulateError> {
+        let mut rings = vec![];
+        // Index of the outer ring of each ring's piece
+        let mut outer_rings = vec![];
+        for (outer, holes) in pieces {
+            let outer_index = rings.len();
+            for (i, ring) in std::iter::once(outer).chain(holes).enumerate() {
+                if ring.iter().any(|p| !p.x.is_finite() || !p.y.is_finite()) {
+                    return Err(TriangulateError::NonFiniteVertex);
+                }
+                let mut ring = ring.clone();
+                ring.dedup();
+                while ring.len() > 1 && ring.first() == ring.last() {
+                    ring.pop();
+                }
+
+                let collinear = match ring.iter().find(|p| **p != ring[0]) {
+                    Some(other) => ring.iter().all(|p| predicates::orient2d(ring[0], *other, *p) == 0.0),
+                    None => true,
+                };
+                if ring.len() < 3 || collinear {
+                    return Err(TriangulateError::DegenerateRing { ring: rings.len() });
+                }
+
+                // The lowest leftmost vertex is convex unless the ring doubles back there,
+                // which validation catches, so the turn there gives the winding order
+                let n = ring.len();
+                let j = (0..n).min_by_key(|j| (FloatOrd(ring[*j].x), FloatOrd(ring[*j].y))).unwrap();
+                let counterclockwise = predicates::orient2d(ring[(j + n - 1) % n], ring[j], ring[(j + 1) % n]) > 0.0;
+                if counterclockwise != (i == 0) {
+                    ring.reverse();
+                }
+
+                rings.push(ring);
+                outer_rings.push(outer_index);
+            }
+        }
+
+        Self::validate_rings(&rings, &outer_rings)?;
+
+        let mut boundary = Graph::new();
+        for ring in &rings {
+            let nodes = ring.iter().map(|pos| boundary.add_node(*pos)).collect::<Vec<_>>();
+            for j in 0..nodes.len() {
+                boundary.add_edge(nodes[j], nodes[(j + 1) % nodes.len()], ());
+            }
+        }
+
+        Self::from_boundary(boundary)
+    }
+
+    /// Checks that rings don't cross or touch and that they nest properly.
+    /// Assumes rings are wound correctly.
+    /// Edges are swept by x, so only edges whose bounding boxes overlap get tested against each other.
+    fn validate_rings(rings: &[Vec<Vec2>], outer_rings: &[usize]) -> Result<(), TriangulateError> {
+        // The first ring that touches itself, and the first pair of rings that touch
+        let mut self_intersecting = None;
+        let mut overlapping = None;
+
+        for (r, ring) in rings.iter().enumerate() {
+            let n = ring.len();
+            // Adjacent edges share a vertex, so they only intersect if they double back
+            let doubles_back = (0..n).any(|j| {
+                let [prev, vertex, next] = [ring[j], ring[(j + 1) % n], ring[(j + 2) % n]];
+                predicates::orient2d(prev, vertex, next) == 0.0 && (next - vertex).dot(prev - vertex) > 0.0
+            });
+            if doubles_back {
+                self_intersecting = Some(r);
+                break;
+            }
+        }
+
+        // Edges as (ring, index in ring, endpoints), by smallest x
+        let mut edges = rings
+            .iter()
+            .enumerate()
+            .flat_map(|(r, ring)| (0..ring.len()).map(move |j| (r, j, [ring[j], ring[(j + 1) % ring.len()]])))
+            .collect::<Vec<_>>();
+        edges.sort_by_key(|(_, _, [a, b])| FloatOrd(a.x.min(b.x)));
+
+        // Edges that reach the x of the current edge
+        let mut active: Vec<usize> = vec![];
+        for (i, (r, j, edge)) in edges.iter().enumerate() {
+            let [a, b] = *edge;
+            active.retain(|k| {
+                let [c, d] = edges[*k].2;
+                c.x.max(d.x) >= a.x.min(b.x)
+            });
+
+            for k in &active {
+                let (r1, j1, edge1) = edges[*k];
+                let [c, d] = edge1;
+                if c.y.max(d.y) < a.y.min(b.y) || a.y.max(b.y) < c.y.min(d.y) {
+                    continue;
+                }
+
+                if r1 == *r {
+                    let n = rings[*r].len();
+                    if (j + 1) % n != j1 && (j1 + 1) % n != *j && segments_intersect(*edge, edge1) {
+                        self_intersecting = Some(self_intersecting.map_or(*r, |s: usize| s.min(*r)));
+                    }
+                } else if segments_intersect(*edge, edge1) {
+                    let pair = [r1.min(*r), r1.max(*r)];
+                    overlapping = Some(overlapping.map_or(pair, |o: [usize; 2]| o.min(pair)));
+                }
+            }
+
+            active.push(i);
+        }
+
+        if let Some(ring) = self_intersecting {
+            return Err(TriangulateError::SelfIntersectingRing { ring });
+        }
+        if let Some(rings) = overlapping {
+            return Err(TriangulateError::OverlappingRings { rings });
+        }
+
+        // Rings don't touch, so a ring is inside another if any of its vertices is
+        for (r, ring) in rings.iter().enumerate() {
+            let outer = outer_rings[r];
+            let contains = |other: usize| ring_contains(&rings[other], ring[0]);
+
+            if r != outer && !contains(outer) {
+                return Err(TriangulateError::HoleOutsideOuterRing { ring: r });
+            }
+
+            for other in 0..rings.len() {
+                if other == r {
+                    continue;
+                }
+                let overlaps = if r != outer {
+                    // A hole inside another hole of the same piece
+                    outer_rings[other] == outer && other != outer && contains(other)
+                } else if other == outer_rings[other] {
+                    // A piece inside another piece, but not inside one of its holes
+                    contains(other)
+                        && !(0..rings.len()).any(|hole| hole != other && outer_rings[hole] == other && contains(hole))
+                } else {
+                    false
+                };
+
+                if overlaps {
+                    return Err(TriangulateError::OverlappingRings { rings: [other.min(r), other.max(r)] });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn from_boundary(mut boundary: Graph<Vec2, ()>) -> Result<Self, TriangulateError> {
+        if boundary.node_indices().any(|node| !boundary[node].x.is_finite() || !boundary[node].y.is_finite()) {
+            return Err(TriangulateError::NonFiniteVertex);
+        }
         Self::fix_bad_degrees(&mut boundary)?;
         // Now every vertex is indegree-1 outdegree-1.
         //println!("graph: {:?}", boundary);
@@ -292,7 +491,7 @@ impl Polygon {
                 // Split/start vertex
                 if ((boundary[node_a] - pos).dot(boundary[node_b] - pos) < 0.0
                     && boundary[node_a].y > boundary[node_b].y)
-                    || (boundary[node_a] - pos).perp_dot(boundary[node_b] - pos) < 0.0
+                    || predicates::orient2d(pos, boundary[node_a], boundary[node_b]) < 0.0
                 {
                     std::mem::swap(&mut node_a, &mut node_b);
                 }
@@ -345,7 +544,7 @@ impl Polygon {
                 // Merge/end vertex
                 if ((boundary[node_a] - pos).dot(boundary[node_b] - pos) < 0.0
                     && boundary[node_a].y > boundary[node_b].y)
-                    || (boundary[node_a] - pos).perp_dot(boundary[node_b] - pos) > 0.0
+                    || predicates::orient2d(pos, boundary[node_a], boundary[node_b]) > 0.0
                 {
                     std::mem::swap(&mut node_a, &mut node_b);
                 }
@@ -465,7 +664,7 @@ impl Polygon {
                 if (b - a).dot(c - b) < 0.0 {
                     flipped == (c.y < b.y)
                 } else {
-                    (b - a).perp_dot(c - b) >= 0.0
+                    predicates::orient2d(a, b, c) >= 0.0
                 }
             };
 
@@ -502,6 +701,88 @@ impl Polygon {
         triangles
     }
 
+    /// Triangulates the polygon using some method.
+    /// Triangles are counterclockwise.
+    pub fn triangulate_with(self, method: TriangulationMethod) -> Result<Vec<[Vec2; 3]>, TriangulateError> {
+        match method {
+            TriangulationMethod::Monotone => Ok(self.triangulate()),
+            TriangulationMethod::ConstrainedDelaunay => self.triangulate_constrained_delaunay(),
+        }
+    }
+
+    /// Triangulates the polygon so that the boundary edges are kept
+    /// and every other edge is Delaunay.
+    /// Boundary edges get split at vertices that lie on them.
+    /// Fails if boundary edges cross, which validation when building the polygon should rule out.
+    pub fn triangulate_constrained_delaunay(self) -> Result<Vec<[Vec2; 3]>, TriangulateError> {
+        let (vertices, triangles) = self.triangulate_indexed(TriangulationMethod::ConstrainedDelaunay)?;
+        Ok(triangles
+            .into_iter()
+            .map(|[a, b, c]| [vertices[a], vertices[b], vertices[c]])
+            .collect())
+    }
+
+    /// Distinct vertex positions in order of the nodes they first appear in,
+    /// and the index of each node's position.
+    /// Splitting high-degree vertices made nodes with the same position, which get merged here.
+    fn merged_vertices(&self) -> (Vec<Vec2>, FnvHashMap<NodeIndex, usize>) {
+        let mut index_map = FnvHashMap::default();
+        let mut vertices = vec![];
+        let node_map = self
+            .boundary
+            .node_indices()
+            .map(|node| {
+                let pos = self.boundary[node];
+                let index = *index_map.entry(HashVec2(pos)).or_insert_with(|| {
+                    vertices.push(pos);
+                    vertices.len() - 1
+                });
+                (node, index)
+            })
+            .collect();
+
+        (vertices, node_map)
+    }
+
+    /// Triangulates the polygon, returning the distinct vertex positions
+    /// and counterclockwise triangles that index into them, so triangles that share a vertex share its index.
+    /// For polygons made with `from_rings` or `from_pieces`,
+    /// the vertices are in the order of the rings after outer rings are made counterclockwise
+    /// and holes clockwise, without repeats.
+    /// Fails like `triangulate_constrained_delaunay`.
+    pub fn triangulate_indexed(
+        mut self,
+        method: TriangulationMethod,
+    ) -> Result<(Vec<Vec2>, Vec<[usize; 3]>), TriangulateError> {
+        let (vertices, node_map) = self.merged_vertices();
+
+        let triangles = match method {
+            TriangulationMethod::Monotone => {
+                let monotones = self.monotone_decompose();
+                self.triangulate_monotone_cycles(monotones)
+                    .into_iter()
+                    .map(|[a, b, c]| [node_map[&a], node_map[&b], node_map[&c]])
+                    // Merged vertices can make 0-area triangles
+                    .filter(|[a, b, c]| a != b && b != c && c != a)
+                    .collect()
+            }
+
+            TriangulationMethod::ConstrainedDelaunay => {
+                let mut cdt = ConstrainedDelaunay::new(vertices.clone())?;
+                let mut boundary = vec![];
+                for edge in self.boundary.edge_references() {
+                    let (s, t) = (node_map[&edge.source()], node_map[&edge.target()]);
+                    if s != t {
+                        boundary.extend(cdt.insert_constraint([s, t])?);
+                    }
+                }
+                cdt.enclosed_triangles(&boundary)
+            }
+        };
+
+        Ok((vertices, triangles))
+    }
+
     pub fn triangulate(mut self) -> Vec<[Vec2; 3]> {
         let monotones = self.monotone_decompose();
         let cycles = self.triangulate_monotone_cycles(monotones);
@@ -513,6 +794,40 @@ impl Polygon {
     }
 }
 
+/// Whether 2 closed segments intersect, including touching
+fn segments_intersect([a, b]: [Vec2; 2], [c, d]: [Vec2; 2]) -> bool {
+    let in_box = |p: Vec2, q: Vec2, r: Vec2| {
+        r.x >= p.x.min(q.x) && r.x <= p.x.max(q.x) && r.y >= p.y.min(q.y) && r.y <= p.y.max(q.y)
+    };
+
+    let o_c = predicates::orient2d(a, b, c);
+    let o_d = predicates::orient2d(a, b, d);
+    let o_a = predicates::orient2d(c, d, a);
+    let o_b = predicates::orient2d(c, d, b);
+
+    (opposite_signs(o_c, o_d) && opposite_signs(o_a, o_b))
+        || (o_c == 0.0 && in_box(a, b, c))
+        || (o_d == 0.0 && in_box(a, b, d))
+        || (o_a == 0.0 && in_box(c, d, a))
+        || (o_b == 0.0 && in_box(c, d, b))
+}
+
+/// Whether a point not on a ring is inside it, by winding number
+fn ring_contains(ring: &[Vec2], point: Vec2) -> bool {
+    let mut winding = 0;
+    for j in 0..ring.len() {
+        let (a, b) = (ring[j], ring[(j + 1) % ring.len()]);
+        if a.y <= point.y {
+            if b.y > point.y && predicates::orient2d(a, b, point) > 0.0 {
+                winding += 1;
+            }
+        } else if b.y <= point.y && predicates::orient2d(a, b, point) < 0.0 {
+            winding -= 1;
+        }
+    }
+    winding != 0
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -1149,6 +1464,155 @@ mod test {
         assert_eq!(cycles, expected_cycles);
     }
 
+    #[test]
+    fn test_constrained_delaunay_triangulation_parallelogram_ring() {
+        // contains a hole
+        let polygon = Polygon::from_boundary(create_graph(
+            vec![
+                vec2(0.0, 0.0),
+                vec2(6.0, 0.0),
+                vec2(7.0, 3.0),
+                vec2(1.0, 3.0),
+                vec2(2.0, 1.0),
+                vec2(3.0, 2.0),
+                vec2(5.0, 2.0),
+                vec2(4.0, 1.0),
+            ],
+            vec![
+                (0, 1),
+                (1, 2),
+                (2, 3),
+                (3, 0),
+                (4, 5),
+                (5, 6),
+                (6, 7),
+                (7, 4),
+            ],
+        ))
+        .unwrap();
+
+        let triangles = polygon.triangulate_with(TriangulationMethod::ConstrainedDelaunay).unwrap();
+        assert_eq!(triangles.len(), 8);
+        for [a, b, c] in &triangles {
+            assert!(predicates::orient2d(*a, *b, *c) > 0.0);
+        }
+        let area = triangles.iter().map(|[a, b, c]| (*b - *a).perp_dot(*c - *a) / 2.0).sum::<f64>();
+        assert!((area - 16.0).abs() < 1e-12);
+    }
+
+    fn square(min: f64, max: f64) -> Vec<Vec2> {
+        vec![vec2(min, min), vec2(max, min), vec2(max, max), vec2(min, max)]
+    }
+
+    #[test]
+    fn test_indexed_triangulation_square_with_hole() {
+        // Hole given counterclockwise on purpose
+        for method in [TriangulationMethod::Monotone, TriangulationMethod::ConstrainedDelaunay].iter() {
+            let polygon = Polygon::from_rings(&square(0.0, 3.0), &[square(1.0, 2.0)]).unwrap();
+            let (vertices, triangles) = polygon.triangulate_indexed(*method).unwrap();
+
+            let mut expected = square(0.0, 3.0);
+            expected.extend(square(1.0, 2.0).into_iter().rev());
+            assert_eq!(vertices, expected);
+            assert_eq!(triangles.len(), 8);
+
+            let area = triangles
+                .iter()
+                .map(|[a, b, c]| {
+                    let area = (vertices[*b] - vertices[*a]).perp_dot(vertices[*c] - vertices[*a]) / 2.0;
+                    assert!(area > 0.0);
+                    area
+                })
+                .sum::<f64>();
+            assert!((area - 8.0).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_from_pieces_island_in_hole() {
+        let polygon = Polygon::from_pieces(&[
+            (square(0.0, 5.0), vec![square(1.0, 4.0)]),
+            (square(2.0, 3.0), vec![]),
+        ])
+        .unwrap();
+
+        let (vertices, triangles) = polygon.triangulate_indexed(TriangulationMethod::ConstrainedDelaunay).unwrap();
+        assert_eq!(vertices.len(), 12);
+        let area = triangles
+            .iter()
+            .map(|[a, b, c]| (vertices[*b] - vertices[*a]).perp_dot(vertices[*c] - vertices[*a]) / 2.0)
+            .sum::<f64>();
+        assert!((area - 17.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_from_rings_errors() {
+        // Closing duplicate is fine, but a line isn't
+        let line = vec![vec2(0.0, 0.0), vec2(1.0, 0.0), vec2(2.0, 0.0), vec2(0.0, 0.0)];
+        assert_eq!(
+            Polygon::from_rings(&line, &[]).unwrap_err(),
+            TriangulateError::DegenerateRing { ring: 0 }
+        );
+
+        let bowtie = vec![vec2(0.0, 0.0), vec2(2.0, 2.0), vec2(2.0, 0.0), vec2(0.0, 1.0)];
+        assert_eq!(
+            Polygon::from_rings(&square(-1.0, 3.0), &[bowtie]).unwrap_err(),
+            TriangulateError::SelfIntersectingRing { ring: 1 }
+        );
+
+        // Ring touches itself at a vertex
+        let pinched = vec![
+            vec2(0.0, 0.0),
+            vec2(2.0, 0.0),
+            vec2(1.0, 1.0),
+            vec2(2.0, 2.0),
+            vec2(0.0, 2.0),
+            vec2(1.0, 1.0),
+        ];
+        assert_eq!(
+            Polygon::from_rings(&pinched, &[]).unwrap_err(),
+            TriangulateError::SelfIntersectingRing { ring: 0 }
+        );
+
+        assert_eq!(
+            Polygon::from_rings(&square(0.0, 2.0), &[square(1.0, 3.0)]).unwrap_err(),
+            TriangulateError::OverlappingRings { rings: [0, 1] }
+        );
+
+        assert_eq!(
+            Polygon::from_rings(&square(0.0, 4.0), &[square(1.0, 3.0), square(1.5, 2.5)]).unwrap_err(),
+            TriangulateError::OverlappingRings { rings: [1, 2] }
+        );
+
+        assert_eq!(
+            Polygon::from_rings(&square(0.0, 1.0), &[square(2.0, 3.0)]).unwrap_err(),
+            TriangulateError::HoleOutsideOuterRing { ring: 1 }
+        );
+
+        assert_eq!(
+            Polygon::from_pieces(&[(square(0.0, 4.0), vec![]), (square(1.0, 2.0), vec![])]).unwrap_err(),
+            TriangulateError::OverlappingRings { rings: [0, 1] }
+        );
+    }
+
+    #[test]
+    fn test_from_rings_non_finite() {
+        for bad in [f64::NAN, f64::INFINITY].iter() {
+            let ring = vec![vec2(0.0, 0.0), vec2(1.0, 0.0), vec2(*bad, 1.0)];
+            assert_eq!(Polygon::from_rings(&ring, &[]).unwrap_err(), TriangulateError::NonFiniteVertex);
+        }
+    }
+
+    #[test]
+    fn test_from_rings_collinear() {
+        // These are exactly on a line, but adding up the area with floats doesn't give 0
+        let line = vec![vec2(1000.1, 1000.6), vec2(1000000.9, 1000001.4), vec2(1000000.1, 1000000.6)];
+        assert_eq!(
+            Polygon::from_rings(&line, &[]).unwrap_err(),
+            TriangulateError::DegenerateRing { ring: 0 }
+        );
+    }
+
     #[test]
     fn test_monotone_triangulation_parallelogram_ring() {
         // contains a hole
@@ -8,6 +8,16 @@ use tri_mesh::prelude::*;
 
 pub type Vec2 = Vector2<f64>;
 
+/// Gets a fully saturated color from a hue, where 0 and 1 are both red.
+pub fn hue_color(hue: f64) -> Vec3 {
+    let wheel = [vec3(1.0, 0.0, 0.0), vec3(1.0, 1.0, 0.0), vec3(0.0, 1.0, 0.0),
+        vec3(0.0, 1.0, 1.0), vec3(0.0, 0.0, 1.0), vec3(1.0, 0.0, 1.0), vec3(1.0, 0.0, 0.0)];
+
+    let index = ((6.0 * hue).floor() as usize).min(5);
+    let frac = 6.0 * hue - index as f64;
+    wheel[index].lerp(wheel[index + 1], frac)
+}
+
 /// A `Vec2` that can be hashed
 #[derive(Copy, Clone, Debug)]
 pub struct HashVec2(pub Vec2);
@@ -0,0 +1,680 @@
+//! Checks and repairs of input meshes.
+//!
+//! Voxelization assumes every material is bounded by closed, consistently outward-facing shells
+//! that don't intersect each other. A mesh that breaks this gives garbage instead of an error,
+//! so `MaterialMesh::validate` reports what's wrong, and `MaterialMesh::repair` fixes what it can.
+//!
+//! Both work on vertex positions rather than the half-edge structure,
+//! since faces of different materials often have separate copies of the same vertex.
+
+use float_ord::FloatOrd;
+use fnv::{FnvHashMap, FnvHashSet};
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::fmt::{self, Display, Formatter};
+use tri_mesh::prelude::*;
+
+use crate::error::VoxelizationError;
+use crate::material_mesh::{BvhTriangle, MaterialID, MaterialMesh};
+use crate::util::HashVec3;
+
+/// Faces with less area than this times the squared size of the mesh are degenerate
+const DEGENERATE_AREA: f64 = 1e-12;
+
+/// Problems found in a mesh by `MaterialMesh::validate`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MeshValidation {
+    /// Boundary loops of each material's shells, as vertex positions in order.
+    /// A loop that can't be closed because of a non-manifold vertex is reported as an open chain.
+    pub holes: Vec<(MaterialID, Vec<Vec3>)>,
+    /// Edges shared by more than 2 faces of the same material
+    pub non_manifold_edges: Vec<(MaterialID, [Vec3; 2])>,
+    /// Faces that disagree with the orientation of their neighbors,
+    /// or that belong to a shell that faces the wrong way
+    pub flipped_faces: Vec<FaceID>,
+    /// Faces with (almost) no area
+    pub degenerate_faces: Vec<FaceID>,
+    /// Pairs of faces that cross each other without sharing a vertex
+    pub self_intersections: Vec<(FaceID, FaceID)>,
+}
+
+impl MeshValidation {
+    /// Whether no problems were found
+    pub fn is_valid(&self) -> bool {
+        self.holes.is_empty()
+            && self.non_manifold_edges.is_empty()
+            && self.flipped_faces.is_empty()
+            && self.degenerate_faces.is_empty()
+            && self.self_intersections.is_empty()
+    }
+}
+
+impl Display for MeshValidation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.is_valid() {
+            return write!(f, "no problems");
+        }
+
+        let problems = [
+            (self.holes.len(), "hole"),
+            (self.non_manifold_edges.len(), "non-manifold edge"),
+            (self.flipped_faces.len(), "flipped face"),
+            (self.degenerate_faces.len(), "degenerate face"),
+            (self.self_intersections.len(), "self-intersection"),
+        ];
+        let problems = problems
+            .iter()
+            .filter(|(count, _)| *count > 0)
+            .map(|(count, name)| format!("{} {}{}", count, name, if *count == 1 { "" } else { "s" }))
+            .collect::<Vec<_>>();
+        write!(f, "{}", problems.join(", "))
+    }
+}
+
+/// Options for `MaterialMesh::repair`
+#[derive(Clone, Debug, PartialEq)]
+pub struct RepairOptions {
+    /// Vertices closer than this are welded. None means a millionth of the bounding box diagonal.
+    weld_tolerance: Option<f64>,
+    /// Holes with at most this many edges are filled
+    max_hole_edges: usize,
+}
+
+impl Default for RepairOptions {
+    fn default() -> Self {
+        Self {
+            weld_tolerance: None,
+            max_hole_edges: 8,
+        }
+    }
+}
+
+impl RepairOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the distance within which vertices are welded, in mesh units
+    pub fn with_weld_tolerance(mut self, tolerance: f64) -> Self {
+        assert!(tolerance >= 0.0, "Weld tolerance can't be negative");
+        self.weld_tolerance = Some(tolerance);
+        self
+    }
+
+    /// Sets the number of edges a hole can have and still get filled. 0 disables hole filling.
+    pub fn with_max_hole_edges(mut self, edges: usize) -> Self {
+        self.max_hole_edges = edges;
+        self
+    }
+
+    pub fn max_hole_edges(&self) -> usize {
+        self.max_hole_edges
+    }
+}
+
+/// Faces as indexes into welded positions
+#[derive(Clone, Debug)]
+struct Soup {
+    positions: Vec<Vec3>,
+    triangles: Vec<([usize; 3], MaterialID)>,
+}
+
+/// Undirected edge of a material's shell, with the smaller vertex index first
+type EdgeKey = (MaterialID, usize, usize);
+
+impl Soup {
+    /// Gets the faces of a mesh, welding vertices with equal positions
+    fn from_mesh(mesh: &MaterialMesh) -> (Self, Vec<FaceID>) {
+        let mesh = mesh.mesh();
+        let mut positions = vec![];
+        let mut index_map = FnvHashMap::default();
+        let mut triangles = vec![];
+        let mut faces = vec![];
+
+        for f in mesh.face_iter() {
+            let (v0, v1, v2) = mesh.face_vertices(f);
+            let mut triangle = [0; 3];
+            for (index, v) in triangle.iter_mut().zip(&[v0, v1, v2]) {
+                let pos = mesh.vertex_position(*v);
+                *index = *index_map.entry(HashVec3(pos)).or_insert_with(|| {
+                    positions.push(pos);
+                    positions.len() - 1
+                });
+            }
+            triangles.push((triangle, mesh.face_tag(f)));
+            faces.push(f);
+        }
+
+        (Self { positions, triangles }, faces)
+    }
+
+    fn points(&self, triangle: usize) -> [Vec3; 3] {
+        let [a, b, c] = self.triangles[triangle].0;
+        [self.positions[a], self.positions[b], self.positions[c]]
+    }
+
+    /// Length of the diagonal of the bounding box
+    fn size(&self) -> f64 {
+        if self.positions.is_empty() {
+            return 0.0;
+        }
+
+        let mut min = self.positions[0];
+        let mut max = self.positions[0];
+        for pos in &self.positions {
+            for i in 0..3 {
+                min[i] = min[i].min(pos[i]);
+                max[i] = max[i].max(pos[i]);
+            }
+        }
+        (max - min).magnitude()
+    }
+
+    /// Whether a triangle has almost no area, given the size of the mesh
+    fn is_degenerate(&self, triangle: usize, size: f64) -> bool {
+        let [a, b, c] = self.points(triangle);
+        (b - a).cross(c - a).magnitude() / 2.0 <= DEGENERATE_AREA * size * size
+    }
+
+    /// Maps each undirected edge to the triangles using it and whether they go from the smaller index
+    fn edge_uses(&self) -> FnvHashMap<EdgeKey, Vec<(usize, bool)>> {
+        let mut uses = FnvHashMap::default();
+        for (t, (triangle, material)) in self.triangles.iter().enumerate() {
+            for i in 0..3 {
+                let (a, b) = (triangle[i], triangle[(i + 1) % 3]);
+                uses.entry((*material, a.min(b), a.max(b)))
+                    .or_insert_with(Vec::new)
+                    .push((t, a < b));
+            }
+        }
+        uses
+    }
+
+    /// Finds the triangles that need to be flipped so that each shell is consistently oriented
+    /// and faces outward, or inward for shells inside an odd number of other shells.
+    fn flips(&self) -> Vec<bool> {
+        let uses = self.edge_uses();
+        let mut flips = vec![false; self.triangles.len()];
+        let mut component = vec![usize::MAX; self.triangles.len()];
+        let mut components: Vec<Vec<usize>> = vec![];
+
+        for seed in 0..self.triangles.len() {
+            if component[seed] != usize::MAX {
+                continue;
+            }
+
+            let mut members = vec![];
+            let mut queue = VecDeque::new();
+            queue.push_back(seed);
+            component[seed] = components.len();
+
+            // Neighbors across a manifold edge should go the opposite way along it
+            while let Some(t) = queue.pop_front() {
+                members.push(t);
+                let (triangle, material) = self.triangles[t];
+                for i in 0..3 {
+                    let (a, b) = (triangle[i], triangle[(i + 1) % 3]);
+                    let edge_uses = &uses[&(material, a.min(b), a.max(b))];
+                    if edge_uses.len() != 2 {
+                        continue;
+                    }
+
+                    let (t_fwd, other) = if edge_uses[0].0 == t {
+                        (edge_uses[0].1, edge_uses[1])
+                    } else {
+                        (edge_uses[1].1, edge_uses[0])
+                    };
+                    if component[other.0] == usize::MAX {
+                        component[other.0] = components.len();
+                        flips[other.0] = flips[t] ^ (t_fwd == other.1);
+                        queue.push_back(other.0);
+                    }
+                }
+            }
+
+            components.push(members);
+        }
+
+        // Signed volume of each component after flipping
+        let volumes = components
+            .iter()
+            .map(|members| {
+                members
+                    .iter()
+                    .map(|t| {
+                        let [a, b, c] = self.points(*t);
+                        let volume = a.dot(b.cross(c)) / 6.0;
+                        if flips[*t] { -volume } else { volume }
+                    })
+                    .sum::<f64>()
+            })
+            .collect::<Vec<_>>();
+
+        for (i, members) in components.iter().enumerate() {
+            let material = self.triangles[members[0]].1;
+            let sample = {
+                let [a, b, c] = self.points(members[0]);
+                (a + b + c) / 3.0
+            };
+
+            // Count the other shells of the same material that contain this one
+            let depth = components
+                .iter()
+                .enumerate()
+                .filter(|(j, other)| *j != i && self.triangles[other[0]].1 == material)
+                .filter(|(_, other)| {
+                    let angle = other
+                        .iter()
+                        .map(|t| BvhTriangle::new(self.points(*t), material).solid_angle(sample))
+                        .sum::<f64>();
+                    angle.abs() > 2.0 * PI
+                })
+                .count();
+
+            let should_be_positive = depth % 2 == 0;
+            if volumes[i] != 0.0 && (volumes[i] > 0.0) != should_be_positive {
+                for t in members {
+                    flips[*t] = !flips[*t];
+                }
+            }
+        }
+
+        flips
+    }
+
+    /// Finds boundary loops of each material's shells. Each loop goes in the direction
+    /// its filling faces should go, so that filling it keeps orientation consistent.
+    fn holes(&self) -> Vec<(MaterialID, Vec<usize>)> {
+        let mut next: FnvHashMap<(MaterialID, usize), Vec<usize>> = FnvHashMap::default();
+        let mut starts = vec![];
+
+        for ((material, a, b), uses) in self.edge_uses() {
+            if let [(_, fwd)] = uses.as_slice() {
+                // The face goes a -> b, so the missing face goes b -> a
+                let (from, to) = if *fwd { (b, a) } else { (a, b) };
+                next.entry((material, from)).or_insert_with(Vec::new).push(to);
+                starts.push((material, from));
+            }
+        }
+        starts.sort();
+
+        let mut holes = vec![];
+        for (material, start) in starts {
+            let mut hole = vec![];
+            let mut current = start;
+            while let Some(to) = next.get_mut(&(material, current)).and_then(|to| to.pop()) {
+                hole.push(current);
+                current = to;
+                if current == start {
+                    break;
+                }
+            }
+
+            if !hole.is_empty() {
+                holes.push((material, hole));
+            }
+        }
+
+        holes
+    }
+
+    /// Finds pairs of triangles that cross each other.
+    /// Triangles sharing a vertex and coplanar triangles are skipped,
+    /// since shells of different materials touch along coplanar faces.
+    fn self_intersections(&self) -> Vec<(usize, usize)> {
+        let bounds = (0..self.triangles.len())
+            .map(|t| {
+                let points = self.points(t);
+                let mut min = points[0];
+                let mut max = points[0];
+                for p in &points[1..] {
+                    for i in 0..3 {
+                        min[i] = min[i].min(p[i]);
+                        max[i] = max[i].max(p[i]);
+                    }
+                }
+                (min, max)
+            })
+            .collect::<Vec<_>>();
+
+        let mut order = (0..self.triangles.len()).collect::<Vec<_>>();
+        order.sort_by_key(|t| FloatOrd(bounds[*t].0.x));
+
+        // Sweep along x, comparing each triangle to the ones whose x range it overlaps
+        let mut intersections = vec![];
+        for (i, t0) in order.iter().enumerate() {
+            let (min0, max0) = bounds[*t0];
+            for t1 in order[i + 1..].iter().take_while(|t1| bounds[**t1].0.x <= max0.x) {
+                let (min1, max1) = bounds[*t1];
+                if (1..3).any(|j| min1[j] > max0[j] || min0[j] > max1[j]) {
+                    continue;
+                }
+
+                let shares_vertex = self.triangles[*t0].0.iter().any(|v| self.triangles[*t1].0.contains(v));
+                if !shares_vertex && triangles_intersect(self.points(*t0), self.points(*t1)) {
+                    intersections.push(((*t0).min(*t1), (*t0).max(*t1)));
+                }
+            }
+        }
+
+        intersections.sort();
+        intersections
+    }
+
+    /// Welds vertices closer than some tolerance, keeping the first vertex of each cluster
+    fn weld(&mut self, tolerance: f64) {
+        if tolerance <= 0.0 {
+            return;
+        }
+
+        let cell = |pos: Vec3| {
+            let cell = pos / tolerance;
+            (cell.x.floor() as i64, cell.y.floor() as i64, cell.z.floor() as i64)
+        };
+
+        let mut grid: FnvHashMap<(i64, i64, i64), Vec<usize>> = FnvHashMap::default();
+        let mut positions = vec![];
+        let index_map = self
+            .positions
+            .iter()
+            .map(|pos| {
+                let (x, y, z) = cell(*pos);
+                let nearby = (-1..=1)
+                    .flat_map(|dx| (-1..=1).flat_map(move |dy| (-1..=1).map(move |dz| (x + dx, y + dy, z + dz))))
+                    .filter_map(|key| grid.get(&key))
+                    .flatten()
+                    .copied()
+                    .find(|i: &usize| (positions[*i] - *pos).magnitude() <= tolerance);
+
+                nearby.unwrap_or_else(|| {
+                    positions.push(*pos);
+                    grid.entry((x, y, z)).or_insert_with(Vec::new).push(positions.len() - 1);
+                    positions.len() - 1
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for (triangle, _) in &mut self.triangles {
+            for v in triangle.iter_mut() {
+                *v = index_map[*v];
+            }
+        }
+        self.positions = positions;
+    }
+
+    /// Removes degenerate triangles and duplicates of the same triangle with the same material
+    fn remove_bad_triangles(&mut self) {
+        let mut seen = FnvHashSet::default();
+        let triangles = std::mem::replace(&mut self.triangles, vec![]);
+
+        for (triangle, material) in triangles {
+            let [a, b, c] = triangle;
+            if a == b || b == c || c == a {
+                continue;
+            }
+
+            // Same triangle up to rotation
+            let min = (0..3).min_by_key(|i| triangle[*i]).unwrap();
+            let key = (material, triangle[min], triangle[(min + 1) % 3], triangle[(min + 2) % 3]);
+            if seen.insert(key) {
+                self.triangles.push((triangle, material));
+            }
+        }
+
+        let size = self.size();
+        let degenerate = (0..self.triangles.len())
+            .filter(|t| self.is_degenerate(*t, size))
+            .collect::<FnvHashSet<_>>();
+        let triangles = std::mem::replace(&mut self.triangles, vec![]);
+        self.triangles = triangles
+            .into_iter()
+            .enumerate()
+            .filter(|(t, _)| !degenerate.contains(t))
+            .map(|(_, triangle)| triangle)
+            .collect();
+    }
+
+    /// Fills holes with at most some number of edges with a fan around their centroid
+    fn fill_holes(&mut self, max_edges: usize) {
+        for (material, hole) in self.holes() {
+            if hole.len() < 3 || hole.len() > max_edges {
+                continue;
+            }
+
+            if hole.len() == 3 {
+                self.triangles.push(([hole[0], hole[1], hole[2]], material));
+                continue;
+            }
+
+            let centroid = hole.iter().map(|v| self.positions[*v]).sum::<Vec3>() / hole.len() as f64;
+            self.positions.push(centroid);
+            let center = self.positions.len() - 1;
+            for i in 0..hole.len() {
+                self.triangles.push(([hole[i], hole[(i + 1) % hole.len()], center], material));
+            }
+        }
+    }
+}
+
+impl MaterialMesh {
+    /// Checks whether the mesh is fit for voxelization: every material should be bounded by
+    /// closed, manifold, outward-facing shells without degenerate faces or self-intersections.
+    pub fn validate(&self) -> MeshValidation {
+        let (soup, faces) = Soup::from_mesh(self);
+
+        let holes = soup
+            .holes()
+            .into_iter()
+            .map(|(material, hole)| (material, hole.into_iter().map(|v| soup.positions[v]).collect()))
+            .collect();
+
+        let mut non_manifold_edges = soup
+            .edge_uses()
+            .into_iter()
+            .filter(|(_, uses)| uses.len() > 2)
+            .map(|((material, a, b), _)| (material, a, b))
+            .collect::<Vec<_>>();
+        non_manifold_edges.sort();
+        let non_manifold_edges = non_manifold_edges
+            .into_iter()
+            .map(|(material, a, b)| (material, [soup.positions[a], soup.positions[b]]))
+            .collect();
+
+        let flipped_faces = soup
+            .flips()
+            .into_iter()
+            .enumerate()
+            .filter(|(_, flip)| *flip)
+            .map(|(t, _)| faces[t])
+            .collect();
+
+        let size = soup.size();
+        let degenerate_faces = (0..soup.triangles.len())
+            .filter(|t| soup.is_degenerate(*t, size))
+            .map(|t| faces[t])
+            .collect();
+
+        let self_intersections = soup
+            .self_intersections()
+            .into_iter()
+            .map(|(t0, t1)| (faces[t0], faces[t1]))
+            .collect();
+
+        MeshValidation {
+            holes,
+            non_manifold_edges,
+            flipped_faces,
+            degenerate_faces,
+            self_intersections,
+        }
+    }
+
+    /// Fixes common problems with input meshes: welds near-duplicate vertices,
+    /// removes degenerate and duplicate faces, fills small holes, and orients shells consistently.
+    /// Non-manifold edges and self-intersections are left alone; check `validate` afterwards.
+    pub fn repair(&self, options: &RepairOptions) -> Result<Self, VoxelizationError> {
+        let (mut soup, _) = Soup::from_mesh(self);
+
+        let tolerance = options.weld_tolerance.unwrap_or_else(|| soup.size() * 1e-6);
+        soup.weld(tolerance);
+        soup.remove_bad_triangles();
+        if options.max_hole_edges > 0 {
+            soup.fill_holes(options.max_hole_edges);
+        }
+
+        let flips = soup.flips();
+        let mut indexes = vec![];
+        let mut tags = vec![];
+        for ((triangle, material), flip) in soup.triangles.iter().zip(flips) {
+            let [a, b, c] = *triangle;
+            let triangle = if flip { [a, c, b] } else { [a, b, c] };
+            indexes.extend(triangle.iter().map(|v| *v as u32));
+            tags.push(*material);
+        }
+
+        let mesh = MeshBuilder::new()
+            .with_positions(soup.positions.iter().flat_map(|pos| vec![pos.x, pos.y, pos.z]).collect())
+            .with_indices(indexes)
+            .with_tags(tags)
+            .build()?;
+
+        Ok(MaterialMesh::new(mesh).with_materials(self.materials().clone()))
+    }
+}
+
+/// Signed distances of some points from the plane of a triangle, scaled by the triangle's area.
+/// Distances too small to trust are snapped to 0.
+fn plane_distances(triangle: [Vec3; 3], points: [Vec3; 3]) -> [f64; 3] {
+    let normal = (triangle[1] - triangle[0]).cross(triangle[2] - triangle[0]);
+    let scale = triangle
+        .iter()
+        .chain(points.iter())
+        .map(|p| (*p - triangle[0]).magnitude())
+        .fold(0.0, f64::max);
+    let epsilon = 1e-10 * normal.magnitude() * scale;
+
+    let mut distances = [0.0; 3];
+    for (distance, p) in distances.iter_mut().zip(&points) {
+        let d = normal.dot(*p - triangle[0]);
+        *distance = if d.abs() <= epsilon { 0.0 } else { d };
+    }
+    distances
+}
+
+/// Interval along a line direction covered by the intersection of a triangle with a plane,
+/// given the signed distances of the triangle's vertices from the plane
+fn plane_interval(points: [Vec3; 3], distances: [f64; 3], dir: Vec3) -> (f64, f64) {
+    let mut min = f64::INFINITY;
+    let mut max = f64::NEG_INFINITY;
+    let mut add = |p: Vec3| {
+        let t = p.dot(dir);
+        min = min.min(t);
+        max = max.max(t);
+    };
+
+    for i in 0..3 {
+        let j = (i + 1) % 3;
+        if distances[i] == 0.0 {
+            add(points[i]);
+        } else if distances[i] * distances[j] < 0.0 {
+            let s = distances[i] / (distances[i] - distances[j]);
+            add(points[i] + (points[j] - points[i]) * s);
+        }
+    }
+
+    (min, max)
+}
+
+/// Whether two non-coplanar triangles cross each other.
+/// Coplanar triangles never count as intersecting.
+fn triangles_intersect(a: [Vec3; 3], b: [Vec3; 3]) -> bool {
+    let db = plane_distances(a, b);
+    if db.iter().all(|d| *d > 0.0) || db.iter().all(|d| *d < 0.0) || db.iter().all(|d| *d == 0.0) {
+        return false;
+    }
+
+    let da = plane_distances(b, a);
+    if da.iter().all(|d| *d > 0.0) || da.iter().all(|d| *d < 0.0) || da.iter().all(|d| *d == 0.0) {
+        return false;
+    }
+
+    // Both triangles cross the line where the planes meet; check whether their intervals on it overlap
+    let dir = (a[1] - a[0]).cross(a[2] - a[0]).cross((b[1] - b[0]).cross(b[2] - b[0]));
+    let (min_a, max_a) = plane_interval(a, da, dir);
+    let (min_b, max_b) = plane_interval(b, db, dir);
+    min_a < max_b && min_b < max_a
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::material_mesh::box_corners_and_triangles;
+
+    /// Unit cube with outward faces, optionally missing the top face
+    fn cube_triangles(top: bool) -> (Vec<f64>, Vec<u32>) {
+        let (corners, triangles) = box_corners_and_triangles(vec3(0.0, 0.0, 0.0), vec3(1.0, 1.0, 1.0));
+        let positions = corners.into_iter().flat_map(|p| vec![p.x, p.y, p.z]).collect();
+        let indexes = triangles
+            .into_iter()
+            .filter(|tri| top || !tri.iter().all(|v| v & 4 != 0))
+            .flat_map(|[a, b, c]| vec![a as u32, b as u32, c as u32])
+            .collect();
+        (positions, indexes)
+    }
+
+    fn build(positions: Vec<f64>, indexes: Vec<u32>) -> MaterialMesh {
+        MaterialMesh::new(
+            MeshBuilder::new()
+                .with_positions(positions)
+                .with_indices(indexes)
+                .build()
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_validate_closed_cube() {
+        let (positions, indexes) = cube_triangles(true);
+        let validation = build(positions, indexes).validate();
+        assert!(validation.is_valid(), "{}", validation);
+    }
+
+    #[test]
+    fn test_validate_and_repair_hole() {
+        let (positions, indexes) = cube_triangles(false);
+        let mesh = build(positions, indexes);
+
+        let validation = mesh.validate();
+        assert_eq!(validation.holes.len(), 1);
+        assert_eq!(validation.holes[0].1.len(), 4);
+
+        let repaired = mesh.repair(&RepairOptions::new()).unwrap();
+        assert!(repaired.validate().is_valid());
+        assert!((repaired.material_volumes()[&MaterialID::default()] - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_validate_and_repair_inside_out() {
+        let (positions, indexes) = cube_triangles(true);
+        let indexes = indexes.chunks_exact(3).flat_map(|t| vec![t[0], t[2], t[1]]).collect();
+        let mesh = build(positions, indexes);
+
+        assert_eq!(mesh.validate().flipped_faces.len(), 12);
+
+        let repaired = mesh.repair(&RepairOptions::new()).unwrap();
+        assert!(repaired.validate().is_valid());
+        assert!(repaired.material_volumes()[&MaterialID::default()] > 0.0);
+    }
+
+    #[test]
+    fn test_triangles_intersect() {
+        let a = [vec3(0.0, 0.0, 0.0), vec3(2.0, 0.0, 0.0), vec3(0.0, 2.0, 0.0)];
+        let crossing = [vec3(0.5, 0.5, -1.0), vec3(0.5, 0.5, 1.0), vec3(1.0, -1.0, 0.0)];
+        let above = [vec3(0.5, 0.5, 1.0), vec3(0.5, 0.5, 2.0), vec3(1.0, -1.0, 1.0)];
+        let coplanar = [vec3(0.5, 0.5, 0.0), vec3(1.5, 0.5, 0.0), vec3(0.5, 1.5, 0.0)];
+
+        assert!(triangles_intersect(a, crossing));
+        assert!(!triangles_intersect(a, above));
+        assert!(!triangles_intersect(a, coplanar));
+    }
+}
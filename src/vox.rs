@@ -0,0 +1,277 @@
+//! Export of voxelizations to MagicaVoxel's .vox format.
+//!
+//! The format is a RIFF-style list of chunks. Each model holds at most 256 voxels along each axis,
+//! so bigger voxelizations are split into several models placed by a scene graph.
+//! Voxels refer to colors by palette index, and palette index 0 means empty,
+//! which leaves room for 255 materials.
+
+use fnv::FnvHashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use tri_mesh::prelude::*;
+
+use crate::material_mesh::MaterialID;
+use crate::material_table::MaterialTable;
+use crate::voxels::{Vec3i, VoxelRef, Voxels};
+
+/// What to do with complex voxels, since .vox files only have pure voxels.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ComplexVoxelPolicy {
+    /// Use the material that takes up the most volume in the voxel
+    Majority,
+    /// Leave the voxel empty
+    Skip,
+}
+
+/// Maximum edge length of a model
+const MODEL_SIZE: i32 = 256;
+
+/// Version of the format written
+const VERSION: i32 = 150;
+
+/// A model and where its min corner is relative to the voxelization's min corner
+struct Model {
+    offset: Vec3i,
+    size: Vec3i,
+    /// Positions relative to the model, and palette indexes
+    voxels: Vec<([u8; 3], u8)>,
+}
+
+impl Voxels {
+    /// Exports pure voxels to a .vox file.
+    /// Each material gets its own palette entry, in order of material ID.
+    /// Fails if there are more than 255 materials.
+    pub fn export_vox<P: AsRef<Path>>(&self, path: P, policy: ComplexVoxelPolicy) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_vox(&mut writer, policy)?;
+        writer.flush()
+    }
+
+    /// Writes pure voxels in .vox format. See `export_vox`.
+    pub fn write_vox<W: Write>(&self, writer: &mut W, policy: ComplexVoxelPolicy) -> io::Result<()> {
+        let voxels = self
+            .iter()
+            .filter_map(|(pos, voxel)| match voxel {
+                VoxelRef::Pure(material) => Some((pos, material)),
+                VoxelRef::Complex(complex) if policy == ComplexVoxelPolicy::Majority => {
+                    complex.majority_material().map(|material| (pos, material))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let mut materials = voxels.iter().map(|(_, material)| *material).collect::<Vec<_>>();
+        materials.sort();
+        materials.dedup();
+        if materials.len() > 255 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(".vox files support at most 255 materials, but there are {}", materials.len()),
+            ));
+        }
+        let palette = materials
+            .iter()
+            .enumerate()
+            .map(|(i, material)| (*material, i as u8 + 1))
+            .collect::<FnvHashMap<_, _>>();
+
+        let models = split_into_models(&voxels, &palette);
+
+        let mut children = vec![];
+        for model in &models {
+            let mut size = vec![];
+            for i in 0..3 {
+                size.extend_from_slice(&model.size[i].to_le_bytes());
+            }
+            write_chunk(&mut children, b"SIZE", &size)?;
+
+            let mut xyzi = (model.voxels.len() as i32).to_le_bytes().to_vec();
+            for ([x, y, z], index) in &model.voxels {
+                xyzi.extend_from_slice(&[*x, *y, *z, *index]);
+            }
+            write_chunk(&mut children, b"XYZI", &xyzi)?;
+        }
+
+        if models.len() > 1 {
+            write_scene_graph(&mut children, &models)?;
+        }
+
+        write_chunk(&mut children, b"RGBA", &palette_colors(&materials, self.materials()))?;
+
+        writer.write_all(b"VOX ")?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(b"MAIN")?;
+        writer.write_all(&0i32.to_le_bytes())?;
+        writer.write_all(&(children.len() as i32).to_le_bytes())?;
+        writer.write_all(&children)
+    }
+}
+
+/// Splits voxels into models of at most 256 voxels along each axis.
+fn split_into_models(voxels: &[(Vec3i, MaterialID)], palette: &FnvHashMap<MaterialID, u8>) -> Vec<Model> {
+    let min = match voxels.first() {
+        Some((first, _)) => voxels.iter().fold(*first, |min, (pos, _)| {
+            vec3(min.x.min(pos.x), min.y.min(pos.y), min.z.min(pos.z))
+        }),
+        None => return vec![],
+    };
+
+    let mut models = FnvHashMap::<Vec3i, Model>::default();
+    for (pos, material) in voxels {
+        let rel = *pos - min;
+        let model_pos = vec3(rel.x / MODEL_SIZE, rel.y / MODEL_SIZE, rel.z / MODEL_SIZE);
+        let model = models.entry(model_pos).or_insert_with(|| Model {
+            offset: model_pos * MODEL_SIZE,
+            size: vec3(1, 1, 1),
+            voxels: vec![],
+        });
+
+        let local = rel - model.offset;
+        model.size = vec3(
+            model.size.x.max(local.x + 1),
+            model.size.y.max(local.y + 1),
+            model.size.z.max(local.z + 1),
+        );
+        model.voxels.push(([local.x as u8, local.y as u8, local.z as u8], palette[material]));
+    }
+
+    let mut models = models.into_iter().collect::<Vec<_>>();
+    models.sort_by_key(|(pos, _)| (pos.z, pos.y, pos.x));
+    models.into_iter().map(|(_, model)| model).collect()
+}
+
+/// Writes a chunk with no children
+fn write_chunk<W: Write>(writer: &mut W, id: &[u8; 4], content: &[u8]) -> io::Result<()> {
+    writer.write_all(id)?;
+    writer.write_all(&(content.len() as i32).to_le_bytes())?;
+    writer.write_all(&0i32.to_le_bytes())?;
+    writer.write_all(content)
+}
+
+fn write_string(content: &mut Vec<u8>, string: &str) {
+    content.extend_from_slice(&(string.len() as i32).to_le_bytes());
+    content.extend_from_slice(string.as_bytes());
+}
+
+fn write_dict(content: &mut Vec<u8>, entries: &[(&str, String)]) {
+    content.extend_from_slice(&(entries.len() as i32).to_le_bytes());
+    for (key, value) in entries {
+        write_string(content, key);
+        write_string(content, value);
+    }
+}
+
+/// Writes a transform node, a group node containing a transform and shape node per model.
+/// Node IDs are 0 for the root transform, 1 for the group, and 2i + 2 and 2i + 3 for model i.
+fn write_scene_graph<W: Write>(writer: &mut W, models: &[Model]) -> io::Result<()> {
+    let write_transform = |writer: &mut W, id: i32, child: i32, layer: i32, translation: Option<Vec3i>| {
+        let mut content = vec![];
+        content.extend_from_slice(&id.to_le_bytes());
+        write_dict(&mut content, &[]);
+        content.extend_from_slice(&child.to_le_bytes());
+        content.extend_from_slice(&(-1i32).to_le_bytes());
+        content.extend_from_slice(&layer.to_le_bytes());
+        content.extend_from_slice(&1i32.to_le_bytes());
+        match translation {
+            Some(t) => write_dict(&mut content, &[("_t", format!("{} {} {}", t.x, t.y, t.z))]),
+            None => write_dict(&mut content, &[]),
+        }
+        write_chunk(writer, b"nTRN", &content)
+    };
+
+    write_transform(writer, 0, 1, -1, None)?;
+
+    let mut group = vec![];
+    group.extend_from_slice(&1i32.to_le_bytes());
+    write_dict(&mut group, &[]);
+    group.extend_from_slice(&(models.len() as i32).to_le_bytes());
+    for i in 0..models.len() as i32 {
+        group.extend_from_slice(&(2 * i + 2).to_le_bytes());
+    }
+    write_chunk(writer, b"nGRP", &group)?;
+
+    for (i, model) in models.iter().enumerate() {
+        let i = i as i32;
+        // MagicaVoxel places a model by its center
+        let center = model.offset + vec3(model.size.x / 2, model.size.y / 2, model.size.z / 2);
+        write_transform(writer, 2 * i + 2, 2 * i + 3, 0, Some(center))?;
+
+        let mut shape = vec![];
+        shape.extend_from_slice(&(2 * i + 3).to_le_bytes());
+        write_dict(&mut shape, &[]);
+        shape.extend_from_slice(&1i32.to_le_bytes());
+        shape.extend_from_slice(&i.to_le_bytes());
+        write_dict(&mut shape, &[]);
+        write_chunk(writer, b"nSHP", &shape)?;
+    }
+
+    Ok(())
+}
+
+/// Colors for the palette, one per material, taken from the material table.
+/// The palette always has 256 entries, and entry i is used by palette index i + 1.
+fn palette_colors(materials: &[MaterialID], table: &MaterialTable) -> Vec<u8> {
+    let mut colors = vec![0; 256 * 4];
+
+    for (i, material) in materials.iter().enumerate() {
+        colors[i * 4..i * 4 + 4].copy_from_slice(&table.color(*material));
+    }
+
+    colors
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::material_table::MaterialInfo;
+
+    /// Reads the header of a chunk, returning its id, content, and the rest of the bytes
+    fn read_chunk(bytes: &[u8]) -> (&[u8], &[u8], &[u8]) {
+        let len = i32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as usize;
+        assert_eq!(&bytes[8..12], &0i32.to_le_bytes(), "Chunk shouldn't have children");
+        (&bytes[0..4], &bytes[12..12 + len], &bytes[12 + len..])
+    }
+
+    #[test]
+    fn test_write_vox() {
+        let m1 = MaterialID::new(1);
+        let m2 = MaterialID::new(2);
+        let mut voxels = Voxels::default();
+        voxels.set(vec3(0, 0, 0), Some(m1));
+        voxels.set(vec3(2, 1, 0), Some(m2));
+        for (material, color) in vec![(m1, [255, 0, 0, 255]), (m2, [0, 0, 255, 128])] {
+            let mut info = MaterialInfo::new(format!("{:?}", material));
+            info.color = Some(color);
+            voxels.materials_mut().insert(material, info);
+        }
+
+        let mut bytes = vec![];
+        voxels.write_vox(&mut bytes, ComplexVoxelPolicy::Majority).unwrap();
+
+        assert_eq!(&bytes[0..4], b"VOX ");
+        assert_eq!(&bytes[4..8], &150i32.to_le_bytes());
+        assert_eq!(&bytes[8..12], b"MAIN");
+        assert_eq!(&bytes[12..16], &0i32.to_le_bytes());
+        let children = &bytes[20..];
+        assert_eq!(&bytes[16..20], &(children.len() as i32).to_le_bytes());
+
+        let (id, size, rest) = read_chunk(children);
+        assert_eq!(id, b"SIZE");
+        let expected_size = [3i32, 2, 1].iter().flat_map(|i| i.to_le_bytes().to_vec()).collect::<Vec<_>>();
+        assert_eq!(size, &expected_size[..]);
+
+        let (id, xyzi, rest) = read_chunk(rest);
+        assert_eq!(id, b"XYZI");
+        assert_eq!(&xyzi[0..4], &2i32.to_le_bytes());
+        assert_eq!(&xyzi[4..], &[0, 0, 0, 1, 2, 1, 0, 2]);
+
+        // A single model doesn't need a scene graph
+        let (id, rgba, rest) = read_chunk(rest);
+        assert_eq!(id, b"RGBA");
+        assert_eq!(rgba.len(), 256 * 4);
+        assert_eq!(&rgba[0..8], &[255, 0, 0, 255, 0, 0, 255, 128]);
+        assert!(rgba[8..].iter().all(|b| *b == 0));
+        assert!(rest.is_empty());
+    }
+}
@@ -1,15 +1,23 @@
 use fnv::{FnvHashMap, FnvHashSet};
 use float_ord::FloatOrd;
 use rayon::prelude::*;
+use std::cmp::Reverse;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::num::NonZeroU32;
 use std::path::Path;
 use tri_mesh::prelude::*;
 use combination::combine;
 use bvh::bvh::BVH;
-use bvh::ray::Ray;
-use bvh::nalgebra::{Point3 as NPoint3, Vector3 as NVec3};
 
-use crate::material_mesh::{Axis, MaterialID, MaterialMesh, BvhTriangle};
+use crate::error::VoxelizationError;
+use crate::material_mesh::{closest_triangle, enclosing_material, Axis, MaterialID, MaterialMesh, BvhTriangle};
 use crate::plc::PiecewiseLinearComplex;
+use crate::predicates;
+use crate::tetrahedralize::{TetError, Tetrahedralization};
+use crate::triangulate::TriangulationMethod;
+use crate::material_table::{MaterialInfo, MaterialTable};
 use crate::util::HashVec3;
 
 pub type Vec3i = Vector3<i32>;
@@ -69,62 +77,351 @@ impl DebugMeshBuilder {
         self.positions.extend(positions.into_iter().flat_map(|p| vec![p.x, p.y, p.z].into_iter()));
     }
 
-    fn build(self) -> MaterialMesh {
-        MaterialMesh::new(
+    fn build(self) -> Result<MaterialMesh, VoxelizationError> {
+        Ok(MaterialMesh::new(
             MeshBuilder::new()
                 .with_positions(self.positions)
                 .with_indices(self.indexes)
                 .with_tags(self.materials)
-                .build()
-                .expect("Invalid mesh"),
-        )
+                .build()?,
+        ))
     }
 }
 
-/// Approximation to closest material. Checks only in orthodirections.
+/// Material of the volume enclosing a point.
+/// Falls back to the material of the closest surface if the point
+/// isn't strictly inside any material, which happens near the surface.
 fn closest_material(bvh: &BVH, triangles: &[BvhTriangle], point: Vec3) -> MaterialID {
-    vec![Vec3::unit_x(), Vec3::unit_y(), Vec3::unit_z(), -Vec3::unit_x(), -Vec3::unit_y(), -Vec3::unit_z()]
-        .into_iter()
-        .flat_map(|dir| {
-            let ray = Ray::new(
-                NPoint3::new(point.x as f32, point.y as f32, point.z as f32),
-                NVec3::new(dir.x as f32, dir.y as f32, dir.z as f32),
-            );
+    enclosing_material(bvh, triangles, point)
+        .or_else(|| closest_triangle(bvh, triangles, point).map(|(tri, _)| tri.material()))
+        .unwrap_or_default()
+}
 
-            bvh.traverse(&ray, triangles)
-                .into_iter()
-                .map(|tri| (
-                    tri.material(),
-                    tri.intersection_time(point, dir)
-                ))
-                .filter_map(|(mat, t)| Some((mat, t?)))
-                .min_by_key(|(_, t)| FloatOrd(*t))
+/// Ranges of faces along a row of voxels, with the in-out gradient of each material in each range
+type RowRanges = Vec<(f64, f64, Vec<(MaterialID, i32)>)>;
+
+/// How deep a sweep along a row is inside each material.
+/// Tracking each material separately gets walls between two materials right,
+/// where in/out parity would think the sweep leaves the model.
+#[derive(Clone, Debug, Default)]
+struct MaterialDepths(FnvHashMap<MaterialID, i32>);
+
+impl MaterialDepths {
+    /// Crosses a range of faces with some in-out gradients
+    fn cross(&mut self, gradients: &[(MaterialID, i32)]) {
+        for (material, grad) in gradients {
+            *self.0.entry(*material).or_insert(0) -= grad;
+        }
+    }
+
+    /// The material the sweep is in. Returns Ok(None) if it's outside everything,
+    /// and Err(()) if it's inside several materials at once, so the material needs another way to be decided.
+    fn material(&self) -> Result<Option<MaterialID>, ()> {
+        let mut inside = self.0.iter().filter(|(_, depth)| **depth > 0).map(|(material, _)| *material);
+        match (inside.next(), inside.next()) {
+            (None, _) => Ok(None),
+            (Some(material), None) => Ok(Some(material)),
+            (Some(_), Some(_)) => Err(()),
+        }
+    }
+}
+
+/// Maps between world coordinates and grid coordinates,
+/// where voxel (x, y, z) spans [x, x + 1] × [y, y + 1] × [z, z + 1] in grid coordinates.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GridTransform {
+    origin: Vec3,
+    voxel_size: Vec3,
+}
+
+impl Default for GridTransform {
+    fn default() -> Self {
+        Self {
+            origin: Vec3::zero(),
+            voxel_size: vec3(1.0, 1.0, 1.0),
+        }
+    }
+}
+
+impl GridTransform {
+    /// World position of the min corner of voxel (0, 0, 0)
+    pub fn origin(&self) -> Vec3 {
+        self.origin
+    }
+
+    /// Side lengths of a voxel along each axis
+    pub fn voxel_size(&self) -> Vec3 {
+        self.voxel_size
+    }
+
+    pub fn world_to_grid(&self, pos: Vec3) -> Vec3 {
+        let pos = pos - self.origin;
+        vec3(pos.x / self.voxel_size.x, pos.y / self.voxel_size.y, pos.z / self.voxel_size.z)
+    }
+
+    pub fn grid_to_world(&self, pos: Vec3) -> Vec3 {
+        vec3(pos.x * self.voxel_size.x, pos.y * self.voxel_size.y, pos.z * self.voxel_size.z) + self.origin
+    }
+
+    /// Gets the index of the voxel containing a world position
+    pub fn voxel_index(&self, pos: Vec3) -> Vec3i {
+        let pos = self.world_to_grid(pos);
+        vec3(pos.x.floor() as i32, pos.y.floor() as i32, pos.z.floor() as i32)
+    }
+
+    fn world_to_grid_matrix(&self) -> Mat4 {
+        Mat4::from_nonuniform_scale(1.0 / self.voxel_size.x, 1.0 / self.voxel_size.y, 1.0 / self.voxel_size.z)
+            * Mat4::from_translation(-self.origin)
+    }
+}
+
+/// Options for voxelizing a mesh.
+#[derive(Clone, Debug)]
+pub struct VoxelizationOptions {
+    transform: GridTransform,
+    /// World-space box to restrict the voxelization to
+    crop: Option<(Vec3, Vec3)>,
+    chunk_size: usize,
+    /// Whether to return an error instead of falling back when a complex voxel can't be tetrahedralized
+    fail_on_fallback: bool,
+    /// Whether to check the input mesh with `MaterialMesh::validate` first
+    validate_input: bool,
+    /// How to triangulate the parts of voxel faces inside the mesh
+    face_triangulation: TriangulationMethod,
+}
+
+impl Default for VoxelizationOptions {
+    fn default() -> Self {
+        Self {
+            transform: GridTransform::default(),
+            crop: None,
+            chunk_size: Chunk::DEFAULT_SIZE,
+            fail_on_fallback: false,
+            validate_input: false,
+            face_triangulation: TriangulationMethod::default(),
+        }
+    }
+}
+
+impl VoxelizationOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the edge length of a chunk in voxels.
+    /// Bigger chunks mean fewer chunks for big, simple models,
+    /// but a chunk can only be uniform if it fits entirely inside a material.
+    /// Voxelizing fails with `VoxelizationError::InvalidOptions` unless it's between 1 and `Chunk::MAX_SIZE`.
+    pub fn with_chunk_size(mut self, size: usize) -> Self {
+        self.chunk_size = size;
+        self
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Sets the side length of a voxel along all axes
+    pub fn with_voxel_size(self, size: f64) -> Self {
+        self.with_anisotropic_voxel_size(vec3(size, size, size))
+    }
+
+    /// Sets the side length of a voxel along each axis separately.
+    /// Voxelizing fails with `VoxelizationError::InvalidOptions` unless they're positive and finite.
+    pub fn with_anisotropic_voxel_size(mut self, size: Vec3) -> Self {
+        self.transform.voxel_size = size;
+        self
+    }
+
+    /// Sets the world position of the min corner of voxel (0, 0, 0)
+    pub fn with_origin(mut self, origin: Vec3) -> Self {
+        self.transform.origin = origin;
+        self
+    }
+
+    /// Only voxelizes voxels that intersect a world-space box
+    pub fn with_crop(mut self, min: Vec3, max: Vec3) -> Self {
+        self.crop = Some((min, max));
+        self
+    }
+
+    /// Makes voxelization fail when a complex voxel can't be tetrahedralized,
+    /// instead of falling back to the convex hull of the voxel's part of the mesh.
+    pub fn with_fail_on_fallback(mut self, fail: bool) -> Self {
+        self.fail_on_fallback = fail;
+        self
+    }
+
+    pub fn fail_on_fallback(&self) -> bool {
+        self.fail_on_fallback
+    }
+
+    /// Makes voxelization fail with `VoxelizationError::InvalidMesh` if the input mesh has holes,
+    /// bad orientation, or other problems that would make the result garbage.
+    /// Off by default, since checking for self-intersections takes a while on big meshes.
+    pub fn with_validate_input(mut self, validate: bool) -> Self {
+        self.validate_input = validate;
+        self
+    }
+
+    pub fn validate_input(&self) -> bool {
+        self.validate_input
+    }
+
+    /// Sets how to triangulate the parts of voxel faces inside the mesh when cutting out complex voxels.
+    /// `TriangulationMethod::ConstrainedDelaunay` makes fewer skinny triangles,
+    /// which helps tetrahedralization succeed.
+    pub fn with_face_triangulation(mut self, method: TriangulationMethod) -> Self {
+        self.face_triangulation = method;
+        self
+    }
+
+    pub fn face_triangulation(&self) -> TriangulationMethod {
+        self.face_triangulation
+    }
+
+    pub fn transform(&self) -> GridTransform {
+        self.transform
+    }
+
+    /// Fails if an option is out of range
+    fn check(&self) -> Result<(), VoxelizationError> {
+        let size = self.transform.voxel_size;
+        if ![size.x, size.y, size.z].iter().all(|s| *s > 0.0 && s.is_finite()) {
+            return Err(VoxelizationError::InvalidOptions(format!(
+                "voxel size ({}, {}, {}) must be positive and finite",
+                size.x, size.y, size.z
+            )));
+        }
+        if self.chunk_size == 0 || self.chunk_size > Chunk::MAX_SIZE {
+            return Err(VoxelizationError::InvalidOptions(format!(
+                "chunk size {} must be between 1 and {}",
+                self.chunk_size,
+                Chunk::MAX_SIZE
+            )));
+        }
+        Ok(())
+    }
+
+    /// Gets the crop box as a range of voxel indexes, min inclusive and max exclusive
+    fn grid_crop(&self) -> Option<(Vec3i, Vec3i)> {
+        self.crop.map(|(min, max)| {
+            let min = self.transform.world_to_grid(min);
+            let max = self.transform.world_to_grid(max);
+            (
+                vec3(min.x.floor() as i32, min.y.floor() as i32, min.z.floor() as i32),
+                vec3(max.x.ceil() as i32, max.y.ceil() as i32, max.z.ceil() as i32),
+            )
         })
-        .min_by_key(|(_, t)| FloatOrd(*t))
-        .unwrap_or_default()
-        .0
+    }
+}
+
+/// A complex voxel that couldn't be tetrahedralized,
+/// and was replaced by the convex hull of the voxel's part of the mesh
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Fallback {
+    /// Position in grid coordinates
+    pub pos: Vec3i,
+    pub error: TetError,
+    /// Volume of the convex hull minus the volume of the voxel's part of the mesh, in world units.
+    /// Positive when the fallback adds material that isn't there.
+    pub volume_error: f64,
+}
+
+/// Diagnostics collected during voxelization
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VoxelizationReport {
+    /// Fallbacks, sorted by position
+    pub fallbacks: Vec<Fallback>,
+}
+
+impl VoxelizationReport {
+    /// Total volume error over all fallbacks, in world units
+    pub fn total_volume_error(&self) -> f64 {
+        self.fallbacks.iter().map(|fallback| fallback.volume_error).sum()
+    }
+}
+
+/// Checks whether a voxel is inside a crop range
+fn in_crop(crop: Option<(Vec3i, Vec3i)>, pos: Vec3i) -> bool {
+    crop.map(|(min, max)| {
+        (0..3).all(|i| min[i] <= pos[i] && pos[i] < max[i])
+    }).unwrap_or(true)
 }
 
 /// The voxelization. Includes chunks, pure voxels, and complex voxels.
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Voxels {
     chunks: FnvHashMap<Vec3i, Chunk>,
+    transform: GridTransform,
+    /// Edge length of a chunk in voxels
+    chunk_size: usize,
+    materials: MaterialTable,
+}
+
+impl Default for Voxels {
+    fn default() -> Self {
+        Self::new(GridTransform::default(), Chunk::DEFAULT_SIZE)
+    }
 }
 
 impl Voxels {
-    fn new() -> Self {
-        Self::default()
+    fn new(transform: GridTransform, chunk_size: usize) -> Self {
+        Self {
+            chunks: FnvHashMap::default(),
+            transform,
+            chunk_size,
+            materials: MaterialTable::default(),
+        }
+    }
+
+    /// Edge length of a chunk in voxels
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Iterates over the chunks along with their positions in chunks
+    pub(crate) fn chunks(&self) -> impl Iterator<Item = (Vec3i, &Chunk)> + '_ {
+        self.chunks.iter().map(|(pos, chunk)| (*pos, chunk))
+    }
+
+    /// Gets the number of uniform chunks and the number of complex chunks.
+    /// Useful for checking how much a chunk size benefits from uniform chunk detection.
+    pub fn chunk_counts(&self) -> (usize, usize) {
+        let uniform = self.chunks.values().filter(|chunk| chunk.is_uniform()).count();
+        (uniform, self.chunks.len() - uniform)
+    }
+
+    /// The mapping between world and grid coordinates
+    pub fn transform(&self) -> GridTransform {
+        self.transform
     }
 
-    /// Export this voxelization as an obj for debugging
+    /// Names and properties of the materials, usually copied from the voxelized mesh
+    pub fn materials(&self) -> &MaterialTable {
+        &self.materials
+    }
+
+    pub fn materials_mut(&mut self) -> &mut MaterialTable {
+        &mut self.materials
+    }
+
+    /// Export this voxelization as an obj for debugging.
+    /// Panics if the files can't be written; see `export_obj`.
     pub fn export_debug_obj<P: AsRef<Path> + Clone>(&self, path: P) {
+        self.export_obj(path).expect("Could not debug obj");
+    }
+
+    /// Exports this voxelization as an OBJ file of cubes and complex voxel boundaries,
+    /// along with an MTL file with the material names and colors
+    pub fn export_obj<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
         let mut builder = DebugMeshBuilder::new();
 
         for (chunk_pos, chunk) in &self.chunks {
             match chunk {
                 Chunk::Uniform(material) => builder.add_cube(
-                    chunk_pos.cast().unwrap() * Chunk::SIZE as f64,
-                    Chunk::SIZE as f64,
+                    chunk_pos.cast().unwrap() * self.chunk_size as f64,
+                    self.chunk_size as f64,
                     *material,
                 ),
 
@@ -140,12 +437,12 @@ impl Voxels {
             if (i + 1) * 100 / len > i * 100 / len {
                 //println!("Vertices: {}%", (i + 1) * 100 / len);
             }
-            output += &format!(
-                "v {} {} {}\n",
+            let pos = self.transform.grid_to_world(vec3(
                 positions[i * 3],
                 positions[i * 3 + 1],
-                positions[i * 3 + 2]
-            );
+                positions[i * 3 + 2],
+            ));
+            output += &format!("v {} {} {}\n", pos.x, pos.y, pos.z);
         }
 
         let mut indexes = builder.indexes.chunks_exact(3).enumerate().collect::<Vec<_>>();
@@ -163,8 +460,8 @@ impl Voxels {
             }
 
             if i == 0 || sorted_mats[i] != sorted_mats[i - 1] {
-                let mat = sorted_mats[i].0.get() - 1;
-                output += &format!("usemtl mat{}\n", mat);
+                let mat = sorted_mats[i];
+                output += &format!("usemtl {}\n", self.materials.obj_name(mat));
                 materials.push(mat);
             }
 
@@ -176,55 +473,395 @@ impl Voxels {
             output += &format!("f{}\n", face);
         }
 
-        let mut mtl = String::new();
+        let mtl = self.materials.to_mtl(materials);
 
-        let wheel = vec![vec3(1.0, 0.0, 0.0), vec3(1.0, 1.0, 0.0), vec3(0.0, 1.0, 0.0),
-            vec3(0.0, 1.0, 1.0), vec3(0.0, 0.0, 1.0), vec3(1.0, 0.0, 1.0), vec3(1.0, 0.0, 0.0)];
-        
-        let len = materials.len();
-        for (i, mat) in materials.into_iter().enumerate() {
-            mtl += &format!("newmtl mat{}\n", mat);
-            
-            let hue = i as f64 / len as f64;
-            let index = (6.0 * hue).floor() as usize;
-            let frac = 6.0 * hue - index as f64;
-            let color = wheel[index].lerp(wheel[index + 1], frac);
-            mtl += &format!("Kd {} {} {}", color.x, color.y, color.z);
-            mtl += "\n";
-        }
-
-        std::fs::write(path.clone(), output).expect("Could not debug obj");
         let path = path.as_ref();
-        std::fs::write(path.with_extension("mtl"), mtl).expect("Could not debug mtl");
+        std::fs::write(path, output)?;
+        std::fs::write(path.with_extension("mtl"), mtl)
+    }
+
+    /// Splits a voxel position into the position of its chunk and its offset in the chunk
+    fn chunk_pos_and_offset(&self, pos: Vec3i) -> (Vec3i, Vec3i) {
+        let size = self.chunk_size as i32;
+        (
+            vec3(pos.x.div_euclid(size), pos.y.div_euclid(size), pos.z.div_euclid(size)),
+            vec3(pos.x.rem_euclid(size), pos.y.rem_euclid(size), pos.z.rem_euclid(size)),
+        )
+    }
+
+    /// Gets the voxel at some position in grid coordinates.
+    /// Use `GridTransform::voxel_index` to find the voxel containing a world position.
+    pub fn get(&self, pos: Vec3i) -> VoxelRef {
+        let (chunk_pos, offset) = self.chunk_pos_and_offset(pos);
+
+        match self.chunks.get(&chunk_pos) {
+            None => VoxelRef::Empty,
+            Some(Chunk::Uniform(material)) => VoxelRef::Pure(*material),
+            Some(Chunk::Complex(chunk)) => chunk.voxel_ref(offset),
+        }
+    }
+
+    /// Gets the bounds of the non-empty voxels as (min inclusive, max exclusive),
+    /// or None if there are no non-empty voxels.
+    pub fn bounds(&self) -> Option<(Vec3i, Vec3i)> {
+        let size = self.chunk_size as i32;
+
+        self.chunks
+            .iter()
+            .filter_map(|(chunk_pos, chunk)| match chunk {
+                Chunk::Uniform(_) => Some((*chunk_pos * size, (*chunk_pos + vec3(1, 1, 1)) * size)),
+                Chunk::Complex(chunk) => chunk.bounds().map(|(min, max)| (*chunk_pos * size + min, *chunk_pos * size + max)),
+            })
+            .fold(None, |bounds, (min, max)| match bounds {
+                None => Some((min, max)),
+                Some((b_min, b_max)) => Some((
+                    vec3(b_min.x.min(min.x), b_min.y.min(min.y), b_min.z.min(min.z)),
+                    vec3(b_max.x.max(max.x), b_max.y.max(max.y), b_max.z.max(max.z)),
+                )),
+            })
+    }
+
+    /// Iterates over all non-empty voxels along with their positions in grid coordinates.
+    /// The order is unspecified.
+    pub fn iter(&self) -> impl Iterator<Item = (Vec3i, VoxelRef)> + '_ {
+        let size = self.chunk_size as i32;
+
+        self.chunks.iter().flat_map(move |(chunk_pos, chunk)| {
+            let chunk_min = *chunk_pos * size;
+
+            chunk_offsets(self.chunk_size).filter_map(move |offset| {
+                let voxel = match chunk {
+                    Chunk::Uniform(material) => VoxelRef::Pure(*material),
+                    Chunk::Complex(chunk) => chunk.voxel_ref(offset),
+                };
+
+                if voxel.is_empty() {
+                    None
+                } else {
+                    Some((chunk_min + offset, voxel))
+                }
+            })
+        })
+    }
+
+    /// Sets a voxel to be pure with some material, or empty if the material is None.
+    /// Replaces complex voxels.
+    pub fn set(&mut self, pos: Vec3i, material: Option<MaterialID>) {
+        let (chunk_pos, offset) = self.chunk_pos_and_offset(pos);
+
+        if let Some(chunk) = self.complex_chunk_mut(chunk_pos, material) {
+            chunk.set_pure(offset, material);
+            self.collapse_chunk(chunk_pos);
+        }
+    }
+
+    /// Empties a voxel
+    pub fn clear(&mut self, pos: Vec3i) {
+        self.set(pos, None);
+    }
+
+    /// Sets all voxels in a box (min inclusive, max exclusive) to be pure with some material,
+    /// or empty if the material is None.
+    pub fn fill_box(&mut self, min: Vec3i, max: Vec3i, material: Option<MaterialID>) {
+        if (0..3).any(|i| min[i] >= max[i]) {
+            return;
+        }
+
+        let size = self.chunk_size as i32;
+        let (chunk_min, _) = self.chunk_pos_and_offset(min);
+        let (chunk_max, _) = self.chunk_pos_and_offset(max - vec3(1, 1, 1));
+
+        for chunk_z in chunk_min.z..=chunk_max.z {
+            for chunk_y in chunk_min.y..=chunk_max.y {
+                for chunk_x in chunk_min.x..=chunk_max.x {
+                    let chunk_pos = vec3(chunk_x, chunk_y, chunk_z);
+                    // Part of the box in this chunk, as offsets
+                    let box_min = vec3(
+                        (min.x - chunk_x * size).max(0),
+                        (min.y - chunk_y * size).max(0),
+                        (min.z - chunk_z * size).max(0),
+                    );
+                    let box_max = vec3(
+                        (max.x - chunk_x * size).min(size),
+                        (max.y - chunk_y * size).min(size),
+                        (max.z - chunk_z * size).min(size),
+                    );
+
+                    // Whole chunk gets replaced
+                    if box_min == vec3(0, 0, 0) && box_max == vec3(size, size, size) {
+                        match material {
+                            Some(material) => self.chunks.insert(chunk_pos, Chunk::Uniform(material)),
+                            None => self.chunks.remove(&chunk_pos),
+                        };
+                        continue;
+                    }
+
+                    if let Some(chunk) = self.complex_chunk_mut(chunk_pos, material) {
+                        for z in box_min.z..box_max.z {
+                            for y in box_min.y..box_max.y {
+                                for x in box_min.x..box_max.x {
+                                    chunk.set_pure(vec3(x, y, z), material);
+                                }
+                            }
+                        }
+                        self.collapse_chunk(chunk_pos);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Gets a chunk as a complex chunk so a voxel in it can be set to some material,
+    /// splitting it if it's uniform and creating it if it doesn't exist.
+    /// Returns None if the voxels in the chunk are already that material.
+    fn complex_chunk_mut(&mut self, chunk_pos: Vec3i, material: Option<MaterialID>) -> Option<&mut ComplexChunk> {
+        let size = self.chunk_size;
+
+        match self.chunks.get(&chunk_pos) {
+            None if material.is_none() => return None,
+            Some(Chunk::Uniform(uniform)) if Some(*uniform) == material => return None,
+            _ => {}
+        }
+
+        let chunk = self.chunks
+            .entry(chunk_pos)
+            .or_insert_with(|| Chunk::Complex(ComplexChunk::new(size)));
+
+        if let Chunk::Uniform(uniform) = chunk {
+            let uniform = *uniform;
+            *chunk = Chunk::Complex(ComplexChunk::filled(size, Some(uniform)));
+        }
+
+        match chunk {
+            Chunk::Complex(chunk) => Some(chunk),
+            Chunk::Uniform(_) => unreachable!(),
+        }
+    }
+
+    /// Turns a complex chunk back into a uniform chunk if all its voxels are pure with the same material,
+    /// or removes it if they are all empty.
+    fn collapse_chunk(&mut self, chunk_pos: Vec3i) {
+        let uniform = match self.chunks.get(&chunk_pos) {
+            Some(Chunk::Complex(chunk)) => chunk.uniform_material(),
+            _ => None,
+        };
+
+        match uniform {
+            Some(Some(material)) => {
+                self.chunks.insert(chunk_pos, Chunk::Uniform(material));
+            }
+            Some(None) => {
+                self.chunks.remove(&chunk_pos);
+            }
+            None => {}
+        }
+    }
+
+    /// Magic bytes at the start of a saved voxelization
+    const MAGIC: &'static [u8; 4] = b"VXLZ";
+    /// Version of the binary format. Bump when the layout changes.
+    /// Version 1 is the same as version 2 without the material table.
+    const FORMAT_VERSION: u32 = 2;
+
+    /// Saves the voxelization in a compact binary format that `load` can read back exactly.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.flush()
+    }
+
+    /// Loads a voxelization saved with `save`.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::read_from(&mut BufReader::new(File::open(path)?))
+    }
+
+    /// Writes the voxelization in binary. All numbers are little-endian.
+    ///
+    /// * magic `VXLZ`, format version (u32)
+    /// * grid origin and voxel size (3 f64 each), chunk size (u32)
+    /// * material count (u32), then each material sorted by ID:
+    ///   ID (u32), name length (u32) and UTF-8 name, flags (u8),
+    ///   then color (4 u8) if bit 0 is set, density (f64) if bit 1 is set, and extruder (u32) if bit 2 is set
+    /// * chunk count (u64), then each chunk sorted by position:
+    ///   position (3 i32), then either 0 (u8) and a material (u32) for a uniform chunk,
+    ///   or 1 (u8), the voxels in z-major order and the complex voxels for a complex chunk.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(Self::MAGIC)?;
+        write_u32(writer, Self::FORMAT_VERSION)?;
+
+        write_vec3(writer, self.transform.origin)?;
+        write_vec3(writer, self.transform.voxel_size)?;
+        write_u32(writer, self.chunk_size as u32)?;
+
+        write_u32(writer, self.materials.iter().count() as u32)?;
+        for (id, info) in self.materials.iter() {
+            write_u32(writer, id.0.get())?;
+            write_u32(writer, info.name.len() as u32)?;
+            writer.write_all(info.name.as_bytes())?;
+
+            let flags = info.color.is_some() as u8
+                | (info.density.is_some() as u8) << 1
+                | (info.extruder.is_some() as u8) << 2;
+            writer.write_all(&[flags])?;
+            if let Some(color) = info.color {
+                writer.write_all(&color)?;
+            }
+            if let Some(density) = info.density {
+                writer.write_all(&density.to_le_bytes())?;
+            }
+            if let Some(extruder) = info.extruder {
+                write_u32(writer, extruder)?;
+            }
+        }
+
+        // Sorted so that equal voxelizations give equal files
+        let mut positions = self.chunks.keys().copied().collect::<Vec<_>>();
+        positions.sort_by_key(|pos| (pos.z, pos.y, pos.x));
+
+        writer.write_all(&(positions.len() as u64).to_le_bytes())?;
+        for pos in positions {
+            for i in 0..3 {
+                writer.write_all(&pos[i].to_le_bytes())?;
+            }
+
+            match &self.chunks[&pos] {
+                Chunk::Uniform(material) => {
+                    writer.write_all(&[0])?;
+                    write_u32(writer, material.0.get())?;
+                }
+
+                Chunk::Complex(chunk) => {
+                    writer.write_all(&[1])?;
+                    chunk.write_to(writer)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads a voxelization written by `write_to`.
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != Self::MAGIC {
+            return Err(invalid_data("Not a voxelization file"));
+        }
+
+        let version = read_u32(reader)?;
+        if version != 1 && version != Self::FORMAT_VERSION {
+            return Err(invalid_data(format!("Unsupported voxelization format version {}", version)));
+        }
+
+        let transform = GridTransform {
+            origin: read_vec3(reader)?,
+            voxel_size: read_vec3(reader)?,
+        };
+        let origin = transform.origin;
+        let voxel_size = transform.voxel_size;
+        if !(origin.x.is_finite() && origin.y.is_finite() && origin.z.is_finite()) {
+            return Err(invalid_data("Grid origin isn't finite"));
+        }
+        if !(voxel_size.x > 0.0 && voxel_size.y > 0.0 && voxel_size.z > 0.0)
+            || !(voxel_size.x.is_finite() && voxel_size.y.is_finite() && voxel_size.z.is_finite())
+        {
+            return Err(invalid_data("Voxel size must be positive and finite"));
+        }
+
+        let chunk_size = read_u32(reader)? as usize;
+        if chunk_size == 0 || chunk_size > Chunk::MAX_SIZE {
+            return Err(invalid_data(format!("Chunk size {} is out of range", chunk_size)));
+        }
+
+        let mut voxels = Self::new(transform, chunk_size);
+
+        if version >= 2 {
+            for _ in 0..read_u32(reader)? {
+                let id = read_material(reader)?;
+
+                // Read through `take` so a corrupt length can't allocate a huge buffer up front
+                let len = read_u32(reader)? as u64;
+                let mut name = vec![];
+                reader.by_ref().take(len).read_to_end(&mut name)?;
+                if name.len() as u64 != len {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                let mut info = MaterialInfo::new(String::from_utf8(name).map_err(invalid_data)?);
+
+                let mut flags = [0];
+                reader.read_exact(&mut flags)?;
+                if flags[0] & 1 != 0 {
+                    let mut color = [0; 4];
+                    reader.read_exact(&mut color)?;
+                    info.color = Some(color);
+                }
+                if flags[0] & 2 != 0 {
+                    let mut bytes = [0; 8];
+                    reader.read_exact(&mut bytes)?;
+                    info.density = Some(f64::from_le_bytes(bytes));
+                }
+                if flags[0] & 4 != 0 {
+                    info.extruder = Some(read_u32(reader)?);
+                }
+
+                voxels.materials.insert(id, info);
+            }
+        }
+
+        let mut len = [0; 8];
+        reader.read_exact(&mut len)?;
+        for _ in 0..u64::from_le_bytes(len) {
+            let pos = vec3(read_i32(reader)?, read_i32(reader)?, read_i32(reader)?);
+
+            let mut kind = [0];
+            reader.read_exact(&mut kind)?;
+            let chunk = match kind[0] {
+                0 => Chunk::Uniform(read_material(reader)?),
+                1 => Chunk::Complex(ComplexChunk::read_from(reader, chunk_size)?),
+                kind => return Err(invalid_data(format!("Invalid chunk kind {}", kind))),
+            };
+
+            voxels.chunks.insert(pos, chunk);
+        }
 
-        //builder.build().export_debug_obj(path)
+        Ok(voxels)
     }
 
-    fn fill_uniform_chunks(&mut self, ranges_yz: Vec<(f64, f64, Vec<(f64, f64, i32)>)>, bvh: &BVH, tris: &[BvhTriangle]) {
+    fn fill_uniform_chunks(
+        &mut self,
+        ranges_yz: Vec<(f64, f64, RowRanges)>,
+        bvh: &BVH,
+        tris: &[BvhTriangle],
+        crop: Option<(Vec3i, Vec3i)>,
+    ) {
+        let size = self.chunk_size as i32;
+
         for (y, z, ranges) in &ranges_yz {
-            let chunk_y = (*y as i32).div_euclid(Chunk::SIZE as i32);
-            let chunk_z = (*z as i32).div_euclid(Chunk::SIZE as i32);
-            let mut inside = 0;
+            let chunk_y = (*y as i32).div_euclid(size);
+            let chunk_z = (*z as i32).div_euclid(size);
+            let mut depths = MaterialDepths::default();
             let mut start = 0;
 
-            for (min, max, grad) in ranges {
-                if inside != 0 {
-                    let end = (*min / Chunk::SIZE as f64).floor() as i32;
+            for (min, max, gradients) in ranges {
+                if depths.material() != Ok(None) {
+                    let end = (*min / self.chunk_size as f64).floor() as i32;
 
                     for chunk_x in start..end {
-                        self.chunks.insert(
-                            vec3(chunk_x, chunk_y, chunk_z),
-                            // Placeholder until multimaterial works
-                            Chunk::Uniform(closest_material(bvh, tris, 
-                                vec3(chunk_x, chunk_y, chunk_z).cast::<f64>().unwrap() * Chunk::SIZE as f64
-                                    + vec3(Chunk::SIZE / 2, Chunk::SIZE / 2, Chunk::SIZE / 2).cast::<f64>().unwrap()
-                            )),
-                        );
+                        // Chunks sticking out of the crop box get filled with pure voxels instead
+                        let chunk_min = vec3(chunk_x, chunk_y, chunk_z) * size;
+                        if !in_crop(crop, chunk_min) || !in_crop(crop, chunk_min + vec3(size - 1, size - 1, size - 1)) {
+                            continue;
+                        }
+
+                        let material = depths.material().ok().flatten().unwrap_or_else(|| {
+                            closest_material(bvh, tris, chunk_min.cast::<f64>().unwrap() + vec3(0.5, 0.5, 0.5) * size as f64)
+                        });
+                        self.chunks.insert(vec3(chunk_x, chunk_y, chunk_z), Chunk::Uniform(material));
                     }
                 }
 
-                inside = (inside + grad).rem_euclid(2);
-                start = (*max / Chunk::SIZE as f64).ceil() as i32;
+                depths.cross(gradients);
+                start = (*max / self.chunk_size as f64).ceil() as i32;
             }
         }
     }
@@ -233,11 +870,20 @@ impl Voxels {
         &self,
         slices: Vec<(Vec3, MaterialMesh)>,
         mut chunks: FnvHashMap<Vec3i, ComplexChunk>,
-    ) -> FnvHashMap<Vec3i, ComplexChunk> {
-        let chunk_size = Chunk::SIZE as i32;
+        crop: Option<(Vec3i, Vec3i)>,
+        fail_on_fallback: bool,
+        face_triangulation: TriangulationMethod,
+    ) -> Result<(FnvHashMap<Vec3i, ComplexChunk>, Vec<Fallback>), VoxelizationError> {
+        let chunk_size = self.chunk_size as i32;
+        let mut fallbacks = vec![];
 
         for (pos, slice) in slices {
-            let mut mesh = slice.intersect_unit_cube(pos);
+            let voxel_pos = pos.cast::<i32>().unwrap();
+            if !in_crop(crop, voxel_pos) {
+                continue;
+            }
+
+            let mut mesh = slice.intersect_unit_cube_with(pos, face_triangulation).map_err(|e| e.at_voxel(voxel_pos))?;
             // Collapse 0-area edges
             //mesh.mesh_mut().collapse_small_faces(f64::MIN_POSITIVE);
             mesh.collapse_small_edges();
@@ -249,35 +895,65 @@ impl Voxels {
 
             let mut plc = PiecewiseLinearComplex::new(MaterialMesh::new(mesh.mesh().translated(-pos)));
             plc.dissolve();
-            let hulls = match plc.tetrahedralize() {
-                Ok(tets) => tets.convex_hulls(),
-                Err(error) => {
-                    eprintln!("Fallback to single convex hull at {:?} because {:?}", pos, error);
-                    vec![ mesh.mesh().vertex_iter().map(|v| mesh.mesh().vertex_position(v) - pos).collect() ]
-                }
-            };
+            let tets = plc.tetrahedralize();
+            let (voxel, fallback) = self.complex_voxel_or_fallback(&mesh, pos, tets, fail_on_fallback)?;
+            fallbacks.extend(fallback);
 
-            let pos = pos.cast::<i32>().unwrap();
+            let pos = voxel_pos;
             let chunk_pos = vec3(pos.x.div_euclid(chunk_size), pos.y.div_euclid(chunk_size), pos.z.div_euclid(chunk_size));
             let in_pos = vec3(pos.x.rem_euclid(chunk_size), pos.y.rem_euclid(chunk_size), pos.z.rem_euclid(chunk_size));
 
-            let chunk = chunks.entry(chunk_pos).or_insert(ComplexChunk::new());
+            let chunk = chunks.entry(chunk_pos).or_insert_with(|| ComplexChunk::new(self.chunk_size));
             *chunk.voxel_mut(in_pos) = Voxel::Complex(chunk.complex.len() as u32);
-            chunk.complex.push(ComplexVoxel::new(hulls));
+            chunk.complex.push(voxel);
         }
 
-        chunks
+        Ok((chunks, fallbacks))
+    }
+
+    /// Turns the tetrahedralization of the part of the mesh in the voxel at `pos` into a complex voxel.
+    /// If tetrahedralization failed, either fails too or falls back to the convex hull
+    /// of the part of the mesh, and returns the fallback to report.
+    fn complex_voxel_or_fallback(
+        &self,
+        mesh: &MaterialMesh,
+        pos: Vec3,
+        tets: Result<Tetrahedralization, TetError>,
+        fail_on_fallback: bool,
+    ) -> Result<(ComplexVoxel, Option<Fallback>), VoxelizationError> {
+        let voxel_pos = pos.cast::<i32>().unwrap();
+        let error = match tets {
+            Ok(tets) => return Ok((ComplexVoxel::new(tets.convex_hulls()), None)),
+            Err(error) if fail_on_fallback => return Err(VoxelizationError::from(error).at_voxel(voxel_pos)),
+            Err(error) => error,
+        };
+
+        let voxel = ComplexVoxel::new(vec![(
+            mesh.mesh().vertex_iter().map(|v| mesh.mesh().vertex_position(v) - pos).collect(),
+            mesh.dominant_material(),
+        )]);
+
+        let voxel_size = self.transform.voxel_size();
+        let hull_volume = voxel.hull_volumes().map(|(volume, _)| volume).sum::<f64>();
+        let mesh_volume = mesh.material_volumes().values().sum::<f64>();
+        let fallback = Fallback {
+            pos: voxel_pos,
+            error,
+            volume_error: (hull_volume - mesh_volume) * voxel_size.x * voxel_size.y * voxel_size.z,
+        };
+        Ok((voxel, Some(fallback)))
     }
 
     fn ranges_to_complex_chunks(
         &self,
-        ranges_yz: Vec<(f64, f64, Vec<(f64, f64, i32)>)>,
+        ranges_yz: Vec<(f64, f64, RowRanges)>,
         bvh: &BVH,
         tris: &[BvhTriangle],
+        crop: Option<(Vec3i, Vec3i)>,
     ) -> FnvHashMap<Vec3i, ComplexChunk> {
         let mut chunks: FnvHashMap<Vec3i, ComplexChunk> = FnvHashMap::default();
-        let mut dummy = ComplexChunk::new();
-        let chunk_size = Chunk::SIZE as i32;
+        let mut dummy = ComplexChunk::new(self.chunk_size);
+        let chunk_size = self.chunk_size as i32;
 
         for (y, z, ranges) in &ranges_yz {
             let chunk_y = (*y as i32).div_euclid(chunk_size);
@@ -285,14 +961,22 @@ impl Voxels {
             let mod_y = (*y as i32).rem_euclid(chunk_size);
             let mod_z = (*z as i32).rem_euclid(chunk_size);
 
-            let mut inside = 0;
+            let mut depths = MaterialDepths::default();
             let mut start = 0i32;
 
             let mut chunk = &mut dummy;
 
-            for (min, max, grad) in ranges {
-                if inside != 0 {
-                    let end = min.floor() as i32;
+            // Range of x values in the crop box, if the row is in it at all
+            let (crop_start, crop_end) = match crop {
+                Some((min, _)) if !in_crop(crop, vec3(min.x, *y as i32, *z as i32)) => continue,
+                Some((min, max)) => (min.x, max.x),
+                None => (i32::MIN, i32::MAX),
+            };
+
+            for (min, max, gradients) in ranges {
+                if depths.material() != Ok(None) {
+                    let start = start.max(crop_start);
+                    let end = (min.floor() as i32).min(crop_end);
 
                     let mut x = start;
 
@@ -311,42 +995,66 @@ impl Voxels {
 
                             chunk = chunks
                                 .entry(vec3(x.div_euclid(chunk_size), chunk_y, chunk_z))
-                                .or_insert(ComplexChunk::new());
+                                .or_insert_with(|| ComplexChunk::new(self.chunk_size));
                         }
 
-                        *chunk.voxel_mut(vec3(x.rem_euclid(chunk_size), mod_y, mod_z)) =
-                            Voxel::Pure(Some(closest_material(
-                                bvh,
-                                tris,
-                                vec3(x as f64 + 0.5, *y + 0.5, *z + 0.5)
-                            )));
+                        let material = depths.material().ok().flatten().unwrap_or_else(|| {
+                            closest_material(bvh, tris, vec3(x as f64 + 0.5, *y + 0.5, *z + 0.5))
+                        });
+                        *chunk.voxel_mut(vec3(x.rem_euclid(chunk_size), mod_y, mod_z)) = Voxel::Pure(Some(material));
                         x += 1;
                     }
                 }
 
-                inside = (inside + grad).rem_euclid(2);
+                depths.cross(gradients);
                 start = max.ceil() as i32;
             }
         }
 
         chunks
     }
-}
 
-impl From<MaterialMesh> for Voxels {
-    fn from(mesh: MaterialMesh) -> Self {
+    /// Voxelizes a mesh given in world coordinates.
+    /// The mesh is mapped into grid coordinates first,
+    /// so slicing and cube intersection happen in voxel units.
+    /// Errors report the grid position of the voxel that failed.
+    pub fn with_options(mesh: MaterialMesh, options: &VoxelizationOptions) -> Result<Self, VoxelizationError> {
+        Self::with_options_and_report(mesh, options).map(|(voxels, _)| voxels)
+    }
+
+    /// Voxelizes a mesh given in world coordinates like `with_options`,
+    /// and also reports complex voxels that needed a fallback.
+    pub fn with_options_and_report(
+        mesh: MaterialMesh,
+        options: &VoxelizationOptions,
+    ) -> Result<(Self, VoxelizationReport), VoxelizationError> {
+        options.check()?;
+        if options.validate_input() {
+            let validation = mesh.validate();
+            if !validation.is_valid() {
+                return Err(VoxelizationError::InvalidMesh(validation));
+            }
+        }
+
+        let transform = options.transform();
+        let crop = options.grid_crop();
+        let size = options.chunk_size();
+        let mesh = mesh.transformed(transform.world_to_grid_matrix());
         let (bvh, triangles) = mesh.bvh();
 
         // Uniform chunks
-        let slices = mesh.axis_slice(Axis::Z, Chunk::SIZE as f64);
+        let slices = mesh.axis_slice(Axis::Z, size as f64)?;
         let mut slices: Vec<(f64, f64, MaterialMesh)> = slices
             .into_par_iter()
-            .flat_map_iter(|(z, slice)| {
-                slice
-                    .axis_slice(Axis::Y, Chunk::SIZE as f64)
+            .map(|(z, slice)| -> Result<_, VoxelizationError> {
+                Ok(slice
+                    .axis_slice(Axis::Y, size as f64)?
                     .into_iter()
-                    .map(move |(y, slice)| (y, z, slice))
+                    .map(move |(y, slice)| (y, z, slice)))
             })
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .flatten()
             .collect::<Vec<_>>();
 
         // Obtain ranges as a map from (y, z) coords to a vector of (min, max, in-out gradient) tuples
@@ -354,65 +1062,66 @@ impl From<MaterialMesh> for Voxels {
             // Using mutable reference only because MaterialMesh is not Sync
             .par_iter_mut()
             .map(|(y, z, slice)| {
-                slice.align_with_slice_planes(Axis::X, Chunk::SIZE as f64);
+                slice.align_with_slice_planes(Axis::X, size as f64);
                 (
                     *y,
                     *z,
-                    slice.axis_ranges_and_in_out_gradients(
+                    slice.axis_ranges_and_material_gradients(
                         Axis::X,
-                        (Chunk::SIZE * Chunk::SIZE) as f64,
+                        (size * size) as f64,
                     ),
                 )
             })
             .collect::<Vec<_>>();
 
-        let mut voxels = Self::new();
+        let mut voxels = Self::new(transform, size);
+        voxels.materials = mesh.materials().clone();
 
-        voxels.fill_uniform_chunks(ranges_yz, &bvh, &triangles);
+        voxels.fill_uniform_chunks(ranges_yz, &bvh, &triangles, crop);
 
         // Pure/complex voxels
         let complex_chunks = slices
             .into_par_iter()
-            .flat_map_iter(|(chunk_y, chunk_z, slice)| {
-                let mut slices: Vec<(f64, f64, MaterialMesh)> = slice
-                    .axis_slice(Axis::Z, 1.0)
-                    .into_iter()
-                    .flat_map(|(z, slice)| {
-                        slice
-                            .axis_slice(Axis::Y, 1.0)
-                            .into_iter()
-                            .map(move |(y, slice)| (y, z, slice))
-                    })
-                    .collect::<Vec<_>>();
+            .map(|(chunk_y, chunk_z, slice)| -> Result<_, VoxelizationError> {
+                let mut slices: Vec<(f64, f64, MaterialMesh)> = vec![];
+                for (z, slice) in slice.axis_slice(Axis::Z, 1.0)? {
+                    let y_slices = slice.axis_slice(Axis::Y, 1.0)?;
+                    slices.extend(y_slices.into_iter().map(|(y, slice)| (y, z, slice)));
+                }
 
                 let ranges_yz = slices
                     // Using mutable reference only because MaterialMesh is not Sync
                     .iter_mut()
                     .map(|(y, z, slice)| {
                         slice.align_with_slice_planes(Axis::X, 1.0);
-                        (*y, *z, slice.axis_ranges_and_in_out_gradients(Axis::X, 1.0))
+                        (*y, *z, slice.axis_ranges_and_material_gradients(Axis::X, 1.0))
                     })
                     .collect::<Vec<_>>();
 
-                let chunks = voxels.ranges_to_complex_chunks(ranges_yz, &bvh, &triangles);
+                let chunks = voxels.ranges_to_complex_chunks(ranges_yz, &bvh, &triangles, crop);
 
-                let slices = slices
-                    .into_iter()
-                    .flat_map(|(y, z, slice)| {
-                        slice
-                            .axis_slice(Axis::X, 1.0)
-                            .into_iter()
-                            .map(move |(x, slice)| (vec3(x, y, z), slice))
-                    })
-                    .collect::<Vec<_>>();
-
-                let chunks = voxels.add_complex_voxels_to_chunks(slices, chunks);
+                let mut x_slices = vec![];
+                for (y, z, slice) in slices {
+                    let row = slice.axis_slice(Axis::X, 1.0)?;
+                    x_slices.extend(row.into_iter().map(|(x, slice)| (vec3(x, y, z), slice)));
+                }
 
-                chunks.into_iter().map(|(k, v)| (k, Chunk::Complex(v)))
+                voxels.add_complex_voxels_to_chunks(
+                    x_slices,
+                    chunks,
+                    crop,
+                    options.fail_on_fallback(),
+                    options.face_triangulation(),
+                )
             })
-            .collect::<Vec<_>>();
+            .collect::<Result<Vec<_>, _>>()?;
 
-        voxels.chunks.extend(complex_chunks.into_iter());
+        let mut report = VoxelizationReport::default();
+        for (chunks, fallbacks) in complex_chunks {
+            voxels.chunks.extend(chunks.into_iter().map(|(k, v)| (k, Chunk::Complex(v))));
+            report.fallbacks.extend(fallbacks);
+        }
+        report.fallbacks.sort_by_key(|fallback| (fallback.pos.z, fallback.pos.y, fallback.pos.x));
 
         //let objs = std::fs::read_dir("assets/debug/complex")
         //    .unwrap()
@@ -436,19 +1145,36 @@ impl From<MaterialMesh> for Voxels {
         //    });
         //MaterialMesh::new(mesh).export_debug_obj("assets/debug/complex_voxels_2.obj");
 
-        voxels
+        Ok((voxels, report))
     }
 }
 
+impl TryFrom<MaterialMesh> for Voxels {
+    type Error = VoxelizationError;
+
+    fn try_from(mesh: MaterialMesh) -> Result<Self, Self::Error> {
+        Self::with_options(mesh, &VoxelizationOptions::default())
+    }
+}
+
+/// Iterates over all offsets in a chunk with some edge length, in z-major order
+pub(crate) fn chunk_offsets(size: usize) -> impl Iterator<Item = Vec3i> {
+    let size = size as i32;
+    (0..size).flat_map(move |z| (0..size).flat_map(move |y| (0..size).map(move |x| vec3(x, y, z))))
+}
+
 /// A chunk. Can be uniform or complex
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Chunk {
     Uniform(MaterialID),
     Complex(ComplexChunk),
 }
 
 impl Chunk {
-    pub const SIZE: usize = 16;
+    /// Default edge length of a chunk in voxels
+    pub const DEFAULT_SIZE: usize = 16;
+    /// Max edge length of a chunk in voxels, so a complex chunk's voxels fit in memory
+    pub const MAX_SIZE: usize = 256;
 
     fn is_uniform(&self) -> bool {
         if let Chunk::Uniform(_) = self {
@@ -460,24 +1186,76 @@ impl Chunk {
 }
 
 /// A complex chunk. Contains a grid of voxels.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct ComplexChunk {
-    voxels: [Voxel; Chunk::SIZE * Chunk::SIZE * Chunk::SIZE],
+    /// Edge length in voxels
+    size: usize,
+    voxels: Vec<Voxel>,
     complex: Vec<ComplexVoxel>,
 }
 
 impl ComplexChunk {
-    fn new() -> Self {
+    fn new(size: usize) -> Self {
         Self {
-            voxels: [Voxel::Pure(None); Chunk::SIZE * Chunk::SIZE * Chunk::SIZE],
+            size,
+            voxels: vec![Voxel::Pure(None); size * size * size],
             complex: vec![],
         }
     }
 
+    /// A chunk full of pure voxels with the same material
+    fn filled(size: usize, material: Option<MaterialID>) -> Self {
+        Self {
+            size,
+            voxels: vec![Voxel::Pure(material); size * size * size],
+            complex: vec![],
+        }
+    }
+
+    /// If all voxels are pure with the same material, returns that material
+    fn uniform_material(&self) -> Option<Option<MaterialID>> {
+        match self.voxels[0] {
+            Voxel::Pure(material) if self.voxels.iter().all(|voxel| *voxel == Voxel::Pure(material)) => {
+                Some(material)
+            }
+            _ => None,
+        }
+    }
+
+    /// Sets a voxel to be pure, removing the complex voxel it replaces from the table
+    fn set_pure(&mut self, offset: Vec3i, material: Option<MaterialID>) {
+        let index = self.offset_to_index(offset);
+
+        if let Voxel::Complex(complex) = self.voxels[index] {
+            self.remove_complex(complex);
+        }
+        self.voxels[index] = Voxel::Pure(material);
+    }
+
+    /// Removes a complex voxel from the table, keeping the table compact
+    /// by moving the last complex voxel into its place.
+    fn remove_complex(&mut self, complex: u32) {
+        let last = self.complex.len() as u32 - 1;
+        self.complex.swap_remove(complex as usize);
+
+        if complex != last {
+            if let Some(voxel) = self.voxels.iter_mut().find(|voxel| **voxel == Voxel::Complex(last)) {
+                *voxel = Voxel::Complex(complex);
+            }
+        }
+    }
+
+    /// Edge length of the chunk in voxels
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
     fn add_to_debug_mesh(&self, chunk_pos: Vec3i, builder: &mut DebugMeshBuilder) {
-        for z in 0..Chunk::SIZE as i32 {
-            for y in 0..Chunk::SIZE as i32 {
-                for x in 0..Chunk::SIZE as i32 {
+        let size = self.size as i32;
+
+        for z in 0..size {
+            for y in 0..size {
+                for x in 0..size {
                     match self.voxel(vec3(x, y, z)) {
                         Voxel::Pure(Some(mat)) =>
                             // Cheaply remove landlocked voxels
@@ -493,15 +1271,15 @@ impl ComplexChunk {
                             .any(|vec| {
                                 let vec = vec + vec3(x, y, z);
                                 vec.x < 0
-                                    || vec.x >= Chunk::SIZE as i32
+                                    || vec.x >= size
                                     || vec.y < 0
-                                    || vec.y >= Chunk::SIZE as i32
+                                    || vec.y >= size
                                     || vec.z < 0
-                                    || vec.z >= Chunk::SIZE as i32
+                                    || vec.z >= size
                                     || self.voxel(vec).is_empty()
                             }) {
                                 builder.add_cube(
-                                    (chunk_pos * Chunk::SIZE as i32 + vec3(x, y, z))
+                                    (chunk_pos * size + vec3(x, y, z))
                                         .cast()
                                         .unwrap(),
                                     1.0,
@@ -512,7 +1290,7 @@ impl ComplexChunk {
                         Voxel::Pure(None) => {} // empty voxel
 
                         Voxel::Complex(index) => {
-                            let boundary = self.complex[index as usize].boundary(chunk_pos * Chunk::SIZE as i32 + vec3(x, y, z));
+                            let boundary = self.complex[index as usize].boundary(chunk_pos * size + vec3(x, y, z));
                             builder.add_mesh(boundary.0, boundary.1);
                         }
                     }
@@ -521,23 +1299,98 @@ impl ComplexChunk {
         }
     }
 
-    fn offset_to_index(offset: Vec3i) -> usize {
-        (offset.z as usize * Chunk::SIZE + offset.y as usize) * Chunk::SIZE + offset.x as usize
+    fn offset_to_index(&self, offset: Vec3i) -> usize {
+        (offset.z as usize * self.size + offset.y as usize) * self.size + offset.x as usize
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for voxel in &self.voxels {
+            match voxel {
+                Voxel::Pure(None) => writer.write_all(&[0])?,
+                Voxel::Pure(Some(material)) => {
+                    writer.write_all(&[1])?;
+                    write_u32(writer, material.0.get())?;
+                }
+                Voxel::Complex(index) => {
+                    writer.write_all(&[2])?;
+                    write_u32(writer, *index)?;
+                }
+            }
+        }
+
+        write_u32(writer, self.complex.len() as u32)?;
+        for complex in &self.complex {
+            complex.write_to(writer)?;
+        }
+
+        Ok(())
+    }
+
+    fn read_from<R: Read>(reader: &mut R, size: usize) -> io::Result<Self> {
+        let mut chunk = Self::new(size);
+
+        for voxel in chunk.voxels.iter_mut() {
+            let mut kind = [0];
+            reader.read_exact(&mut kind)?;
+            *voxel = match kind[0] {
+                0 => Voxel::Pure(None),
+                1 => Voxel::Pure(Some(read_material(reader)?)),
+                2 => Voxel::Complex(read_u32(reader)?),
+                kind => return Err(invalid_data(format!("Invalid voxel kind {}", kind))),
+            };
+        }
+
+        for _ in 0..read_u32(reader)? {
+            chunk.complex.push(ComplexVoxel::read_from(reader)?);
+        }
+
+        // Complex voxel indexes must point into the table
+        if chunk.voxels.iter().any(|voxel| match voxel {
+            Voxel::Complex(index) => *index as usize >= chunk.complex.len(),
+            Voxel::Pure(_) => false,
+        }) {
+            return Err(invalid_data("Complex voxel index out of range"));
+        }
+
+        Ok(chunk)
+    }
+
+    /// Get the voxel at a certain offset in the chunk, resolving complex voxel indexes
+    pub fn voxel_ref(&self, offset: Vec3i) -> VoxelRef {
+        match self.voxel(offset) {
+            Voxel::Pure(None) => VoxelRef::Empty,
+            Voxel::Pure(Some(material)) => VoxelRef::Pure(material),
+            Voxel::Complex(index) => VoxelRef::Complex(&self.complex[index as usize]),
+        }
+    }
+
+    /// Gets the bounds of the non-empty voxels in the chunk as (min inclusive, max exclusive) offsets
+    fn bounds(&self) -> Option<(Vec3i, Vec3i)> {
+        chunk_offsets(self.size)
+            .filter(|offset| !self.voxel(*offset).is_empty())
+            .fold(None, |bounds, pos| match bounds {
+                None => Some((pos, pos + vec3(1, 1, 1))),
+                Some((min, max)) => Some((
+                    vec3(min.x.min(pos.x), min.y.min(pos.y), min.z.min(pos.z)),
+                    vec3(max.x.max(pos.x + 1), max.y.max(pos.y + 1), max.z.max(pos.z + 1)),
+                )),
+            })
     }
 
     /// Get the voxel at a certain offset in the chunk
     pub fn voxel(&self, offset: Vec3i) -> Voxel {
-        self.voxels[Self::offset_to_index(offset)]
+        self.voxels[self.offset_to_index(offset)]
     }
 
     /// Get a mutable reference to the voxel at a certain offset in the chunk
     pub fn voxel_mut(&mut self, offset: Vec3i) -> &mut Voxel {
-        &mut self.voxels[Self::offset_to_index(offset)]
+        let index = self.offset_to_index(offset);
+        &mut self.voxels[index]
     }
 }
 
 /// A complex chunk entry. Can be a pure voxel or an index to a complex voxel
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Voxel {
     Pure(Option<MaterialID>),
     Complex(u32),
@@ -553,11 +1406,27 @@ impl Voxel {
     }
 }
 
+/// A voxel as seen from outside the voxelization.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum VoxelRef<'a> {
+    Empty,
+    /// A voxel filled with a single material
+    Pure(MaterialID),
+    /// A voxel partially filled with possibly multiple materials
+    Complex(&'a ComplexVoxel),
+}
+
+impl<'a> VoxelRef<'a> {
+    pub fn is_empty(self) -> bool {
+        self == VoxelRef::Empty
+    }
+}
+
 /// A complex voxel, including inner vertices and hulls.
 /// Each hull contains indexes to points.
 /// Inner vertices have indexes 8 and greater.
 /// Corner point (x, y, z) where x, y, z ∈ {0, 1} has index x + 2y + 4z
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct ComplexVoxel {
     /// Vertices that are not corners of the cube.
     inner_vertices: Vec<Vec3>,
@@ -567,8 +1436,74 @@ pub struct ComplexVoxel {
 impl ComplexVoxel {
     pub const MAX_HULL_SIZE: usize = 8;
 
-    fn new(hulls: Vec<Vec<Vec3>>) -> Self {
-        let vertices = hulls.iter().flat_map(|hull| hull.iter().copied().map(HashVec3))
+    /// Gets a vertex by index. Positions are relative to the voxel's min corner, in grid units.
+    pub fn vertex(&self, index: u32) -> Vec3 {
+        if index < 8 {
+            vec3((index & 1) as f64, (index >> 1 & 1) as f64, (index >> 2 & 1) as f64)
+        } else {
+            self.inner_vertices[index as usize - 8]
+        }
+    }
+
+    /// Vertices that are not corners of the voxel
+    pub fn inner_vertices(&self) -> &[Vec3] {
+        &self.inner_vertices
+    }
+
+    /// Iterates over the convex hulls as their vertex positions and material.
+    /// Positions are relative to the voxel's min corner, in grid units.
+    pub fn hulls(&self) -> impl Iterator<Item = (Vec<Vec3>, MaterialID)> + '_ {
+        self.hulls.iter().map(move |(hull, material)| {
+            (hull.iter().map(|index| self.vertex(*index)).collect(), *material)
+        })
+    }
+
+    /// Volume of each hull along with its material, in grid units.
+    pub fn hull_volumes(&self) -> impl Iterator<Item = (f64, MaterialID)> + '_ {
+        self.hulls().map(|(hull, material)| {
+            // Flat hulls have no volume, and the hull algorithm needs a tet to start with
+            let volume = if hull.len() < 4 {
+                0.0
+            } else {
+                Self::convex_hull(hull)
+                    .into_iter()
+                    .map(|[a, b, c]| a.dot(b.cross(c)) / 6.0)
+                    .sum::<f64>()
+                    .abs()
+            };
+            (volume, material)
+        })
+    }
+
+    /// Boundary triangles of each hull along with its material, facing outward.
+    /// Positions are relative to the voxel's min corner, in grid units.
+    pub(crate) fn hull_triangles(&self) -> impl Iterator<Item = ([Vec3; 3], MaterialID)> + '_ {
+        self.hulls()
+            .filter(|(hull, _)| hull.len() >= 4)
+            .flat_map(|(hull, material)| Self::convex_hull(hull).into_iter().map(move |tri| (tri, material)))
+    }
+
+    /// Total volume of each material in the voxel, in grid units
+    pub fn material_volumes(&self) -> FnvHashMap<MaterialID, f64> {
+        let mut volumes = FnvHashMap::default();
+        for (volume, material) in self.hull_volumes() {
+            *volumes.entry(material).or_insert(0.0) += volume;
+        }
+        volumes
+    }
+
+    /// The material taking up the most volume in the voxel, if there are any hulls
+    pub fn majority_material(&self) -> Option<MaterialID> {
+        self.material_volumes()
+            .into_iter()
+            .max_by_key(|(material, volume)| (FloatOrd(*volume), Reverse(*material)))
+            .map(|(material, _)| material)
+    }
+
+    /// Constructs a complex voxel from hulls given by their vertices and material.
+    /// Vertices are relative to the voxel's min corner.
+    fn new(hulls: Vec<(Vec<Vec3>, MaterialID)>) -> Self {
+        let vertices = hulls.iter().flat_map(|(hull, _)| hull.iter().copied().map(HashVec3))
             .collect::<FnvHashSet<_>>();
         
         // including corners
@@ -584,10 +1519,10 @@ impl ComplexVoxel {
                 
         let hulls = hulls
             .into_iter()
-            .map(|hull| (hull
+            .map(|(hull, material)| (hull
                 .into_iter()
                 .map(|pos| index_map[&HashVec3(pos)] as u32)
-                .collect::<FnvHashSet<_>>().into_iter().collect(), MaterialID::new(1)))
+                .collect::<FnvHashSet<_>>().into_iter().collect(), material))
             .collect();
                 
         Self {
@@ -596,6 +1531,50 @@ impl ComplexVoxel {
         }
     }
 
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write_u32(writer, self.inner_vertices.len() as u32)?;
+        for vertex in &self.inner_vertices {
+            write_vec3(writer, *vertex)?;
+        }
+
+        write_u32(writer, self.hulls.len() as u32)?;
+        for (hull, material) in &self.hulls {
+            write_u32(writer, material.0.get())?;
+            write_u32(writer, hull.len() as u32)?;
+            for index in hull {
+                write_u32(writer, *index)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let inner_vertices = (0..read_u32(reader)?)
+            .map(|_| read_vec3(reader))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let num_vertices = 8 + inner_vertices.len() as u32;
+        let hulls = (0..read_u32(reader)?)
+            .map(|_| {
+                let material = read_material(reader)?;
+                let hull = (0..read_u32(reader)?)
+                    .map(|_| read_u32(reader))
+                    .collect::<io::Result<Vec<_>>>()?;
+
+                if hull.iter().any(|index| *index >= num_vertices) {
+                    return Err(invalid_data("Hull vertex index out of range"));
+                }
+                Ok((hull, material))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            inner_vertices,
+            hulls,
+        })
+    }
+
     fn toggle_face(edges: &mut FnvHashMap<[usize; 2], usize>, face: [usize; 3]) {
         if edges.get(&[face[1], face[0]]).unwrap_or(&face[0]) == &face[2] {
             for [i, j] in vec![[1, 0], [2, 1], [0, 2]] {
@@ -615,9 +1594,10 @@ impl ComplexVoxel {
 
         let mut first_tet = [0, 1, 2, 3];
         for tet in combine::combine_vec(&(0..points.len()).collect(), 4) {
-            let det = (points[1] - points[0]).cross(points[2] - points[0]).dot(points[3] - points[0]);
-            if det.abs() >= 1e-5 {
-                first_tet = if det >= 0.0 {
+            let [p0, p1, p2, p3] = [points[tet[0]], points[tet[1]], points[tet[2]], points[tet[3]]];
+            let det = predicates::orient3d(p0, p1, p2, p3);
+            if det != 0.0 {
+                first_tet = if det > 0.0 {
                     [tet[0], tet[1], tet[3], tet[2]]
                 } else {
                     [tet[0], tet[1], tet[2], tet[3]]
@@ -634,7 +1614,7 @@ impl ComplexVoxel {
         for (i, pos) in points.iter().enumerate() {
             if !added.contains(&i) {
                 if let Some([v0, v1, v2]) = edges.iter().find(|([v0, v1], v2)| {
-                    (points[*v1] - points[*v0]).cross(points[**v2] - points[*v0]).dot(pos - points[*v0]) > 1e-5
+                    predicates::orient3d(points[*v0], points[*v1], points[**v2], *pos) > 0.0
                 }).map(|([v0, v1], v2)| [*v0, *v1, *v2]) {
                     added.insert(i);
 
@@ -651,8 +1631,8 @@ impl ComplexVoxel {
                 while let Some([v0, v1]) = angle_check.pop() {
                     // Check for concavity
                     if let (Some(v2), Some(v3)) = (edges.get(&[v0, v1]).copied(), edges.get(&[v1, v0]).copied()) {
-                        // Epsilon to avoid infinite loop
-                        if (points[v1] - points[v0]).cross(points[v2] - points[v0]).dot(points[v3] - points[v0]) > 1e-10 {
+                        // Exact, so coplanar faces don't flip back and forth forever
+                        if predicates::orient3d(points[v0], points[v1], points[v2], points[v3]) > 0.0 {
                             // Flip!
                             Self::toggle_face(&mut edges, [v0, v2, v1]);
                             Self::toggle_face(&mut edges, [v0, v1, v3]);
@@ -713,3 +1693,385 @@ impl ComplexVoxel {
         (positions, faces.into_iter().collect())
     }
 }
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+fn write_u32<W: Write>(writer: &mut W, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_vec3<W: Write>(writer: &mut W, vec: Vec3) -> io::Result<()> {
+    for i in 0..3 {
+        writer.write_all(&vec[i].to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_i32<R: Read>(reader: &mut R) -> io::Result<i32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(i32::from_le_bytes(bytes))
+}
+
+fn read_vec3<R: Read>(reader: &mut R) -> io::Result<Vec3> {
+    let mut coords = [0.0; 3];
+    for coord in coords.iter_mut() {
+        let mut bytes = [0; 8];
+        reader.read_exact(&mut bytes)?;
+        *coord = f64::from_le_bytes(bytes);
+    }
+    Ok(vec3(coords[0], coords[1], coords[2]))
+}
+
+fn read_material<R: Read>(reader: &mut R) -> io::Result<MaterialID> {
+    NonZeroU32::new(read_u32(reader)?)
+        .map(MaterialID)
+        .ok_or_else(|| invalid_data("Material ID is 0"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::material_mesh::box_corners_and_triangles;
+
+    /// A closed box with outward faces
+    fn box_mesh(min: Vec3, max: Vec3, material: MaterialID) -> MaterialMesh {
+        let mut builder = DebugMeshBuilder::new();
+        add_box(&mut builder, min, max, material);
+        builder.build().unwrap()
+    }
+
+    fn add_box(builder: &mut DebugMeshBuilder, min: Vec3, max: Vec3, material: MaterialID) {
+        let (corners, triangles) = box_corners_and_triangles(min, max);
+        builder.add_mesh(corners, triangles.into_iter().map(|tri| (tri, material)).collect());
+    }
+
+    #[test]
+    fn test_convex_hull_coplanar_start() {
+        // The first 4 points are the bottom of a unit cube, so they can't be the first tet
+        let points = (0..8)
+            .map(|i| vec3((i & 1) as f64, ((i >> 1) & 1) as f64, (i >> 2) as f64))
+            .collect::<Vec<_>>();
+        let hull = ComplexVoxel::convex_hull(points);
+
+        assert_eq!(hull.len(), 12);
+        let volume = hull.iter().map(|[a, b, c]| a.dot(b.cross(*c)) / 6.0).sum::<f64>();
+        assert!((volume.abs() - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_grid_transform() {
+        let transform = VoxelizationOptions::new()
+            .with_anisotropic_voxel_size(vec3(0.5, 1.0, 2.0))
+            .with_origin(vec3(1.0, -1.0, 0.0))
+            .transform();
+
+        assert_eq!(transform.world_to_grid(vec3(2.0, 0.0, 3.0)), vec3(2.0, 1.0, 1.5));
+        assert_eq!(transform.grid_to_world(vec3(2.0, 1.0, 1.5)), vec3(2.0, 0.0, 3.0));
+        assert_eq!(transform.voxel_index(vec3(2.0, 0.0, 3.0)), vec3(2, 1, 1));
+        // Rounds down on the negative side too
+        assert_eq!(transform.voxel_index(vec3(0.9, -1.5, -0.1)), vec3(-1, -1, -1));
+
+        let grid = transform.world_to_grid_matrix().transform_point(Point3::new(2.0, 0.0, 3.0));
+        assert!((grid.to_vec() - vec3(2.0, 1.0, 1.5)).magnitude() < 1e-12);
+    }
+
+    #[test]
+    fn test_invalid_voxel_size() {
+        for size in vec![vec3(1.0, 0.0, 1.0), vec3(-1.0, 1.0, 1.0), vec3(1.0, 1.0, f64::NAN), vec3(f64::INFINITY, 1.0, 1.0)] {
+            let mesh = box_mesh(vec3(0.5, 0.5, 0.5), vec3(1.5, 1.5, 1.5), MaterialID::new(1));
+            let options = VoxelizationOptions::new().with_anisotropic_voxel_size(size);
+            assert!(matches!(
+                Voxels::with_options(mesh, &options),
+                Err(VoxelizationError::InvalidOptions(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_grid_crop() {
+        let options = VoxelizationOptions::new()
+            .with_voxel_size(0.5)
+            .with_crop(vec3(0.2, 0.0, -0.3), vec3(1.0, 0.75, 0.3));
+        let crop = options.grid_crop();
+        assert_eq!(crop, Some((vec3(0, 0, -1), vec3(2, 2, 1))));
+
+        assert!(in_crop(crop, vec3(0, 0, -1)));
+        assert!(in_crop(crop, vec3(1, 1, 0)));
+        assert!(!in_crop(crop, vec3(2, 1, 0)));
+        assert!(!in_crop(crop, vec3(0, 0, -2)));
+        assert!(in_crop(None, vec3(100, -100, 0)));
+    }
+
+    #[test]
+    fn test_chunk_size_uniform_chunks() {
+        let material = MaterialID::new(1);
+        let voxelize = |chunk_size| {
+            let mesh = box_mesh(vec3(0.5, 0.5, 0.5), vec3(4.5, 4.5, 4.5), material);
+            Voxels::with_options(mesh, &VoxelizationOptions::new().with_chunk_size(chunk_size)).unwrap()
+        };
+        let voxels = vec![voxelize(1), voxelize(2), voxelize(4)];
+
+        // The 3x3x3 voxels fully inside the box can be uniform chunks only if chunks fit inside them
+        assert_eq!(voxels[0].chunk_counts(), (27, 98));
+        assert_eq!(voxels[1].chunk_counts(), (1, 26));
+        assert_eq!(voxels[2].chunk_counts(), (0, 8));
+
+        for chunk_size in vec![0, Chunk::MAX_SIZE + 1] {
+            let mesh = box_mesh(vec3(0.5, 0.5, 0.5), vec3(4.5, 4.5, 4.5), material);
+            let options = VoxelizationOptions::new().with_chunk_size(chunk_size);
+            assert!(matches!(
+                Voxels::with_options(mesh, &options),
+                Err(VoxelizationError::InvalidOptions(_))
+            ));
+        }
+
+        // Chunk size doesn't change what the voxels are
+        for other in &voxels[1..] {
+            assert_eq!(other.iter().count(), voxels[0].iter().count());
+            for (pos, voxel) in voxels[0].iter() {
+                let same = match (voxel, other.get(pos)) {
+                    (VoxelRef::Pure(a), VoxelRef::Pure(b)) => a == b,
+                    (VoxelRef::Complex(_), VoxelRef::Complex(_)) => true,
+                    _ => false,
+                };
+                assert!(same, "Voxel at {:?} differs", pos);
+            }
+        }
+    }
+
+    #[test]
+    fn test_get_bounds_iter() {
+        let m1 = MaterialID::new(1);
+        let m2 = MaterialID::new(2);
+        let mut voxels = Voxels::default();
+        assert_eq!(voxels.bounds(), None);
+        assert_eq!(voxels.iter().count(), 0);
+        assert_eq!(voxels.get(vec3(0, 0, 0)), VoxelRef::Empty);
+
+        // One uniform chunk and one complex chunk
+        voxels.fill_box(vec3(0, 0, 0), vec3(16, 16, 16), Some(m1));
+        voxels.set(vec3(-1, 2, 3), Some(m2));
+        assert_eq!(voxels.chunk_counts(), (1, 1));
+
+        assert_eq!(voxels.get(vec3(0, 0, 0)), VoxelRef::Pure(m1));
+        assert_eq!(voxels.get(vec3(15, 15, 15)), VoxelRef::Pure(m1));
+        assert_eq!(voxels.get(vec3(16, 0, 0)), VoxelRef::Empty);
+        assert_eq!(voxels.get(vec3(-1, 2, 3)), VoxelRef::Pure(m2));
+        assert_eq!(voxels.get(vec3(-2, 2, 3)), VoxelRef::Empty);
+        assert_eq!(voxels.get(vec3(-1000, 0, 1000)), VoxelRef::Empty);
+
+        assert_eq!(voxels.bounds(), Some((vec3(-1, 0, 0), vec3(16, 16, 16))));
+
+        let all = voxels.iter().collect::<FnvHashMap<_, _>>();
+        assert_eq!(all.len(), 16 * 16 * 16 + 1);
+        assert_eq!(all[&vec3(-1, 2, 3)], VoxelRef::Pure(m2));
+        assert_eq!(all[&vec3(7, 8, 9)], VoxelRef::Pure(m1));
+        assert!(all.keys().all(|pos| voxels.get(*pos) == all[pos]));
+    }
+
+    #[test]
+    fn test_get_complex() {
+        let material = MaterialID::new(1);
+        let voxels = Voxels::try_from(box_mesh(vec3(0.5, 0.5, 0.5), vec3(2.5, 2.5, 2.5), material)).unwrap();
+
+        assert_eq!(voxels.bounds(), Some((vec3(0, 0, 0), vec3(3, 3, 3))));
+        assert_eq!(voxels.get(vec3(1, 1, 1)), VoxelRef::Pure(material));
+        assert_eq!(voxels.get(vec3(3, 1, 1)), VoxelRef::Empty);
+        assert_eq!(voxels.get(vec3(-1, 0, 0)), VoxelRef::Empty);
+
+        match voxels.get(vec3(0, 1, 1)) {
+            VoxelRef::Complex(voxel) => {
+                // Half of the voxel is inside the box
+                let volume = voxel.material_volumes()[&material];
+                assert!((volume - 0.5).abs() < 1e-9);
+            }
+            voxel => panic!("Expected a complex voxel, got {:?}", voxel),
+        }
+        assert_eq!(voxels.iter().count(), 27);
+    }
+
+    #[test]
+    fn test_set_promotes_and_collapses() {
+        let m1 = MaterialID::new(1);
+        let m2 = MaterialID::new(2);
+        let mut voxels = Voxels::default();
+
+        voxels.fill_box(vec3(0, 0, 0), vec3(16, 16, 16), Some(m1));
+        assert_eq!(voxels.chunk_counts(), (1, 0));
+
+        // Setting a voxel to the chunk's material changes nothing
+        voxels.set(vec3(3, 3, 3), Some(m1));
+        assert_eq!(voxels.chunk_counts(), (1, 0));
+
+        // Setting it to something else splits the uniform chunk
+        voxels.set(vec3(3, 3, 3), Some(m2));
+        assert_eq!(voxels.chunk_counts(), (0, 1));
+        assert_eq!(voxels.get(vec3(3, 3, 3)), VoxelRef::Pure(m2));
+        assert_eq!(voxels.get(vec3(3, 3, 4)), VoxelRef::Pure(m1));
+
+        // Setting it back collapses the chunk again
+        voxels.set(vec3(3, 3, 3), Some(m1));
+        assert_eq!(voxels.chunk_counts(), (1, 0));
+
+        voxels.clear(vec3(15, 0, 0));
+        assert_eq!(voxels.chunk_counts(), (0, 1));
+        assert_eq!(voxels.get(vec3(15, 0, 0)), VoxelRef::Empty);
+        assert_eq!(voxels.iter().count(), 16 * 16 * 16 - 1);
+    }
+
+    #[test]
+    fn test_fill_box() {
+        let m1 = MaterialID::new(1);
+        let m2 = MaterialID::new(2);
+        let mut voxels = Voxels::default();
+
+        // Spans a whole chunk and parts of its neighbors
+        voxels.fill_box(vec3(-2, 0, 0), vec3(18, 16, 16), Some(m1));
+        assert_eq!(voxels.chunk_counts(), (1, 2));
+        assert_eq!(voxels.bounds(), Some((vec3(-2, 0, 0), vec3(18, 16, 16))));
+        assert_eq!(voxels.iter().count(), 20 * 16 * 16);
+
+        // Filling the rest of the neighbors makes them uniform too
+        voxels.fill_box(vec3(-16, 0, 0), vec3(-2, 16, 16), Some(m1));
+        voxels.fill_box(vec3(18, 0, 0), vec3(32, 16, 16), Some(m1));
+        assert_eq!(voxels.chunk_counts(), (3, 0));
+
+        // Emptying part of a uniform chunk, then all of it
+        voxels.fill_box(vec3(0, 0, 0), vec3(8, 16, 16), None);
+        assert_eq!(voxels.chunk_counts(), (2, 1));
+        assert_eq!(voxels.get(vec3(7, 5, 5)), VoxelRef::Empty);
+        assert_eq!(voxels.get(vec3(8, 5, 5)), VoxelRef::Pure(m1));
+        voxels.fill_box(vec3(8, 0, 0), vec3(16, 16, 16), None);
+        assert_eq!(voxels.chunk_counts(), (2, 0));
+        assert_eq!(voxels.bounds(), Some((vec3(-16, 0, 0), vec3(32, 16, 16))));
+
+        // Empty boxes do nothing
+        voxels.fill_box(vec3(0, 0, 0), vec3(0, 16, 16), Some(m2));
+        assert_eq!(voxels.chunk_counts(), (2, 0));
+    }
+
+    fn write(voxels: &Voxels) -> Vec<u8> {
+        let mut bytes = vec![];
+        voxels.write_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_save_load_round_trip() {
+        let material = MaterialID::new(2);
+        let options = VoxelizationOptions::new().with_voxel_size(0.5).with_origin(vec3(-1.0, 0.0, 2.0));
+        let mut voxels = Voxels::with_options(box_mesh(vec3(-0.8, 0.2, 2.1), vec3(0.3, 1.2, 2.9), material), &options)
+            .unwrap();
+        voxels.materials_mut().insert(material, MaterialInfo::new("steel"));
+        voxels.set(vec3(-20, 5, 0), Some(MaterialID::new(3)));
+        assert!(voxels.iter().any(|(_, voxel)| matches!(voxel, VoxelRef::Complex(_))));
+
+        let bytes = write(&voxels);
+        let read = Voxels::read_from(&mut &bytes[..]).unwrap();
+        assert_eq!(read, voxels);
+        // Deterministic output
+        assert_eq!(write(&read), bytes);
+    }
+
+    #[test]
+    fn test_export_obj_unwritable() {
+        let mut voxels = Voxels::default();
+        voxels.set(vec3(0, 0, 0), Some(MaterialID::new(1)));
+        let dir = std::env::temp_dir().join("voxelization_missing_dir");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(voxels.export_obj(dir.join("voxels.obj")).is_err());
+        let surface = voxels.surface().unwrap().unwrap();
+        assert!(surface.export_obj(dir.join("surface.obj")).is_err());
+    }
+
+    #[test]
+    fn test_load_corrupt_header() {
+        let mut voxels = Voxels::default();
+        voxels.fill_box(vec3(0, 0, 0), vec3(20, 3, 3), Some(MaterialID::new(1)));
+        let bytes = write(&voxels);
+
+        // Header is magic, version, origin, voxel size, chunk size
+        let corrupt = |offset: usize, value: &[u8]| {
+            let mut bytes = bytes.clone();
+            bytes[offset..offset + value.len()].copy_from_slice(value);
+            Voxels::read_from(&mut &bytes[..]).unwrap_err().kind()
+        };
+
+        assert_eq!(corrupt(0, b"ABCD"), io::ErrorKind::InvalidData);
+        assert_eq!(corrupt(4, &99u32.to_le_bytes()), io::ErrorKind::InvalidData);
+        assert_eq!(corrupt(8, &f64::NAN.to_le_bytes()), io::ErrorKind::InvalidData);
+        assert_eq!(corrupt(32, &0f64.to_le_bytes()), io::ErrorKind::InvalidData);
+        assert_eq!(corrupt(40, &(-1f64).to_le_bytes()), io::ErrorKind::InvalidData);
+        assert_eq!(corrupt(48, &f64::INFINITY.to_le_bytes()), io::ErrorKind::InvalidData);
+        assert_eq!(corrupt(56, &0u32.to_le_bytes()), io::ErrorKind::InvalidData);
+        assert_eq!(corrupt(56, &u32::MAX.to_le_bytes()), io::ErrorKind::InvalidData);
+
+        // Truncated file
+        let kind = Voxels::read_from(&mut &bytes[..bytes.len() - 1]).unwrap_err().kind();
+        assert_eq!(kind, io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_voxelize_cropped() {
+        let material = MaterialID::new(1);
+        let min = vec3(0.5, 0.5, 0.5);
+        let max = vec3(4.5, 4.5, 4.5);
+
+        let full = Voxels::with_options(box_mesh(min, max, material), &VoxelizationOptions::new()).unwrap();
+        let options = VoxelizationOptions::new().with_crop(vec3(0.0, 0.0, 0.0), vec3(1.5, 5.0, 5.0));
+        let cropped = Voxels::with_options(box_mesh(min, max, material), &options).unwrap();
+
+        assert_eq!(full.bounds(), Some((vec3(0, 0, 0), vec3(5, 5, 5))));
+        assert_eq!(cropped.bounds(), Some((vec3(0, 0, 0), vec3(2, 5, 5))));
+
+        // Voxels inside the crop box are the same as without cropping
+        for (pos, voxel) in cropped.iter() {
+            assert_eq!(voxel, full.get(pos));
+        }
+        assert_eq!(cropped.iter().count(), full.iter().filter(|(pos, _)| pos.x < 2).count());
+    }
+
+    #[test]
+    fn test_fallback() {
+        let material = MaterialID::new(1);
+        let voxels = Voxels::new(VoxelizationOptions::new().with_voxel_size(0.5).transform(), 4);
+
+        // An L shape in voxel (2, 2, 2), whose convex hull also fills the inner corner
+        let mut builder = DebugMeshBuilder::new();
+        add_box(&mut builder, vec3(2.0, 2.0, 2.0), vec3(3.0, 3.0, 2.5), material);
+        add_box(&mut builder, vec3(2.0, 2.0, 2.5), vec3(2.5, 3.0, 3.0), material);
+        let mesh = builder.build().unwrap();
+        let pos = vec3(2.0, 2.0, 2.0);
+
+        let (voxel, fallback) =
+            voxels.complex_voxel_or_fallback(&mesh, pos, Err(TetError::EmptyBoundary), false).unwrap();
+        let fallback = fallback.unwrap();
+        assert_eq!(fallback.pos, vec3(2, 2, 2));
+        assert_eq!(fallback.error, TetError::EmptyBoundary);
+        // The hull is 0.875 voxels and the L is 0.75, and a voxel is 0.125 in world units
+        assert!((fallback.volume_error - 0.125 * 0.125).abs() < 1e-9);
+        assert_eq!(voxel.majority_material(), Some(material));
+
+        let report = VoxelizationReport { fallbacks: vec![fallback] };
+        assert!((report.total_volume_error() - 0.125 * 0.125).abs() < 1e-9);
+
+        let error = voxels
+            .complex_voxel_or_fallback(&mesh, pos, Err(TetError::EmptyBoundary), true)
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            VoxelizationError::AtVoxel(at, ref error)
+                if at == vec3(2, 2, 2) && matches!(**error, VoxelizationError::Tet(TetError::EmptyBoundary))
+        ));
+    }
+}